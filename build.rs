@@ -0,0 +1,52 @@
+//! Embeds the sql migrations of every backend into the binary
+//!
+//! For each migration directory a constant list of `Migration`s is written to
+//! `$OUT_DIR/migrations.rs`, sorted by version. The version is the date prefix
+//! of the directory name without dashes, the same as diesel uses.
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+const DIRECTORIES: &[(&str, &str)] = &[
+    ("MYSQL", "migrations"),
+    ("POSTGRES", "migrations_postgres"),
+    ("SQLITE", "migrations_sqlite"),
+];
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let mut out = File::create(Path::new(&out_dir).join("migrations.rs")).unwrap();
+
+    for (constant, directory) in DIRECTORIES {
+        let directory = Path::new(&manifest_dir).join(directory);
+        println!("cargo:rerun-if-changed={}", directory.display());
+
+        let mut migrations: Vec<_> = fs::read_dir(&directory)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.join("up.sql").is_file())
+            .collect();
+        migrations.sort();
+
+        writeln!(out, "pub const {}: &[Migration] = &[", constant).unwrap();
+        for path in migrations {
+            let dir_name = path.file_name().unwrap().to_str().unwrap();
+            let mut parts = dir_name.splitn(2, '_');
+            let version = parts.next().unwrap().replace("-", "");
+            let name = parts.next().unwrap_or("");
+
+            writeln!(
+                out,
+                "    Migration {{ version: {:?}, name: {:?}, up: include_str!({:?}), down: include_str!({:?}) }},",
+                version,
+                name,
+                path.join("up.sql").display().to_string(),
+                path.join("down.sql").display().to_string(),
+            ).unwrap();
+        }
+        writeln!(out, "];").unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+//! A cache of `get_user`, kept fresh by the service
+//!
+//! Valid tokens are cached for `CacheOptions::ttl`, but never past their
+//! expiry, and tokens the service
+//! refused for `CacheOptions::negative_ttl`, so a flood of bogus tokens does
+//! not reach the service either. A refused token never becomes valid, tokens
+//! are only made by `authenticate`.
+//!
+//! Next to the cache runs a subscription to `invalidations`, which drops the
+//! entries of every user whose session was revoked or whose role changed, so
+//! that is seen right away instead of after the TTL. The cache is only used
+//! while the subscription is up. When it fails, everything is dropped and
+//! calls go to the service until the subscription is back.
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_core::reactor::Timeout;
+
+use datatypes::auth::responses::*;
+use datatypes::valid::token::Token;
+
+use client::AuthClient;
+use error::Error;
+use {Cursor, Invalidations, UserInfo};
+
+/// Seconds to wait before subscribing again after the subscription failed
+const RESUBSCRIBE_DELAY_SECS: u64 = 1;
+
+/// How much is cached, and for how long
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheOptions {
+    /// Number of tokens which are cached at most
+    pub capacity: usize,
+    /// How long a valid token is cached
+    pub ttl: Duration,
+    /// How long a token the service refused is cached
+    pub negative_ttl: Duration,
+}
+
+impl Default for CacheOptions {
+    fn default() -> CacheOptions {
+        CacheOptions {
+            capacity: 10_000,
+            ttl: Duration::from_secs(30),
+            negative_ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What the service said about a token
+#[derive(Debug, Clone, PartialEq)]
+enum Answer {
+    User(UserInfo),
+    Invalid,
+}
+
+struct Entry {
+    answer: Answer,
+    expires: Instant,
+}
+
+struct Cache {
+    options: CacheOptions,
+    entries: HashMap<Token, Entry>,
+    /// Whether the subscription is up, nothing is cached while it is not
+    subscribed: bool,
+    /// Counts the invalidations, an answer which was asked for before an
+    /// invalidation may be stale, so it is not cached
+    generation: u64,
+}
+
+impl Cache {
+    fn new(options: CacheOptions) -> Cache {
+        Cache {
+            options,
+            entries: HashMap::new(),
+            subscribed: false,
+            generation: 0,
+        }
+    }
+
+    fn get(&self, token: &Token, now: Instant) -> Option<Answer> {
+        if !self.subscribed {
+            return None;
+        }
+        self.entries
+            .get(token)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.answer.clone())
+    }
+
+    /// Caches an answer, unless it was asked for before the last
+    /// invalidation
+    fn insert(&mut self, token: Token, answer: Answer, generation: u64, now: Instant) {
+        if !self.subscribed || generation != self.generation {
+            return;
+        }
+        if self.entries.len() >= self.options.capacity && !self.entries.contains_key(&token) {
+            self.entries.retain(|_, entry| entry.expires > now);
+        }
+        if self.entries.len() >= self.options.capacity && !self.entries.contains_key(&token) {
+            let soonest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(token, _)| token.clone());
+            match soonest {
+                Some(soonest) => {
+                    self.entries.remove(&soonest);
+                }
+                None => return,
+            }
+        }
+        let ttl = match answer {
+            Answer::User(ref user) => self.options.ttl.min(until(user.expires_at)),
+            Answer::Invalid => self.options.negative_ttl,
+        };
+        self.entries.insert(
+            token,
+            Entry {
+                answer,
+                expires: now + ttl,
+            },
+        );
+    }
+
+    fn apply(&mut self, invalidations: &Invalidations) {
+        if invalidations.reset {
+            self.entries.clear();
+        } else if !invalidations.users.is_empty() {
+            let users = &invalidations.users;
+            self.entries.retain(|_, entry| match entry.answer {
+                Answer::User(ref user) => !users.contains(&user.id),
+                Answer::Invalid => true,
+            });
+        }
+        if invalidations.reset || !invalidations.users.is_empty() {
+            self.generation += 1;
+        }
+        self.subscribed = true;
+    }
+
+    fn unsubscribe(&mut self) {
+        self.entries.clear();
+        self.generation += 1;
+        self.subscribed = false;
+    }
+}
+
+/// An [`AuthClient`] which caches `get_user`
+///
+/// Cloned clients share their cache.
+#[derive(Clone)]
+pub struct CachedClient {
+    client: AuthClient,
+    cache: Rc<RefCell<Cache>>,
+}
+
+impl CachedClient {
+    /// Wraps `client`, and subscribes to invalidations on its reactor
+    ///
+    /// The subscription ends when the last clone of the client is dropped,
+    /// at the latest `LONG_POLL_SECS` later.
+    pub fn new(client: AuthClient, options: CacheOptions) -> CachedClient {
+        let cache = Rc::new(RefCell::new(Cache::new(options)));
+        client
+            .handle()
+            .spawn(subscribe(client.clone(), Rc::downgrade(&cache)));
+        CachedClient { client, cache }
+    }
+
+    /// The client without the cache, for the other rpcs
+    pub fn client(&self) -> &AuthClient {
+        &self.client
+    }
+
+    pub fn get_user(&self, token: Token) -> impl Future<Item = UserInfo, Error = Error> {
+        let cached = self.cache.borrow().get(&token, Instant::now());
+        match cached {
+            Some(Answer::User(user)) => return Either::A(future::ok(user)),
+            Some(Answer::Invalid) => {
+                // Not asked for now, so there is no request in the service
+                let error = Error::Service(AuthError::InvalidToken, String::new(), None);
+                return Either::A(future::err(error));
+            }
+            None => {}
+        }
+
+        let cache = self.cache.clone();
+        let generation = cache.borrow().generation;
+        Either::B(self.client.get_user(token.clone()).then(move |result| {
+            let answer = match result {
+                Ok(ref user) => Some(Answer::User(user.clone())),
+                Err(Error::Service(AuthError::InvalidToken, _, _)) => Some(Answer::Invalid),
+                Err(_) => None,
+            };
+            if let Some(answer) = answer {
+                cache
+                    .borrow_mut()
+                    .insert(token, answer, generation, Instant::now());
+            }
+            result
+        }))
+    }
+}
+
+/// Time left until `timestamp`, in seconds since the epoch
+fn until(timestamp: i64) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    Duration::from_secs(timestamp.saturating_sub(now).max(0) as u64)
+}
+
+/// Long-polls `invalidations` for as long as the cache is alive
+fn subscribe(
+    client: AuthClient,
+    cache: Weak<RefCell<Cache>>,
+) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn(Cursor::default(), move |cursor| {
+        let cache = cache.clone();
+        let handle = client.handle().clone();
+        let delay = Duration::from_secs(RESUBSCRIBE_DELAY_SECS);
+        client.invalidations(cursor).then(move |result| {
+            let cache = match cache.upgrade() {
+                Some(cache) => cache,
+                None => return Either::A(future::ok(Loop::Break(()))),
+            };
+            match result {
+                Ok(invalidations) => {
+                    cache.borrow_mut().apply(&invalidations);
+                    Either::A(future::ok(Loop::Continue(invalidations.cursor)))
+                }
+                Err(e) => {
+                    warn!(
+                        "Lost the subscription to invalidations, not caching until it is back: {}",
+                        e
+                    );
+                    cache.borrow_mut().unsubscribe();
+                    Either::B(
+                        future::result(Timeout::new(delay, &handle))
+                            .flatten()
+                            .map(|_| Loop::Continue(Cursor::default()))
+                            .map_err(|e| error!("Unable to wait before subscribing again: {}", e)),
+                    )
+                }
+            }
+        })
+    })
+}
+
+#[test]
+fn test_cache() {
+    let options = CacheOptions {
+        capacity: 2,
+        ..CacheOptions::default()
+    };
+    let mut cache = Cache::new(options.clone());
+    let token = |token: &str| Token::new(token.to_owned());
+    let user = |id: u32| {
+        Answer::User(UserInfo {
+            version: ::USER_INFO_VERSION,
+            id: id.into(),
+            username: format!("user{}", id),
+            role: "user".into(),
+            verified: true,
+            issued_at: 0,
+            expires_at: i64::max_value(),
+        })
+    };
+    let now = Instant::now();
+
+    // Nothing is cached before the subscription is up
+    cache.insert(token("a"), user(1), 0, now);
+    assert_eq!(None, cache.get(&token("a"), now));
+
+    cache.apply(&Invalidations {
+        cursor: Cursor::default(),
+        reset: true,
+        users: vec![],
+    });
+    let generation = cache.generation;
+    cache.insert(token("a"), user(1), generation, now);
+    cache.insert(token("bogus"), Answer::Invalid, generation, now);
+    assert_eq!(Some(user(1)), cache.get(&token("a"), now));
+    assert_eq!(Some(Answer::Invalid), cache.get(&token("bogus"), now));
+
+    // Refused tokens expire first
+    let later = now + options.negative_ttl;
+    assert_eq!(None, cache.get(&token("bogus"), later));
+    assert_eq!(Some(user(1)), cache.get(&token("a"), later));
+    assert_eq!(None, cache.get(&token("a"), now + options.ttl));
+
+    // A full cache makes room by dropping the entry which expires first
+    cache.insert(token("b"), user(2), generation, now);
+    assert_eq!(2, cache.entries.len());
+    assert_eq!(None, cache.get(&token("bogus"), now));
+
+    // An invalidation drops every token of the user, and answers which were
+    // asked for before it
+    cache.apply(&Invalidations {
+        cursor: Cursor::default(),
+        reset: false,
+        users: vec![1.into()],
+    });
+    assert_eq!(None, cache.get(&token("a"), now));
+    assert_eq!(Some(user(2)), cache.get(&token("b"), now));
+    cache.insert(token("a"), user(1), generation, now);
+    assert_eq!(None, cache.get(&token("a"), now));
+
+    cache.unsubscribe();
+    assert_eq!(None, cache.get(&token("b"), now));
+}
@@ -0,0 +1,534 @@
+//! Pooled connections, with timeouts and retries
+//!
+//! Calls are spread over `Options::connections` connections in turn. Each
+//! connection is opened on its first call, and dropped when a call on it
+//! fails with an io error or times out, so the next call on it reconnects.
+//!
+//! Only the calls which are safe to repeat are retried: `get_user`,
+//! `get_users`, `set_user_role`, `health`, `webhooks`, `dead_letters`,
+//! `list_users`, `get_user_by_id` and `update_user`. The others might have
+//! been handled before the connection broke, e.g. logging in twice would open
+//! two sessions.
+use futures::future::{self, Either, Loop};
+use futures::{stream, Future, Stream};
+use std::cell::{Cell, RefCell};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+use tarpc::future::client::{ClientExt, Options as TarpcOptions};
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+use datatypes::auth::requests::*;
+use datatypes::auth::responses::*;
+use datatypes::content::requests::AddUserPayload;
+use datatypes::valid::ids::UserId;
+use datatypes::valid::token::Token;
+
+use error::Error;
+use {
+    AddWebhookPayload, Cursor, DeadLetter, Event, FutureClient, HealthReport, Invalidations,
+    ListUsersPayload, NewWebhook, ReplayReport, ServiceError, UpdateUserPayload, UserInfo,
+    UserPage, UserProfile, Webhook, LONG_POLL_SECS,
+};
+
+/// How a client talks to the service
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// Number of connections which are used in turn
+    pub connections: usize,
+    /// How long a call may take, connecting included
+    pub timeout: Duration,
+    /// How often a call which is safe to repeat is tried again after an io
+    /// error or a timeout
+    pub retries: u32,
+    /// How long to wait before the first retry, doubled for every next one
+    pub retry_delay: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            connections: 4,
+            timeout: Duration::from_secs(5),
+            retries: 2,
+            retry_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+type Call<T> = Box<Future<Item = T, Error = Error>>;
+
+/// The connections of a client, which are opened when they are first used
+struct Pool {
+    address: SocketAddr,
+    handle: Handle,
+    connections: RefCell<Vec<Option<FutureClient>>>,
+    next: Cell<usize>,
+}
+
+impl Pool {
+    /// Forgets a connection, the next call on it reconnects
+    fn drop_connection(&self, slot: usize) {
+        if self.connections.borrow_mut()[slot].take().is_some() {
+            debug!("Dropped connection {} to {}", slot, self.address);
+        }
+    }
+}
+
+/// The next connection in turn, which is opened if it is not open
+fn checkout(pool: &Rc<Pool>) -> (usize, Call<FutureClient>) {
+    let slot = pool.next.get();
+    let count = pool.connections.borrow().len();
+    pool.next.set((slot + 1) % count);
+
+    if let Some(ref client) = pool.connections.borrow()[slot] {
+        return (slot, Box::new(future::ok(client.clone())));
+    }
+    trace!("Opening connection {} to {}", slot, pool.address);
+    let options = TarpcOptions::default().handle(pool.handle.clone());
+    let opened = pool.clone();
+    let connect = FutureClient::connect(pool.address, options)
+        .map_err(Error::Io)
+        .map(move |client| {
+            opened.connections.borrow_mut()[slot] = Some(client.clone());
+            client
+        });
+    (slot, Box::new(connect))
+}
+
+/// Makes a single call, which fails if it takes longer than `timeout`
+fn attempt<T, F, R>(pool: &Rc<Pool>, timeout: Duration, call: F) -> Call<T>
+where
+    T: 'static,
+    F: FnOnce(FutureClient) -> R + 'static,
+    R: Future<Item = T, Error = ::tarpc::Error<ServiceError>> + 'static,
+{
+    let deadline = match Timeout::new(timeout, &pool.handle) {
+        Ok(deadline) => deadline,
+        Err(e) => return Box::new(future::err(Error::Io(e))),
+    };
+    let (slot, client) = checkout(pool);
+    let response = client.and_then(|client| call(client).map_err(Error::from));
+
+    let pool = pool.clone();
+    Box::new(response.select2(deadline).then(move |result| {
+        let result = match result {
+            Ok(Either::A((value, _))) => Ok(value),
+            Ok(Either::B(_)) => Err(Error::Timeout),
+            Err(Either::A((e, _))) => Err(e),
+            Err(Either::B((e, _))) => Err(Error::Io(e)),
+        };
+        // The connection may be half open, which only shows as a timeout
+        if let Err(ref e) = result {
+            if e.is_transient() {
+                pool.drop_connection(slot);
+            }
+        }
+        result
+    }))
+}
+
+/// A client for code running on a tokio reactor
+///
+/// Cloned clients share their connections.
+#[derive(Clone)]
+pub struct AuthClient {
+    pool: Rc<Pool>,
+    options: Options,
+    /// Sent with every call, see [`AuthClient::with_request_id`]
+    request_id: Option<String>,
+}
+
+impl AuthClient {
+    /// Makes a client for the service on `address`
+    ///
+    /// Nothing is connected yet, so this does not fail if the service is
+    /// down. The first call on each connection connects it.
+    pub fn new(address: SocketAddr, options: Options, handle: &Handle) -> AuthClient {
+        let connections = options.connections.max(1);
+        AuthClient {
+            pool: Rc::new(Pool {
+                address,
+                handle: handle.clone(),
+                connections: RefCell::new(vec![None; connections]),
+                next: Cell::new(0),
+            }),
+            options,
+            request_id: None,
+        }
+    }
+
+    /// The same client, which sends `request_id` with its calls so the
+    /// service logs them under the id of the caller's own request
+    ///
+    /// Ids longer than 64 characters, or with other characters than ascii
+    /// letters, digits, `-` and `_`, are replaced by the service.
+    pub fn with_request_id(&self, request_id: &str) -> AuthClient {
+        AuthClient {
+            request_id: Some(request_id.to_owned()),
+            ..self.clone()
+        }
+    }
+
+    /// Makes a call once
+    fn once<T, F, R>(&self, call: F) -> Call<T>
+    where
+        T: 'static,
+        F: FnOnce(FutureClient) -> R + 'static,
+        R: Future<Item = T, Error = ::tarpc::Error<ServiceError>> + 'static,
+    {
+        attempt(&self.pool, self.options.timeout, call)
+    }
+
+    /// Makes a call which is safe to repeat, and tries it again after
+    /// transient errors
+    fn retry<T, F, R>(&self, call: F) -> Call<T>
+    where
+        T: 'static,
+        F: Fn(FutureClient) -> R + 'static,
+        R: Future<Item = T, Error = ::tarpc::Error<ServiceError>> + 'static,
+    {
+        let pool = self.pool.clone();
+        let Options {
+            timeout,
+            retries,
+            retry_delay,
+            ..
+        } = self.options.clone();
+        let call = Rc::new(call);
+
+        Box::new(future::loop_fn((0, retry_delay), move |(tries, delay)| {
+            let call = call.clone();
+            let handle = pool.handle.clone();
+            attempt(&pool, timeout, move |client| call(client)).then(move |result| {
+                match result {
+                    Err(ref e) if e.is_transient() && tries < retries => {
+                        debug!("Retrying in {:?} after: {}", delay, e);
+                        let wait = match Timeout::new(delay, &handle) {
+                            Ok(wait) => wait,
+                            Err(e) => return Either::B(future::err(Error::Io(e))),
+                        };
+                        Either::A(
+                            wait.map_err(Error::Io)
+                                .map(move |_| Loop::Continue((tries + 1, delay * 2))),
+                        )
+                    }
+                    result => Either::B(future::result(result.map(Loop::Break))),
+                }
+            })
+        }))
+    }
+
+    pub fn authenticate(&self, payload: AuthPayload) -> impl Future<Item = Token, Error = Error> {
+        let id = self.request_id.clone();
+        self.once(move |client| client.authenticate(id, payload))
+    }
+
+    pub fn deauthenticate(&self, token: Token) -> impl Future<Item = (), Error = Error> {
+        let id = self.request_id.clone();
+        self.once(move |client| client.deauthenticate(id, token))
+    }
+
+    pub fn register(
+        &self,
+        payload: RegisterUserPayload,
+    ) -> impl Future<Item = AddUserPayload, Error = Error> {
+        let id = self.request_id.clone();
+        self.once(move |client| client.register(id, payload))
+    }
+
+    pub fn get_user(&self, token: Token) -> impl Future<Item = UserInfo, Error = Error> {
+        let id = self.request_id.clone();
+        self.retry(move |client| client.get_user(id.clone(), token.clone()))
+    }
+
+    /// Looks up many tokens in one call, with a result per token
+    pub fn get_users(
+        &self,
+        tokens: Vec<Token>,
+    ) -> impl Future<Item = Vec<Result<UserInfo, AuthError>>, Error = Error> {
+        let id = self.request_id.clone();
+        self.retry(move |client| client.get_users(id.clone(), tokens.clone()))
+    }
+
+    pub fn set_user_role(
+        &self,
+        payload: SetUserRolePayload,
+    ) -> impl Future<Item = (), Error = Error> {
+        let id = self.request_id.clone();
+        self.retry(move |client| client.set_user_role(id.clone(), payload.clone()))
+    }
+
+    pub fn health(&self) -> impl Future<Item = HealthReport, Error = Error> {
+        let id = self.request_id.clone();
+        self.retry(move |client| client.health(id.clone()))
+    }
+
+    /// Waits for invalidations after `since`, see [`CachedClient`]
+    ///
+    /// The call takes up to `LONG_POLL_SECS` longer than `Options::timeout`.
+    ///
+    /// [`CachedClient`]: crate::CachedClient
+    pub fn invalidations(
+        &self,
+        since: Cursor,
+    ) -> impl Future<Item = Invalidations, Error = Error> {
+        let timeout = self.options.timeout + Duration::from_secs(LONG_POLL_SECS);
+        let id = self.request_id.clone();
+        attempt(&self.pool, timeout, move |client| client.invalidations(id, since))
+    }
+
+    /// Waits for the events after the one with id `since`, 0 for the first
+    ///
+    /// Only privileged clients may read events. The call takes up to
+    /// `LONG_POLL_SECS` longer than `Options::timeout`.
+    pub fn events(&self, since: u64) -> impl Future<Item = Vec<Event>, Error = Error> {
+        let timeout = self.options.timeout + Duration::from_secs(LONG_POLL_SECS);
+        let id = self.request_id.clone();
+        attempt(&self.pool, timeout, move |client| client.events(id, since))
+    }
+
+    /// Every event after the one with id `since`, as they happen
+    ///
+    /// The stream ends at the first error. Events are delivered at least
+    /// once, a consumer keeps the id of the last event it handled and
+    /// continues from there.
+    pub fn event_stream(&self, since: u64) -> impl Stream<Item = Event, Error = Error> {
+        let client = self.clone();
+        stream::unfold(since, move |since| {
+            Some(client.events(since).map(move |events| {
+                let next = events.last().map(|event| event.id).unwrap_or(since);
+                (stream::iter_ok(events), next)
+            }))
+        }).flatten()
+    }
+
+    /// Adds a webhook, see [`Webhook`]. Only admins may manage webhooks.
+    pub fn add_webhook(
+        &self,
+        payload: AddWebhookPayload,
+    ) -> impl Future<Item = NewWebhook, Error = Error> {
+        let id = self.request_id.clone();
+        self.once(move |client| client.add_webhook(id, payload))
+    }
+
+    /// Removes a webhook and its dead letters
+    pub fn remove_webhook(&self, token: Token, id: u32) -> impl Future<Item = (), Error = Error> {
+        let request_id = self.request_id.clone();
+        self.once(move |client| client.remove_webhook(request_id, token, id))
+    }
+
+    pub fn webhooks(&self, token: Token) -> impl Future<Item = Vec<Webhook>, Error = Error> {
+        let id = self.request_id.clone();
+        self.retry(move |client| client.webhooks(id.clone(), token.clone()))
+    }
+
+    /// The dead letters of a webhook, or of every webhook
+    pub fn dead_letters(
+        &self,
+        token: Token,
+        webhook_id: Option<u32>,
+    ) -> impl Future<Item = Vec<DeadLetter>, Error = Error> {
+        let id = self.request_id.clone();
+        self.retry(move |client| client.dead_letters(id.clone(), token.clone(), webhook_id))
+    }
+
+    /// Delivers the dead letters of a webhook, or of every webhook, once more.
+    /// The letters it had no time for are reported as pending.
+    pub fn replay_dead_letters(
+        &self,
+        token: Token,
+        webhook_id: Option<u32>,
+    ) -> impl Future<Item = ReplayReport, Error = Error> {
+        let id = self.request_id.clone();
+        self.once(move |client| client.replay_dead_letters(id, token, webhook_id))
+    }
+
+    /// A page of the users which match a filter, for admins
+    pub fn list_users(
+        &self,
+        payload: ListUsersPayload,
+    ) -> impl Future<Item = UserPage, Error = Error> {
+        let id = self.request_id.clone();
+        self.retry(move |client| client.list_users(id.clone(), payload.clone()))
+    }
+
+    /// The user with an id, for admins
+    pub fn get_user_by_id(
+        &self,
+        token: Token,
+        id: UserId,
+    ) -> impl Future<Item = UserProfile, Error = Error> {
+        let request_id = self.request_id.clone();
+        self.retry(move |client| client.get_user_by_id(request_id.clone(), token.clone(), id))
+    }
+
+    /// Changes a user, for admins. Setting the same values twice is the same
+    /// as once, so it is retried.
+    pub fn update_user(
+        &self,
+        payload: UpdateUserPayload,
+    ) -> impl Future<Item = UserProfile, Error = Error> {
+        let id = self.request_id.clone();
+        self.retry(move |client| client.update_user(id.clone(), payload.clone()))
+    }
+
+    /// The reactor the client runs on
+    pub(crate) fn handle(&self) -> &Handle {
+        &self.pool.handle
+    }
+}
+
+/// A client which blocks until the service responds
+///
+/// It runs an [`AuthClient`] on a reactor of its own.
+pub struct BlockingClient {
+    core: Core,
+    client: AuthClient,
+}
+
+impl BlockingClient {
+    /// Makes a client for the service on `address`, see [`AuthClient::new`]
+    pub fn new(address: SocketAddr, options: Options) -> Result<BlockingClient, Error> {
+        let core = Core::new().map_err(Error::Io)?;
+        let client = AuthClient::new(address, options, &core.handle());
+        Ok(BlockingClient { core, client })
+    }
+
+    /// Sends `request_id` with the following calls, see
+    /// [`AuthClient::with_request_id`]
+    pub fn set_request_id(&mut self, request_id: &str) {
+        self.client = self.client.with_request_id(request_id);
+    }
+
+    pub fn authenticate(&mut self, payload: AuthPayload) -> Result<Token, Error> {
+        let call = self.client.authenticate(payload);
+        self.core.run(call)
+    }
+
+    pub fn deauthenticate(&mut self, token: Token) -> Result<(), Error> {
+        let call = self.client.deauthenticate(token);
+        self.core.run(call)
+    }
+
+    pub fn register(&mut self, payload: RegisterUserPayload) -> Result<AddUserPayload, Error> {
+        let call = self.client.register(payload);
+        self.core.run(call)
+    }
+
+    pub fn get_user(&mut self, token: Token) -> Result<UserInfo, Error> {
+        let call = self.client.get_user(token);
+        self.core.run(call)
+    }
+
+    pub fn get_users(
+        &mut self,
+        tokens: Vec<Token>,
+    ) -> Result<Vec<Result<UserInfo, AuthError>>, Error> {
+        let call = self.client.get_users(tokens);
+        self.core.run(call)
+    }
+
+    pub fn set_user_role(&mut self, payload: SetUserRolePayload) -> Result<(), Error> {
+        let call = self.client.set_user_role(payload);
+        self.core.run(call)
+    }
+
+    pub fn health(&mut self) -> Result<HealthReport, Error> {
+        let call = self.client.health();
+        self.core.run(call)
+    }
+
+    pub fn events(&mut self, since: u64) -> Result<Vec<Event>, Error> {
+        let call = self.client.events(since);
+        self.core.run(call)
+    }
+
+    pub fn add_webhook(&mut self, payload: AddWebhookPayload) -> Result<NewWebhook, Error> {
+        let call = self.client.add_webhook(payload);
+        self.core.run(call)
+    }
+
+    pub fn remove_webhook(&mut self, token: Token, id: u32) -> Result<(), Error> {
+        let call = self.client.remove_webhook(token, id);
+        self.core.run(call)
+    }
+
+    pub fn webhooks(&mut self, token: Token) -> Result<Vec<Webhook>, Error> {
+        let call = self.client.webhooks(token);
+        self.core.run(call)
+    }
+
+    pub fn dead_letters(
+        &mut self,
+        token: Token,
+        webhook_id: Option<u32>,
+    ) -> Result<Vec<DeadLetter>, Error> {
+        let call = self.client.dead_letters(token, webhook_id);
+        self.core.run(call)
+    }
+
+    pub fn replay_dead_letters(
+        &mut self,
+        token: Token,
+        webhook_id: Option<u32>,
+    ) -> Result<ReplayReport, Error> {
+        let call = self.client.replay_dead_letters(token, webhook_id);
+        self.core.run(call)
+    }
+
+    pub fn list_users(&mut self, payload: ListUsersPayload) -> Result<UserPage, Error> {
+        let call = self.client.list_users(payload);
+        self.core.run(call)
+    }
+
+    pub fn get_user_by_id(&mut self, token: Token, id: UserId) -> Result<UserProfile, Error> {
+        let call = self.client.get_user_by_id(token, id);
+        self.core.run(call)
+    }
+
+    pub fn update_user(&mut self, payload: UpdateUserPayload) -> Result<UserProfile, Error> {
+        let call = self.client.update_user(payload);
+        self.core.run(call)
+    }
+}
+
+#[test]
+fn test_timeouts_and_retries() {
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    // Connections are accepted by the kernel, but nobody ever responds
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = Options {
+        connections: 2,
+        timeout: Duration::from_millis(100),
+        retries: 2,
+        retry_delay: Duration::from_millis(10),
+    };
+    let mut client = BlockingClient::new(silent.local_addr().unwrap(), options.clone()).unwrap();
+
+    let start = Instant::now();
+    match client.health() {
+        Err(Error::Timeout) => {}
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(300));
+
+    // Logging out twice is not the same as once, so it is not retried
+    let start = Instant::now();
+    match client.deauthenticate(Token::new("token".to_owned())) {
+        Err(Error::Timeout) => {}
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    assert!(start.elapsed() < Duration::from_millis(300));
+
+    // Nothing listens on the port any more
+    let address = silent.local_addr().unwrap();
+    drop(silent);
+    let mut client = BlockingClient::new(address, options).unwrap();
+    match client.get_user(Token::new("token".to_owned())) {
+        Err(Error::Io(_)) => {}
+        other => panic!("expected an io error, got {:?}", other),
+    }
+}
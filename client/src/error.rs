@@ -0,0 +1,53 @@
+use std::io;
+
+use datatypes::auth::responses::AuthError;
+
+use {ErrorDetail, ServiceError};
+
+/// Why a call failed
+#[derive(Debug, Fail)]
+pub enum Error {
+    /// The service handled the call, and refused it. The string is the id
+    /// of the request in the logs of the service, followed by what the error
+    /// is about if `AuthError` does not tell.
+    #[fail(display = "the service responded with {:?} (request id {})", _0, _1)]
+    Service(AuthError, String, Option<ErrorDetail>),
+    /// The service could not be reached, or the connection broke
+    #[fail(display = "unable to reach the service: {}", _0)]
+    Io(#[cause] io::Error),
+    /// The service did not respond within `Options::timeout`
+    #[fail(display = "the service did not respond in time")]
+    Timeout,
+    /// A request or a response could not be read
+    #[fail(display = "unable to read a message: {}", _0)]
+    Protocol(String),
+}
+
+impl Error {
+    /// Whether the call may have failed before the service handled it, so
+    /// that trying again on another connection can help
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(_) | Error::Timeout => true,
+            Error::Service(..) | Error::Protocol(_) => false,
+        }
+    }
+
+    /// The id of the request in the logs of the service, if it handled it
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            Error::Service(_, request_id, _) => Some(request_id),
+            _ => None,
+        }
+    }
+}
+
+impl From<::tarpc::Error<ServiceError>> for Error {
+    fn from(e: ::tarpc::Error<ServiceError>) -> Error {
+        match e {
+            ::tarpc::Error::App(e) => Error::Service(e.error, e.request_id, e.detail),
+            ::tarpc::Error::Io(e) => Error::Io(e),
+            e => Error::Protocol(format!("{:?}", e)),
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! The contract of the auth service, and clients for it
+//!
+//! The `service!` definition below is the only one, the server implements the
+//! `FutureService` it generates and every consumer talks to it through the
+//! same types, so the two can not drift apart.
+//!
+//! Every rpc takes the id of the request first. The service logs the call
+//! under that id, or a new one if none is given, and returns the id with
+//! every error, see [`ServiceError`].
+//!
+//! [`AuthClient`] is the client for code on a tokio reactor, and
+//! [`BlockingClient`] runs one on its own reactor for everything else. Both
+//! keep a pool of connections, reconnect when a connection breaks, give up on
+//! calls which take too long, and retry the calls which are safe to repeat.
+//! [`CachedClient`] adds a cache of `get_user` to an [`AuthClient`], which the
+//! service keeps fresh by pushing invalidations.
+//!
+//! Changes to users are published as [`Event`]s, which consumers read with
+//! [`AuthClient::event_stream`], have pushed to them by implementing
+//! [`sink::FutureService`], or have posted to a [`Webhook`].
+//!
+//! ```no_run
+//! # extern crate auth_client;
+//! # extern crate datatypes;
+//! use auth_client::{BlockingClient, Options};
+//! use datatypes::valid::token::Token;
+//!
+//! let mut client = BlockingClient::new(([127, 0, 0, 1], 10001).into(), Options::default()).unwrap();
+//! let user = client.get_user(Token::new("...".to_owned()));
+//! ```
+#![feature(plugin)]
+#![plugin(tarpc_plugins)]
+
+#[macro_use]
+extern crate tarpc;
+extern crate datatypes;
+#[macro_use]
+extern crate failure;
+extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate tokio_core;
+
+mod cache;
+mod client;
+mod error;
+pub mod sink;
+
+pub use cache::{CacheOptions, CachedClient};
+pub use client::{AuthClient, BlockingClient, Options};
+pub use error::Error;
+
+use datatypes::auth::requests::*;
+use datatypes::auth::responses::*;
+use datatypes::content::requests::AddUserPayload;
+use datatypes::valid::ids::UserId;
+use datatypes::valid::token::Token;
+
+service! {
+    rpc authenticate(request_id: Option<String>, payload: AuthPayload) -> Token | ServiceError;
+    rpc deauthenticate(request_id: Option<String>, payload: Token) -> () | ServiceError;
+    rpc register(request_id: Option<String>, payload: RegisterUserPayload) -> AddUserPayload | ServiceError;
+    rpc get_user(request_id: Option<String>, payload: Token) -> UserInfo | ServiceError;
+    rpc get_users(request_id: Option<String>, payload: Vec<Token>) -> Vec<Result<UserInfo, AuthError>> | ServiceError;
+    rpc set_user_role(request_id: Option<String>, payload: SetUserRolePayload) -> () | ServiceError;
+    rpc health(request_id: Option<String>) -> HealthReport | ServiceError;
+    rpc invalidations(request_id: Option<String>, since: Cursor) -> Invalidations | ServiceError;
+    rpc events(request_id: Option<String>, since: u64) -> Vec<Event> | ServiceError;
+    rpc add_webhook(request_id: Option<String>, payload: AddWebhookPayload) -> NewWebhook | ServiceError;
+    rpc remove_webhook(request_id: Option<String>, token: Token, id: u32) -> () | ServiceError;
+    rpc webhooks(request_id: Option<String>, token: Token) -> Vec<Webhook> | ServiceError;
+    rpc dead_letters(request_id: Option<String>, token: Token, webhook_id: Option<u32>) -> Vec<DeadLetter> | ServiceError;
+    rpc replay_dead_letters(request_id: Option<String>, token: Token, webhook_id: Option<u32>) -> ReplayReport | ServiceError;
+    rpc list_users(request_id: Option<String>, payload: ListUsersPayload) -> UserPage | ServiceError;
+    rpc get_user_by_id(request_id: Option<String>, token: Token, id: UserId) -> UserProfile | ServiceError;
+    rpc update_user(request_id: Option<String>, payload: UpdateUserPayload) -> UserProfile | ServiceError;
+}
+
+/// How long `invalidations` and `events` wait for something to happen before
+/// they return an empty batch
+pub const LONG_POLL_SECS: u64 = 30;
+
+/// Most users `list_users` returns at once
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+/// Why the service refused a call
+///
+/// The request id is the one the call is logged under in the service, so
+/// e.g. an internal error can be looked up there.
+#[derive(Serialize, Deserialize, Debug, Fail)]
+#[fail(display = "{:?} (request id {})", error, request_id)]
+pub struct ServiceError {
+    pub error: AuthError,
+    /// Set where `error` alone does not tell what went wrong
+    pub detail: Option<ErrorDetail>,
+    pub request_id: String,
+}
+
+/// What a [`ServiceError`] is about, for the errors `AuthError` has no
+/// variant of its own for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorDetail {
+    /// An `ExistingUser` because the email is taken, not the username
+    ExistingEmail,
+}
+
+/// Version of [`UserInfo`], raised whenever its fields change
+pub const USER_INFO_VERSION: u32 = 1;
+
+/// The user of a token, as told by `get_user`
+///
+/// Everything but the role is as it was when the user logged in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    /// The `USER_INFO_VERSION` of the service
+    pub version: u32,
+    pub id: UserId,
+    pub username: String,
+    pub role: Role,
+    /// Whether the user has verified their email address
+    pub verified: bool,
+    /// When the session started, in seconds since the epoch
+    pub issued_at: i64,
+    /// When the token expires, in seconds since the epoch
+    pub expires_at: i64,
+}
+
+/// The result of a single check
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+/// The result of all checks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    pub live: bool,
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+/// A position in the invalidations of a server
+///
+/// The epoch changes when the server restarts, so positions of different
+/// runs are never mixed up. The default cursor is not a position of any
+/// server, so it starts a subscription.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cursor {
+    pub epoch: u64,
+    pub position: u64,
+}
+
+/// What changed since a cursor
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Invalidations {
+    /// Where the next call should continue
+    pub cursor: Cursor,
+    /// The changes since the cursor are not known, so everything which was
+    /// cached must be dropped
+    pub reset: bool,
+    /// Users whose sessions were revoked, or who changed
+    pub users: Vec<UserId>,
+}
+
+/// A change to a user, as written to the outbox of the service
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    /// Position in the outbox, events are read and delivered in this order
+    pub id: u64,
+    /// When the change was made, in seconds since the epoch
+    pub created_at: i64,
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum EventKind {
+    UserRegistered { id: UserId, username: String },
+    /// An admin changed the username of the user
+    UserRenamed { id: UserId, username: String },
+    /// The user verified their email address, or it was unverified if
+    /// `verified` is false
+    UserVerified { id: UserId, verified: bool },
+    /// The user was banned, or unbanned if `banned` is false
+    UserBanned { id: UserId, banned: bool },
+    RoleChanged { id: UserId, role: Role },
+    UserDeleted { id: UserId },
+}
+
+/// The kinds of events, which webhooks are subscribed to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
+    UserRegistered,
+    UserRenamed,
+    UserVerified,
+    UserBanned,
+    RoleChanged,
+    UserDeleted,
+}
+
+impl EventKind {
+    pub fn event_type(&self) -> EventType {
+        match self {
+            EventKind::UserRegistered { .. } => EventType::UserRegistered,
+            EventKind::UserRenamed { .. } => EventType::UserRenamed,
+            EventKind::UserVerified { .. } => EventType::UserVerified,
+            EventKind::UserBanned { .. } => EventType::UserBanned,
+            EventKind::RoleChanged { .. } => EventType::RoleChanged,
+            EventKind::UserDeleted { .. } => EventType::UserDeleted,
+        }
+    }
+}
+
+/// A url the events of the chosen types are posted to, signed with a secret
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub id: u32,
+    pub url: String,
+    pub events: Vec<EventType>,
+    /// When the webhook was added, in seconds since the epoch
+    pub created_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddWebhookPayload {
+    /// Token of an admin
+    pub token: Token,
+    /// An `http://` or `https://` url
+    pub url: String,
+    /// The types of events to post, at least one
+    pub events: Vec<EventType>,
+}
+
+/// A webhook which was just added, with the secret its deliveries are signed
+/// with. The secret is only ever returned here.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NewWebhook {
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+/// An event a webhook did not accept after the last attempt
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub webhook_id: u32,
+    pub event: Event,
+    /// Number of deliveries which were tried, replays included
+    pub attempts: u32,
+    /// Why the last attempt failed
+    pub error: String,
+    /// When the last attempt failed, in seconds since the epoch
+    pub failed_at: i64,
+}
+
+/// What became of replayed dead letters
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayReport {
+    /// Delivered, and no longer dead letters
+    pub delivered: u32,
+    /// Failed again, and still dead letters
+    pub failed: u32,
+    /// Not tried before the replay ran out of time, and still dead letters
+    /// which the next replay tries
+    pub pending: u32,
+}
+
+/// Which users `list_users` returns, every filter which is set must match
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UserFilter {
+    /// Start of the username, in any case: both are compared in lowercase
+    pub username_prefix: Option<String>,
+    /// Start of the email address, compared like `username_prefix`
+    pub email_prefix: Option<String>,
+    pub role: Option<Role>,
+    pub banned: Option<bool>,
+    pub verified: Option<bool>,
+    /// Registered at or after, in seconds since the epoch
+    pub created_after: Option<i64>,
+    /// Registered before, in seconds since the epoch
+    pub created_before: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ListUsersPayload {
+    /// Token of an admin
+    pub token: Token,
+    pub filter: UserFilter,
+    /// The `next` of the previous page, none for the first page
+    pub after: Option<UserId>,
+    /// Most users in the page, 0 or more than `MAX_PAGE_SIZE` is
+    /// `MAX_PAGE_SIZE`
+    pub limit: u32,
+}
+
+/// A user as admins and privileged services see it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserProfile {
+    pub id: UserId,
+    pub username: String,
+    pub email: String,
+    pub role: Role,
+    pub banned: bool,
+    pub verified: bool,
+    /// When the user registered, in seconds since the epoch
+    pub created_at: i64,
+}
+
+/// Users by id, and where the next page starts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserPage {
+    pub users: Vec<UserProfile>,
+    /// The `after` of the next page, none if this is the last one
+    pub next: Option<UserId>,
+}
+
+/// Changes an admin makes to a user, fields which are none are left as they
+/// are
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateUserPayload {
+    /// Token of an admin
+    pub token: Token,
+    pub id: UserId,
+    /// Checked like the username of a registration
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub verified: Option<bool>,
+    pub banned: Option<bool>,
+}
@@ -0,0 +1,16 @@
+//! The contract of a service which has events pushed to it
+//!
+//! A service implements the `FutureService` generated here, and is configured
+//! as a `tarpc://<address>` sink of the auth service. Every event is delivered
+//! until the sink accepts it, in the order of the outbox, so a sink must
+//! handle an event it already got before.
+use Event;
+
+service! {
+    rpc deliver(event: Event) -> () | Rejected;
+}
+
+/// Why a sink did not take an event, it is delivered again later
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Fail)]
+#[fail(display = "the sink rejected the event: {}", _0)]
+pub struct Rejected(pub String);
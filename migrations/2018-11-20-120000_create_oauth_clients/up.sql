@@ -0,0 +1,9 @@
+CREATE TABLE oauth_clients (
+
+  id            VARCHAR(64) NOT NULL,
+  name          VARCHAR(255) NOT NULL,
+  secret_hash   VARCHAR(64),
+  redirect_uris TEXT NOT NULL,
+
+  PRIMARY KEY (id)
+);
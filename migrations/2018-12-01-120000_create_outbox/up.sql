@@ -0,0 +1,16 @@
+CREATE TABLE outbox (
+
+  id            BIGINT UNSIGNED AUTO_INCREMENT NOT NULL,
+  event         TEXT NOT NULL,
+  created_at    TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+  PRIMARY KEY (id)
+);
+
+CREATE TABLE event_sinks (
+
+  name          VARCHAR(64) NOT NULL,
+  position      BIGINT UNSIGNED DEFAULT 0 NOT NULL,
+
+  PRIMARY KEY (name)
+);
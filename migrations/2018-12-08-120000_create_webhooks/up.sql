@@ -0,0 +1,23 @@
+CREATE TABLE webhooks (
+
+  id            INT UNSIGNED AUTO_INCREMENT NOT NULL,
+  url           VARCHAR(2048) NOT NULL,
+  secret        VARCHAR(64) NOT NULL,
+  events        TEXT NOT NULL,
+  created_at    TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+  PRIMARY KEY (id)
+);
+
+CREATE TABLE dead_letters (
+
+  id            BIGINT UNSIGNED AUTO_INCREMENT NOT NULL,
+  webhook_id    INT UNSIGNED NOT NULL,
+  event         TEXT NOT NULL,
+  attempts      INT UNSIGNED NOT NULL,
+  error         TEXT NOT NULL,
+  failed_at     TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+  PRIMARY KEY (id),
+  FOREIGN KEY(webhook_id) REFERENCES webhooks(id) ON DELETE CASCADE
+);
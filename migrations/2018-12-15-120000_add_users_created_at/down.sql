@@ -0,0 +1,2 @@
+drop index users_created_at on users;
+alter table users drop column created_at;
@@ -0,0 +1,4 @@
+-- Users which registered before this migration get its time
+ALTER TABLE users ADD COLUMN created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL;
+
+CREATE INDEX users_created_at ON users (created_at);
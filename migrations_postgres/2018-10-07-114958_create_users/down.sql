@@ -0,0 +1,2 @@
+drop table roles;
+drop table users;
@@ -0,0 +1,21 @@
+CREATE TABLE users (
+
+  id            SERIAL NOT NULL,
+  email         VARCHAR(255) NOT NULL UNIQUE,
+  username      VARCHAR(20) NOT NULL UNIQUE,
+  password      TEXT NOT NULL,
+  banned        BOOLEAN DEFAULT FALSE NOT NULL,
+  verified      BOOLEAN DEFAULT FALSE NOT NULL,
+  email_token   VARCHAR(255),
+
+  PRIMARY KEY (id)
+);
+
+CREATE TABLE roles (
+
+  id            INTEGER NOT NULL,
+  name          VARCHAR(20) NOT NULL,
+
+  PRIMARY KEY (id),
+  FOREIGN KEY(id) REFERENCES users(id)
+);
@@ -0,0 +1,2 @@
+drop table event_sinks;
+drop table outbox;
@@ -0,0 +1,23 @@
+CREATE TABLE webhooks (
+
+  id            SERIAL NOT NULL,
+  url           VARCHAR(2048) NOT NULL,
+  secret        VARCHAR(64) NOT NULL,
+  events        TEXT NOT NULL,
+  created_at    TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+  PRIMARY KEY (id)
+);
+
+CREATE TABLE dead_letters (
+
+  id            BIGSERIAL NOT NULL,
+  webhook_id    INTEGER NOT NULL,
+  event         TEXT NOT NULL,
+  attempts      INTEGER NOT NULL,
+  error         TEXT NOT NULL,
+  failed_at     TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+  PRIMARY KEY (id),
+  FOREIGN KEY(webhook_id) REFERENCES webhooks(id) ON DELETE CASCADE
+);
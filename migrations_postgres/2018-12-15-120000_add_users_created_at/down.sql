@@ -0,0 +1,2 @@
+drop index users_created_at;
+alter table users drop column created_at;
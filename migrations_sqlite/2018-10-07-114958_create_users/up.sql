@@ -0,0 +1,18 @@
+CREATE TABLE users (
+
+  id            INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  email         VARCHAR(255) NOT NULL UNIQUE,
+  username      VARCHAR(20) NOT NULL UNIQUE,
+  password      TEXT NOT NULL,
+  banned        BOOLEAN DEFAULT 0 NOT NULL,
+  verified      BOOLEAN DEFAULT 0 NOT NULL,
+  email_token   VARCHAR(255)
+);
+
+CREATE TABLE roles (
+
+  id            INTEGER PRIMARY KEY NOT NULL,
+  name          VARCHAR(20) NOT NULL,
+
+  FOREIGN KEY(id) REFERENCES users(id)
+);
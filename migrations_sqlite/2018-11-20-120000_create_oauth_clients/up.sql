@@ -0,0 +1,7 @@
+CREATE TABLE oauth_clients (
+
+  id            VARCHAR(64) PRIMARY KEY NOT NULL,
+  name          VARCHAR(255) NOT NULL,
+  secret_hash   VARCHAR(64),
+  redirect_uris TEXT NOT NULL
+);
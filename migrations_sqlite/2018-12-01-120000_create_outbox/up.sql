@@ -0,0 +1,12 @@
+CREATE TABLE outbox (
+
+  id            INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  event         TEXT NOT NULL,
+  created_at    TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE TABLE event_sinks (
+
+  name          VARCHAR(64) PRIMARY KEY NOT NULL,
+  position      BIGINT DEFAULT 0 NOT NULL
+);
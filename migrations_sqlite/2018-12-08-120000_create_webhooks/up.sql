@@ -0,0 +1,20 @@
+CREATE TABLE webhooks (
+
+  id            INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  url           VARCHAR(2048) NOT NULL,
+  secret        VARCHAR(64) NOT NULL,
+  events        TEXT NOT NULL,
+  created_at    TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE TABLE dead_letters (
+
+  id            INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  webhook_id    INTEGER NOT NULL,
+  event         TEXT NOT NULL,
+  attempts      INTEGER NOT NULL,
+  error         TEXT NOT NULL,
+  failed_at     TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+  FOREIGN KEY(webhook_id) REFERENCES webhooks(id) ON DELETE CASCADE
+);
@@ -0,0 +1,18 @@
+drop index users_created_at;
+
+create table users_old (
+
+  id            INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  email         VARCHAR(255) NOT NULL UNIQUE,
+  username      VARCHAR(20) NOT NULL UNIQUE,
+  password      TEXT NOT NULL,
+  banned        BOOLEAN DEFAULT 0 NOT NULL,
+  verified      BOOLEAN DEFAULT 0 NOT NULL,
+  email_token   VARCHAR(255)
+);
+
+insert into users_old (id, email, username, password, banned, verified, email_token)
+  select id, email, username, password, banned, verified, email_token from users;
+
+drop table users;
+alter table users_old rename to users;
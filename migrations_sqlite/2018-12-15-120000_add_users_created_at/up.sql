@@ -0,0 +1,21 @@
+-- SQLite can not add a column with a default which is not constant, so the
+-- table is copied. Users which registered before this migration get its time.
+CREATE TABLE users_new (
+
+  id            INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  email         VARCHAR(255) NOT NULL UNIQUE,
+  username      VARCHAR(20) NOT NULL UNIQUE,
+  password      TEXT NOT NULL,
+  banned        BOOLEAN DEFAULT 0 NOT NULL,
+  verified      BOOLEAN DEFAULT 0 NOT NULL,
+  email_token   VARCHAR(255),
+  created_at    TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+INSERT INTO users_new (id, email, username, password, banned, verified, email_token)
+  SELECT id, email, username, password, banned, verified, email_token FROM users;
+
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+CREATE INDEX users_created_at ON users (created_at);
@@ -0,0 +1,362 @@
+//! Checks passwords by binding to an LDAP directory as the user
+//!
+//! The user binds as `ldap.user_dn` with `{username}` replaced, and the
+//! email and groups are read from their own entry. A directory can not tell
+//! an unknown user from a wrong password, so both are `InvalidPassword`.
+//!
+//! The first time a user logs in, they are added to the [`UserStore`] with
+//! [`EXTERNAL_PASSWORD`], so they get an id like every other user. Their
+//! role follows their groups in `ldap.group_roles`, and the server updates it
+//! on every login like `set_user_role` does, so roles of LDAP users are
+//! managed in the directory. A local user with the same username is never
+//! taken over.
+//!
+//! Talking to a real directory needs the `ldap` feature, the rest works
+//! against any [`Directory`].
+//!
+//! [`UserStore`]: crate::store::UserStore
+#[cfg(test)]
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use datatypes::auth::responses::AuthError;
+
+use super::{Authenticator, Identity, EXTERNAL_PASSWORD};
+use crate::config::LdapConfig;
+use crate::redact::{Masked, Secret};
+use crate::store::{Store, User};
+use crate::{logging, IntErrorKind, IntResult};
+
+/// The attributes of an entry, every attribute may have several values
+pub type Entry = HashMap<String, Vec<String>>;
+
+/// An LDAP directory
+pub trait Directory: Send + Sync {
+    /// Binds as `dn` and reads `attributes` of its entry
+    ///
+    /// Returns `None` if the directory rejects the password.
+    fn bind(&self, dn: &str, password: &str, attributes: &[&str]) -> IntResult<Option<Entry>>;
+}
+
+/// The `ldap` backend
+pub struct LdapAuthenticator {
+    config: LdapConfig,
+    directory: Box<Directory>,
+    store: Store,
+}
+
+impl LdapAuthenticator {
+    pub fn new(config: LdapConfig, directory: Box<Directory>, store: Store) -> LdapAuthenticator {
+        LdapAuthenticator {
+            config,
+            directory,
+            store,
+        }
+    }
+
+    /// The role of the first group in `group_roles` the user is in
+    fn role(&self, entry: &Entry) -> String {
+        let groups = entry
+            .get(&self.config.group_attribute)
+            .map(|groups| groups.as_slice())
+            .unwrap_or(&[]);
+        self.config
+            .group_roles
+            .iter()
+            .find(|mapping| {
+                groups
+                    .iter()
+                    .any(|group| group.eq_ignore_ascii_case(&mapping.group))
+            }).map(|mapping| mapping.role.clone())
+            .unwrap_or_else(|| self.config.default_role.clone())
+    }
+
+    /// Adds the user to the store on their first login, and returns whether
+    /// their role is not `role` anymore
+    fn provision(
+        &self,
+        username: &str,
+        entry: &Entry,
+        role: &str,
+    ) -> Result<(User, bool), AuthError> {
+        let user = match self.store.fetch_user(username) {
+            Ok(user) => {
+                if user.password != EXTERNAL_PASSWORD {
+                    warn!(
+                        "Refusing LDAP login of {}, a local user has the same username",
+                        Masked(username)
+                    );
+                    return Err(AuthError::InvalidUsername);
+                }
+                user
+            }
+            Err(ref e) if e.kind() == IntErrorKind::InvalidUsername => {
+                let email = match entry.get(&self.config.email_attribute) {
+                    Some(values) if !values.is_empty() => values[0].clone(),
+                    _ => {
+                        error!(
+                            "The LDAP entry of {} has no '{}'",
+                            Masked(username),
+                            self.config.email_attribute
+                        );
+                        return Err(AuthError::InternalServerError);
+                    }
+                };
+                let user = self
+                    .store
+                    .insert_user(username.to_owned(), email, EXTERNAL_PASSWORD.to_owned())
+                    .map_err(|e| {
+                        error!("Unable to provision LDAP user: {}", e);
+                        AuthError::InternalServerError
+                    })?;
+                info!("Provisioned LDAP user {}", user.id);
+                user
+            }
+            Err(e) => return Err(e.into()),
+        };
+        logging::set_user_id(user.id);
+
+        let current = match self.store.fetch_user_role(user.id) {
+            Ok(current) => current.name,
+            Err(e) => return Err(e.into()),
+        };
+        if current != role {
+            debug!("Changing the role of an LDAP user from {} to {}", current, role);
+        }
+        Ok((user, current != role))
+    }
+}
+
+impl Authenticator for LdapAuthenticator {
+    fn name(&self) -> &'static str {
+        "ldap"
+    }
+
+    fn authenticate(
+        &self,
+        username: &str,
+        password: &Secret<String>,
+    ) -> Result<Identity, AuthError> {
+        // An empty password is an anonymous bind, which most directories
+        // accept for any dn
+        if password.expose().is_empty() {
+            return Err(AuthError::InvalidPassword);
+        }
+
+        let dn = self
+            .config
+            .user_dn
+            .replace("{username}", &escape_dn_value(username));
+        let attributes = [
+            self.config.email_attribute.as_str(),
+            self.config.group_attribute.as_str(),
+        ];
+        let entry = match self.directory.bind(&dn, password.expose(), &attributes) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                trace!("The directory rejected the password");
+                return Err(AuthError::InvalidPassword);
+            }
+            Err(e) => {
+                error!("Unable to bind to the directory: {}", e);
+                return Err(AuthError::InternalServerError);
+            }
+        };
+
+        let role = self.role(&entry);
+        let (user, role_changed) = self.provision(username, &entry, &role)?;
+        Ok(Identity {
+            user_id: user.id,
+            username: user.username,
+            role,
+            verified: user.verified,
+            role_changed,
+        })
+    }
+}
+
+/// Escapes a value to be put in a dn, see section 2.4 of RFC 4514
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let special = match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => true,
+            '#' | ' ' if i == 0 => true,
+            ' ' if i == value.chars().count() - 1 => true,
+            _ => false,
+        };
+        if special {
+            escaped.push('\\');
+        }
+        if c == '\0' {
+            escaped.push_str("\\00");
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// A directory reached over the network (feature `ldap`)
+#[cfg(feature = "ldap")]
+pub struct LdapServer {
+    url: String,
+}
+
+#[cfg(feature = "ldap")]
+impl LdapServer {
+    pub fn new(url: &str) -> LdapServer {
+        LdapServer {
+            url: url.to_owned(),
+        }
+    }
+}
+
+#[cfg(feature = "ldap")]
+impl Directory for LdapServer {
+    fn bind(&self, dn: &str, password: &str, attributes: &[&str]) -> IntResult<Option<Entry>> {
+        use failure::ResultExt;
+        use ldap3::{LdapConn, Scope, SearchEntry};
+
+        /// The result code of a rejected bind, see appendix A of RFC 4511
+        const INVALID_CREDENTIALS: u32 = 49;
+
+        // A connection per login keeps one user's bind from leaking into
+        // the next request
+        let ldap = LdapConn::new(&self.url).context(IntErrorKind::DirectoryError)?;
+        let bind = ldap
+            .simple_bind(dn, password)
+            .context(IntErrorKind::DirectoryError)?;
+        if bind.rc == INVALID_CREDENTIALS {
+            return Ok(None);
+        }
+        bind.success().context(IntErrorKind::DirectoryError)?;
+
+        let (entries, _) = ldap
+            .search(dn, Scope::Base, "(objectClass=*)", attributes.to_vec())
+            .context(IntErrorKind::DirectoryError)?
+            .success()
+            .context(IntErrorKind::DirectoryError)?;
+        let _ = ldap.unbind();
+
+        Ok(Some(
+            entries
+                .into_iter()
+                .next()
+                .map(|entry| SearchEntry::construct(entry).attrs)
+                .unwrap_or_default(),
+        ))
+    }
+}
+
+/// An in-process stand-in for a directory
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeDirectory {
+    entries: BTreeMap<String, (String, Entry)>,
+}
+
+#[cfg(test)]
+impl FakeDirectory {
+    pub fn add(&mut self, dn: &str, password: &str, entry: Entry) {
+        self.entries
+            .insert(dn.to_owned(), (password.to_owned(), entry));
+    }
+}
+
+#[cfg(test)]
+impl Directory for FakeDirectory {
+    fn bind(&self, dn: &str, password: &str, attributes: &[&str]) -> IntResult<Option<Entry>> {
+        Ok(self
+            .entries
+            .get(dn)
+            .filter(|(expected, _)| expected == password)
+            .map(|(_, entry)| {
+                entry
+                    .iter()
+                    .filter(|(name, _)| attributes.contains(&name.as_str()))
+                    .map(|(name, values)| (name.clone(), values.clone()))
+                    .collect()
+            }))
+    }
+}
+
+#[test]
+fn test_escape_dn_value() {
+    assert_eq!("jdoe", escape_dn_value("jdoe"));
+    assert_eq!("doe\\, john", escape_dn_value("doe, john"));
+    assert_eq!("\\#admin\\ ", escape_dn_value("#admin "));
+    assert_eq!("a\\=b\\+c", escape_dn_value("a=b+c"));
+}
+
+#[test]
+fn test_provisioning_and_roles() {
+    use crate::config::GroupRole;
+    use crate::store::memory::MemoryStore;
+    use crate::store::UserStore;
+    use std::sync::Arc;
+
+    let admins = "cn=admins,ou=groups,dc=example,dc=com";
+    let mut config = LdapConfig::default();
+    config.user_dn = "uid={username},ou=people,dc=example,dc=com".to_owned();
+    config.group_roles = vec![GroupRole {
+        group: admins.to_owned(),
+        role: "admin".to_owned(),
+    }];
+
+    let store: Store = Arc::new(MemoryStore::default());
+    store
+        .insert_user("taken".to_owned(), "taken@example.com".to_owned(), "hash".to_owned())
+        .unwrap();
+
+    let entry = |groups: &[&str]| {
+        let mut entry = Entry::new();
+        entry.insert("mail".to_owned(), vec!["jdoe@example.com".to_owned()]);
+        entry.insert(
+            "memberOf".to_owned(),
+            groups.iter().map(|g| g.to_string()).collect(),
+        );
+        entry
+    };
+    let authenticator = |entry: Entry| {
+        let mut directory = FakeDirectory::default();
+        directory.add("uid=jdoe,ou=people,dc=example,dc=com", "Secr3t", entry.clone());
+        directory.add("uid=taken,ou=people,dc=example,dc=com", "Secr3t", entry);
+        LdapAuthenticator::new(config.clone(), Box::new(directory), store.clone())
+    };
+    let password = |password: &str| Secret::new(password.to_owned());
+
+    // The first login adds the user, with the role of their group, which the
+    // server then stores
+    let ldap = authenticator(entry(&[admins]));
+    let identity = ldap.authenticate("jdoe", &password("Secr3t")).unwrap();
+    assert_eq!("admin", identity.role);
+    assert!(identity.role_changed);
+    let user = store.fetch_user("jdoe").unwrap();
+    assert_eq!(identity.user_id, user.id);
+    assert_eq!("jdoe@example.com", user.email);
+    assert_eq!(EXTERNAL_PASSWORD, user.password);
+    store.update_role(user.id, identity.role).unwrap();
+    assert!(!ldap.authenticate("jdoe", &password("Secr3t")).unwrap().role_changed);
+
+    // Leaving the group takes the role away on the next login
+    let ldap = authenticator(entry(&[]));
+    let identity = ldap.authenticate("jdoe", &password("Secr3t")).unwrap();
+    assert_eq!(user.id, identity.user_id);
+    assert_eq!("user", identity.role);
+    assert!(identity.role_changed);
+
+    match ldap.authenticate("jdoe", &password("Wr0ng")) {
+        Err(AuthError::InvalidPassword) => {}
+        _ => panic!("expected InvalidPassword"),
+    }
+    match ldap.authenticate("jdoe", &password("")) {
+        Err(AuthError::InvalidPassword) => {}
+        _ => panic!("expected InvalidPassword"),
+    }
+    // A local user is never taken over
+    match ldap.authenticate("taken", &password("Secr3t")) {
+        Err(AuthError::InvalidUsername) => {}
+        _ => panic!("expected InvalidUsername"),
+    }
+}
@@ -0,0 +1,109 @@
+//! Checks passwords against the hashes in the `users` table
+use pbkdf2::{pbkdf2_check, CheckError};
+use std::time::Instant;
+
+use datatypes::auth::responses::AuthError;
+
+use super::{Authenticator, Identity, EXTERNAL_PASSWORD};
+use crate::config::SecurityConfig;
+use crate::metrics;
+use crate::redact::Secret;
+use crate::store::{self, Store};
+use crate::{logging, IntErrorKind};
+
+/// The `local` backend, for users who registered with the service
+pub struct LocalAuthenticator {
+    store: Store,
+    security: SecurityConfig,
+}
+
+impl LocalAuthenticator {
+    pub fn new(store: Store, security: SecurityConfig) -> LocalAuthenticator {
+        LocalAuthenticator { store, security }
+    }
+}
+
+impl Authenticator for LocalAuthenticator {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn authenticate(
+        &self,
+        username: &str,
+        password: &Secret<String>,
+    ) -> Result<Identity, AuthError> {
+        // Get hashed password from database for current username
+        let store::User {
+            password: hashed_password,
+            id: user_id,
+            username: stored_username,
+            verified,
+            ..
+        } = match self.store.fetch_user(username) {
+            Ok(v) => {
+                trace!("Found user");
+                v
+            }
+            Err(ref e) if e.kind() == IntErrorKind::InvalidUsername => {
+                trace!("User not found");
+                return Err(AuthError::InvalidUsername);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // The password of the user is checked by another backend
+        if hashed_password == EXTERNAL_PASSWORD {
+            return Err(AuthError::InvalidUsername);
+        }
+        logging::set_user_id(user_id);
+
+        // 'Pepper' the password
+        let pepper_pass = Secret::new(password.expose().clone() + &self.security.pepper);
+
+        // Check if the already stored hashed password matches the password
+        // that the user sent
+        let hash_start = Instant::now();
+        let checked = pbkdf2_check(pepper_pass.expose(), &hashed_password);
+        metrics::PASSWORD_HASH_DURATION
+            .with_label_values(&["check"])
+            .observe(metrics::seconds(hash_start));
+
+        match checked {
+            Ok(_) => {
+                trace!("Password matches");
+                let store::Role { name: role, .. } = match self.store.fetch_user_role(user_id) {
+                    Ok(v) => {
+                        trace!("Found user role");
+                        v
+                    }
+                    Err(e) => {
+                        trace!("Failed to find user role");
+                        return Err(e.into());
+                    }
+                };
+                Ok(Identity {
+                    user_id,
+                    username: stored_username,
+                    role,
+                    verified,
+                    role_changed: false,
+                })
+            }
+            // The password does NOT match, return `InvalidPassword`
+            Err(CheckError::HashMismatch) => {
+                trace!("Password does not match");
+                Err(AuthError::InvalidPassword)
+            }
+            // TODO handle the situation where the internally stored password
+            // is badly formatted
+            //
+            // The internal 'hashed_password' does not have the correct
+            // format. This is probably a result of corruption. This
+            // will hopefully be a rare occurence. Perhaps reset password?
+            Err(CheckError::InvalidFormat) => {
+                error!("Hashed password has invalid format");
+                Err(AuthError::InternalServerError)
+            }
+        }
+    }
+}
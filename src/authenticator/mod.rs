@@ -0,0 +1,185 @@
+//! Backends which check the password of a user on `authenticate`
+//!
+//! The backends in `auth.backends` are asked in order, until one of them
+//! accepts the password:
+//!
+//! - `local` checks the password hashes in the `users` table, see [`local`]
+//! - `ldap` binds to an LDAP directory as the user, see [`ldap`]
+//!
+//! A backend which does not know the user lets the next one try. Whatever
+//! backend accepts the password, the session belongs to a user in the
+//! [`UserStore`], so every other rpc works the same for all users.
+//!
+//! [`UserStore`]: crate::store::UserStore
+use datatypes::auth::responses::AuthError;
+
+use crate::config::Config;
+use crate::redact::Secret;
+use crate::store::Store;
+
+pub mod ldap;
+pub mod local;
+
+use self::local::LocalAuthenticator;
+
+/// Stored as the password of users whose password is checked elsewhere
+///
+/// It is not a valid pbkdf2 hash, so no password can ever match it.
+pub const EXTERNAL_PASSWORD: &str = "!external";
+
+/// A user whose password has been accepted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub user_id: u32,
+    pub username: String,
+    pub role: String,
+    /// Whether the user has verified their email address
+    pub verified: bool,
+    /// Whether `role` is new to the store, which the server then updates
+    /// like `set_user_role` does
+    pub role_changed: bool,
+}
+
+/// A backend which can check passwords
+pub trait Authenticator: Send + Sync {
+    /// Name of the backend in `auth.backends`
+    fn name(&self) -> &'static str;
+
+    /// Checks the password of a user
+    ///
+    /// Returns `InvalidUsername` if the backend does not know the user, and
+    /// `InvalidPassword` if the password is wrong.
+    fn authenticate(&self, username: &str, password: &Secret<String>)
+        -> Result<Identity, AuthError>;
+}
+
+/// The configured backends, in the order they are asked
+pub struct Authenticators {
+    backends: Vec<Box<Authenticator>>,
+}
+
+impl Authenticators {
+    pub fn new(backends: Vec<Box<Authenticator>>) -> Authenticators {
+        Authenticators { backends }
+    }
+
+    /// Makes the backends of `auth.backends`
+    ///
+    /// Unknown backends are skipped, they are reported when the configuration
+    /// is validated.
+    pub fn from_config(config: &Config, store: &Store) -> Authenticators {
+        let mut backends: Vec<Box<Authenticator>> = Vec::new();
+        for backend in &config.auth.backends {
+            match backend.as_str() {
+                "local" => backends.push(Box::new(LocalAuthenticator::new(
+                    store.clone(),
+                    config.security.clone(),
+                ))),
+                #[cfg(feature = "ldap")]
+                "ldap" => backends.push(Box::new(ldap::LdapAuthenticator::new(
+                    config.ldap.clone(),
+                    Box::new(ldap::LdapServer::new(&config.ldap.url)),
+                    store.clone(),
+                ))),
+                _ => error!("Skipping the unavailable authentication backend '{}'", backend),
+            }
+        }
+        Authenticators::new(backends)
+    }
+
+    /// Asks every backend in turn until one accepts the password
+    ///
+    /// If none does, the most telling error is returned: `InvalidPassword` if
+    /// any backend knew the user, else the failure of a backend which could
+    /// not be asked, else `InvalidUsername`.
+    pub fn authenticate(
+        &self,
+        username: &str,
+        password: &Secret<String>,
+    ) -> Result<Identity, AuthError> {
+        let mut error = AuthError::InvalidUsername;
+        for backend in &self.backends {
+            match backend.authenticate(username, password) {
+                Ok(identity) => {
+                    trace!("Authenticated by the {} backend", backend.name());
+                    return Ok(identity);
+                }
+                Err(AuthError::InvalidUsername) => {
+                    trace!("The {} backend does not know the user", backend.name())
+                }
+                Err(AuthError::InvalidPassword) => {
+                    trace!("The {} backend rejected the password", backend.name());
+                    error = AuthError::InvalidPassword;
+                }
+                Err(e) => {
+                    warn!("The {} backend failed, trying the next one", backend.name());
+                    if let AuthError::InvalidUsername = error {
+                        error = e;
+                    }
+                }
+            }
+        }
+        Err(error)
+    }
+}
+
+#[test]
+fn test_backends_are_asked_in_order() {
+    use self::ldap::{Entry, FakeDirectory, LdapAuthenticator};
+    use crate::store::memory::MemoryStore;
+    use crate::store::UserStore;
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    config.security.hash_cycles = 1000;
+    config.ldap.user_dn = "uid={username},ou=people,dc=example,dc=com".to_owned();
+
+    let store: Store = Arc::new(MemoryStore::default());
+    let peppered = format!("L0cal-Passw0rd{}", config.security.pepper);
+    let hash = pbkdf2::pbkdf2_simple(&peppered, 1000).unwrap();
+    let local = store
+        .insert_user("local".to_owned(), "local@example.com".to_owned(), hash)
+        .unwrap();
+
+    let mut directory = FakeDirectory::default();
+    let mut entry = Entry::new();
+    entry.insert("mail".to_owned(), vec!["staff@example.com".to_owned()]);
+    directory.add("uid=staff,ou=people,dc=example,dc=com", "St4ff-Passw0rd", entry);
+
+    let authenticators = Authenticators::new(vec![
+        Box::new(LocalAuthenticator::new(store.clone(), config.security.clone())),
+        Box::new(LdapAuthenticator::new(
+            config.ldap.clone(),
+            Box::new(directory),
+            store.clone(),
+        )),
+    ]);
+    let password = |password: &str| Secret::new(password.to_owned());
+
+    let identity = authenticators
+        .authenticate("local", &password("L0cal-Passw0rd"))
+        .unwrap();
+    assert_eq!(local.id, identity.user_id);
+    assert_eq!("user", identity.role);
+
+    // The staff member is unknown to the local backend
+    let identity = authenticators
+        .authenticate("staff", &password("St4ff-Passw0rd"))
+        .unwrap();
+    assert_ne!(local.id, identity.user_id);
+
+    match authenticators.authenticate("local", &password("Wr0ng-Passw0rd")) {
+        Err(AuthError::InvalidPassword) => {}
+        _ => panic!("expected InvalidPassword"),
+    }
+    // A directory does not tell an unknown user from a wrong password
+    match authenticators.authenticate("nobody", &password("N0body-Passw0rd")) {
+        Err(AuthError::InvalidPassword) => {}
+        _ => panic!("expected InvalidPassword"),
+    }
+    let local_only = Authenticators::from_config(&config, &store);
+    match local_only.authenticate("nobody", &password("N0body-Passw0rd")) {
+        Err(AuthError::InvalidUsername) => {}
+        _ => panic!("expected InvalidUsername"),
+    }
+}
@@ -1,10 +1,7 @@
-#![feature(plugin)]
 #![feature(try_from)]
 #![feature(try_trait)]
-#![plugin(tarpc_plugins)]
 
-#[macro_use]
-extern crate tarpc;
+extern crate auth_client;
 extern crate rustyline;
 extern crate serde_derive;
 
@@ -19,35 +16,24 @@ use std::default::Default;
 use std::fmt::Debug;
 use std::net::{SocketAddr, ToSocketAddrs};
 
-use tarpc::sync::client;
-use tarpc::sync::client::ClientExt;
-use tarpc::util::FirstSocketAddr;
+use auth_client::{BlockingClient, ListUsersPayload, Options, UpdateUserPayload, UserFilter};
 
 use datatypes::auth::requests::*;
-use datatypes::auth::responses::*;
-use datatypes::content::requests::AddUserPayload;
-use datatypes::payloads::*;
-use datatypes::valid::fields::*;
-use datatypes::valid::ids::*;
 use datatypes::valid::token::Token;
 
 use failure::Error;
 use failure::Fallible;
 
-service! {
-    rpc authenticate(payload: AuthPayload) -> Token | AuthError;
-    rpc deauthenticate(payload: Token) -> () | AuthError;
-    rpc register(payload: RegisterUserPayload) -> AddUserPayload | AuthError;
-    rpc get_user_role(payload: Token) -> Role | AuthError;
-    rpc set_user_role(payload: SetUserRolePayload) -> () | AuthError;
-}
-
 #[derive(Copy, Clone)]
 pub enum Cmd {
     Auth,
     Deauth,
     Register,
+    GetUser,
     SetRole,
+    ListUsers,
+    GetUserById,
+    UpdateUser,
 }
 
 impl<'a> TryFrom<&'a str> for Cmd {
@@ -58,7 +44,11 @@ impl<'a> TryFrom<&'a str> for Cmd {
             "auth" => Ok(Auth),
             "deauth" => Ok(Deauth),
             "register" => Ok(Register),
+            "get-user" => Ok(GetUser),
             "set-role" => Ok(SetRole),
+            "list-users" => Ok(ListUsers),
+            "get-user-by-id" => Ok(GetUserById),
+            "update-user" => Ok(UpdateUser),
             e => Err(format_err!("Invalid command: {}", e)),
         }
     }
@@ -98,7 +88,11 @@ fn cmd_handler<'a>(state: &State, s: &'a str) -> Fallible<()> {
         (Mode::Main, Cmd::Auth) => run_auth(args),
         (Mode::Main, Cmd::Deauth) => run_deauth(args),
         (Mode::Main, Cmd::Register) => run_register(args),
+        (Mode::Main, Cmd::GetUser) => run_get_user(args),
         (Mode::Main, Cmd::SetRole) => run_set_user_role(args),
+        (Mode::Main, Cmd::ListUsers) => run_list_users(args),
+        (Mode::Main, Cmd::GetUserById) => run_get_user_by_id(args),
+        (Mode::Main, Cmd::UpdateUser) => run_update_user(args),
     }
 }
 
@@ -139,7 +133,12 @@ fn run_auth<'a>(mut args: impl Iterator<Item = &'a str>) -> Fallible<()> {
 }
 
 fn run_deauth<'a>(mut args: impl Iterator<Item = &'a str>) -> Fallible<()> {
-    unimplemented!()
+    let token = args
+        .next()
+        .map(|token| Token::new(token.to_owned()))
+        .ok_or(format_err!("Missing argument <token>"))?;
+    run_client_action(|client| client.deauthenticate(token));
+    Ok(())
 }
 
 fn run_register<'a>(mut args: impl Iterator<Item = &'a str>) -> Fallible<()> {
@@ -155,6 +154,15 @@ fn run_register<'a>(mut args: impl Iterator<Item = &'a str>) -> Fallible<()> {
     Ok(())
 }
 
+fn run_get_user<'a>(mut args: impl Iterator<Item = &'a str>) -> Fallible<()> {
+    let token = args
+        .next()
+        .map(|token| Token::new(token.to_owned()))
+        .ok_or(format_err!("Missing argument <token>"))?;
+    run_client_action(|client| client.get_user(token));
+    Ok(())
+}
+
 fn run_set_user_role<'a>(mut args: impl Iterator<Item = &'a str>) -> Fallible<()> {
     let id = get_next_id!(args, u32 => user_id)?;
 
@@ -166,8 +174,97 @@ fn run_set_user_role<'a>(mut args: impl Iterator<Item = &'a str>) -> Fallible<()
     Ok(())
 }
 
+// list-users <token> [username=<prefix>] [email=<prefix>] [role=<role>]
+// [banned=<bool>] [verified=<bool>] [since=<timestamp>] [until=<timestamp>]
+// [after=<user_id>] [limit=<n>]
+fn run_list_users<'a>(mut args: impl Iterator<Item = &'a str>) -> Fallible<()> {
+    let token = args
+        .next()
+        .map(|token| Token::new(token.to_owned()))
+        .ok_or(format_err!("Missing argument <token>"))?;
+    let mut payload = ListUsersPayload {
+        token,
+        filter: UserFilter::default(),
+        after: None,
+        limit: 0,
+    };
+
+    for arg in args.filter(|arg| !arg.is_empty()) {
+        let mut parts = arg.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => return Err(format_err!("Invalid filter '{}', expected <key>=<value>", arg)),
+        };
+        let invalid = || format_err!("Invalid <{}>", key);
+        let filter = &mut payload.filter;
+        match key {
+            "username" => filter.username_prefix = Some(value.to_owned()),
+            "email" => filter.email_prefix = Some(value.to_owned()),
+            "role" => filter.role = Some(value.into()),
+            "banned" => filter.banned = Some(value.parse().map_err(|_| invalid())?),
+            "verified" => filter.verified = Some(value.parse().map_err(|_| invalid())?),
+            "since" => filter.created_after = Some(value.parse().map_err(|_| invalid())?),
+            "until" => filter.created_before = Some(value.parse().map_err(|_| invalid())?),
+            "after" => payload.after = Some(value.parse::<u32>().map_err(|_| invalid())?.into()),
+            "limit" => payload.limit = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format_err!("Unknown filter '{}'", key)),
+        }
+    }
+
+    run_client_action(|client| client.list_users(payload));
+    Ok(())
+}
+
+// get-user-by-id <token> <user_id>
+fn run_get_user_by_id<'a>(mut args: impl Iterator<Item = &'a str>) -> Fallible<()> {
+    let token = args
+        .next()
+        .map(|token| Token::new(token.to_owned()))
+        .ok_or(format_err!("Missing argument <token>"))?;
+    let id = get_next_id!(args, u32 => user_id)?;
+    run_client_action(|client| client.get_user_by_id(token, id));
+    Ok(())
+}
+
+// update-user <token> <user_id> [username=<username>] [email=<email>]
+// [verified=<bool>] [banned=<bool>]
+fn run_update_user<'a>(mut args: impl Iterator<Item = &'a str>) -> Fallible<()> {
+    let token = args
+        .next()
+        .map(|token| Token::new(token.to_owned()))
+        .ok_or(format_err!("Missing argument <token>"))?;
+    let id = get_next_id!(args, u32 => user_id)?;
+    let mut payload = UpdateUserPayload {
+        token,
+        id,
+        username: None,
+        email: None,
+        verified: None,
+        banned: None,
+    };
+
+    for arg in args.filter(|arg| !arg.is_empty()) {
+        let mut parts = arg.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => return Err(format_err!("Invalid change '{}', expected <key>=<value>", arg)),
+        };
+        let invalid = || format_err!("Invalid <{}>", key);
+        match key {
+            "username" => payload.username = Some(value.to_owned()),
+            "email" => payload.email = Some(value.to_owned()),
+            "verified" => payload.verified = Some(value.parse().map_err(|_| invalid())?),
+            "banned" => payload.banned = Some(value.parse().map_err(|_| invalid())?),
+            _ => return Err(format_err!("Unknown change '{}'", key)),
+        }
+    }
+
+    run_client_action(|client| client.update_user(payload));
+    Ok(())
+}
+
 // Connect to server
-fn connect() -> Option<SyncClient> {
+fn connect() -> Option<BlockingClient> {
     let address = match std::env::var("AUTH_ADDRESS") {
         Ok(value) => value
             .to_socket_addrs()
@@ -180,21 +277,19 @@ fn connect() -> Option<SyncClient> {
         }
     };
 
-    let options = client::Options::default();
-    SyncClient::connect(address, options).ok()
+    BlockingClient::new(address, Options::default()).ok()
 }
 
 // Run a action on the server and print the result
-fn run_client_action<T, E, F>(f: F)
+fn run_client_action<T, F>(f: F)
 where
     T: Debug,
-    E: Debug,
-    F: FnOnce(SyncClient) -> Result<T, E>,
+    F: FnOnce(&mut BlockingClient) -> Result<T, auth_client::Error>,
 {
-    if let Some(client) = connect() {
-        match f(client) {
+    if let Some(mut client) = connect() {
+        match f(&mut client) {
             Ok(value) => println!("The server responded with: {:#?}", value),
-            Err(error) => println!("The server responded with error: {:#?}", error),
+            Err(error) => println!("The server responded with error: {}", error),
         }
     } else {
         println!("Unable to connect");
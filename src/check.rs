@@ -0,0 +1,229 @@
+//! Compares the live database schema with the schema the queries expect
+//!
+//! The tables are introspected through `information_schema` (MySQL and
+//! PostgreSQL) or `sqlite_master` (SQLite). The expected columns are taken
+//! from the diesel `table!` definitions, so a column renamed in `schema.rs` is
+//! caught at compile time and a column missing in the database at startup.
+use diesel::deserialize::QueryableByName;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Text;
+use diesel::Column;
+use failure::ResultExt;
+use std::fmt::{self, Display};
+
+#[cfg(feature = "mysql")]
+use crate::schema::{dead_letters, event_sinks, oauth_clients, outbox, roles, users, webhooks};
+#[cfg(all(not(feature = "mysql"), any(feature = "sqlite", feature = "postgres")))]
+use crate::store::sql::schema::{
+    dead_letters, event_sinks, oauth_clients, outbox, roles, users, webhooks,
+};
+use crate::{IntErrorKind, IntResult};
+
+const MYSQL_COLUMNS: &str = "SELECT table_name AS table_name, column_name AS column_name
+    FROM information_schema.columns
+    WHERE table_schema = DATABASE()";
+
+const POSTGRES_COLUMNS: &str = "SELECT table_name AS table_name, column_name AS column_name
+    FROM information_schema.columns
+    WHERE table_schema = current_schema()";
+
+const SQLITE_COLUMNS: &str = "SELECT m.name AS table_name, p.name AS column_name
+    FROM sqlite_master m, pragma_table_info(m.name) p
+    WHERE m.type = 'table'";
+
+/// A table and the columns the queries expect it to have
+#[derive(Debug, PartialEq)]
+pub struct ExpectedTable {
+    pub name: &'static str,
+    pub columns: Vec<&'static str>,
+}
+
+macro_rules! expected_tables {
+    ($($table:ident: [$($column:ident),*],)*) => {
+        vec![$(
+            ExpectedTable {
+                name: stringify!($table),
+                columns: vec![$(<$table::$column as Column>::NAME),*],
+            },
+        )*]
+    };
+}
+
+/// The tables the service reads from and writes to
+#[cfg(any(feature = "mysql", feature = "sqlite", feature = "postgres"))]
+pub fn expected() -> Vec<ExpectedTable> {
+    expected_tables! {
+        dead_letters: [id, webhook_id, event, attempts, error, failed_at],
+        event_sinks: [name, position],
+        oauth_clients: [id, name, secret_hash, redirect_uris],
+        outbox: [id, event, created_at],
+        roles: [id, name],
+        users: [id, email, username, password, banned, verified, email_token, created_at],
+        webhooks: [id, url, secret, events, created_at],
+    }
+}
+
+/// A difference between the expected and the live schema
+#[derive(Debug, PartialEq)]
+pub enum Mismatch {
+    MissingTable(String),
+    MissingColumn(String, String),
+    UnexpectedColumn(String, String),
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::MissingTable(table) => write!(f, "table '{}' is missing", table),
+            Mismatch::MissingColumn(table, column) => {
+                write!(f, "column '{}.{}' is missing", table, column)
+            }
+            Mismatch::UnexpectedColumn(table, column) => {
+                write!(f, "column '{}.{}' is not in the schema", table, column)
+            }
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+pub struct LiveColumn {
+    #[sql_type = "Text"]
+    table_name: String,
+    #[sql_type = "Text"]
+    column_name: String,
+}
+
+/// Compares the live columns with the expected tables
+///
+/// Tables which are not expected, like the migration bookkeeping, are ignored.
+pub fn compare(expected: &[ExpectedTable], live: &[(String, String)]) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+
+    for table in expected {
+        let live_columns: Vec<&str> = live
+            .iter()
+            .filter(|(t, _)| t == table.name)
+            .map(|(_, c)| c.as_str())
+            .collect();
+
+        if live_columns.is_empty() {
+            mismatches.push(Mismatch::MissingTable(table.name.to_owned()));
+            continue;
+        }
+
+        for column in &table.columns {
+            if !live_columns.contains(column) {
+                mismatches.push(Mismatch::MissingColumn(
+                    table.name.to_owned(),
+                    column.to_string(),
+                ));
+            }
+        }
+        for column in live_columns {
+            if !table.columns.contains(&column) {
+                mismatches.push(Mismatch::UnexpectedColumn(
+                    table.name.to_owned(),
+                    column.to_owned(),
+                ));
+            }
+        }
+    }
+    mismatches
+}
+
+/// Checks the schema of the database at `database_url`
+///
+/// Every mismatch is logged, and `SchemaMismatch` is returned if there are any.
+pub fn run(database_url: &str) -> IntResult<()> {
+    let mismatches = mismatches(database_url)?;
+    if mismatches.is_empty() {
+        info!("Database schema matches the expected schema");
+        return Ok(());
+    }
+
+    for mismatch in &mismatches {
+        error!("Schema mismatch: {}", mismatch);
+    }
+    Err(IntErrorKind::SchemaMismatch)?
+}
+
+fn mismatches(database_url: &str) -> IntResult<Vec<Mismatch>> {
+    let scheme = database_url.split("://").next().unwrap_or("");
+
+    match scheme {
+        #[cfg(feature = "mysql")]
+        "mysql" => {
+            let conn = MysqlConnection::establish(database_url)
+                .context(IntErrorKind::ConnectionError)?;
+            Ok(compare(&expected(), &live_columns(&conn, MYSQL_COLUMNS)?))
+        }
+        #[cfg(feature = "postgres")]
+        "postgres" | "postgresql" => {
+            let conn =
+                PgConnection::establish(database_url).context(IntErrorKind::ConnectionError)?;
+            Ok(compare(&expected(), &live_columns(&conn, POSTGRES_COLUMNS)?))
+        }
+        #[cfg(feature = "sqlite")]
+        "sqlite" => {
+            let conn = SqliteConnection::establish(database_url.trim_left_matches("sqlite://"))
+                .context(IntErrorKind::ConnectionError)?;
+            Ok(compare(&expected(), &live_columns(&conn, SQLITE_COLUMNS)?))
+        }
+        "memory" => Ok(Vec::new()),
+        _ => {
+            error!("Unable to check schema of database '{}'", scheme);
+            Err(IntErrorKind::ConnectionError)?
+        }
+    }
+}
+
+fn live_columns<C>(conn: &C, query: &str) -> IntResult<Vec<(String, String)>>
+where
+    C: Connection,
+    LiveColumn: QueryableByName<C::Backend>,
+{
+    let columns = sql_query(query)
+        .load::<LiveColumn>(conn)
+        .context(IntErrorKind::QueryError)?;
+    Ok(columns
+        .into_iter()
+        .map(|c| (c.table_name, c.column_name))
+        .collect())
+}
+
+#[test]
+fn test_compare() {
+    let expected = vec![
+        ExpectedTable {
+            name: "roles",
+            columns: vec!["id", "name"],
+        },
+        ExpectedTable {
+            name: "users",
+            columns: vec!["id", "email"],
+        },
+    ];
+    let live = vec![
+        ("__diesel_schema_migrations".to_owned(), "version".to_owned()),
+        ("users".to_owned(), "id".to_owned()),
+        ("users".to_owned(), "mail".to_owned()),
+    ];
+
+    assert_eq!(
+        vec![
+            Mismatch::MissingTable("roles".to_owned()),
+            Mismatch::MissingColumn("users".to_owned(), "email".to_owned()),
+            Mismatch::UnexpectedColumn("users".to_owned(), "mail".to_owned()),
+        ],
+        compare(&expected, &live)
+    );
+
+    let live = vec![
+        ("roles".to_owned(), "id".to_owned()),
+        ("roles".to_owned(), "name".to_owned()),
+        ("users".to_owned(), "email".to_owned()),
+        ("users".to_owned(), "id".to_owned()),
+    ];
+    assert!(compare(&expected, &live).is_empty());
+}
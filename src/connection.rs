@@ -0,0 +1,360 @@
+//! Serves the rpcs on a single connection, of any kind
+//!
+//! tarpc can only accept connections on TCP, and serves every connection of a
+//! listener with the same server. The connections of the TLS listener and of
+//! the Unix socket are served here instead, so each of them gets the server
+//! its client may use, see [`AuthServer::unprivileged`].
+//!
+//! It speaks the protocol of tarpc, so clients can not tell the difference. A
+//! frame is the id of the request and the length of the payload, both big
+//! endian `u64`, followed by the payload in bincode. The payloads are the
+//! request and response types `service!` generates.
+//!
+//! The listeners which are served here are stopped on shutdown with a
+//! [`Tracker`], which also waits for their connections to close, like
+//! `tarpc::future::server::Handle` does for the TCP listeners.
+use bytes::{Buf, BufMut, BytesMut};
+use futures::future::{self, Shared};
+use futures::sync::oneshot;
+use futures::unsync::mpsc;
+use futures::{Future, IntoFuture, Stream};
+use std::io::{self, Cursor};
+use tokio_codec::{Decoder, Encoder, Framed};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use auth_client::tarpc_service_Error__ as RpcError;
+use auth_client::tarpc_service_Request__ as Request;
+use auth_client::tarpc_service_Response__ as Response;
+use auth_client::ServiceError;
+
+use crate::service::{AuthServer, FutureService};
+
+/// Largest payload of a request, the default of tarpc
+const MAX_PAYLOAD_SIZE: u64 = 2 << 20;
+
+/// The request id and the length of the payload
+const HEADER_SIZE: usize = 16;
+
+/// Requests of one connection which are handled at once, the rest wait
+const MAX_IN_FLIGHT: usize = 32;
+
+/// The error of a reply, the same as `tarpc::WireError`
+#[derive(Serialize)]
+enum WireError {
+    /// The request could not be deserialized
+    RequestDeserialize(String),
+    App(RpcError),
+}
+
+type Reply = Result<Response, WireError>;
+
+type ReplyFuture = Box<Future<Item = Reply, Error = ()>>;
+
+/// Serves `server` on `stream` until the client closes it
+pub fn serve<S>(stream: S, server: AuthServer) -> impl Future<Item = (), Error = ()>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let (replies, requests) = Framed::new(stream, Codec).split();
+    requests
+        .map(move |(id, request)| {
+            let reply: ReplyFuture = match request {
+                Ok(request) => dispatch(&server, request),
+                Err(e) => {
+                    debug!("Unable to deserialize request {}: {}", id, e);
+                    let error = WireError::RequestDeserialize(e.to_string());
+                    Box::new(future::ok(Err(error)))
+                }
+            };
+            reply.map(move |reply| (id, reply))
+        }).buffer_unordered(MAX_IN_FLIGHT)
+        .forward(replies)
+        .map(|_| ())
+        .map_err(|e| debug!("Connection closed: {}", e))
+}
+
+/// Stops the accept loops of a set of listeners, and keeps their connections
+/// open until they are closed, see [`tracker`]
+#[derive(Clone)]
+pub struct Tracker {
+    stop: Shared<oneshot::Receiver<()>>,
+    /// Held by every open connection, the drain waits until none is left
+    open: mpsc::UnboundedSender<()>,
+}
+
+/// Shuts down the listeners of a [`Tracker`]
+pub struct Drain {
+    stop: oneshot::Sender<()>,
+    closed: mpsc::UnboundedReceiver<()>,
+}
+
+/// A tracker for accept loops and their connections, and what stops them
+pub fn tracker() -> (Tracker, Drain) {
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let (open_tx, open_rx) = mpsc::unbounded();
+    let tracker = Tracker {
+        stop: stop_rx.shared(),
+        open: open_tx,
+    };
+    let drain = Drain {
+        stop: stop_tx,
+        closed: open_rx,
+    };
+    (tracker, drain)
+}
+
+impl Tracker {
+    /// Runs an accept loop until the drain is shut down
+    pub fn accept<F>(&self, accept: F) -> impl Future<Item = (), Error = ()>
+    where
+        F: Future<Item = (), Error = ()>,
+    {
+        // A dropped drain never stops the loop
+        let stop = self
+            .stop
+            .clone()
+            .map(|_| ())
+            .or_else(|_| future::empty::<(), ()>());
+        accept.select(stop).then(|_| Ok(()))
+    }
+
+    /// Counts a connection as open until it finishes
+    pub fn connection<F>(&self, connection: F) -> impl Future<Item = (), Error = ()>
+    where
+        F: Future<Item = (), Error = ()>,
+    {
+        let open = self.open.clone();
+        connection.then(move |result| {
+            drop(open);
+            result
+        })
+    }
+}
+
+impl Drain {
+    /// Stops the accept loops, and resolves once every connection is closed
+    pub fn shutdown(self) -> impl Future<Item = (), Error = ()> {
+        // The loops are gone already if the reactor stopped running them
+        let _ = self.stop.send(());
+        self.closed.for_each(|()| Ok(()))
+    }
+}
+
+/// Calls the rpc of a request
+fn dispatch(server: &AuthServer, request: Request) -> ReplyFuture {
+    match request {
+        Request::authenticate((id, payload)) => reply(
+            server.authenticate(id, payload),
+            Response::authenticate,
+            RpcError::authenticate,
+        ),
+        Request::deauthenticate((id, token)) => reply(
+            server.deauthenticate(id, token),
+            Response::deauthenticate,
+            RpcError::deauthenticate,
+        ),
+        Request::register((id, payload)) => reply(
+            server.register(id, payload),
+            Response::register,
+            RpcError::register,
+        ),
+        Request::get_user((id, token)) => reply(
+            server.get_user(id, token),
+            Response::get_user,
+            RpcError::get_user,
+        ),
+        Request::get_users((id, tokens)) => reply(
+            server.get_users(id, tokens),
+            Response::get_users,
+            RpcError::get_users,
+        ),
+        Request::set_user_role((id, payload)) => reply(
+            server.set_user_role(id, payload),
+            Response::set_user_role,
+            RpcError::set_user_role,
+        ),
+        Request::health((id,)) => reply(server.health(id), Response::health, RpcError::health),
+        Request::invalidations((id, since)) => reply(
+            server.invalidations(id, since),
+            Response::invalidations,
+            RpcError::invalidations,
+        ),
+        Request::events((id, since)) => reply(
+            server.events(id, since),
+            Response::events,
+            RpcError::events,
+        ),
+        Request::add_webhook((id, payload)) => reply(
+            server.add_webhook(id, payload),
+            Response::add_webhook,
+            RpcError::add_webhook,
+        ),
+        Request::remove_webhook((id, token, webhook_id)) => reply(
+            server.remove_webhook(id, token, webhook_id),
+            Response::remove_webhook,
+            RpcError::remove_webhook,
+        ),
+        Request::webhooks((id, token)) => reply(
+            server.webhooks(id, token),
+            Response::webhooks,
+            RpcError::webhooks,
+        ),
+        Request::dead_letters((id, token, webhook_id)) => reply(
+            server.dead_letters(id, token, webhook_id),
+            Response::dead_letters,
+            RpcError::dead_letters,
+        ),
+        Request::replay_dead_letters((id, token, webhook_id)) => reply(
+            server.replay_dead_letters(id, token, webhook_id),
+            Response::replay_dead_letters,
+            RpcError::replay_dead_letters,
+        ),
+        Request::list_users((id, payload)) => reply(
+            server.list_users(id, payload),
+            Response::list_users,
+            RpcError::list_users,
+        ),
+        Request::get_user_by_id((id, token, user_id)) => reply(
+            server.get_user_by_id(id, token, user_id),
+            Response::get_user_by_id,
+            RpcError::get_user_by_id,
+        ),
+        Request::update_user((id, payload)) => reply(
+            server.update_user(id, payload),
+            Response::update_user,
+            RpcError::update_user,
+        ),
+        // Never sent, it only keeps the enum from being matched irrefutably
+        Request::NotIrrefutable(()) => {
+            let error = WireError::RequestDeserialize("unknown rpc".to_owned());
+            Box::new(future::ok(Err(error)))
+        }
+    }
+}
+
+/// Wraps the result of an rpc in the reply of its kind
+fn reply<F, T>(call: F, ok: fn(T) -> Response, err: fn(ServiceError) -> RpcError) -> ReplyFuture
+where
+    F: IntoFuture<Item = T, Error = ServiceError>,
+    F::Future: 'static,
+    T: 'static,
+{
+    Box::new(
+        call.into_future()
+            .then(move |result| Ok(result.map(ok).map_err(|e| WireError::App(err(e))))),
+    )
+}
+
+/// Reads requests and writes replies
+struct Codec;
+
+impl Decoder for Codec {
+    type Item = (u64, bincode::Result<Request>);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        if buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let (id, len) = {
+            let mut header = Cursor::new(&buf[..HEADER_SIZE]);
+            (header.get_u64_be(), header.get_u64_be())
+        };
+        if len > MAX_PAYLOAD_SIZE {
+            let message = format!("request of {} bytes is too large", len);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+
+        let frame_size = HEADER_SIZE + len as usize;
+        if buf.len() < frame_size {
+            buf.reserve(frame_size - buf.len());
+            return Ok(None);
+        }
+        let frame = buf.split_to(frame_size);
+        Ok(Some((id, bincode::deserialize(&frame[HEADER_SIZE..]))))
+    }
+}
+
+impl Encoder for Codec {
+    type Item = (u64, Reply);
+    type Error = io::Error;
+
+    fn encode(&mut self, (id, reply): Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        let payload =
+            bincode::serialize(&reply).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        buf.reserve(HEADER_SIZE + payload.len());
+        buf.put_u64_be(id);
+        buf.put_u64_be(payload.len() as u64);
+        buf.put_slice(&payload);
+        Ok(())
+    }
+}
+
+/// Serves each connection to a TCP listener with `serve`, and returns the
+/// address of the listener
+#[cfg(test)]
+pub fn listen(server: AuthServer, handle: &tokio_core::reactor::Handle) -> std::net::SocketAddr {
+    use tokio_core::net::TcpListener;
+
+    let listener = TcpListener::bind(&([127, 0, 0, 1], 0).into(), handle).unwrap();
+    let address = listener.local_addr().unwrap();
+    let connections = handle.clone();
+    handle.spawn(
+        listener
+            .incoming()
+            .for_each(move |(stream, _)| {
+                connections.spawn(serve(stream, server.clone()));
+                Ok(())
+            }).map_err(|e| panic!("{}", e)),
+    );
+    address
+}
+
+#[test]
+fn test_tarpc_client() {
+    use crate::config::Config;
+    use crate::store::memory::MemoryStore;
+    use auth_client::{BlockingClient, Error, Options};
+    use datatypes::auth::requests::{AuthPayload, RegisterUserPayload, SetUserRolePayload};
+    use datatypes::auth::responses::AuthError;
+    use std::convert::TryInto;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    let mut config = Config::default();
+    config.security.hash_cycles = 1000;
+    let server = AuthServer::with_store(&config, Arc::new(MemoryStore::default()));
+    let (address_tx, address_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        address_tx
+            .send(listen(server.unprivileged(), &core.handle()))
+            .unwrap();
+        core.run(future::empty::<(), ()>()).unwrap();
+    });
+    let mut client = BlockingClient::new(address_rx.recv().unwrap(), Options::default()).unwrap();
+
+    let user = client
+        .register(RegisterUserPayload {
+            username: "wire".to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+            password: "W1re-Passw0rd".to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+            email: "wire@example.com".to_owned().try_into().unwrap_or_else(|_| panic!("email")),
+        }).unwrap();
+    let token = client
+        .authenticate(AuthPayload {
+            username: "wire".to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+            password: "W1re-Passw0rd".to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+        }).unwrap();
+    assert_eq!(user.id, client.get_user(token).unwrap().id);
+
+    // The connection is served by the unprivileged server
+    client.set_request_id("wire-1");
+    let role = SetUserRolePayload {
+        id: user.id.into(),
+        role: "admin".into(),
+    };
+    match client.set_user_role(role) {
+        Err(Error::Service(AuthError::InvalidToken, ref id, _)) if id == "wire-1" => {}
+        other => panic!("expected InvalidToken for wire-1, got {:?}", other),
+    }
+}
@@ -1,31 +1,37 @@
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
 use diesel::result::Error;
-use dotenv::dotenv;
 use failure::ResultExt;
-use std::env;
 
+use auth_client::{DeadLetter, Event, EventKind, EventType, UserFilter};
+
+use crate::config::Config;
 use crate::schema::*;
-use crate::{IntErrorKind, IntResult};
+pub use crate::store::{Client, Role, User, UserChanges, Webhook};
+use crate::store::{
+    decode_event, decode_json, encode_event, encode_json, lower, prefix_pattern,
+    unique_violation, webhook_sink,
+};
+use crate::{IntError, IntErrorKind, IntResult};
 
 pub type DbConn = MysqlConnection;
 pub type DbPool = diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::MysqlConnection>>;
 
 /*
-Connects to database to URL set in .env
+Connects to the database in the configuration, used by the tests
 */
 pub fn establish_connection() -> MysqlConnection {
-    dotenv().ok();
-
-    let database_url = env::var("AUTH_DATABASE_URL").expect("AUTH_DATABASE_URL must be set");
+    let config = Config::load(None).unwrap_or_else(|e| panic!("{}", e));
+    let database_url = config.database.url;
     MysqlConnection::establish(&database_url)
         .expect(&format!("Error connecting to {}", database_url))
 }
 
 /// Makes a pool of connections to the database
-pub fn setup_connection_pool(database_url: &str) -> IntResult<DbPool> {
+pub fn setup_connection_pool(database_url: &str, pool_size: u32) -> IntResult<DbPool> {
     let manager = ConnectionManager::<DbConn>::new(database_url);
-    let diesel_db_config = r2d2::Pool::builder();
+    let diesel_db_config = r2d2::Pool::builder().max_size(pool_size);
 
     diesel_db_config
         .build(manager)
@@ -33,29 +39,33 @@ pub fn setup_connection_pool(database_url: &str) -> IntResult<DbPool> {
         .map_err(|e| e.into())
 }
 
-#[derive(Queryable, PartialEq, Debug)]
-pub struct User {
-    pub id: u32,
+#[derive(Debug, PartialEq, Insertable)]
+#[table_name = "users"]
+pub struct NewUser {
     pub email: String,
     pub username: String,
     pub password: String,
-    pub banned: bool,
-    pub verified: bool,
-    pub email_token: Option<String>,
 }
 
-#[derive(Queryable, PartialEq, Debug)]
-pub struct Role {
-    pub id: u32,
-    pub name: String,
+/// The columns `UserChanges` sets, none are left as they are
+#[derive(Debug, PartialEq, AsChangeset)]
+#[table_name = "users"]
+pub struct UserChangeset<'a> {
+    pub username: Option<&'a str>,
+    pub email: Option<&'a str>,
+    pub verified: Option<bool>,
+    pub banned: Option<bool>,
 }
 
-#[derive(Debug, PartialEq, Insertable)]
-#[table_name = "users"]
-pub struct NewUser {
-    pub email: String,
-    pub username: String,
-    pub password: String,
+impl<'a> From<&'a UserChanges> for UserChangeset<'a> {
+    fn from(changes: &'a UserChanges) -> UserChangeset<'a> {
+        UserChangeset {
+            username: changes.username.as_ref().map(|s| s.as_str()),
+            email: changes.email.as_ref().map(|s| s.as_str()),
+            verified: changes.verified,
+            banned: changes.banned,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Insertable)]
@@ -70,6 +80,10 @@ Creates a user based on username, email and hashed password.
 Updates the newly created user's role.
 Returns newly created user
 
+The inserts and the `UserRegistered` event happen in one transaction, so a
+user is never left without a role, nor unknown to other services. A taken
+username or email is reported as `ExistingUser` or `ExistingEmail`
+respectively.
 */
 
 pub fn insert_user(
@@ -78,39 +92,54 @@ pub fn insert_user(
     new_email: String,
     new_password: String,
 ) -> IntResult<User> {
-    use schema::roles::dsl::*;
-    use schema::users::dsl::*;
+    use crate::schema::roles::dsl::*;
+    use crate::schema::users::dsl::*;
     let new_user = NewUser {
         email: new_email,
         username: user_name.clone(),
         password: new_password,
     };
 
-    diesel::insert_into(users)
-        .values(&new_user)
-        .execute(conn)
-        .context(IntErrorKind::QueryError)
-        .map_err(|e| {
-            error!("Unable to insert user: {}", e);
-            e
-        })?;
-    let fetched_user = fetch_user(conn, &user_name)?;
-
-    let new_role = NewRole {
-        id: fetched_user.id,
-        name: "user".to_string(),
-    };
-
-    diesel::insert_into(roles)
-        .values(&new_role)
-        .execute(conn)
-        .context(IntErrorKind::QueryError)
-        .map_err(|e| {
-            error!("Unable to insert user role: {}", e);
-            e
-        })?;
+    conn.transaction::<_, IntError, _>(|| {
+        diesel::insert_into(users)
+            .values(&new_user)
+            .execute(conn)
+            .map_err(|e| match unique_violation(&e) {
+                Some(kind) => {
+                    trace!("Unable to insert user: {}", kind);
+                    IntError::from(kind)
+                }
+                None => {
+                    error!("Unable to insert user: {}", e);
+                    e.into()
+                }
+            })?;
+        let fetched_user = fetch_user(conn, &user_name)?;
+
+        let new_role = NewRole {
+            id: fetched_user.id,
+            name: "user".to_string(),
+        };
 
-    Ok(fetched_user)
+        diesel::insert_into(roles)
+            .values(&new_role)
+            .execute(conn)
+            .context(IntErrorKind::QueryError)
+            .map_err(|e| {
+                error!("Unable to insert user role: {}", e);
+                e
+            })?;
+
+        insert_event(
+            conn,
+            &EventKind::UserRegistered {
+                id: fetched_user.id.into(),
+                username: fetched_user.username.clone(),
+            },
+        )?;
+
+        Ok(fetched_user)
+    })
 }
 
 /*
@@ -133,41 +162,170 @@ pub fn fetch_user(conn: &MysqlConnection, new_username: &str) -> IntResult<User>
 }
 
 /*
-Updates banned status of a user based on user id.
-Returns true if updated, false if not.
+Returns user based on user id
 */
-pub fn update_ban(conn: &MysqlConnection, user_id: u32, banned_value: bool) -> IntResult<bool> {
+pub fn fetch_user_by_id(conn: &MysqlConnection, user_id: u32) -> IntResult<User> {
     use crate::schema::users::dsl::*;
-    let updated = diesel::update(users)
-        .set(banned.eq(banned_value))
-        .filter(id.eq(user_id))
-        .execute(conn)
+
+    users
+        .find(user_id)
+        .first(conn)
+        .optional()
+        .context(IntErrorKind::QueryError)?
+        .ok_or(IntErrorKind::InvalidUsername)
+        .map_err(|e| {
+            error!("Unable to fetch user: {}", e);
+            e.into()
+        })
+}
+
+/*
+Returns at most `limit` users whose id is above `after` and which match the
+filter, by id, with their roles
+*/
+pub fn list_users(
+    conn: &MysqlConnection,
+    filter: &UserFilter,
+    after: u32,
+    limit: u32,
+) -> IntResult<Vec<(User, Role)>> {
+    let mut query = users::table
+        .inner_join(roles::table)
+        .filter(users::id.gt(after))
+        .order(users::id)
+        .limit(i64::from(limit))
+        .into_boxed();
+    // Backslash is the escape character of LIKE in MySQL
+    if let Some(ref prefix) = filter.username_prefix {
+        query = query.filter(lower(users::username).like(prefix_pattern(prefix)));
+    }
+    if let Some(ref prefix) = filter.email_prefix {
+        query = query.filter(lower(users::email).like(prefix_pattern(prefix)));
+    }
+    if let Some(role) = filter.role {
+        let role: String = role.into();
+        query = query.filter(roles::name.eq(role));
+    }
+    if let Some(banned) = filter.banned {
+        query = query.filter(users::banned.eq(banned));
+    }
+    if let Some(verified) = filter.verified {
+        query = query.filter(users::verified.eq(verified));
+    }
+    if let Some(created_after) = filter.created_after {
+        let created_after = NaiveDateTime::from_timestamp(created_after, 0);
+        query = query.filter(users::created_at.ge(created_after));
+    }
+    if let Some(created_before) = filter.created_before {
+        let created_before = NaiveDateTime::from_timestamp(created_before, 0);
+        query = query.filter(users::created_at.lt(created_before));
+    }
+
+    query
+        .load::<(User, Role)>(conn)
         .context(IntErrorKind::QueryError)
         .map_err(|e| {
-            error!("Failed to update banned status: {}", e);
-            e
-        })?;
+            error!("Unable to list users: {}", e);
+            e.into()
+        })
+}
 
-    Ok(updated > 0)
+/*
+Updates banned status of a user based on user id, with a `UserBanned` event.
+Returns true if updated, false if not.
+*/
+pub fn update_ban(conn: &MysqlConnection, user_id: u32, banned_value: bool) -> IntResult<bool> {
+    use crate::schema::users::dsl::*;
+    conn.transaction::<_, IntError, _>(|| {
+        let updated = diesel::update(users)
+            .set(banned.eq(banned_value))
+            .filter(id.eq(user_id))
+            .execute(conn)
+            .context(IntErrorKind::QueryError)
+            .map_err(|e| {
+                error!("Failed to update banned status: {}", e);
+                e
+            })?;
+
+        if updated > 0 {
+            insert_event(
+                conn,
+                &EventKind::UserBanned {
+                    id: user_id.into(),
+                    banned: banned_value,
+                },
+            )?;
+        }
+        Ok(updated > 0)
+    })
 }
 
 /*
-Updates verified status of a user based on user id.
+Updates verified status of a user based on user id, with a `UserVerified`
+event.
 Returns true if updated, false if not.
 */
 pub fn update_verify(conn: &MysqlConnection, user_id: u32, verify_value: bool) -> IntResult<bool> {
     use crate::schema::users::dsl::*;
-    let updated = diesel::update(users)
-        .filter(id.eq(user_id))
-        .set(verified.eq(verify_value))
-        .execute(conn)
-        .context(IntErrorKind::QueryError)
-        .map_err(|e| {
-            error!("Failed to update verified status: {}", e);
-            e
-        })?;
+    conn.transaction::<_, IntError, _>(|| {
+        let updated = diesel::update(users)
+            .filter(id.eq(user_id))
+            .set(verified.eq(verify_value))
+            .execute(conn)
+            .context(IntErrorKind::QueryError)
+            .map_err(|e| {
+                error!("Failed to update verified status: {}", e);
+                e
+            })?;
+
+        if updated > 0 {
+            insert_event(
+                conn,
+                &EventKind::UserVerified {
+                    id: user_id.into(),
+                    verified: verify_value,
+                },
+            )?;
+        }
+        Ok(updated > 0)
+    })
+}
 
-    Ok(updated > 0)
+/*
+Changes the username, email, verified or banned status of a user, with an
+event for every value which is different.
+Returns the user as it was before, a taken username or email is reported as
+`ExistingUser` or `ExistingEmail` respectively.
+*/
+pub fn update_user(
+    conn: &MysqlConnection,
+    user_id: u32,
+    changes: &UserChanges,
+) -> IntResult<User> {
+    conn.transaction::<_, IntError, _>(|| {
+        let previous = fetch_user_by_id(conn, user_id)?;
+        // Diesel refuses a changeset without columns
+        if !changes.is_empty() {
+            diesel::update(users::table.find(user_id))
+                .set(&UserChangeset::from(changes))
+                .execute(conn)
+                .map_err(|e| match unique_violation(&e) {
+                    Some(kind) => {
+                        trace!("Unable to update user: {}", kind);
+                        IntError::from(kind)
+                    }
+                    None => {
+                        error!("Unable to update user: {}", e);
+                        e.into()
+                    }
+                })?;
+        }
+
+        for kind in changes.events(&previous) {
+            insert_event(conn, &kind)?;
+        }
+        Ok(previous)
+    })
 }
 
 /*
@@ -194,22 +352,55 @@ pub fn update_email_token(
 }
 
 /*
-Updates role status of a user based on user id.
+Updates role status of a user based on user id, with a `RoleChanged` event.
 Returns true if updated, false if not.
 */
 pub fn update_role(conn: &MysqlConnection, user_id: u32, new_role: String) -> IntResult<bool> {
     use schema::roles::dsl::*;
-    let updated = diesel::update(roles)
-        .set(name.eq(new_role))
-        .filter(id.eq(user_id))
-        .execute(conn)
-        .context(IntErrorKind::QueryError)
-        .map_err(|e| {
-            error!("Failed to update user role: {}", e);
-            e
-        })?;
+    let event = EventKind::RoleChanged {
+        id: user_id.into(),
+        role: new_role.as_str().into(),
+    };
+    conn.transaction::<_, IntError, _>(|| {
+        let updated = diesel::update(roles)
+            .set(name.eq(new_role))
+            .filter(id.eq(user_id))
+            .execute(conn)
+            .context(IntErrorKind::QueryError)
+            .map_err(|e| {
+                error!("Failed to update user role: {}", e);
+                e
+            })?;
+
+        if updated > 0 {
+            insert_event(conn, &event)?;
+        }
+        Ok(updated > 0)
+    })
+}
 
-    Ok(updated > 0)
+/*
+Deletes a user and their role based on user id, with a `UserDeleted` event.
+Returns true if deleted, false if there was no such user.
+*/
+pub fn delete_user(conn: &MysqlConnection, user_id: u32) -> IntResult<bool> {
+    conn.transaction::<_, IntError, _>(|| {
+        diesel::delete(roles::table.find(user_id))
+            .execute(conn)
+            .context(IntErrorKind::QueryError)?;
+        let deleted = diesel::delete(users::table.find(user_id))
+            .execute(conn)
+            .context(IntErrorKind::QueryError)
+            .map_err(|e| {
+                error!("Failed to delete user: {}", e);
+                e
+            })?;
+
+        if deleted > 0 {
+            insert_event(conn, &EventKind::UserDeleted { id: user_id.into() })?;
+        }
+        Ok(deleted > 0)
+    })
 }
 
 /*
@@ -230,6 +421,299 @@ pub fn fetch_user_role(conn: &MysqlConnection, user_id: u32) -> IntResult<Role>
         })
 }
 
+/*
+Registers an OAuth client
+*/
+pub fn insert_client(conn: &MysqlConnection, client: &Client) -> IntResult<()> {
+    use crate::schema::oauth_clients::dsl::*;
+    diesel::insert_into(oauth_clients)
+        .values((
+            id.eq(&client.id),
+            name.eq(&client.name),
+            secret_hash.eq(&client.secret_hash),
+            redirect_uris.eq(&client.redirect_uris),
+        )).execute(conn)
+        .context(IntErrorKind::QueryError)
+        .map_err(|e| {
+            error!("Unable to insert oauth client: {}", e);
+            e
+        })?;
+
+    Ok(())
+}
+
+/*
+Returns an OAuth client based on its id
+*/
+pub fn fetch_client(conn: &MysqlConnection, client_id: &str) -> IntResult<Client> {
+    use crate::schema::oauth_clients::dsl::*;
+    oauth_clients
+        .find(client_id)
+        .first::<Client>(conn)
+        .optional()
+        .context(IntErrorKind::QueryError)?
+        .ok_or_else(|| {
+            trace!("No oauth client found");
+            IntErrorKind::InvalidClient.into()
+        })
+}
+
+/*
+Writes an event to the outbox, in the transaction of the change it is about
+*/
+fn insert_event(conn: &MysqlConnection, kind: &EventKind) -> IntResult<()> {
+    use crate::schema::outbox::dsl::*;
+    diesel::insert_into(outbox)
+        .values(event.eq(encode_event(kind)?))
+        .execute(conn)
+        .context(IntErrorKind::QueryError)
+        .map_err(|e| {
+            error!("Unable to insert event: {}", e);
+            e
+        })?;
+    Ok(())
+}
+
+/*
+Returns at most `limit` events with an id above `after`, by id
+*/
+pub fn fetch_events(conn: &MysqlConnection, after: u64, limit: u32) -> IntResult<Vec<Event>> {
+    use crate::schema::outbox::dsl::*;
+    outbox
+        .filter(id.gt(after))
+        .order(id)
+        .limit(i64::from(limit))
+        .load::<(u64, String, NaiveDateTime)>(conn)
+        .context(IntErrorKind::QueryError)?
+        .into_iter()
+        .map(|(event_id, text, created)| decode_event(event_id, created.timestamp(), &text))
+        .collect()
+}
+
+/*
+Deletes the events up to `up_to` which are older than `created_before`
+Returns the number of deleted events
+*/
+pub fn delete_events(conn: &MysqlConnection, up_to: u64, created_before: i64) -> IntResult<usize> {
+    use crate::schema::outbox::dsl::*;
+    let deleted = diesel::delete(
+        outbox
+            .filter(id.le(up_to))
+            .filter(created_at.lt(NaiveDateTime::from_timestamp(created_before, 0))),
+    ).execute(conn)
+    .context(IntErrorKind::QueryError)
+    .map_err(|e| {
+        error!("Failed to delete events: {}", e);
+        e
+    })?;
+    Ok(deleted)
+}
+
+/*
+Returns the id of the last event delivered to a sink, 0 if none was
+*/
+pub fn fetch_sink_position(conn: &MysqlConnection, sink: &str) -> IntResult<u64> {
+    use crate::schema::event_sinks::dsl::*;
+    Ok(event_sinks
+        .find(sink)
+        .select(position)
+        .first::<u64>(conn)
+        .optional()
+        .context(IntErrorKind::QueryError)?
+        .unwrap_or(0))
+}
+
+/*
+Records that the events up to `new_position` were delivered to a sink
+*/
+pub fn update_sink_position(conn: &MysqlConnection, sink: &str, new_position: u64) -> IntResult<()> {
+    use crate::schema::event_sinks::dsl::*;
+    diesel::replace_into(event_sinks)
+        .values((name.eq(sink), position.eq(new_position)))
+        .execute(conn)
+        .context(IntErrorKind::QueryError)
+        .map_err(|e| {
+            error!("Failed to update sink position: {}", e);
+            e
+        })?;
+    Ok(())
+}
+
+/*
+Adds a webhook, positioned after the last event so it only gets new ones
+Returns the new webhook
+*/
+pub fn insert_webhook(
+    conn: &MysqlConnection,
+    new_url: &str,
+    new_secret: &str,
+    new_events: &[EventType],
+) -> IntResult<Webhook> {
+    use crate::schema::webhooks::dsl::*;
+    let encoded_events = encode_json(&new_events)?;
+
+    conn.transaction::<_, IntError, _>(|| {
+        diesel::insert_into(webhooks)
+            .values((
+                url.eq(new_url),
+                secret.eq(new_secret),
+                events.eq(&encoded_events),
+            )).execute(conn)
+            .context(IntErrorKind::QueryError)
+            .map_err(|e| {
+                error!("Unable to insert webhook: {}", e);
+                e
+            })?;
+        // The secret is random, so it finds the new row
+        let webhook = webhooks
+            .filter(secret.eq(new_secret))
+            .first::<(u32, String, String, String, NaiveDateTime)>(conn)
+            .context(IntErrorKind::QueryError)?;
+        let webhook = webhook_from_row(webhook)?;
+
+        let last_event = outbox::table
+            .select(diesel::dsl::max(outbox::id))
+            .first::<Option<u64>>(conn)
+            .context(IntErrorKind::QueryError)?;
+        update_sink_position(conn, &webhook_sink(webhook.id), last_event.unwrap_or(0))?;
+        Ok(webhook)
+    })
+}
+
+fn webhook_from_row(row: (u32, String, String, String, NaiveDateTime)) -> IntResult<Webhook> {
+    let (webhook_id, webhook_url, webhook_secret, webhook_events, created) = row;
+    Ok(Webhook {
+        id: webhook_id,
+        url: webhook_url,
+        secret: webhook_secret,
+        events: decode_json(&format!("webhook {}", webhook_id), &webhook_events)?,
+        created_at: created.timestamp(),
+    })
+}
+
+/*
+Returns every webhook by id
+*/
+pub fn fetch_webhooks(conn: &MysqlConnection) -> IntResult<Vec<Webhook>> {
+    use crate::schema::webhooks::dsl::*;
+    webhooks
+        .order(id)
+        .load::<(u32, String, String, String, NaiveDateTime)>(conn)
+        .context(IntErrorKind::QueryError)?
+        .into_iter()
+        .map(webhook_from_row)
+        .collect()
+}
+
+/*
+Deletes a webhook with its dead letters and position
+Returns true if deleted, false if not
+*/
+pub fn delete_webhook(conn: &MysqlConnection, webhook_id: u32) -> IntResult<bool> {
+    conn.transaction::<_, IntError, _>(|| {
+        diesel::delete(dead_letters::table.filter(dead_letters::webhook_id.eq(webhook_id)))
+            .execute(conn)
+            .context(IntErrorKind::QueryError)?;
+        diesel::delete(event_sinks::table.find(webhook_sink(webhook_id)))
+            .execute(conn)
+            .context(IntErrorKind::QueryError)?;
+        let deleted = diesel::delete(webhooks::table.find(webhook_id))
+            .execute(conn)
+            .context(IntErrorKind::QueryError)
+            .map_err(|e| {
+                error!("Failed to delete webhook: {}", e);
+                e
+            })?;
+        Ok(deleted > 0)
+    })
+}
+
+/*
+Stores an event a webhook did not accept
+*/
+pub fn insert_dead_letter(
+    conn: &MysqlConnection,
+    failed_webhook: u32,
+    failed_event: &Event,
+    failed_attempts: u32,
+    last_error: &str,
+) -> IntResult<()> {
+    use crate::schema::dead_letters::dsl::*;
+    diesel::insert_into(dead_letters)
+        .values((
+            webhook_id.eq(failed_webhook),
+            event.eq(encode_json(failed_event)?),
+            attempts.eq(failed_attempts),
+            error.eq(last_error),
+        )).execute(conn)
+        .context(IntErrorKind::QueryError)
+        .map_err(|e| {
+            error!("Unable to insert dead letter: {}", e);
+            e
+        })?;
+    Ok(())
+}
+
+/*
+Returns the dead letters of a webhook, or of all webhooks, by id
+*/
+pub fn fetch_dead_letters(
+    conn: &MysqlConnection,
+    of_webhook: Option<u32>,
+) -> IntResult<Vec<DeadLetter>> {
+    use crate::schema::dead_letters::dsl::*;
+    let mut query = dead_letters.order(id).into_boxed();
+    if let Some(of_webhook) = of_webhook {
+        query = query.filter(webhook_id.eq(of_webhook));
+    }
+    query
+        .load::<(u64, u32, String, u32, String, NaiveDateTime)>(conn)
+        .context(IntErrorKind::QueryError)?
+        .into_iter()
+        .map(|(letter_id, letter_webhook, letter_event, letter_attempts, letter_error, failed)| {
+            Ok(DeadLetter {
+                id: letter_id,
+                webhook_id: letter_webhook,
+                event: decode_json(&format!("dead letter {}", letter_id), &letter_event)?,
+                attempts: letter_attempts,
+                error: letter_error,
+                failed_at: failed.timestamp(),
+            })
+        }).collect()
+}
+
+/*
+Records another failed delivery of a dead letter
+Returns true if updated, false if not
+*/
+pub fn update_dead_letter(conn: &MysqlConnection, letter_id: u64, last_error: &str) -> IntResult<bool> {
+    use crate::schema::dead_letters::dsl::*;
+    let updated = diesel::update(dead_letters.find(letter_id))
+        .set((
+            attempts.eq(attempts + 1),
+            error.eq(last_error),
+            failed_at.eq(diesel::dsl::now),
+        )).execute(conn)
+        .context(IntErrorKind::QueryError)
+        .map_err(|e| {
+            error!("Failed to update dead letter: {}", e);
+            e
+        })?;
+    Ok(updated > 0)
+}
+
+/*
+Deletes a dead letter
+Returns true if deleted, false if not
+*/
+pub fn delete_dead_letter(conn: &MysqlConnection, letter_id: u64) -> IntResult<bool> {
+    use crate::schema::dead_letters::dsl::*;
+    let deleted = diesel::delete(dead_letters.find(letter_id))
+        .execute(conn)
+        .context(IntErrorKind::QueryError)?;
+    Ok(deleted > 0)
+}
+
 #[test]
 fn test_insert_user() {
     let mut test_user = User {
@@ -240,6 +724,7 @@ fn test_insert_user() {
         banned: false,
         verified: false,
         email_token: Option::None,
+        created_at: NaiveDateTime::from_timestamp(0, 0),
     };
 
     let new_user = NewUser {
@@ -253,6 +738,7 @@ fn test_insert_user() {
         let userv = insert_user(&conn, new_user.username, new_user.email, new_user.password);
         let user = userv.unwrap();
         test_user.id += user.id;
+        test_user.created_at = user.created_at;
         let test_role = Role {
             id: user.id,
             name: "user".to_string(),
@@ -292,6 +778,7 @@ fn test_update_functions() {
         banned: true,
         verified: true,
         email_token: Option::Some("123456789".to_string()),
+        created_at: NaiveDateTime::from_timestamp(0, 0),
     };
 
     let new_user = NewUser {
@@ -304,6 +791,7 @@ fn test_update_functions() {
         let userv = insert_user(&conn, new_user.username, new_user.email, new_user.password);
         let user = userv.unwrap();
         test_user.id += user.id;
+        test_user.created_at = user.created_at;
         let test_role = Role {
             id: test_user.id,
             name: "moderator".to_string(),
@@ -324,6 +812,135 @@ fn test_update_functions() {
 
         let userv = fetch_user(&conn, &test_user.username);
         assert_eq!(test_user, userv.unwrap());
+        assert_eq!(test_user, fetch_user_by_id(&conn, user.id).unwrap());
+        Err(Error::RollbackTransaction)
+    });
+}
+
+#[test]
+fn test_insert_user_existing() {
+    let conn = establish_connection();
+    &conn.transaction::<(), _, _>(|| {
+        insert_user(
+            &conn,
+            "existing_user".to_string(),
+            "existing_email".to_string(),
+            "password1".to_string(),
+        ).unwrap();
+
+        let same_username = insert_user(
+            &conn,
+            "existing_user".to_string(),
+            "other_email".to_string(),
+            "password1".to_string(),
+        );
+        assert_eq!(
+            IntErrorKind::ExistingUser,
+            same_username.unwrap_err().kind()
+        );
+
+        let same_email = insert_user(
+            &conn,
+            "other_user".to_string(),
+            "existing_email".to_string(),
+            "password1".to_string(),
+        );
+        assert_eq!(IntErrorKind::ExistingEmail, same_email.unwrap_err().kind());
+        assert!(fetch_user(&conn, "other_user").is_err());
+
+        Err(Error::RollbackTransaction)
+    });
+}
+
+#[test]
+fn test_outbox() {
+    let conn = establish_connection();
+    &conn.transaction::<(), _, _>(|| {
+        let last = fetch_events(&conn, 0, u32::max_value())
+            .unwrap()
+            .last()
+            .map(|event| event.id)
+            .unwrap_or(0);
+        let user = insert_user(
+            &conn,
+            "outbox_user".to_string(),
+            "outbox_email".to_string(),
+            "password1".to_string(),
+        ).unwrap();
+        update_ban(&conn, user.id, true).unwrap();
+        assert_eq!(true, delete_user(&conn, user.id).unwrap());
+
+        let kinds: Vec<EventKind> = fetch_events(&conn, last, 10)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        let id = user.id.into();
+        assert_eq!(
+            vec![
+                EventKind::UserRegistered {
+                    id,
+                    username: "outbox_user".to_string()
+                },
+                EventKind::UserBanned { id, banned: true },
+                EventKind::UserDeleted { id },
+            ],
+            kinds
+        );
+
+        update_sink_position(&conn, "test_sink", last + 1).unwrap();
+        assert_eq!(last + 1, fetch_sink_position(&conn, "test_sink").unwrap());
+        Err(Error::RollbackTransaction)
+    });
+}
+
+#[test]
+fn test_webhooks() {
+    let conn = establish_connection();
+    &conn.transaction::<(), _, _>(|| {
+        let webhook = insert_webhook(
+            &conn,
+            "http://127.0.0.1:9999/hook",
+            "test_webhook_secret",
+            &[EventType::UserRegistered, EventType::UserBanned],
+        ).unwrap();
+        assert_eq!(vec![EventType::UserRegistered, EventType::UserBanned], webhook.events);
+        assert!(fetch_webhooks(&conn).unwrap().contains(&webhook));
+
+        let event = Event {
+            id: 1,
+            created_at: 0,
+            kind: EventKind::UserDeleted { id: 1.into() },
+        };
+        insert_dead_letter(&conn, webhook.id, &event, 5, "timed out").unwrap();
+        let letters = fetch_dead_letters(&conn, Some(webhook.id)).unwrap();
+        assert_eq!(event, letters[0].event);
+        assert_eq!(true, update_dead_letter(&conn, letters[0].id, "refused").unwrap());
+        assert_eq!(6, fetch_dead_letters(&conn, Some(webhook.id)).unwrap()[0].attempts);
+
+        assert_eq!(true, delete_webhook(&conn, webhook.id).unwrap());
+        assert!(fetch_dead_letters(&conn, Some(webhook.id)).unwrap().is_empty());
+        Err(Error::RollbackTransaction)
+    });
+}
+
+#[test]
+fn test_insert_client() {
+    let client = Client {
+        id: "test_client".to_string(),
+        name: "Test client".to_string(),
+        secret_hash: Some("0123abcd".to_string()),
+        redirect_uris: "https://example.com/cb https://example.com/other".to_string(),
+    };
+
+    let conn = establish_connection();
+    &conn.transaction::<(), _, _>(|| {
+        insert_client(&conn, &client).unwrap();
+        assert_eq!(client, fetch_client(&conn, "test_client").unwrap());
+        assert_eq!(
+            IntErrorKind::InvalidClient,
+            fetch_client(&conn, "other_client").unwrap_err().kind()
+        );
         Err(Error::RollbackTransaction)
     });
 }
@@ -1,9 +1,12 @@
+use diesel::result::Error as DieselError;
 use failure::{Backtrace, Context, Fail};
 use std::convert::From;
 use std::fmt::{self, Display};
 
 use datatypes::auth::responses::AuthError;
 
+use crate::logging;
+
 /// The type of an internal error ([struct.Error.html])
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Fail)]
 pub enum ErrorKind {
@@ -19,6 +22,18 @@ pub enum ErrorKind {
     InvalidPassword,
     #[fail(display = "invalid token")]
     InvalidToken,
+    #[fail(display = "username is already taken")]
+    ExistingUser,
+    #[fail(display = "email is already in use")]
+    ExistingEmail,
+    #[fail(display = "database schema does not match the expected schema")]
+    SchemaMismatch,
+    #[fail(display = "unknown oauth client")]
+    InvalidClient,
+    #[fail(display = "a request to the LDAP directory failed")]
+    DirectoryError,
+    #[fail(display = "unable to set up TLS")]
+    TlsError,
 }
 
 /// An internal error which can be used for debugging or error tracing
@@ -97,15 +112,44 @@ impl From<Context<ErrorKind>> for Error {
     }
 }
 
+/// Any otherwise unhandled database error is treated as a failed query
+impl From<DieselError> for Error {
+    fn from(e: DieselError) -> Error {
+        e.context(ErrorKind::QueryError).into()
+    }
+}
+
 impl Into<AuthError> for Error {
     fn into(self) -> AuthError {
-        match self.kind() {
+        let error = match self.kind() {
             ErrorKind::ConnectionError => AuthError::InternalServerError,
             ErrorKind::QueryError => AuthError::InternalServerError,
             ErrorKind::InvalidUsername => AuthError::InvalidUsername,
             ErrorKind::InvalidPassword => AuthError::InvalidPassword,
             ErrorKind::InvalidToken => AuthError::InvalidToken,
             ErrorKind::ServerError => AuthError::InternalServerError,
+            ErrorKind::ExistingUser => AuthError::ExistingUser,
+            // `datatypes` has no dedicated variant for a taken email, so the
+            // client is told that the user already exists, and the rpcs add
+            // `ErrorDetail::ExistingEmail` to the `ServiceError`
+            ErrorKind::ExistingEmail => AuthError::ExistingUser,
+            ErrorKind::SchemaMismatch => AuthError::InternalServerError,
+            // Only the OAuth layer looks up clients, and it maps this to its
+            // own `OAuthError::InvalidClient`
+            ErrorKind::InvalidClient => AuthError::InternalServerError,
+            ErrorKind::DirectoryError => AuthError::InternalServerError,
+            ErrorKind::TlsError => AuthError::InternalServerError,
+        };
+
+        // The client only gets the request id, so make sure the id and the
+        // actual cause end up in the same log line
+        if let AuthError::InternalServerError = error {
+            error!(
+                "Internal error returned to client (request id {}): {}",
+                logging::request_id().unwrap_or_else(|| "-".to_owned()),
+                self
+            );
         }
+        error
     }
 }
@@ -0,0 +1,877 @@
+//! Events about users, for other services
+//!
+//! Every change other services care about is written to the `outbox` table in
+//! the same transaction as the change itself, see [`crate::store`], so an
+//! event is never lost when the service crashes, nor sent for a change which
+//! was rolled back. Events are read in two ways:
+//!
+//! - Pushed to the configured sinks over HTTP, HTTPS or tarpc, see
+//! [`auth_client::sink`]. Each sink gets every event in order, and an event
+//! is delivered again until the sink accepts it, waiting longer after every
+//! failure. What a sink has is stored in `event_sinks`.
+//! - Posted to webhooks the same way, see [`crate::webhooks`].
+//! - Pulled by privileged clients with the `events` rpc, which long-polls like
+//! `invalidations` does.
+//!
+//! Every sink and webhook is delivered to by a thread of its own, so one
+//! which is slow or down does not hold up the others.
+//!
+//! Events are kept for `events.retention` once every sink has them.
+use chrono::Utc;
+use failure::ResultExt;
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, HeaderMap, Request, Uri};
+use hyper_openssl::HttpsConnector;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tarpc::future::client::{ClientExt, Options as TarpcOptions};
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+
+use auth_client::sink::FutureClient as SinkClient;
+use auth_client::{Event, EventType, LONG_POLL_SECS};
+use datatypes::auth::responses::AuthError;
+
+use crate::config::EventsConfig;
+use crate::metrics;
+use crate::store::{webhook_sink, Store, UserStore, Webhook};
+use crate::webhooks;
+use crate::{IntErrorKind, IntResult};
+
+/// Most events which are read at once
+pub const MAX_EVENTS: u32 = 100;
+
+/// Seconds after which a gap in the ids of the outbox is taken to be a
+/// transaction which was rolled back, see [`contiguous`]
+const GAP_TIMEOUT_SECS: i64 = 5;
+
+/// Seconds a sink has to accept an event
+const DELIVERY_TIMEOUT_SECS: u64 = 10;
+
+/// How often a sink is checked for new events
+const POLL_INTERVAL_MS: u64 = 500;
+
+/// How often events past their retention are deleted
+const PRUNE_INTERVAL_SECS: u64 = 3600;
+
+/// How often the webhooks are read again, to pick up added and removed ones
+const WEBHOOK_RELOAD_SECS: u64 = 10;
+
+/// Where a sink is reached
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Http(Uri),
+    Tarpc(SocketAddr),
+}
+
+impl Target {
+    /// Parses the url of a sink, `http://...`, `https://...` or
+    /// `tarpc://<address>`
+    pub fn parse(url: &str) -> Result<Target, String> {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            url.parse()
+                .map(Target::Http)
+                .map_err(|e| format!("invalid url '{}': {}", url, e))
+        } else if url.starts_with("tarpc://") {
+            let address = url.trim_left_matches("tarpc://");
+            address
+                .to_socket_addrs()
+                .map_err(|e| format!("unable to resolve '{}': {}", address, e))?
+                .next()
+                .map(Target::Tarpc)
+                .ok_or_else(|| format!("'{}' resolved to nothing", address))
+        } else {
+            Err(format!(
+                "url '{}' must start with 'http://', 'https://' or 'tarpc://'",
+                url
+            ))
+        }
+    }
+}
+
+/// The events which directly follow the one with id `after`
+///
+/// Ids are handed out when an event is inserted, not when its transaction
+/// commits, so an event can show up after one with a higher id. Stopping at
+/// a gap keeps readers from skipping such an event for good. A gap which is
+/// still open `GAP_TIMEOUT_SECS` after the next event was written is a
+/// transaction which was rolled back, or events which were deleted, and is
+/// skipped.
+pub fn contiguous(after: u64, events: Vec<Event>, now: i64) -> Vec<Event> {
+    let mut next = after + 1;
+    let mut result = Vec::new();
+    for event in events {
+        if event.id != next && now - event.created_at < GAP_TIMEOUT_SECS {
+            break;
+        }
+        next = event.id + 1;
+        result.push(event);
+    }
+    result
+}
+
+/// The clients waiting in the `events` rpc
+#[derive(Clone, Default)]
+pub struct EventFeed(Arc<Mutex<Vec<Waiter>>>);
+
+struct Waiter {
+    deadline: Instant,
+    sender: oneshot::Sender<()>,
+}
+
+impl EventFeed {
+    fn lock(&self) -> Result<MutexGuard<Vec<Waiter>>, AuthError> {
+        self.0.lock().map_err(|e| {
+            error!("Unable to lock 'events': {}", e);
+            AuthError::InternalServerError
+        })
+    }
+
+    /// Wakes every waiting client, after events were written
+    ///
+    /// Events written by other instances of the service wake nobody, they
+    /// are read when the long poll ends.
+    pub fn notify(&self) {
+        if let Ok(mut waiters) = self.lock() {
+            for waiter in waiters.drain(..) {
+                // The client may have hung up
+                let _ = waiter.sender.send(());
+            }
+        }
+    }
+
+    /// Resolves on the next `notify`, or after `LONG_POLL_SECS`
+    pub fn wait(&self) -> impl Future<Item = (), Error = AuthError> {
+        let mut waiters = match self.lock() {
+            Ok(waiters) => waiters,
+            Err(e) => return Either::A(future::err(e)),
+        };
+        let (sender, receiver) = oneshot::channel();
+        waiters.push(Waiter {
+            deadline: Instant::now() + Duration::from_secs(LONG_POLL_SECS),
+            sender,
+        });
+        Either::B(receiver.map_err(|_| {
+            error!("A client waiting for events was dropped");
+            AuthError::InternalServerError
+        }))
+    }
+
+    /// Wakes the clients which waited for `LONG_POLL_SECS`, every second on
+    /// the event loop of `handle`
+    pub fn spawn(&self, handle: &Handle) -> IntResult<()> {
+        let feed = self.clone();
+        let expire = Interval::new(Duration::from_secs(1), handle)
+            .map_err(|e| {
+                error!("Unable to create interval: {}", e);
+                IntErrorKind::ServerError
+            })?.for_each(move |()| {
+                feed.expire(Instant::now());
+                Ok(())
+            }).map_err(|e| error!("Event feed timer stopped: {}", e));
+        handle.spawn(expire);
+        Ok(())
+    }
+
+    fn expire(&self, now: Instant) {
+        let mut waiters = match self.lock() {
+            Ok(waiters) => waiters,
+            Err(_) => return,
+        };
+        let all = std::mem::replace(&mut *waiters, Vec::new());
+        for waiter in all {
+            if waiter.sender.is_canceled() {
+                continue;
+            }
+            if waiter.deadline <= now {
+                let _ = waiter.sender.send(());
+            } else {
+                waiters.push(waiter);
+            }
+        }
+    }
+}
+
+/// What a sink which is a webhook has on top of a configured one
+struct Subscription {
+    webhook_id: u32,
+    secret: String,
+    /// Other events are skipped
+    events: Vec<EventType>,
+    /// Attempts after which an event becomes a dead letter
+    max_attempts: u32,
+}
+
+/// A configured sink or a webhook, and how its deliveries are going
+struct Sink {
+    name: String,
+    target: Target,
+    webhook: Option<Subscription>,
+    /// Id of the last event the sink accepted, read from the store once
+    position: Option<u64>,
+    first_delay: Duration,
+    max_delay: Duration,
+    /// How long was waited after the last failure, none if the last delivery
+    /// succeeded
+    delay: Option<Duration>,
+    retry_at: Instant,
+    /// Failed attempts to deliver the next event
+    attempts: u32,
+}
+
+impl Sink {
+    fn new(name: String, target: Target, config: &EventsConfig, now: Instant) -> Sink {
+        Sink {
+            name,
+            target,
+            webhook: None,
+            position: None,
+            first_delay: Duration::from_secs(config.retry_delay),
+            max_delay: Duration::from_secs(config.max_retry_delay),
+            delay: None,
+            retry_at: now,
+            attempts: 0,
+        }
+    }
+
+    fn webhook(webhook: Webhook, config: &EventsConfig, now: Instant) -> IntResult<Sink> {
+        let uri = webhooks::parse_url(&webhook.url).map_err(|e| {
+            error!("Invalid webhook {}: {}", webhook.id, e);
+            IntErrorKind::ServerError
+        })?;
+        let mut sink = Sink::new(webhook_sink(webhook.id), Target::Http(uri), config, now);
+        sink.webhook = Some(Subscription {
+            webhook_id: webhook.id,
+            secret: webhook.secret,
+            events: webhook.events,
+            max_attempts: config.webhook_attempts,
+        });
+        Ok(sink)
+    }
+
+    /// Waits longer before the next attempt, returns how long
+    fn fail(&mut self, now: Instant) -> Duration {
+        let delay = match self.delay {
+            Some(delay) => (delay * 2).min(self.max_delay),
+            None => self.first_delay,
+        };
+        self.delay = Some(delay);
+        self.retry_at = now + delay;
+        delay
+    }
+
+    /// Delivers the pending events in order, until one is refused
+    fn deliver_pending(
+        &mut self,
+        core: &mut Core,
+        http: &HttpClient,
+        connection: &mut Option<SinkClient>,
+        store: &UserStore,
+        now: Instant,
+    ) -> IntResult<()> {
+        let mut position = match self.position {
+            Some(position) => position,
+            None => store.fetch_sink_position(&self.name)?,
+        };
+        self.position = Some(position);
+
+        loop {
+            let events = store.fetch_events(position, MAX_EVENTS)?;
+            let events = contiguous(position, events, Utc::now().timestamp());
+            if events.is_empty() {
+                return Ok(());
+            }
+
+            for event in events {
+                let subscribed = match self.webhook {
+                    Some(ref webhook) => webhook.events.contains(&event.kind.event_type()),
+                    None => true,
+                };
+                if subscribed {
+                    let delivered = {
+                        let secret = self.webhook.as_ref().map(|w| w.secret.as_str());
+                        let timeout = Duration::from_secs(DELIVERY_TIMEOUT_SECS);
+                        match self.target {
+                            Target::Http(ref uri) => {
+                                post(core, http, uri, &event, secret, timeout)
+                            }
+                            Target::Tarpc(address) => call(core, address, connection, &event),
+                        }
+                    };
+                    match delivered {
+                        Ok(()) => {
+                            metrics::EVENT_DELIVERIES
+                                .with_label_values(&[self.name.as_str(), "delivered"])
+                                .inc();
+                            trace!("Delivered event {} to '{}'", event.id, self.name);
+                        }
+                        Err(e) => {
+                            if !self.give_up(store, &event, &e)? {
+                                metrics::EVENT_DELIVERIES
+                                    .with_label_values(&[self.name.as_str(), "failed"])
+                                    .inc();
+                                let delay = self.fail(now);
+                                warn!(
+                                    "Unable to deliver event {} to '{}', trying again in {}s: {}",
+                                    event.id,
+                                    self.name,
+                                    delay.as_secs(),
+                                    e
+                                );
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+
+                position = event.id;
+                self.attempts = 0;
+                self.position = Some(position);
+                self.delay = None;
+                store.update_sink_position(&self.name, position)?;
+            }
+        }
+    }
+
+    /// Counts a failed delivery, and makes the event a dead letter if it was
+    /// the last attempt of a webhook. Returns true if it was.
+    fn give_up(&mut self, store: &UserStore, event: &Event, error: &str) -> IntResult<bool> {
+        self.attempts += 1;
+        let webhook = match self.webhook {
+            Some(ref webhook) if self.attempts >= webhook.max_attempts => webhook,
+            _ => return Ok(false),
+        };
+        store.insert_dead_letter(webhook.webhook_id, event, self.attempts, error)?;
+        metrics::EVENT_DELIVERIES
+            .with_label_values(&[self.name.as_str(), "dead_lettered"])
+            .inc();
+        warn!(
+            "Gave up on delivering event {} to '{}' after {} attempts: {}",
+            event.id, self.name, self.attempts, error
+        );
+        Ok(true)
+    }
+}
+
+pub type HttpClient = hyper::Client<HttpsConnector<HttpConnector>, Body>;
+
+/// A client for HTTP and HTTPS sinks, which runs on `core`
+pub fn http_client(core: &Core) -> IntResult<HttpClient> {
+    let connector = HttpsConnector::new(4).map_err(|e| {
+        error!("Unable to set up TLS for HTTPS sinks: {}", e);
+        IntErrorKind::TlsError
+    })?;
+    Ok(hyper::Client::builder()
+        .executor(core.remote())
+        .build(connector))
+}
+
+/// Delivers the events to one sink, see [`Dispatcher::start`]
+struct Worker {
+    sink: Sink,
+    core: Core,
+    http: HttpClient,
+    /// The open connection of a tarpc sink
+    connection: Option<SinkClient>,
+    store: Store,
+}
+
+impl Worker {
+    fn new(sink: Sink, store: Store) -> IntResult<Worker> {
+        let core = Core::new().context(IntErrorKind::ServerError)?;
+        let http = http_client(&core)?;
+        Ok(Worker {
+            sink,
+            core,
+            http,
+            connection: None,
+            store,
+        })
+    }
+
+    /// Delivers the pending events, unless the sink is waiting to try again
+    fn tick(&mut self, now: Instant) {
+        if self.sink.retry_at > now {
+            return;
+        }
+        let delivered = self.sink.deliver_pending(
+            &mut self.core,
+            &self.http,
+            &mut self.connection,
+            &*self.store,
+            now,
+        );
+        if let Err(e) = delivered {
+            let delay = self.sink.fail(now);
+            error!(
+                "Unable to read events for '{}', trying again in {}s: {}",
+                self.sink.name,
+                delay.as_secs(),
+                e
+            );
+        }
+    }
+}
+
+/// A sink whose worker is running
+struct Running {
+    name: String,
+    webhook_id: Option<u32>,
+    /// Tells the worker to stop after its current delivery
+    stop: Arc<AtomicBool>,
+}
+
+/// Starts a worker for every sink and webhook, and deletes old events
+struct Dispatcher {
+    config: EventsConfig,
+    store: Store,
+    /// The configured sinks, then the webhooks
+    sinks: Vec<Running>,
+    retention: i64,
+    prune_at: Instant,
+    reload_at: Instant,
+}
+
+impl Dispatcher {
+    /// Starts the workers of the configured sinks
+    fn new(config: &EventsConfig, store: Store) -> IntResult<Dispatcher> {
+        let now = Instant::now();
+        let mut dispatcher = Dispatcher {
+            config: config.clone(),
+            store,
+            sinks: Vec::new(),
+            retention: config.retention,
+            prune_at: now,
+            reload_at: now,
+        };
+        for sink in &config.sinks {
+            let target = Target::parse(&sink.url).map_err(|e| {
+                error!("Invalid event sink '{}': {}", sink.name, e);
+                IntErrorKind::ServerError
+            })?;
+            dispatcher.start(Sink::new(sink.name.clone(), target, config, now))?;
+        }
+        Ok(dispatcher)
+    }
+
+    /// Delivers to `sink` on a thread of its own, which waits for nothing but
+    /// the sink
+    fn start(&mut self, sink: Sink) -> IntResult<()> {
+        let name = sink.name.clone();
+        let webhook_id = sink.webhook.as_ref().map(|webhook| webhook.webhook_id);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let store = self.store.clone();
+
+        thread::Builder::new()
+            .name(format!("events-{}", name))
+            .spawn(move || {
+                let mut worker = match Worker::new(sink, store) {
+                    Ok(worker) => worker,
+                    Err(e) => {
+                        error!("Unable to start delivering events: {}", e);
+                        return;
+                    }
+                };
+                while !stopped.load(Ordering::SeqCst) {
+                    worker.tick(Instant::now());
+                    thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                }
+                debug!("Stopped delivering events to '{}'", worker.sink.name);
+            }).context(IntErrorKind::ServerError)?;
+        self.sinks.push(Running {
+            name,
+            webhook_id,
+            stop,
+        });
+        Ok(())
+    }
+
+    /// Starts the webhooks which are new in the store, and stops the removed
+    /// ones
+    fn reload_webhooks(&mut self, now: Instant) -> IntResult<()> {
+        let webhooks = self.store.fetch_webhooks()?;
+        self.sinks.retain(|sink| match sink.webhook_id {
+            Some(id) if !webhooks.iter().any(|w| w.id == id) => {
+                sink.stop.store(true, Ordering::SeqCst);
+                false
+            }
+            _ => true,
+        });
+        for webhook in webhooks {
+            if self.sinks.iter().any(|sink| sink.webhook_id == Some(webhook.id)) {
+                continue;
+            }
+            let id = webhook.id;
+            // An invalid webhook was logged, the others still get their events
+            if let Ok(sink) = Sink::webhook(webhook, &self.config, now) {
+                self.start(sink)?;
+                info!("Delivering events to webhook {}", id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks up changed webhooks, and deletes old events, when it is time to
+    fn tick(&mut self, now: Instant) {
+        if self.reload_at <= now {
+            self.reload_at = now + Duration::from_secs(WEBHOOK_RELOAD_SECS);
+            if let Err(e) = self.reload_webhooks(now) {
+                error!("Unable to read webhooks: {}", e);
+            }
+        }
+
+        if self.prune_at <= now {
+            self.prune_at = now + Duration::from_secs(PRUNE_INTERVAL_SECS);
+            if let Err(e) = self.prune() {
+                error!("Unable to delete old events: {}", e);
+            }
+        }
+    }
+
+    /// Deletes the events which every sink has, and which are older than the
+    /// retention
+    fn prune(&self) -> IntResult<()> {
+        let mut up_to = u64::max_value();
+        for sink in &self.sinks {
+            up_to = up_to.min(self.store.fetch_sink_position(&sink.name)?);
+        }
+        let created_before = Utc::now().timestamp() - self.retention;
+        let deleted = self.store.delete_events(up_to, created_before)?;
+        if deleted > 0 {
+            debug!("Deleted {} old events", deleted);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        for sink in &self.sinks {
+            sink.stop.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Starts delivering events to the configured sinks and the webhooks in
+/// background threads
+///
+/// The dispatcher thread also deletes old events, so it is started without
+/// sinks too.
+pub fn spawn_dispatcher(config: &EventsConfig, store: Store) -> IntResult<()> {
+    let config = config.clone();
+    // Fail on startup, instead of in the thread
+    for sink in &config.sinks {
+        Target::parse(&sink.url).map_err(|e| {
+            error!("Invalid event sink '{}': {}", sink.name, e);
+            IntErrorKind::ServerError
+        })?;
+    }
+
+    thread::Builder::new()
+        .name("event-dispatcher".to_owned())
+        .spawn(move || {
+            let mut dispatcher = match Dispatcher::new(&config, store) {
+                Ok(dispatcher) => dispatcher,
+                Err(e) => {
+                    error!("Unable to start the event dispatcher: {}", e);
+                    return;
+                }
+            };
+            loop {
+                dispatcher.tick(Instant::now());
+                thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            }
+        }).context(IntErrorKind::ServerError)?;
+
+    for sink in &config.sinks {
+        info!("Delivering events to '{}' at {}", sink.name, sink.url);
+    }
+    Ok(())
+}
+
+/// Runs `future` on `core`, giving up after `DELIVERY_TIMEOUT_SECS`
+fn within<F>(core: &mut Core, future: F) -> Result<F::Item, String>
+where
+    F: Future<Error = String>,
+{
+    within_for(core, Duration::from_secs(DELIVERY_TIMEOUT_SECS), future)
+}
+
+/// Runs `future` on `core`, giving up after `timeout`
+fn within_for<F>(core: &mut Core, timeout: Duration, future: F) -> Result<F::Item, String>
+where
+    F: Future<Error = String>,
+{
+    let timeout = Timeout::new(timeout, &core.handle()).map_err(|e| e.to_string())?;
+    match core.run(future.select2(timeout)) {
+        Ok(Either::A((item, _))) => Ok(item),
+        Ok(Either::B(_)) => Err("timed out".to_owned()),
+        Err(Either::A((e, _))) => Err(e),
+        Err(Either::B((e, _))) => Err(e.to_string()),
+    }
+}
+
+/// Posts an event as json, signed if there is a secret, any status but 2xx
+/// and no response within `timeout` are failures
+pub fn post(
+    core: &mut Core,
+    http: &HttpClient,
+    uri: &Uri,
+    event: &Event,
+    secret: Option<&str>,
+    timeout: Duration,
+) -> Result<(), String> {
+    let body = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+    let mut headers = HeaderMap::new();
+    if let Some(secret) = secret {
+        webhooks::sign(&mut headers, secret, event.id, &body);
+    }
+    let mut request = Request::post(uri.clone())
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .map_err(|e| e.to_string())?;
+    request.headers_mut().extend(headers);
+
+    let response = within_for(core, timeout, http.request(request).map_err(|e| e.to_string()))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("the sink responded with {}", response.status()))
+    }
+}
+
+/// Calls `deliver` on a tarpc sink, connecting first if needed
+fn call(
+    core: &mut Core,
+    address: SocketAddr,
+    connection: &mut Option<SinkClient>,
+    event: &Event,
+) -> Result<(), String> {
+    let client = match connection.take() {
+        Some(client) => client,
+        None => {
+            let options = TarpcOptions::default().handle(core.handle());
+            within(
+                core,
+                SinkClient::connect(address, options).map_err(|e| e.to_string()),
+            )?
+        }
+    };
+    within(
+        core,
+        client.deliver(event.clone()).map_err(|e| match e {
+            tarpc::Error::App(rejected) => rejected.to_string(),
+            e => format!("{:?}", e),
+        }),
+    )?;
+    // Only a connection which just worked is kept
+    *connection = Some(client);
+    Ok(())
+}
+
+#[test]
+fn test_contiguous() {
+    use auth_client::EventKind;
+
+    let event = |id: u64, created_at: i64| Event {
+        id,
+        created_at,
+        kind: EventKind::UserDeleted { id: 1.into() },
+    };
+    let ids = |events: Vec<Event>| events.iter().map(|e| e.id).collect::<Vec<_>>();
+    let now = 100;
+
+    assert_eq!(vec![3, 4], ids(contiguous(2, vec![event(3, now), event(4, now)], now)));
+    // Event 4 may still be committed
+    assert_eq!(vec![3], ids(contiguous(2, vec![event(3, now), event(5, now)], now)));
+    assert!(contiguous(2, vec![event(4, now)], now).is_empty());
+    // But not this long after event 5 was written
+    let old = now - GAP_TIMEOUT_SECS;
+    assert_eq!(vec![3, 5], ids(contiguous(2, vec![event(3, old), event(5, old)], now)));
+}
+
+/// Serves one request for each of `statuses` on a local port, and returns
+/// the headers and body of each
+#[cfg(test)]
+pub fn receive(
+    statuses: &'static [&'static str],
+) -> (
+    SocketAddr,
+    thread::JoinHandle<Vec<(std::collections::HashMap<String, String>, Vec<u8>)>>,
+) {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    let receiver = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = receiver.local_addr().unwrap();
+    let received = thread::spawn(move || {
+        let mut requests = Vec::new();
+        for status in statuses {
+            let (stream, _) = receiver.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(colon) = line.find(':') {
+                    let name = line[..colon].to_lowercase();
+                    headers.insert(name, line[colon + 1..].trim().to_owned());
+                }
+            }
+            let length = headers
+                .get("content-length")
+                .map(|length| length.parse().unwrap())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            requests.push((headers, body));
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            ).unwrap();
+        }
+        requests
+    });
+    (address, received)
+}
+
+#[test]
+fn test_dispatch() {
+    use std::sync::Arc;
+
+    use crate::store::memory::{add_user, MemoryStore};
+
+    // Refuses the first event, then accepts everything
+    let (address, received) = receive(&["500 Internal Server Error", "200 OK", "200 OK"]);
+
+    let store = Arc::new(MemoryStore::default());
+    for name in &["first", "second"] {
+        add_user(&store, name);
+    }
+    let config = EventsConfig::default();
+    let now = Instant::now();
+    let target = Target::parse(&format!("http://{}/events", address)).unwrap();
+    let sink = Sink::new("receiver".to_owned(), target, &config, now);
+    let mut worker = Worker::new(sink, store.clone()).unwrap();
+
+    worker.tick(now);
+    assert_eq!(0, store.fetch_sink_position("receiver").unwrap());
+    // Nothing is sent before the retry delay has passed
+    worker.tick(now);
+    worker.tick(now + Duration::from_secs(config.retry_delay));
+    assert_eq!(2, store.fetch_sink_position("receiver").unwrap());
+
+    let ids: Vec<u64> = received
+        .join()
+        .unwrap()
+        .iter()
+        .map(|(_, body)| serde_json::from_slice::<Event>(body).unwrap().id)
+        .collect();
+    assert_eq!(vec![1, 1, 2], ids);
+}
+
+#[test]
+fn test_webhook_dispatch() {
+    use std::sync::Arc;
+
+    use crate::store::memory::{add_user, MemoryStore};
+
+    // Refuses the first event until it is dead-lettered, then accepts
+    let (address, received) = receive(&[
+        "500 Internal Server Error",
+        "503 Service Unavailable",
+        "200 OK",
+    ]);
+
+    let store = Arc::new(MemoryStore::default());
+    add_user(&store, "before");
+    let webhook = store
+        .insert_webhook(
+            &format!("http://{}/hook", address),
+            "secret",
+            &[EventType::UserRegistered],
+        ).unwrap();
+    let first = add_user(&store, "first");
+    // Not subscribed to
+    store.update_verify(first.id, true).unwrap();
+    add_user(&store, "second");
+
+    let config = EventsConfig {
+        webhook_attempts: 2,
+        ..EventsConfig::default()
+    };
+    let now = Instant::now();
+    let sink = Sink::webhook(store.fetch_webhooks().unwrap().remove(0), &config, now).unwrap();
+    let mut worker = Worker::new(sink, store.clone()).unwrap();
+    worker.tick(now);
+    worker.tick(now + Duration::from_secs(config.retry_delay));
+    assert_eq!(4, store.fetch_sink_position(&webhook_sink(webhook.id)).unwrap());
+
+    let dead_letters = store.fetch_dead_letters(Some(webhook.id)).unwrap();
+    assert_eq!(1, dead_letters.len());
+    assert_eq!(2, dead_letters[0].event.id);
+    assert_eq!(2, dead_letters[0].attempts);
+
+    let requests = received.join().unwrap();
+    let ids: Vec<u64> = requests
+        .iter()
+        .map(|(headers, _)| headers["x-auth-event-id"].parse().unwrap())
+        .collect();
+    // The event before the webhook was added is not posted
+    assert_eq!(vec![2, 2, 4], ids);
+    for (headers, body) in &requests {
+        let timestamp = headers["x-auth-timestamp"].parse().unwrap();
+        assert_eq!(
+            webhooks::signature("secret", timestamp, body),
+            headers["x-auth-signature"]
+        );
+    }
+}
+
+#[test]
+fn test_slow_sink() {
+    use std::sync::Arc;
+
+    use crate::config::EventSink;
+    use crate::store::memory::{add_user, MemoryStore};
+
+    // Connections are accepted by the kernel, but nobody ever responds
+    let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let (address, received) = receive(&["200 OK"]);
+
+    let store = Arc::new(MemoryStore::default());
+    store
+        .insert_webhook(
+            &format!("http://{}/hook", address),
+            "secret",
+            &[EventType::UserRegistered],
+        ).unwrap();
+    add_user(&store, "user");
+    let config = EventsConfig {
+        sinks: vec![EventSink {
+            name: "silent".to_owned(),
+            url: format!("http://{}/events", silent.local_addr().unwrap()),
+        }],
+        ..EventsConfig::default()
+    };
+
+    // The webhook gets the event while the silent sink is still waiting
+    let start = Instant::now();
+    let mut dispatcher = Dispatcher::new(&config, store.clone()).unwrap();
+    dispatcher.tick(start);
+    assert_eq!(1, received.join().unwrap().len());
+    assert!(start.elapsed() < Duration::from_secs(DELIVERY_TIMEOUT_SECS));
+    assert_eq!(0, store.fetch_sink_position("silent").unwrap());
+}
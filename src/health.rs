@@ -0,0 +1,132 @@
+//! Liveness and readiness of the service
+//!
+//! - Live: the event loop is running, which is shown by a heartbeat it
+//! updates every second.
+//! - Ready: live, and the database can be reached and has no pending
+//! migrations.
+use futures::{Future, Stream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_core::reactor::{Handle, Interval};
+
+pub use auth_client::{Check, HealthReport};
+
+use crate::store::UserStore;
+use crate::IntResult;
+
+/// Seconds without a heartbeat after which the event loop is considered stuck
+const MAX_HEARTBEAT_AGE: u64 = 5;
+
+/// The last time the event loop was seen running, in seconds since the epoch
+#[derive(Clone, Default)]
+pub struct Heartbeat(Arc<AtomicUsize>);
+
+impl Heartbeat {
+    /// Beats every second on the event loop of `handle`
+    pub fn spawn(&self, handle: &Handle) -> IntResult<()> {
+        let heartbeat = self.clone();
+        heartbeat.beat();
+
+        let beats = Interval::new(Duration::from_secs(1), handle)
+            .map_err(|e| {
+                error!("Unable to start heartbeat: {}", e);
+                crate::IntErrorKind::ServerError
+            })?.for_each(move |_| {
+                heartbeat.beat();
+                Ok(())
+            }).map_err(|e| error!("Heartbeat stopped: {}", e));
+        handle.spawn(beats);
+        Ok(())
+    }
+
+    fn beat(&self) {
+        self.0.store(now() as usize, Ordering::SeqCst);
+    }
+
+    fn last(&self) -> u64 {
+        self.0.load(Ordering::SeqCst) as u64
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Checks whether the event loop is running
+pub fn liveness(heartbeat: &Heartbeat) -> Check {
+    let age = now().saturating_sub(heartbeat.last());
+    Check {
+        name: "reactor".to_owned(),
+        ok: heartbeat.last() > 0 && age <= MAX_HEARTBEAT_AGE,
+        detail: if heartbeat.last() == 0 {
+            "not started".to_owned()
+        } else {
+            format!("last heartbeat {}s ago", age)
+        },
+    }
+}
+
+/// Runs every check, this blocks on the database
+pub fn report(heartbeat: &Heartbeat, store: &UserStore) -> HealthReport {
+    let reactor = liveness(heartbeat);
+
+    let database = match store.ping() {
+        Ok(()) => Check {
+            name: "database".to_owned(),
+            ok: true,
+            detail: "connection checked out".to_owned(),
+        },
+        Err(e) => Check {
+            name: "database".to_owned(),
+            ok: false,
+            detail: e.to_string(),
+        },
+    };
+
+    let migrations = match store.pending_migrations() {
+        Ok(0) => Check {
+            name: "migrations".to_owned(),
+            ok: true,
+            detail: "up to date".to_owned(),
+        },
+        Ok(pending) => Check {
+            name: "migrations".to_owned(),
+            ok: false,
+            detail: format!("{} pending", pending),
+        },
+        Err(e) => Check {
+            name: "migrations".to_owned(),
+            ok: false,
+            detail: e.to_string(),
+        },
+    };
+
+    let checks = vec![reactor, database, migrations];
+    HealthReport {
+        live: checks[0].ok,
+        ready: checks.iter().all(|c| c.ok),
+        checks,
+    }
+}
+
+#[test]
+fn test_report() {
+    use crate::store::memory::MemoryStore;
+
+    let heartbeat = Heartbeat::default();
+    let store = MemoryStore::default();
+
+    let health = report(&heartbeat, &store);
+    assert!(!health.live);
+    assert!(!health.ready);
+    assert_eq!("not started", health.checks[0].detail);
+
+    heartbeat.beat();
+    let health = report(&heartbeat, &store);
+    assert!(health.live);
+    assert!(health.ready);
+}
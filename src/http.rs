@@ -0,0 +1,110 @@
+//! A small HTTP listener for operators
+//!
+//! Serves the prometheus metrics on `GET /metrics`, liveness on `GET /healthz`
+//! and readiness on `GET /readyz`. It runs on its own thread with its own
+//! event loop, so a slow scrape never holds up the rpc server.
+use failure::ResultExt;
+use futures::future::{self, Future};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{Encoder, TextEncoder};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::thread;
+
+use crate::service::AuthServer;
+use crate::{IntErrorKind, IntResult};
+
+/// Starts the admin listener on `address` in a background thread
+pub fn spawn_admin(address: SocketAddr, server: AuthServer) -> IntResult<()> {
+    let builder = Server::try_bind(&address).context(IntErrorKind::ServerError)?;
+
+    let new_service = move || {
+        let server = server.clone();
+        service_fn(move |request: Request<Body>| route(&server, &request))
+    };
+    let listener = builder
+        .serve(new_service)
+        .map_err(|e| error!("Admin listener failed: {}", e));
+
+    thread::Builder::new()
+        .name("admin-http".to_owned())
+        .spawn(move || hyper::rt::run(listener))
+        .context(IntErrorKind::ServerError)?;
+
+    info!("Serving metrics and health checks on http://{}", address);
+    Ok(())
+}
+
+type HttpFuture = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+fn route(server: &AuthServer, request: &Request<Body>) -> HttpFuture {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => metrics(server),
+        (&Method::GET, "/healthz") => {
+            let check = server.liveness();
+            json(status(check.ok), &check)
+        }
+        (&Method::GET, "/readyz") => {
+            // The report blocks on the database, which must not hold up the
+            // event loop
+            let response = server.spawn_health().then(|report| {
+                let response = match report {
+                    Ok(report) => json(status(report.ready), &report),
+                    Err(()) => text(StatusCode::INTERNAL_SERVER_ERROR, "health check failed"),
+                };
+                Ok::<_, hyper::Error>(response)
+            });
+            return Box::new(response);
+        }
+        _ => text(StatusCode::NOT_FOUND, "not found"),
+    };
+    Box::new(future::ok(response))
+}
+
+fn metrics(server: &AuthServer) -> Response<Body> {
+    server.update_gauges();
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!("Unable to encode metrics: {}", e);
+        return text(StatusCode::INTERNAL_SERVER_ERROR, "unable to encode metrics");
+    }
+
+    Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap_or_else(|_| text(StatusCode::INTERNAL_SERVER_ERROR, "invalid response"))
+}
+
+fn status(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// A json response
+pub fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    match serde_json::to_vec(body) {
+        Ok(body) => Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap_or_else(|_| text(StatusCode::INTERNAL_SERVER_ERROR, "invalid response")),
+        Err(e) => {
+            error!("Unable to encode response: {}", e);
+            text(StatusCode::INTERNAL_SERVER_ERROR, "unable to encode response")
+        }
+    }
+}
+
+/// A plain text response
+pub fn text(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,214 @@
+//! Invalidations for clients which cache `get_user`
+//!
+//! Every revoked session and every role change is logged with the id of the
+//! user. Clients long-poll `invalidations` with the cursor of the last batch
+//! they got: the call returns as soon as there is something newer, or empty
+//! after `LONG_POLL_SECS`.
+//!
+//! Only the latest `RETAINED` invalidations are kept. A client which fell
+//! further behind, or whose cursor is from before a restart, is told to
+//! `reset`, and drops everything it cached.
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use rand::{thread_rng, Rng};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Interval};
+
+use auth_client::{Cursor, Invalidations, LONG_POLL_SECS};
+use datatypes::auth::responses::AuthError;
+use datatypes::valid::ids::UserId;
+
+use crate::{IntErrorKind, IntResult};
+
+/// Number of invalidations which are kept for clients which are behind
+const RETAINED: usize = 4096;
+
+/// The invalidations, and the clients waiting for them
+#[derive(Clone)]
+pub struct InvalidationLog(Arc<Mutex<Log>>);
+
+struct Log {
+    epoch: u64,
+    /// Position of the latest invalidation
+    position: u64,
+    events: VecDeque<(u64, UserId)>,
+    waiters: Vec<Waiter>,
+}
+
+struct Waiter {
+    since: Cursor,
+    deadline: Instant,
+    sender: oneshot::Sender<Invalidations>,
+}
+
+impl Default for InvalidationLog {
+    fn default() -> InvalidationLog {
+        // Never 0, which is the epoch of the default cursor
+        let epoch = thread_rng().gen::<u64>() | 1;
+        InvalidationLog(Arc::new(Mutex::new(Log {
+            epoch,
+            position: 0,
+            events: VecDeque::new(),
+            waiters: Vec::new(),
+        })))
+    }
+}
+
+impl InvalidationLog {
+    fn lock(&self) -> Result<MutexGuard<Log>, AuthError> {
+        self.0.lock().map_err(|e| {
+            error!("Unable to lock 'invalidations': {}", e);
+            AuthError::InternalServerError
+        })
+    }
+
+    /// Logs that the sessions or the role of a user changed, and wakes every
+    /// waiting client
+    pub fn publish(&self, user_id: UserId) {
+        let mut log = match self.lock() {
+            Ok(log) => log,
+            Err(_) => return,
+        };
+        log.position += 1;
+        let position = log.position;
+        log.events.push_back((position, user_id));
+        if log.events.len() > RETAINED {
+            log.events.pop_front();
+        }
+
+        let waiters = std::mem::replace(&mut log.waiters, Vec::new());
+        for waiter in waiters {
+            // The client may have hung up
+            let _ = waiter.sender.send(log.since(waiter.since));
+        }
+    }
+
+    /// Resolves with the invalidations after `since`, once there are any
+    pub fn poll(&self, since: Cursor) -> impl Future<Item = Invalidations, Error = AuthError> {
+        let mut log = match self.lock() {
+            Ok(log) => log,
+            Err(e) => return Either::A(future::err(e)),
+        };
+        let invalidations = log.since(since);
+        if invalidations.reset || !invalidations.users.is_empty() {
+            return Either::A(future::ok(invalidations));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        log.waiters.push(Waiter {
+            since,
+            deadline: Instant::now() + Duration::from_secs(LONG_POLL_SECS),
+            sender,
+        });
+        Either::B(receiver.map_err(|_| {
+            error!("A client waiting for invalidations was dropped");
+            AuthError::InternalServerError
+        }))
+    }
+
+    /// Answers the clients which waited for `LONG_POLL_SECS`, every second
+    /// on the event loop of `handle`
+    pub fn spawn(&self, handle: &Handle) -> IntResult<()> {
+        let log = self.clone();
+        let expire = Interval::new(Duration::from_secs(1), handle)
+            .map_err(|e| {
+                error!("Unable to create interval: {}", e);
+                IntErrorKind::ServerError
+            })?.for_each(move |()| {
+                log.expire(Instant::now());
+                Ok(())
+            }).map_err(|e| error!("Invalidation timer stopped: {}", e));
+        handle.spawn(expire);
+        Ok(())
+    }
+
+    fn expire(&self, now: Instant) {
+        let mut log = match self.lock() {
+            Ok(log) => log,
+            Err(_) => return,
+        };
+        let waiters = std::mem::replace(&mut log.waiters, Vec::new());
+        for waiter in waiters {
+            if waiter.sender.is_canceled() {
+                continue;
+            }
+            if waiter.deadline <= now {
+                let _ = waiter.sender.send(log.since(waiter.since));
+            } else {
+                log.waiters.push(waiter);
+            }
+        }
+    }
+}
+
+impl Log {
+    fn since(&self, since: Cursor) -> Invalidations {
+        // Every invalidation after this position is still kept
+        let oldest = self
+            .events
+            .front()
+            .map(|(position, _)| position - 1)
+            .unwrap_or(self.position);
+        let reset =
+            since.epoch != self.epoch || since.position > self.position || since.position < oldest;
+
+        let mut users = Vec::new();
+        if !reset {
+            for (position, user_id) in &self.events {
+                if *position > since.position && !users.contains(user_id) {
+                    users.push(*user_id);
+                }
+            }
+        }
+        Invalidations {
+            cursor: Cursor {
+                epoch: self.epoch,
+                position: self.position,
+            },
+            reset,
+            users,
+        }
+    }
+}
+
+#[test]
+fn test_invalidations() {
+    let log = InvalidationLog::default();
+
+    // A new subscription starts with a reset
+    let start = log.poll(Cursor::default()).wait().unwrap();
+    assert!(start.reset);
+    assert!(start.users.is_empty());
+
+    // A client which is up to date waits for the next invalidation
+    let waiting = log.poll(start.cursor);
+    log.publish(7.into());
+    log.publish(7.into());
+    let next = waiting.wait().unwrap();
+    assert!(!next.reset);
+    assert_eq!(vec![UserId::from(7)], next.users);
+    assert_eq!(start.cursor.position + 1, next.cursor.position);
+
+    // A client which is behind gets everything it missed, once per user
+    log.publish(8.into());
+    let behind = log.poll(next.cursor).wait().unwrap();
+    assert_eq!(vec![UserId::from(7), UserId::from(8)], behind.users);
+
+    // Waiting clients get an empty batch after the long poll
+    let waiting = log.poll(behind.cursor);
+    log.expire(Instant::now() + Duration::from_secs(LONG_POLL_SECS));
+    let empty = waiting.wait().unwrap();
+    assert!(!empty.reset);
+    assert!(empty.users.is_empty());
+
+    // Cursors of another run, or from before what is kept, reset
+    let restarted = InvalidationLog::default();
+    assert!(restarted.poll(behind.cursor).wait().unwrap().reset);
+    for user_id in 0..=RETAINED as u32 {
+        log.publish(user_id.into());
+    }
+    assert!(log.poll(behind.cursor).wait().unwrap().reset);
+}
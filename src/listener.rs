@@ -0,0 +1,260 @@
+//! The sockets the rpcs are served on
+//!
+//! - TCP on every address `server.address` resolves to, e.g. both
+//! `127.0.0.1` and `::1` for `localhost`
+//! - TLS on those addresses instead, if `tls.cert` is set, see [`crate::tls`]
+//! - a Unix domain socket on `server.unix_socket`, for services on the same
+//! host. Who may connect is decided by the permissions of the socket, and
+//! everyone who can may call privileged rpcs
+//!
+//! tarpc can only listen on TCP, so TLS connections and the Unix socket are
+//! served in process, see [`crate::connection`].
+//!
+//! On Linux, `[::]` also accepts IPv4 connections unless the
+//! `net.ipv6.bindv6only` sysctl is set, so `0.0.0.0` and `[::]` can not be
+//! listened on with the same port. `[::]` alone serves both.
+use failure::ResultExt;
+use futures::{Future, Stream};
+use std::fs::{self, DirBuilder, Permissions};
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tarpc::future::server::{Handle as ServerHandle, Options};
+use tokio_core::reactor::Handle;
+use tokio_uds::UnixListener;
+
+use crate::config::Config;
+use crate::connection::{self, Drain, Tracker};
+use crate::service::{AuthServer, FutureServiceExt};
+use crate::{IntErrorKind, IntResult};
+
+/// Every listener of the server
+pub struct Listeners {
+    handles: Vec<ServerHandle>,
+    /// Stops the listeners which are served in process
+    drain: Drain,
+    unix_socket: Option<PathBuf>,
+}
+
+impl Listeners {
+    /// Starts every configured listener on the reactor
+    pub fn start(config: &Config, server: &AuthServer, reactor: &Handle) -> IntResult<Listeners> {
+        let addresses = config.server.socket_addrs().map_err(|e| {
+            error!("{}", e);
+            IntErrorKind::ServerError
+        })?;
+        let (tracker, drain) = connection::tracker();
+        let mut listeners = Listeners {
+            handles: Vec::new(),
+            drain,
+            unix_socket: None,
+        };
+
+        // The configuration is only valid with TLS if the feature is on
+        if config.tls.enabled() {
+            serve_tls(config, server, &tracker, &addresses, reactor)?;
+        } else {
+            for address in addresses {
+                listeners.listen(server.clone(), address, reactor)?;
+                info!("Serving rpcs on {}", address);
+            }
+        }
+
+        if !config.server.unix_socket.is_empty() {
+            let path = Path::new(&config.server.unix_socket);
+            let mode = config.server.unix_socket_mode().map_err(|e| {
+                error!("{}", e);
+                IntErrorKind::ServerError
+            })?;
+            serve_unix(path, mode, server, &tracker, reactor)?;
+            listeners.unix_socket = Some(path.to_owned());
+            info!("Serving rpcs on {}", path.display());
+        }
+        Ok(listeners)
+    }
+
+    /// Stops accepting connections, and resolves once every connection is
+    /// closed
+    pub fn shutdown(self) -> impl Future<Item = (), Error = ()> {
+        if let Some(ref path) = self.unix_socket {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Unable to remove '{}': {}", path.display(), e);
+            }
+        }
+        let tarpc = futures::future::join_all(
+            self.handles
+                .iter()
+                .map(|handle| handle.shutdown().shutdown())
+                .collect::<Vec<_>>(),
+        );
+        tarpc.join(self.drain.shutdown()).map(|_| ())
+    }
+
+    /// Serves `server` with tarpc on `address`, and returns the address it
+    /// is bound to
+    fn listen(
+        &mut self,
+        server: AuthServer,
+        address: SocketAddr,
+        reactor: &Handle,
+    ) -> IntResult<SocketAddr> {
+        let (handle, listener) = server
+            .listen(address, reactor, Options::default())
+            .map_err(|e| {
+                error!("Unable to listen on {}: {:?}", address, e);
+                IntErrorKind::ServerError
+            })?;
+        reactor.spawn(listener);
+        let bound = handle.addr();
+        self.handles.push(handle);
+        Ok(bound)
+    }
+}
+
+/// Serves TLS on every address
+#[cfg(feature = "tls")]
+fn serve_tls(
+    config: &Config,
+    server: &AuthServer,
+    tracker: &Tracker,
+    addresses: &[SocketAddr],
+    reactor: &Handle,
+) -> IntResult<()> {
+    use crate::signal;
+    use crate::tls::TlsListener;
+
+    let listener = TlsListener::new(&config.tls)?;
+    for address in addresses {
+        reactor.spawn(listener.serve(*address, server, tracker, reactor)?);
+        info!("Serving rpcs over TLS on {}", address);
+    }
+    reactor.spawn(
+        signal::reload()
+            .for_each(move |()| {
+                listener.reload();
+                Ok(())
+            }).map_err(|e| error!("Unable to listen for SIGHUP: {}", e)),
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "tls"))]
+fn serve_tls(
+    _: &Config,
+    _: &AuthServer,
+    _: &Tracker,
+    _: &[SocketAddr],
+    _: &Handle,
+) -> IntResult<()> {
+    error!("TLS is configured, but the service was built without the 'tls' feature");
+    Err(IntErrorKind::TlsError)?
+}
+
+/// Accepts connections on a Unix domain socket and serves `server` on them,
+/// until `tracker` stops it
+///
+/// The socket is bound in a directory only the service can enter, and moved
+/// to `path` once it has its permissions, so nobody can connect before.
+fn serve_unix(
+    path: &Path,
+    mode: u32,
+    server: &AuthServer,
+    tracker: &Tracker,
+    reactor: &Handle,
+) -> IntResult<()> {
+    // A socket left behind by a previous run would make the rename fail
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path).context(IntErrorKind::ServerError)?;
+        }
+    }
+    let mut private = path.as_os_str().to_owned();
+    private.push(".bind");
+    let private = PathBuf::from(private);
+    // Left behind by a crash
+    if private.exists() {
+        fs::remove_dir_all(&private).context(IntErrorKind::ServerError)?;
+    }
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .context(IntErrorKind::ServerError)?;
+    let bound = private.join("socket");
+    let listener = UnixListener::bind(&bound, reactor).context(IntErrorKind::ServerError)?;
+    fs::set_permissions(&bound, Permissions::from_mode(mode)).context(IntErrorKind::ServerError)?;
+    fs::rename(&bound, path).context(IntErrorKind::ServerError)?;
+    fs::remove_dir(&private).context(IntErrorKind::ServerError)?;
+
+    let handle = reactor.clone();
+    let server = server.clone();
+    let connections = tracker.clone();
+    let serve = listener
+        .incoming()
+        .map_err(|e| error!("Unix socket listener failed: {}", e))
+        .for_each(move |(stream, _)| {
+            let connection = connection::serve(stream, server.clone());
+            handle.spawn(connections.connection(connection));
+            Ok(())
+        });
+    reactor.spawn(tracker.accept(serve));
+    Ok(())
+}
+
+#[test]
+fn test_unix_socket() {
+    use crate::store::memory::{add_user, MemoryStore};
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use futures::future::Either;
+    use std::thread;
+    use std::time::Duration;
+    use tokio_core::reactor::{Core, Timeout};
+
+    let path = std::env::temp_dir().join(format!("auth-service-{}.sock", std::process::id()));
+    let mut config = Config::default();
+    config.server.address = "127.0.0.1:0, [::1]:0".to_owned();
+    config.server.unix_socket = path.to_string_lossy().into_owned();
+
+    // A stale socket is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let store = Arc::new(MemoryStore::default());
+    add_user(&store, "user");
+    let server = AuthServer::with_store(&config, store);
+
+    let mut core = Core::new().unwrap();
+    let listeners = Listeners::start(&config, &server, &core.handle()).unwrap();
+    // The socket is served without a tarpc listener
+    assert_eq!(2, listeners.handles.len());
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(0o660, mode & 0o777);
+    assert!(!Path::new(&format!("{}.bind", path.display())).exists());
+
+    // The connection is closed on a frame which can not be parsed
+    let client = thread::spawn({
+        let path = path.clone();
+        move || {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            stream.write_all(&[0xff; 64]).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).is_ok()
+        }
+    });
+    let timeout = Timeout::new(Duration::from_millis(500), &core.handle()).unwrap();
+    core.run(timeout).unwrap();
+    assert!(client.join().unwrap());
+
+    // Shutting down waits for the connections which are still open
+    let open = UnixStream::connect(&path).unwrap();
+    let handle = core.handle();
+    let wait = |millis| Timeout::new(Duration::from_millis(millis), &handle).unwrap();
+    core.run(wait(100)).unwrap();
+    let shutdown = match core.run(listeners.shutdown().select2(wait(200))) {
+        Ok(Either::B((_, shutdown))) => shutdown,
+        _ => panic!("shutdown did not wait for the open connection"),
+    };
+    assert!(!path.exists());
+    drop(open);
+    core.run(shutdown).unwrap();
+}
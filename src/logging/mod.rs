@@ -0,0 +1,405 @@
+//! Logging setup
+//!
+//! Logs go to stdout and/or a rotated file, either as human readable lines or
+//! as one JSON object per line. The rpc currently handled by a thread is
+//! tracked in a thread local [`Context`], which is included in every line.
+//!
+//! Every rpc runs in a span, entered with [`enter_request`], which gives it a
+//! request id. Work moved to another thread re-enters the same span:
+//!
+//! ```ignore
+//! let _span = logging::enter_request("register", None);
+//! let span = logging::current();
+//! pool.spawn(futures::lazy(move || {
+//!     let _span = logging::enter(span);
+//!     logging::set_user_id(id);
+//!     info!("Registered");  // [register 5f2c3a9e1b7d4c60 user=1] Registered
+//! }))
+//! ```
+use std::cell::RefCell;
+use std::io;
+use std::str::FromStr;
+
+use crate::config::LoggingConfig;
+
+pub mod rotate;
+
+/// Target of the audit log, the changes admins make to users. Its lines are
+/// written with all others, at info level, and told apart by the target.
+pub const AUDIT_TARGET: &str = "audit";
+
+/// The format of each log line
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the current thread is working on
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    pub rpc: Option<&'static str>,
+    pub request_id: Option<String>,
+    pub user_id: Option<u32>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+/// Restores the previous [`Context`] of the thread when dropped
+pub struct ContextGuard {
+    previous: Context,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = std::mem::replace(&mut self.previous, Context::default());
+        CONTEXT.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+/// Sets the context of the current thread until the guard is dropped
+pub fn enter(context: Context) -> ContextGuard {
+    let previous = CONTEXT.with(|c| std::mem::replace(&mut *c.borrow_mut(), context));
+    ContextGuard { previous }
+}
+
+/// Marks the current thread as handling `rpc` until the guard is dropped
+pub fn enter_rpc(rpc: &'static str) -> ContextGuard {
+    enter(Context {
+        rpc: Some(rpc),
+        ..current()
+    })
+}
+
+/// Enters the span of a new request to `rpc`
+///
+/// The caller may supply a request id, which is used if it is a reasonable
+/// id. Otherwise the id of the enclosing request is kept, e.g. when the REST
+/// gateway calls an rpc, or a new one is generated.
+pub fn enter_request(rpc: &'static str, request_id: Option<String>) -> ContextGuard {
+    let request_id = request_id
+        .filter(|id| is_valid_request_id(id))
+        .or_else(self::request_id)
+        .unwrap_or_else(new_request_id);
+
+    enter(Context {
+        rpc: Some(rpc),
+        request_id: Some(request_id),
+        user_id: None,
+    })
+}
+
+/// Records which user the current request concerns
+pub fn set_user_id(user_id: u32) {
+    CONTEXT.with(|c| c.borrow_mut().user_id = Some(user_id));
+}
+
+/// Returns the context of the current thread
+pub fn current() -> Context {
+    CONTEXT.with(|c| c.borrow().clone())
+}
+
+/// Returns the request id of the current thread, if any
+pub fn request_id() -> Option<String> {
+    CONTEXT.with(|c| c.borrow().request_id.clone())
+}
+
+/// Generates a random request id of 16 hex characters
+pub fn new_request_id() -> String {
+    let bytes: [u8; 8] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Request ids supplied by callers end up in logs, so only allow short ids
+/// without any special characters
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    ts: String,
+    level: &'a str,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rpc: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<u32>,
+    message: String,
+}
+
+/// Formats a record as a single line of JSON
+pub fn json_line(
+    ts: &str,
+    level: log::Level,
+    target: &str,
+    context: &Context,
+    message: &std::fmt::Arguments,
+) -> String {
+    let line = JsonLine {
+        ts: ts.to_owned(),
+        level: level.as_str(),
+        target,
+        rpc: context.rpc,
+        request_id: context.request_id.as_ref().map(|s| s.as_str()),
+        user_id: context.user_id,
+        message: message.to_string(),
+    };
+    // Serializing strings to JSON can not fail
+    serde_json::to_string(&line).unwrap_or_default()
+}
+
+fn json_dispatch() -> fern::Dispatch {
+    fern::Dispatch::new().format(|out, message, record| {
+        let ts = chrono::Local::now().to_rfc3339();
+        let line = json_line(&ts, record.level(), record.target(), &current(), message);
+        out.finish(format_args!("{}", line))
+    })
+}
+
+/// Formats a context as e.g. `[register 5f2c3a9e1b7d4c60 user=1]`
+fn text_span(context: &Context) -> String {
+    let rpc = match context.rpc {
+        Some(rpc) => rpc,
+        None => return String::new(),
+    };
+    let mut span = format!("[{}", rpc);
+    if let Some(ref id) = context.request_id {
+        span.push(' ');
+        span.push_str(id);
+    }
+    if let Some(user_id) = context.user_id {
+        span.push_str(&format!(" user={}", user_id));
+    }
+    span.push(']');
+    span
+}
+
+fn text_dispatch(with_date: bool) -> fern::Dispatch {
+    fern::Dispatch::new().format(move |out, message, record| {
+        let rpc = text_span(&current());
+
+        if with_date {
+            out.finish(format_args!(
+                "{}[{}][{}]{} {}",
+                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                record.target(),
+                record.level(),
+                rpc,
+                message
+            ))
+        // special format for debug messages coming from our own crate.
+        } else if record.level() > log::LevelFilter::Info && record.target() == "auth-service" {
+            out.finish(format_args!(
+                "---\nDEBUG: {}: {}\n---",
+                chrono::Local::now().format("%H:%M:%S"),
+                message
+            ))
+        } else {
+            out.finish(format_args!(
+                "[{}][{}][{}]{} {}",
+                chrono::Local::now().format("%H:%M"),
+                record.target(),
+                record.level(),
+                rpc,
+                message
+            ))
+        }
+    })
+}
+
+pub fn setup_logging(verbosity: u64, config: &LoggingConfig) -> Result<(), fern::InitError> {
+    let mut base_config = fern::Dispatch::new();
+
+    base_config = match verbosity {
+        0 => base_config.level(log::LevelFilter::Info),
+
+        1 => base_config
+            .level(log::LevelFilter::Debug)
+            .level_for("tokio_core", log::LevelFilter::Info)
+            .level_for("tokio_reactor", log::LevelFilter::Info)
+            .level_for("tokio_proto", log::LevelFilter::Info)
+            .level_for("tokio_io", log::LevelFilter::Info)
+            .level_for("mio", log::LevelFilter::Info)
+            .level_for("tarpc", log::LevelFilter::Info),
+
+        2 => base_config
+            .level(log::LevelFilter::Trace)
+            .level_for("tokio_core", log::LevelFilter::Info)
+            .level_for("tokio_reactor", log::LevelFilter::Info)
+            .level_for("tokio_proto", log::LevelFilter::Info)
+            .level_for("tokio_io", log::LevelFilter::Info)
+            .level_for("mio", log::LevelFilter::Info)
+            .level_for("tarpc", log::LevelFilter::Info),
+
+        _3_or_more => base_config.level(log::LevelFilter::Trace),
+    };
+
+    // Separate file config so we can include year, month and day in file logs
+    if !config.file.is_empty() {
+        let file = rotate::RotatingFile::open(
+            &config.file,
+            config.rotate_size,
+            config.rotate_interval,
+            config.keep,
+        )?;
+        let file_config = match config.format {
+            Format::Text => text_dispatch(true),
+            Format::Json => json_dispatch(),
+        }.chain(Box::new(file) as Box<io::Write + Send>);
+        base_config = base_config.chain(file_config);
+    }
+
+    if config.stdout {
+        let stdout_config = match config.format {
+            Format::Text => text_dispatch(false),
+            Format::Json => json_dispatch(),
+        }.chain(io::stdout());
+        base_config = base_config.chain(stdout_config);
+    }
+
+    base_config.apply()?;
+
+    Ok(())
+}
+
+/// Collects what the formatters of every format write, for tests
+///
+/// The logger of a process can only be set once, so every test which looks
+/// at log output shares the one from [`capture`].
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct Capture(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Capture {
+    /// Everything written so far
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+#[cfg(test)]
+impl io::Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+lazy_static! {
+    static ref CAPTURE: Capture = {
+        let capture = Capture::default();
+        let output = || Box::new(capture.clone()) as Box<io::Write + Send>;
+        let (_, logger) = fern::Dispatch::new()
+            .level(log::LevelFilter::Trace)
+            .chain(text_dispatch(true).chain(output()))
+            .chain(text_dispatch(false).chain(output()))
+            .chain(json_dispatch().chain(output()))
+            .into_log();
+        log::set_boxed_logger(logger).expect("only the capture sets a logger in tests");
+        log::set_max_level(log::LevelFilter::Trace);
+        capture
+    };
+}
+
+/// Installs the logger of the tests, and returns what it captures
+#[cfg(test)]
+pub fn capture() -> &'static Capture {
+    &CAPTURE
+}
+
+#[test]
+fn test_json_line() {
+    let context = Context {
+        rpc: Some("authenticate"),
+        request_id: Some("abc".to_owned()),
+        user_id: Some(7),
+    };
+    let line = json_line(
+        "2018-10-20T12:00:00+02:00",
+        log::Level::Info,
+        "auth_service::service",
+        &context,
+        &format_args!("said \"{}\"", "hi"),
+    );
+    assert_eq!(
+        r#"{"ts":"2018-10-20T12:00:00+02:00","level":"INFO","target":"auth_service::service","rpc":"authenticate","request_id":"abc","user_id":7,"message":"said \"hi\""}"#,
+        line
+    );
+
+    let line = json_line(
+        "ts",
+        log::Level::Debug,
+        "t",
+        &Context::default(),
+        &format_args!("m"),
+    );
+    assert_eq!(r#"{"ts":"ts","level":"DEBUG","target":"t","message":"m"}"#, line);
+}
+
+#[test]
+fn test_context_is_restored() {
+    {
+        let _rpc = enter_rpc("get_user");
+        assert_eq!(Some("get_user"), current().rpc);
+        {
+            let _inner = enter_rpc("register");
+            assert_eq!(Some("register"), current().rpc);
+        }
+        assert_eq!(Some("get_user"), current().rpc);
+    }
+    assert_eq!(Context::default(), current());
+}
+
+#[test]
+fn test_request_span() {
+    {
+        let _span = enter_request("register", Some("from-caller_1".to_owned()));
+        set_user_id(3);
+        assert_eq!("[register from-caller_1 user=3]", text_span(&current()));
+
+        // The span can be carried over to another thread
+        let span = current();
+        std::thread::spawn(move || {
+            let _span = enter(span);
+            assert_eq!(Some("from-caller_1".to_owned()), request_id());
+        }).join()
+        .unwrap();
+    }
+    assert_eq!(None, request_id());
+
+    let _span = enter_request("register", Some("no spaces\nor newlines".to_owned()));
+    let generated = request_id().unwrap();
+    assert_eq!(16, generated.len());
+    assert!(is_valid_request_id(&generated));
+
+    // An rpc called within a request keeps its id
+    let _nested = enter_request("get_user", None);
+    assert_eq!(Some(generated), request_id());
+}
@@ -0,0 +1,207 @@
+//! A log file which is rotated by size and time
+//!
+//! When the file is rotated it is renamed to `<file>.<timestamp>` and a new
+//! file is started. Only the newest `keep` rotated files are retained.
+use chrono::{DateTime, Local, Timelike};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Format of the suffix of a rotated file, e.g. `.20181020-120000.123`
+const SUFFIX_FORMAT: &str = ".%Y%m%d-%H%M%S%.3f";
+
+/// How often the log file is rotated, regardless of its size
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for Interval {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Interval::Never),
+            "hourly" => Ok(Interval::Hourly),
+            "daily" => Ok(Interval::Daily),
+            _ => Err(()),
+        }
+    }
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: DateTime<Local>,
+    max_size: u64,
+    interval: Interval,
+    keep: usize,
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending
+    ///
+    /// A `max_size` of 0 disables rotation by size.
+    pub fn open(
+        path: impl AsRef<Path>,
+        max_size: u64,
+        interval: Interval,
+        keep: usize,
+    ) -> io::Result<RotatingFile> {
+        let path = path.as_ref().to_path_buf();
+        let (file, size) = open_append(&path)?;
+
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            opened: Local::now(),
+            max_size,
+            interval,
+            keep,
+        })
+    }
+
+    fn should_rotate(&self, incoming: usize, now: &DateTime<Local>) -> bool {
+        let too_big = self.max_size > 0 && self.size > 0 && self.size + incoming as u64 > self.max_size;
+        let too_old = match self.interval {
+            Interval::Never => false,
+            Interval::Daily => now.date() != self.opened.date(),
+            Interval::Hourly => now.date() != self.opened.date() || now.hour() != self.opened.hour(),
+        };
+        too_big || too_old
+    }
+
+    fn rotate(&mut self, now: DateTime<Local>) -> io::Result<()> {
+        self.file.flush()?;
+
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(now.format(SUFFIX_FORMAT).to_string());
+        fs::rename(&self.path, &rotated)?;
+
+        let (file, size) = open_append(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.opened = now;
+
+        self.remove_old()
+    }
+
+    /// Removes all but the newest `keep` rotated files
+    ///
+    /// Only files named like `rotate` names them are touched, so e.g.
+    /// `<file>.bak` is left alone.
+    fn remove_old(&self) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let name = match self.path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_owned(),
+            None => return Ok(()),
+        };
+
+        // The timestamp suffix sorts in chronological order
+        let mut rotated: Vec<PathBuf> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|n| n.to_str())
+                    .map_or(false, |n| is_rotated(&name, n))
+            }).collect();
+        rotated.sort();
+
+        let excess = rotated.len().saturating_sub(self.keep);
+        for path in rotated.into_iter().take(excess) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = Local::now();
+        if self.should_rotate(buf.len(), &now) {
+            self.rotate(now)?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Whether `file` is `name` followed by a suffix in `SUFFIX_FORMAT`
+fn is_rotated(name: &str, file: &str) -> bool {
+    if !file.starts_with(name) {
+        return false;
+    }
+    let suffix = file[name.len()..].as_bytes();
+    let digits = |range: std::ops::Range<usize>| suffix[range].iter().all(u8::is_ascii_digit);
+    suffix.len() == 20
+        && suffix[0] == b'.'
+        && digits(1..9)
+        && suffix[9] == b'-'
+        && digits(10..16)
+        && suffix[16] == b'.'
+        && digits(17..20)
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+#[test]
+fn test_rotate_by_size() {
+    let dir = std::env::temp_dir().join(format!("auth-service-rotate-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("test.log");
+    // Files which were not made by the rotation are kept
+    for name in &["test.log.bak", "test.log.tmp", "test.log.20181020-120000.123.gz"] {
+        fs::write(dir.join(name), b"").unwrap();
+    }
+
+    let mut file = RotatingFile::open(&path, 10, Interval::Never, 2).unwrap();
+    for _ in 0..4 {
+        file.write_all(b"12345678\n").unwrap();
+        // Make sure the timestamps of the rotated files differ
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+
+    let mut names: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+
+    // One current file, the two newest of three rotated files and the others
+    assert_eq!(6, names.len());
+    assert_eq!("test.log", names[0]);
+    assert!(names.contains(&"test.log.bak".to_owned()));
+    assert!(names.contains(&"test.log.20181020-120000.123.gz".to_owned()));
+    assert_eq!(9, fs::metadata(&path).unwrap().len());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_is_rotated() {
+    let suffix = Local::now().format(SUFFIX_FORMAT).to_string();
+    assert!(is_rotated("auth.log", &format!("auth.log{}", suffix)));
+    assert!(!is_rotated("auth.log", "auth.log"));
+    assert!(!is_rotated("auth.log", "auth.log.tmp"));
+    assert!(!is_rotated("auth.log", "auth.log.2018102a-120000.123"));
+    assert!(!is_rotated("auth.log", &format!("other.log{}", suffix)));
+}
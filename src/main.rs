@@ -1,46 +1,92 @@
-#![feature(plugin)]
-#![plugin(tarpc_plugins)]
 #![allow(proc_macro_derive_resolution_fallback)]
 #![feature(try_from)]
 #![feature(crate_in_paths)]
 #![feature(extern_prelude)]
 
+pub mod authenticator;
+pub mod check;
+pub mod config;
+pub mod connection;
+#[cfg(feature = "mysql")]
 pub mod db;
 pub mod error;
+pub mod events;
+pub mod health;
+pub mod http;
+pub mod invalidation;
+pub mod listener;
 pub mod logging;
+pub mod metrics;
 pub mod migration;
+#[cfg(feature = "rest")]
+pub mod oauth;
+#[cfg(feature = "mysql")]
 pub mod schema;
+pub mod redact;
+#[cfg(feature = "rest")]
+pub mod rest;
 pub mod service;
+pub mod sessions;
+pub mod signal;
+pub mod store;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod webhooks;
 
 #[macro_use]
 extern crate diesel;
 extern crate dotenv;
-#[macro_use]
 extern crate tarpc;
 extern crate chrono;
 extern crate clap;
 extern crate regex;
 extern crate serde;
+#[macro_use]
 extern crate serde_derive;
 #[macro_use]
+extern crate serde_json;
+extern crate sha2;
+extern crate tokio_signal;
+extern crate toml;
+#[macro_use]
 extern crate log;
 extern crate fern;
+extern crate tokio_codec;
 extern crate tokio_core;
+extern crate tokio_io;
+#[cfg(feature = "tls")]
+extern crate tokio_openssl;
+extern crate tokio_uds;
 #[macro_use]
 extern crate failure;
+extern crate auth_client;
 extern crate base64;
+extern crate bincode;
+extern crate bytes;
 extern crate datatypes;
 extern crate futures;
 extern crate futures_cpupool;
+extern crate hmac;
+extern crate hyper;
+extern crate hyper_openssl;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate prometheus;
 extern crate pbkdf2;
 extern crate rand;
+extern crate url;
+#[cfg(any(feature = "rest", feature = "tls"))]
+extern crate openssl;
+#[cfg(feature = "ldap")]
+extern crate ldap3;
 
-use dotenv::dotenv;
+use config::Config;
 use error::{Error as IntError, ErrorKind as IntErrorKind};
 use failure::Error;
-use service::FutureServiceExt;
-use std::net::{SocketAddr, ToSocketAddrs};
-use tarpc::future::server::Options;
+use futures::future::Either;
+use futures::{Future, Stream};
+use std::time::Duration;
 use tokio_core::reactor;
 
 type IntResult<T> = Result<T, IntError>;
@@ -49,6 +95,13 @@ pub fn run() -> Result<(), Error> {
     // Get command line arguments
     let cmd_arguments = clap::App::new("auth-service")
         .arg(
+            clap::Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Reads configuration from a TOML file, overridden by AUTH_* environment variables"),
+        ).arg(
             clap::Arg::with_name("verbose")
                 .short("v")
                 .long("verbose")
@@ -58,59 +111,131 @@ pub fn run() -> Result<(), Error> {
             clap::Arg::with_name("migrate")
                 .short("m")
                 .long("migrate")
-                .multiple(true)
-                .help("Runs db migration"),
+                .help("Applies pending db migrations before starting the server"),
+        ).subcommand(
+            clap::SubCommand::with_name("migrate")
+                .about("Manages the database schema")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("status")
+                        .about("Lists all migrations and whether they are applied"),
+                ).subcommand(
+                    clap::SubCommand::with_name("up").about("Applies all pending migrations"),
+                ).subcommand(
+                    clap::SubCommand::with_name("down")
+                        .about("Reverts the latest applied migration"),
+                ).subcommand(
+                    clap::SubCommand::with_name("redo")
+                        .about("Reverts and re-applies the latest applied migration"),
+                ),
+        ).subcommand(
+            clap::SubCommand::with_name("check")
+                .about("Checks that the database schema matches the expected schema"),
         ).get_matches();
 
+    // Load configuration, logging is not set up yet so problems are printed
+    let config = Config::load(cmd_arguments.value_of("config")).map_err(|e| {
+        eprintln!("{}", e);
+        e
+    })?;
+    let database_url = &config.database.url;
+
     // Setup logging
     let verbosity: u64 = cmd_arguments.occurrences_of("verbose");
 
-    logging::setup_logging(verbosity)
+    logging::setup_logging(verbosity, &config.logging)
         .map_err(|e| format_err!("failed to initialize logging: {:?}", e))?;
 
+    // Run a migration command and exit
+    if let Some(migrate_arguments) = cmd_arguments.subcommand_matches("migrate") {
+        let command = migrate_arguments
+            .subcommand_name()
+            .and_then(migration::Command::from_name)
+            .ok_or_else(|| format_err!("missing migration command"))?;
+        migration::run(database_url, command)?;
+        return Ok(());
+    }
+
+    // Check the schema and exit
+    if cmd_arguments.subcommand_matches("check").is_some() {
+        check::run(database_url)?;
+        return Ok(());
+    }
+
     // Create an "eventloop"
     let mut reactor = reactor::Core::new()
         .map_err(|e| format_err!("unable to create a tokio runtime: {:?}", e))?;
 
-    // Get db url
-    dotenv().ok();
-    let database_url = std::env::var("AUTH_DATABASE_URL")
-        .expect("AUTH_DATABASE_URL must be set as an environment variable or in a '.env' file");
-
-    // Get the server address
-    let address = match std::env::var("AUTH_ADDRESS") {
-        Ok(value) => value
-            .to_socket_addrs()
-            .expect("Unable to perorm AUTH_ADDRESS resolving")
-            .next()
-            .expect(&format!("Unable to resolve '{}'", value)),
-        Err(_) => {
-            warn!("AUTH_ADDRESS is not set, using '127.0.0.1:10001'");
-            SocketAddr::from(([127, 0, 0, 1], 10001))
-        }
-    };
-
     // Setup server
     info!("Setting up server");
-    let auth_server = service::AuthServer::try_new(&database_url)?;
+    let auth_server = service::AuthServer::try_new(&config)?;
 
     //Migrate
-    let migrate: u64 = cmd_arguments.occurrences_of("migrate");
-    if migrate > 0 {
+    if cmd_arguments.is_present("migrate") {
         info!("Running db migration");
-        let _ = migration::run();
+        migration::run(database_url, migration::Command::Up)?;
     }
 
+    // Refuse to start if the database does not look like we expect
+    check::run(database_url)?;
+
     // Start
-    let opts = Options::default();
-    let (_handle, server) = auth_server
-        .listen(address, &reactor.handle(), opts)
-        .map_err(|e| format_err!("Unable to startup server: {:?}", e))?;
-
-    info!("Starting server on {}", address);
-    reactor
-        .run(server)
-        .map_err(|_| format_err!("quit from eventloop with error"))
+    let listeners = listener::Listeners::start(&config, &auth_server, &reactor.handle())?;
+
+    if let Some(admin_address) = config.admin.socket_addr().map_err(|e| format_err!("{}", e))? {
+        http::spawn_admin(admin_address, auth_server.clone())?;
+    }
+    #[cfg(feature = "rest")]
+    {
+        let rest_address = config.rest.socket_addr().map_err(|e| format_err!("{}", e))?;
+        if let Some(rest_address) = rest_address {
+            let oidc = oauth::oidc::Oidc::new(&config.oidc, &config.rest);
+            rest::spawn(rest_address, auth_server.clone(), oidc)?;
+        }
+    }
+
+    auth_server.heartbeat().spawn(&reactor.handle())?;
+    auth_server.invalidations().spawn(&reactor.handle())?;
+    auth_server.events().spawn(&reactor.handle())?;
+    auth_server.spawn_pruning(&reactor.handle())?;
+    events::spawn_dispatcher(&config.events, auth_server.store().clone())?;
+
+    // Serve until asked to stop
+    let received = reactor
+        .run(signal::shutdown())
+        .map_err(|e| format_err!("unable to listen for signals: {:?}", e))?;
+
+    // Stop accepting connections, and give the running requests some time to
+    // finish before giving up on them
+    let timeout = Duration::from_secs(config.server.shutdown_timeout);
+    info!(
+        "Received {}, shutting down (waiting up to {}s for {} running requests)",
+        received,
+        timeout.as_secs(),
+        auth_server.in_flight()
+    );
+
+    let counter = auth_server.clone();
+    let requests_done = reactor::Interval::new(Duration::from_millis(50), &reactor.handle())
+        .map_err(|e| format_err!("unable to create interval: {:?}", e))?
+        .take_while(move |_| Ok(counter.in_flight() > 0))
+        .for_each(|_| Ok(()))
+        .map_err(|_| ());
+    let connections_closed = listeners.shutdown();
+    let deadline = reactor::Timeout::new(timeout, &reactor.handle())
+        .map_err(|e| format_err!("unable to create timeout: {:?}", e))?;
+
+    match reactor.run(requests_done.join(connections_closed).select2(deadline)) {
+        Ok(Either::A(_)) => info!("All requests finished"),
+        _ => warn!(
+            "Shutdown timeout reached with {} requests still running",
+            auth_server.in_flight()
+        ),
+    }
+
+    auth_server.flush_sessions()?;
+    info!("Shutdown complete");
+    Ok(())
 }
 
 pub fn main() {
@@ -0,0 +1,100 @@
+//! Prometheus metrics of the service
+//!
+//! The metrics are kept in the default prometheus registry and served as text
+//! on `/metrics` by the admin listener in [`crate::http`].
+use prometheus::{CounterVec, Gauge, GaugeVec, HistogramVec};
+use std::time::Instant;
+
+use datatypes::auth::responses::AuthError;
+
+lazy_static! {
+    pub static ref REQUESTS: CounterVec = register_counter_vec!(
+        "auth_requests_total",
+        "Number of handled rpc requests by outcome",
+        &["rpc", "outcome"]
+    ).unwrap();
+    pub static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "auth_request_duration_seconds",
+        "Time spent handling rpc requests",
+        &["rpc"]
+    ).unwrap();
+    pub static ref PASSWORD_HASH_DURATION: HistogramVec = register_histogram_vec!(
+        "auth_password_hash_duration_seconds",
+        "Time spent hashing ('hash') or verifying ('check') passwords with pbkdf2",
+        &["operation"],
+        vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    ).unwrap();
+    pub static ref ACTIVE_SESSIONS: Gauge = register_gauge!(
+        "auth_active_sessions",
+        "Number of tokens in the session map"
+    ).unwrap();
+    pub static ref IN_FLIGHT: Gauge = register_gauge!(
+        "auth_requests_in_flight",
+        "Number of requests running on the worker pool"
+    ).unwrap();
+    pub static ref DB_CONNECTIONS: GaugeVec = register_gauge_vec!(
+        "auth_db_pool_connections",
+        "Connections of the database pool by state ('idle', 'in_use' and 'max')",
+        &["state"]
+    ).unwrap();
+    pub static ref EVENT_DELIVERIES: CounterVec = register_counter_vec!(
+        "auth_event_deliveries_total",
+        "Number of attempts to deliver an event by sink and outcome ('delivered' or 'failed')",
+        &["sink", "outcome"]
+    ).unwrap();
+}
+
+/// The label of the outcome of a request
+pub fn outcome<T>(result: &Result<T, AuthError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(AuthError::InvalidUsername) => "invalid_username",
+        Err(AuthError::InvalidPassword) => "invalid_password",
+        Err(AuthError::InvalidToken) => "invalid_token",
+        Err(AuthError::ExistingUser) => "existing_user",
+        Err(AuthError::InternalServerError) => "internal_error",
+        #[allow(unreachable_patterns)]
+        Err(_) => "error",
+    }
+}
+
+/// Measures a single rpc request
+pub struct RequestTimer {
+    rpc: &'static str,
+    start: Instant,
+}
+
+impl RequestTimer {
+    pub fn start(rpc: &'static str) -> RequestTimer {
+        RequestTimer {
+            rpc,
+            start: Instant::now(),
+        }
+    }
+
+    /// Counts the request with its outcome and records how long it took
+    pub fn observe<T>(self, result: &Result<T, AuthError>) {
+        REQUESTS
+            .with_label_values(&[self.rpc, outcome(result)])
+            .inc();
+        REQUEST_DURATION
+            .with_label_values(&[self.rpc])
+            .observe(seconds(self.start));
+    }
+}
+
+/// Seconds elapsed since `start`
+pub fn seconds(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9
+}
+
+#[test]
+fn test_outcome() {
+    assert_eq!("ok", outcome::<()>(&Ok(())));
+    assert_eq!("invalid_token", outcome::<()>(&Err(AuthError::InvalidToken)));
+
+    let before = REQUESTS.with_label_values(&["test", "ok"]).get();
+    RequestTimer::start("test").observe::<()>(&Ok(()));
+    assert_eq!(before + 1.0, REQUESTS.with_label_values(&["test", "ok"]).get());
+}
@@ -1,39 +1,360 @@
+//! Versioned sql migrations which are embedded in the binary
+//!
+//! The migrations are read from `migrations/` (MySQL), `migrations_postgres/`
+//! and `migrations_sqlite/` at build time. Applied versions are tracked in
+//! `__diesel_schema_migrations`, the same table the diesel cli uses, so the two
+//! can be used interchangeably.
+//!
+//! Before the migrations were versioned, `-m` created the `users` and `roles`
+//! tables without tracking it. Such a database has the first migration
+//! recorded as applied when the table of applied versions is created.
+use diesel::connection::SimpleConnection;
+use diesel::deserialize::QueryableByName;
 use diesel::prelude::*;
+use diesel::result::Error as DieselError;
 use diesel::sql_query;
+use diesel::sql_types::Text;
+use failure::ResultExt;
+use std::fmt::{self, Display};
 
-use crate::db::establish_connection;
-use crate::{IntErrorKind, IntResult};
+use crate::{IntError, IntErrorKind, IntResult};
 
-pub fn run() -> IntResult<()> {
-    let con = establish_connection();
+/// A migration as found in the migration directory
+#[derive(Debug)]
+pub struct Migration {
+    /// The date prefix of the directory, e.g. `20181007114958`
+    pub version: &'static str,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.version, self.name)
+    }
+}
+
+/// The migrations of every backend, generated by `build.rs`
+pub mod embedded {
+    use super::Migration;
+    include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+}
+
+/// A migration command given on the command line
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// List all migrations and whether they are applied
+    Status,
+    /// Apply all pending migrations
+    Up,
+    /// Revert the latest applied migration
+    Down,
+    /// Revert and re-apply the latest applied migration
+    Redo,
+}
+
+impl Command {
+    pub fn from_name(name: &str) -> Option<Command> {
+        match name {
+            "status" => Some(Command::Status),
+            "up" => Some(Command::Up),
+            "down" => Some(Command::Down),
+            "redo" => Some(Command::Redo),
+            _ => None,
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+pub struct AppliedVersion {
+    #[sql_type = "Text"]
+    version: String,
+}
+
+const MIGRATIONS_TABLE: &str = "__diesel_schema_migrations";
+
+const CREATE_MIGRATIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS __diesel_schema_migrations (
+    version VARCHAR(50) PRIMARY KEY NOT NULL,
+    run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+)";
+
+/// Runs a migration command against the database at `database_url`
+pub fn run(database_url: &str, command: Command) -> IntResult<()> {
+    let scheme = database_url.split("://").next().unwrap_or("");
+
+    match scheme {
+        #[cfg(feature = "mysql")]
+        "mysql" => {
+            let conn = MysqlConnection::establish(database_url)
+                .context(IntErrorKind::ConnectionError)?;
+            run_command(&conn, embedded::MYSQL, command)
+        }
+        #[cfg(feature = "postgres")]
+        "postgres" | "postgresql" => {
+            let conn =
+                PgConnection::establish(database_url).context(IntErrorKind::ConnectionError)?;
+            run_command(&conn, embedded::POSTGRES, command)
+        }
+        #[cfg(feature = "sqlite")]
+        "sqlite" => {
+            let conn = SqliteConnection::establish(database_url.trim_left_matches("sqlite://"))
+                .context(IntErrorKind::ConnectionError)?;
+            run_command(&conn, embedded::SQLITE, command)
+        }
+        "memory" => {
+            info!("The in-memory store has no schema to migrate");
+            Ok(())
+        }
+        _ => {
+            error!("No migrations for database '{}'", scheme);
+            Err(IntErrorKind::ConnectionError)?
+        }
+    }
+}
+
+/// Runs a migration command with the given set of migrations
+pub fn run_command<C>(conn: &C, migrations: &[Migration], command: Command) -> IntResult<()>
+where
+    C: Connection,
+    AppliedVersion: QueryableByName<C::Backend>,
+{
+    match command {
+        Command::Status => {
+            for (migration, applied) in status(conn, migrations)? {
+                let mark = if applied { "x" } else { " " };
+                println!("[{}] {}", mark, migration);
+            }
+            Ok(())
+        }
+        Command::Up => {
+            let applied = up(conn, migrations)?;
+            if applied.is_empty() {
+                info!("Database schema is up to date");
+            }
+            Ok(())
+        }
+        Command::Down => down(conn, migrations).map(|_| ()),
+        Command::Redo => {
+            if let Some(migration) = down(conn, migrations)? {
+                apply(conn, migration)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Returns every migration and whether it has been applied
+pub fn status<'a, C>(conn: &C, migrations: &'a [Migration]) -> IntResult<Vec<(&'a Migration, bool)>>
+where
+    C: Connection,
+    AppliedVersion: QueryableByName<C::Backend>,
+{
+    let applied = applied_versions(conn, migrations)?;
+    Ok(migrations
+        .iter()
+        .map(|m| (m, applied.iter().any(|v| v == m.version)))
+        .collect())
+}
+
+/// The number of migrations which have not been applied
+///
+/// Unlike [`status`] this only reads, so it is safe for health checks. A
+/// database without the table of applied versions has every migration
+/// pending.
+pub fn pending<C>(conn: &C, migrations: &[Migration]) -> IntResult<usize>
+where
+    C: Connection,
+    AppliedVersion: QueryableByName<C::Backend>,
+{
+    let applied = match load_versions(conn) {
+        Ok(versions) => versions,
+        // Every backend names the missing table in the error
+        Err(DieselError::DatabaseError(_, ref info))
+            if info.message().contains(MIGRATIONS_TABLE) =>
+        {
+            Vec::new()
+        }
+        Err(e) => Err(e).context(IntErrorKind::QueryError)?,
+    };
+    Ok(migrations
+        .iter()
+        .filter(|m| !applied.iter().any(|v| v == m.version))
+        .count())
+}
 
-    let _ = sql_query(
-        r#"CREATE TABLE users (
+/// Applies all pending migrations in version order and returns them
+pub fn up<'a, C>(conn: &C, migrations: &'a [Migration]) -> IntResult<Vec<&'a Migration>>
+where
+    C: Connection,
+    AppliedVersion: QueryableByName<C::Backend>,
+{
+    let pending: Vec<_> = status(conn, migrations)?
+        .into_iter()
+        .filter(|(_, applied)| !applied)
+        .map(|(m, _)| m)
+        .collect();
 
-  id            INT UNSIGNED AUTO_INCREMENT NOT NULL,
-  email         VARCHAR(255) NOT NULL UNIQUE,
-  username      VARCHAR(20) NOT NULL UNIQUE,
-  password      TINYTEXT NOT NULL,
-  banned        BOOLEAN DEFAULT FALSE NOT NULL,
-  verified      BOOLEAN DEFAULT FALSE NOT NULL,
-  email_token   VARCHAR(255),
+    for migration in &pending {
+        apply(conn, migration)?;
+    }
+    Ok(pending)
+}
+
+/// Reverts the latest applied migration and returns it
+pub fn down<'a, C>(conn: &C, migrations: &'a [Migration]) -> IntResult<Option<&'a Migration>>
+where
+    C: Connection,
+    AppliedVersion: QueryableByName<C::Backend>,
+{
+    let latest = status(conn, migrations)?
+        .into_iter()
+        .filter(|(_, applied)| *applied)
+        .map(|(m, _)| m)
+        .last();
+
+    match latest {
+        Some(migration) => {
+            info!("Reverting migration {}", migration);
+            conn.transaction::<_, IntError, _>(|| {
+                execute_batch(conn, migration, migration.down)?;
+                sql_query(format!(
+                    "DELETE FROM __diesel_schema_migrations WHERE version = '{}'",
+                    migration.version
+                )).execute(conn)?;
+                Ok(())
+            })?;
+            Ok(Some(migration))
+        }
+        None => {
+            info!("No migrations to revert");
+            Ok(None)
+        }
+    }
+}
+
+fn apply<C: Connection>(conn: &C, migration: &Migration) -> IntResult<()> {
+    info!("Applying migration {}", migration);
+    conn.transaction::<_, IntError, _>(|| {
+        execute_batch(conn, migration, migration.up)?;
+        record(conn, migration)?;
+        Ok(())
+    })
+}
+
+/// Marks a migration as applied
+fn record<C: Connection>(conn: &C, migration: &Migration) -> Result<usize, DieselError> {
+    // The version is a string of digits generated by the build script, so
+    // it is safe to put directly into the query
+    sql_query(format!(
+        "INSERT INTO __diesel_schema_migrations (version) VALUES ('{}')",
+        migration.version
+    )).execute(conn)
+}
+
+fn execute_batch<C: Connection>(conn: &C, migration: &Migration, sql: &str) -> IntResult<()> {
+    conn.batch_execute(sql)
+        .context(IntErrorKind::QueryError)
+        .map_err(|e| {
+            error!("Migration {} failed: {}", migration, e);
+            e.into()
+        })
+}
+
+fn applied_versions<C>(conn: &C, migrations: &[Migration]) -> IntResult<Vec<String>>
+where
+    C: Connection,
+    AppliedVersion: QueryableByName<C::Backend>,
+{
+    let tracked = match load_versions(conn) {
+        Ok(_) => true,
+        Err(DieselError::DatabaseError(_, ref info))
+            if info.message().contains(MIGRATIONS_TABLE) =>
+        {
+            false
+        }
+        Err(e) => Err(e).context(IntErrorKind::QueryError)?,
+    };
+    conn.batch_execute(CREATE_MIGRATIONS_TABLE)
+        .context(IntErrorKind::QueryError)?;
 
-  PRIMARY KEY (id)
-);"#,
-    ).execute(&con)
-    .map_err(|_| IntErrorKind::QueryError);
+    if !tracked && has_untracked_schema(conn) {
+        if let Some(first) = migrations.first() {
+            info!("Recording migration {} as applied by an older -m", first);
+            record(conn, first).context(IntErrorKind::QueryError)?;
+        }
+    }
 
-    let _ = sql_query(
-        r#"CREATE TABLE roles (
+    Ok(load_versions(conn).context(IntErrorKind::QueryError)?)
+}
+
+/// Whether the `users` and `roles` tables exist, as the unversioned `-m`
+/// created them
+fn has_untracked_schema<C>(conn: &C) -> bool
+where
+    C: Connection,
+    AppliedVersion: QueryableByName<C::Backend>,
+{
+    let queries = [
+        "SELECT username AS version FROM users WHERE 1 = 0",
+        "SELECT name AS version FROM roles WHERE 1 = 0",
+    ];
+    queries
+        .iter()
+        .all(|query| sql_query(*query).load::<AppliedVersion>(conn).is_ok())
+}
+
+fn load_versions<C>(conn: &C) -> Result<Vec<String>, DieselError>
+where
+    C: Connection,
+    AppliedVersion: QueryableByName<C::Backend>,
+{
+    let versions = sql_query("SELECT version FROM __diesel_schema_migrations")
+        .load::<AppliedVersion>(conn)?;
+    Ok(versions.into_iter().map(|v| v.version).collect())
+}
+
+#[test]
+fn test_embedded_migrations_are_ordered() {
+    for migrations in &[embedded::MYSQL, embedded::POSTGRES, embedded::SQLITE] {
+        assert!(!migrations.is_empty());
+        assert!(
+            migrations
+                .windows(2)
+                .all(|pair| pair[0].version < pair[1].version)
+        );
+    }
+    assert_eq!(embedded::MYSQL.len(), embedded::POSTGRES.len());
+    assert_eq!(embedded::MYSQL.len(), embedded::SQLITE.len());
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_pending_does_not_write() {
+    let conn = SqliteConnection::establish(":memory:").unwrap();
+    let migrations = embedded::SQLITE;
+
+    assert_eq!(migrations.len(), pending(&conn, migrations).unwrap());
+    assert!(load_versions(&conn).is_err());
+
+    up(&conn, &migrations[..1]).unwrap();
+    assert_eq!(migrations.len() - 1, pending(&conn, migrations).unwrap());
+}
 
-  id            INT UNSIGNED NOT NULL,
-  name          VARCHAR(20) NOT NULL,
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_untracked_schema() {
+    let conn = SqliteConnection::establish(":memory:").unwrap();
+    let migrations = embedded::SQLITE;
+    // As the unversioned `-m` left the database
+    conn.batch_execute(migrations[0].up).unwrap();
+    assert!(load_versions(&conn).is_err());
 
-  PRIMARY KEY (id),
-  FOREIGN KEY(id) REFERENCES users(id)
-);"#,
-    ).execute(&con)
-    .map_err(|_| IntErrorKind::QueryError);
+    let applied = up(&conn, migrations).unwrap();
+    assert_eq!(migrations.len() - 1, applied.len());
+    assert!(status(&conn, migrations).unwrap().iter().all(|(_, applied)| *applied));
 
-    Ok(())
+    // A new database still gets every migration
+    let conn = SqliteConnection::establish(":memory:").unwrap();
+    assert_eq!(migrations.len(), up(&conn, migrations).unwrap().len());
 }
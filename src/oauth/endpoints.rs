@@ -0,0 +1,680 @@
+//! The HTTP endpoints of the authorization server, routed by [`crate::rest`]
+//!
+//! | Endpoint                  | Who                                   |
+//! |---------------------------|---------------------------------------|
+//! | `POST /oauth/clients`     | an admin, with a bearer token         |
+//! | `GET /oauth/authorize`    | the user, with a bearer token         |
+//! | `POST /oauth/token`       | the client                            |
+//! | `POST /oauth/revoke`      | the client                            |
+//! | `POST /oauth/introspect`  | a confidential client                 |
+//! | `GET /oauth/userinfo`     | the client, with the user's token     |
+//! | `GET /.well-known/openid-configuration` | anyone                  |
+//! | `GET /.well-known/jwks.json`            | anyone                  |
+//!
+//! The service has no login page, so the front-end logs the user in and asks
+//! for their consent, and then calls `/oauth/authorize` with the user's token.
+//! The response redirects to the client with the code.
+//!
+//! Clients authenticate with HTTP Basic, or with `client_id` and
+//! `client_secret` in the form, as described in section 2.3.1 of RFC 6749.
+use futures::future;
+use futures::Future;
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, CACHE_CONTROL, LOCATION};
+use hyper::{Body, Response, StatusCode};
+use std::collections::HashMap;
+use std::str;
+use url::form_urlencoded;
+
+use datatypes::valid::token::Token;
+
+use super::oidc::UserClaims;
+use super::{AuthorizeRequest, OAuth, OAuthError};
+use crate::http::json;
+use crate::rest::{self, RestError, RestFuture};
+use crate::service::{AuthServer, FutureService};
+use crate::store::Client;
+
+/// The request body of `POST /oauth/clients`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewClient {
+    name: String,
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+    #[serde(default)]
+    confidential: bool,
+}
+
+/// `POST /oauth/clients`
+pub fn register_client(
+    oauth: &OAuth,
+    server: &AuthServer,
+    token: Result<Token, RestError>,
+    body: Body,
+) -> RestFuture {
+    let oauth = oauth.clone();
+    Box::new(
+        future::result(token.and_then(|token| rest::require_admin(server, token)))
+            .and_then(|()| rest::read_json(body))
+            .and_then(move |client: NewClient| {
+                oauth
+                    .register_client(&client.name, &client.redirect_uris, client.confidential)
+                    .map_err(RestError::OAuth)
+            }).map(|registration| no_store(json(StatusCode::CREATED, &registration))),
+    )
+}
+
+/// `GET /oauth/authorize`
+pub fn authorize(
+    oauth: &OAuth,
+    server: &AuthServer,
+    token: Result<Token, RestError>,
+    query: Option<&str>,
+) -> RestFuture {
+    let params = parse_form(query.unwrap_or("").as_bytes());
+    let param = |name: &str| params.get(name).map(|v| v.as_str());
+    let request = AuthorizeRequest {
+        response_type: param("response_type"),
+        client_id: param("client_id"),
+        redirect_uri: param("redirect_uri"),
+        state: param("state"),
+        code_challenge: param("code_challenge"),
+        code_challenge_method: param("code_challenge_method"),
+        scope: param("scope"),
+        nonce: param("nonce"),
+    };
+
+    let redirect = token
+        .and_then(|token| Ok(server.get_user(None, token)?))
+        .and_then(|user| {
+            oauth
+                .authorize(user.id, user.role, &request)
+                .map_err(RestError::OAuth)
+        });
+    Box::new(future::result(redirect.map(|url| {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::FOUND;
+        if let Ok(location) = HeaderValue::from_str(url.as_str()) {
+            response.headers_mut().insert(LOCATION, location);
+        }
+        no_store(response)
+    })))
+}
+
+/// `POST /oauth/token`
+pub fn token(oauth: &OAuth, headers: &HeaderMap, body: Body) -> RestFuture {
+    let oauth = oauth.clone();
+    let basic = basic_credentials(headers);
+    Box::new(
+        read_form(body)
+            .and_then(move |form| {
+                let client = authenticate_client(&oauth, basic, &form)?;
+                let param = |name: &str| form.get(name).map(|v| v.as_str());
+                match param("grant_type") {
+                    Some("authorization_code") => oauth.exchange_code(
+                        &client,
+                        param("code").ok_or(OAuthError::InvalidRequest("code is required"))?,
+                        param("redirect_uri"),
+                        param("code_verifier")
+                            .ok_or(OAuthError::InvalidRequest("code_verifier is required"))?,
+                    ),
+                    Some("client_credentials") => oauth.client_credentials(&client),
+                    Some(_) => Err(OAuthError::UnsupportedGrantType),
+                    None => Err(OAuthError::InvalidRequest("grant_type is required")),
+                }.map_err(RestError::OAuth)
+            }).map(|response| no_store(json(StatusCode::OK, &response))),
+    )
+}
+
+/// `POST /oauth/revoke`
+pub fn revoke(oauth: &OAuth, headers: &HeaderMap, body: Body) -> RestFuture {
+    let oauth = oauth.clone();
+    let basic = basic_credentials(headers);
+    Box::new(
+        read_form(body)
+            .and_then(move |form| {
+                let client = authenticate_client(&oauth, basic, &form)?;
+                let token = form_token(&form)?;
+                oauth.revoke(&client, &token).map_err(RestError::OAuth)
+            }).map(|()| {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::OK;
+                response
+            }),
+    )
+}
+
+/// `POST /oauth/introspect`
+pub fn introspect(oauth: &OAuth, headers: &HeaderMap, body: Body) -> RestFuture {
+    let oauth = oauth.clone();
+    let basic = basic_credentials(headers);
+    Box::new(
+        read_form(body)
+            .and_then(move |form| {
+                let client = authenticate_client(&oauth, basic, &form)?;
+                let token = form_token(&form)?;
+                oauth.introspect(&client, &token).map_err(RestError::OAuth)
+            }).map(|introspection| no_store(json(StatusCode::OK, &introspection))),
+    )
+}
+
+/// `GET /oauth/userinfo`, see section 5.3 of OpenID Connect Core
+///
+/// A client gets the claims of the scope it was granted, a user every claim.
+pub fn userinfo(
+    oauth: &OAuth,
+    server: &AuthServer,
+    token: Result<Token, RestError>,
+) -> RestFuture {
+    let claims = token
+        .and_then(|token| Ok((server.get_user(None, token.clone())?, token)))
+        .and_then(|(user, token)| {
+            let user = server
+                .store()
+                .fetch_user_by_id(*user.id)
+                .map_err(OAuthError::from)?;
+            Ok(match oauth.scope(&token)? {
+                Some(scope) => UserClaims::scoped(&user, &scope),
+                None => UserClaims::from(&user),
+            })
+        });
+    Box::new(future::result(
+        claims.map(|claims| no_store(json(StatusCode::OK, &claims))),
+    ))
+}
+
+/// `GET /.well-known/openid-configuration`
+pub fn discovery(oauth: &OAuth) -> RestFuture {
+    Box::new(future::ok(json(StatusCode::OK, &oauth.oidc().discovery())))
+}
+
+/// `GET /.well-known/jwks.json`
+pub fn jwks(oauth: &OAuth) -> RestFuture {
+    let jwks = oauth.oidc().jwks().map_err(RestError::OAuth);
+    Box::new(future::result(jwks.map(|jwks| json(StatusCode::OK, &jwks))))
+}
+
+/// Tokens and secrets must not end up in a cache, see section 5.1 of RFC 6749
+fn no_store(mut response: Response<Body>) -> Response<Body> {
+    response
+        .headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+fn read_form(body: Body) -> impl Future<Item = HashMap<String, String>, Error = RestError> + Send {
+    rest::read_body(body).map(|bytes| parse_form(&bytes))
+}
+
+fn parse_form(bytes: &[u8]) -> HashMap<String, String> {
+    form_urlencoded::parse(bytes).into_owned().collect()
+}
+
+fn form_token(form: &HashMap<String, String>) -> Result<Token, RestError> {
+    form.get("token")
+        .filter(|token| !token.is_empty())
+        .map(|token| Token::new(token.to_owned()))
+        .ok_or(RestError::OAuth(OAuthError::InvalidRequest(
+            "token is required",
+        )))
+}
+
+/// The client id and secret of the `Authorization: Basic` header
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    if !value.starts_with("Basic ") {
+        return None;
+    }
+    let decoded = base64::decode(value["Basic ".len()..].trim()).ok()?;
+    let decoded = str::from_utf8(&decoded).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    let client_id = parts.next()?;
+    let secret = parts.next()?;
+    Some((decode(client_id), decode(secret)))
+}
+
+/// The id and secret are form encoded before they are put in the header
+fn decode(value: &str) -> String {
+    form_urlencoded::parse(format!("v={}", value).as_bytes())
+        .next()
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default()
+}
+
+fn authenticate_client(
+    oauth: &OAuth,
+    basic: Option<(String, String)>,
+    form: &HashMap<String, String>,
+) -> Result<Client, RestError> {
+    let result = match basic {
+        Some((client_id, secret)) => oauth.authenticate_client(&client_id, Some(&secret)),
+        None => match form.get("client_id") {
+            Some(client_id) => oauth.authenticate_client(
+                client_id,
+                form.get("client_secret").map(|s| s.as_str()),
+            ),
+            None => Err(OAuthError::InvalidClient),
+        },
+    };
+    result.map_err(RestError::OAuth)
+}
+
+#[cfg(test)]
+fn request(
+    gateway: &rest::Gateway,
+    method: &str,
+    uri: &str,
+    headers: &[(&str, String)],
+    body: String,
+) -> (StatusCode, HeaderMap, serde_json::Value) {
+    use futures::Stream;
+
+    let mut request = hyper::Request::builder();
+    request.method(method).uri(uri);
+    for (name, value) in headers {
+        request.header(*name, value.as_str());
+    }
+    if !body.starts_with('{') {
+        request.header("content-type", "application/x-www-form-urlencoded");
+    }
+    let request = request.body(Body::from(body)).unwrap();
+
+    let response = rest::handle(gateway, request).wait().unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = response.into_body().concat2().wait().unwrap();
+    let body = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+    (status, headers, body)
+}
+
+#[cfg(test)]
+fn form(pairs: &[(&str, &str)]) -> String {
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs)
+        .finish()
+}
+
+/// A server with an admin and a user, and the tokens of both
+#[cfg(test)]
+fn setup() -> (rest::Gateway, String, String) {
+    use super::oidc::Oidc;
+    use crate::config::Config;
+    use crate::store::memory::MemoryStore;
+    use crate::store::{User, UserStore};
+    use std::sync::Arc;
+
+    let store = Arc::new(MemoryStore::default());
+    let admin = store
+        .insert_user("admin".to_owned(), "admin@example.com".to_owned(), "-".to_owned())
+        .unwrap();
+    store.update_role(admin.id, "admin".to_owned()).unwrap();
+    let user = store
+        .insert_user("user".to_owned(), "user@example.com".to_owned(), "-".to_owned())
+        .unwrap();
+
+    let config = Config::default();
+    let server = AuthServer::with_store(&config, store);
+    let token = |user: &User, role: &str| {
+        let token = server.start_session(user, role.into()).unwrap();
+        serde_json::to_value(&token).unwrap().as_str().unwrap().to_owned()
+    };
+    let admin_token = token(&admin, "admin");
+    let user_token = token(&user, "user");
+    let oidc = Oidc::new(&config.oidc, &config.rest);
+    (rest::Gateway::new(server, oidc), admin_token, user_token)
+}
+
+#[cfg(test)]
+fn register(gateway: &rest::Gateway, admin: &str, body: serde_json::Value) -> (String, String) {
+    let bearer = vec![("authorization", format!("Bearer {}", admin))];
+    let (status, _, registration) =
+        request(gateway, "POST", "/oauth/clients", &bearer, body.to_string());
+    assert_eq!(StatusCode::CREATED, status);
+    (
+        registration["client_id"].as_str().unwrap().to_owned(),
+        registration["client_secret"].as_str().unwrap_or("").to_owned(),
+    )
+}
+
+#[test]
+fn test_authorization_code_flow() {
+    let (gateway, admin, user) = setup();
+    let redirect_uri = "https://app.example.com/callback";
+    let (client_id, _) = register(
+        &gateway,
+        &admin,
+        json!({ "name": "Mobile app", "redirect_uris": [redirect_uri] }),
+    );
+
+    // Only admins may register clients
+    let bearer = vec![("authorization", format!("Bearer {}", user))];
+    let body = json!({ "name": "Other app", "redirect_uris": [redirect_uri] });
+    let (status, _, _) = request(&gateway, "POST", "/oauth/clients", &bearer, body.to_string());
+    assert_eq!(StatusCode::FORBIDDEN, status);
+
+    let verifier = "a-code-verifier-which-is-long-enough-for-pkce-0123";
+    let authorize = format!(
+        "/oauth/authorize?{}",
+        form(&[
+            ("response_type", "code"),
+            ("client_id", &client_id),
+            ("redirect_uri", redirect_uri),
+            ("state", "xyz"),
+            ("code_challenge", &super::pkce_challenge(verifier)),
+            ("code_challenge_method", "S256"),
+        ])
+    );
+    let (status, headers, _) = request(&gateway, "GET", &authorize, &bearer, String::new());
+    assert_eq!(StatusCode::FOUND, status);
+    let location = url::Url::parse(headers[LOCATION].to_str().unwrap()).unwrap();
+    assert!(location.as_str().starts_with(redirect_uri));
+    let query: HashMap<_, _> = location.query_pairs().into_owned().collect();
+    assert_eq!("xyz", query["state"]);
+    let code = query["code"].clone();
+
+    // A wrong verifier fails, and uses up the code
+    let exchange = |code: &str, verifier: &str| {
+        let body = form(&[
+            ("grant_type", "authorization_code"),
+            ("client_id", &client_id),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("code_verifier", verifier),
+        ]);
+        request(&gateway, "POST", "/oauth/token", &[], body)
+    };
+    let (status, _, body) = exchange(&code, "a-code-verifier-which-is-long-enough-for-pkce-9999");
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+    assert_eq!("invalid_grant", body["error"]);
+    let (status, _, _) = exchange(&code, verifier);
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+
+    // A new code with the right verifier gives a token for the user
+    let (_, headers, _) = request(&gateway, "GET", &authorize, &bearer, String::new());
+    let location = url::Url::parse(headers[LOCATION].to_str().unwrap()).unwrap();
+    let query: HashMap<_, _> = location.query_pairs().into_owned().collect();
+    let (status, headers, body) = exchange(&query["code"], verifier);
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("no-store", headers[CACHE_CONTROL]);
+    assert_eq!("Bearer", body["token_type"]);
+    let access_token = body["access_token"].as_str().unwrap().to_owned();
+
+    let bearer = vec![("authorization", format!("Bearer {}", access_token))];
+    let (status, _, body) = request(&gateway, "GET", "/v1/user", &bearer, String::new());
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(2, body["id"]);
+}
+
+#[test]
+fn test_client_tokens_are_not_admin() {
+    let (gateway, admin, _) = setup();
+    let redirect_uri = "https://app.example.com/callback";
+    let (client_id, _) = register(
+        &gateway,
+        &admin,
+        json!({ "name": "Admin app", "redirect_uris": [redirect_uri] }),
+    );
+
+    // An admin lets a client act for them
+    let verifier = "a-code-verifier-which-is-long-enough-for-pkce-0123";
+    let authorize = format!(
+        "/oauth/authorize?{}",
+        form(&[
+            ("response_type", "code"),
+            ("client_id", &client_id),
+            ("code_challenge", &super::pkce_challenge(verifier)),
+            ("code_challenge_method", "S256"),
+        ])
+    );
+    let bearer = vec![("authorization", format!("Bearer {}", admin))];
+    let (_, headers, _) = request(&gateway, "GET", &authorize, &bearer, String::new());
+    let location = url::Url::parse(headers[LOCATION].to_str().unwrap()).unwrap();
+    let query: HashMap<_, _> = location.query_pairs().into_owned().collect();
+    let body = form(&[
+        ("grant_type", "authorization_code"),
+        ("client_id", &client_id),
+        ("code", &query["code"]),
+        ("code_verifier", verifier),
+    ]);
+    let (status, _, body) = request(&gateway, "POST", "/oauth/token", &[], body);
+    assert_eq!(StatusCode::OK, status);
+
+    // The client sees the admin, but can not do what only admins may
+    let access_token = body["access_token"].as_str().unwrap();
+    let bearer = vec![("authorization", format!("Bearer {}", access_token))];
+    let (status, _, body) = request(&gateway, "GET", "/v1/user", &bearer, String::new());
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("admin", body["role"]);
+    let body = json!({ "name": "Other app", "redirect_uris": [redirect_uri] });
+    let (status, _, _) = request(&gateway, "POST", "/oauth/clients", &bearer, body.to_string());
+    assert_eq!(StatusCode::FORBIDDEN, status);
+}
+
+#[test]
+fn test_pkce_is_required() {
+    let (gateway, admin, user) = setup();
+    let redirect_uri = "https://app.example.com/callback";
+    let (client_id, _) = register(
+        &gateway,
+        &admin,
+        json!({ "name": "Mobile app", "redirect_uris": [redirect_uri] }),
+    );
+    let bearer = vec![("authorization", format!("Bearer {}", user))];
+
+    let authorize = format!(
+        "/oauth/authorize?{}",
+        form(&[("response_type", "code"), ("client_id", &client_id)])
+    );
+    let (status, headers, _) = request(&gateway, "GET", &authorize, &bearer, String::new());
+    assert_eq!(StatusCode::FOUND, status);
+    let location = url::Url::parse(headers[LOCATION].to_str().unwrap()).unwrap();
+    let query: HashMap<_, _> = location.query_pairs().into_owned().collect();
+    assert_eq!("invalid_request", query["error"]);
+    assert!(!query.contains_key("code"));
+
+    // An unregistered redirect uri is never redirected to
+    let authorize = format!(
+        "/oauth/authorize?{}",
+        form(&[
+            ("response_type", "code"),
+            ("client_id", &client_id),
+            ("redirect_uri", "https://evil.example.com/"),
+        ])
+    );
+    let (status, headers, body) = request(&gateway, "GET", &authorize, &bearer, String::new());
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+    assert!(!headers.contains_key(LOCATION));
+    assert_eq!("invalid_request", body["error"]);
+}
+
+#[test]
+fn test_client_credentials_flow() {
+    let (gateway, admin, _) = setup();
+    let (client_id, secret) = register(
+        &gateway,
+        &admin,
+        json!({ "name": "Billing service", "confidential": true }),
+    );
+    let basic = |secret: &str| {
+        vec![(
+            "authorization",
+            format!("Basic {}", base64::encode(&format!("{}:{}", client_id, secret))),
+        )]
+    };
+
+    let grant = form(&[("grant_type", "client_credentials")]);
+    let wrong = basic("wrong");
+    let (status, _, body) = request(&gateway, "POST", "/oauth/token", &wrong, grant.clone());
+    assert_eq!(StatusCode::UNAUTHORIZED, status);
+    assert_eq!("invalid_client", body["error"]);
+
+    let (status, _, body) = request(&gateway, "POST", "/oauth/token", &basic(&secret), grant);
+    assert_eq!(StatusCode::OK, status);
+    let access_token = body["access_token"].as_str().unwrap().to_owned();
+
+    // The token belongs to no user
+    let bearer = vec![("authorization", format!("Bearer {}", access_token))];
+    let (status, _, _) = request(&gateway, "GET", "/v1/user", &bearer, String::new());
+    assert_eq!(StatusCode::UNAUTHORIZED, status);
+
+    let token = form(&[("token", &access_token)]);
+    let (status, _, body) = request(&gateway, "POST", "/oauth/introspect", &basic(&secret), token);
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(true, body["active"]);
+    assert_eq!(client_id.as_str(), body["client_id"]);
+    assert!(body.get("sub").is_none());
+}
+
+#[test]
+fn test_revoke_and_introspect() {
+    let (gateway, admin, user) = setup();
+    let (client_id, secret) = register(
+        &gateway,
+        &admin,
+        json!({
+            "name": "Web app",
+            "redirect_uris": ["https://web.example.com/cb"],
+            "confidential": true
+        }),
+    );
+    let credentials = |pairs: &[(&str, &str)]| {
+        let mut all = vec![
+            ("client_id", client_id.as_str()),
+            ("client_secret", secret.as_str()),
+        ];
+        all.extend_from_slice(pairs);
+        form(&all)
+    };
+
+    // Tokens from `authenticate` can be introspected too
+    let (status, _, body) = request(
+        &gateway,
+        "POST",
+        "/oauth/introspect",
+        &[],
+        credentials(&[("token", &user)]),
+    );
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(true, body["active"]);
+    assert_eq!("2", body["sub"]);
+    assert_eq!("user", body["role"]);
+
+    let (_, _, body) = request(
+        &gateway,
+        "POST",
+        "/oauth/token",
+        &[],
+        credentials(&[("grant_type", "client_credentials")]),
+    );
+    let access_token = body["access_token"].as_str().unwrap().to_owned();
+
+    // A client can only revoke its own tokens
+    let (status, _, _) = request(
+        &gateway,
+        "POST",
+        "/oauth/revoke",
+        &[],
+        credentials(&[("token", &user)]),
+    );
+    assert_eq!(StatusCode::OK, status);
+    let bearer = vec![("authorization", format!("Bearer {}", user))];
+    let (status, _, _) = request(&gateway, "GET", "/v1/user", &bearer, String::new());
+    assert_eq!(StatusCode::OK, status);
+
+    let (status, _, _) = request(
+        &gateway,
+        "POST",
+        "/oauth/revoke",
+        &[],
+        credentials(&[("token", &access_token)]),
+    );
+    assert_eq!(StatusCode::OK, status);
+    let (_, _, body) = request(
+        &gateway,
+        "POST",
+        "/oauth/introspect",
+        &[],
+        credentials(&[("token", &access_token)]),
+    );
+    assert_eq!(json!({ "active": false }), body);
+}
+
+#[test]
+fn test_openid_connect() {
+    let (gateway, admin, user) = setup();
+    let redirect_uri = "https://app.example.com/callback";
+    let (client_id, _) = register(
+        &gateway,
+        &admin,
+        json!({ "name": "Mobile app", "redirect_uris": [redirect_uri] }),
+    );
+
+    let (status, _, discovery) = request(
+        &gateway,
+        "GET",
+        "/.well-known/openid-configuration",
+        &[],
+        String::new(),
+    );
+    assert_eq!(StatusCode::OK, status);
+    let issuer = discovery["issuer"].as_str().unwrap().to_owned();
+    assert_eq!(
+        format!("{}/.well-known/jwks.json", issuer),
+        discovery["jwks_uri"]
+    );
+
+    let verifier = "a-code-verifier-which-is-long-enough-for-pkce-0123";
+    let authorize = format!(
+        "/oauth/authorize?{}",
+        form(&[
+            ("response_type", "code"),
+            ("client_id", &client_id),
+            ("scope", "openid email"),
+            ("nonce", "n-0S6_WzA2Mj"),
+            ("code_challenge", &super::pkce_challenge(verifier)),
+            ("code_challenge_method", "S256"),
+        ])
+    );
+    let bearer = vec![("authorization", format!("Bearer {}", user))];
+    let (_, headers, _) = request(&gateway, "GET", &authorize, &bearer, String::new());
+    let location = url::Url::parse(headers[LOCATION].to_str().unwrap()).unwrap();
+    let query: HashMap<_, _> = location.query_pairs().into_owned().collect();
+    let body = form(&[
+        ("grant_type", "authorization_code"),
+        ("client_id", &client_id),
+        ("code", &query["code"]),
+        ("code_verifier", verifier),
+    ]);
+    let (status, _, body) = request(&gateway, "POST", "/oauth/token", &[], body);
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("openid email", body["scope"]);
+
+    let (_, _, jwks) = request(&gateway, "GET", "/.well-known/jwks.json", &[], String::new());
+    let claims = super::oidc::verify(body["id_token"].as_str().unwrap(), &jwks);
+    assert_eq!(issuer.as_str(), claims["iss"]);
+    assert_eq!(client_id.as_str(), claims["aud"]);
+    assert_eq!("2", claims["sub"]);
+    assert_eq!("user@example.com", claims["email"]);
+    assert_eq!("n-0S6_WzA2Mj", claims["nonce"]);
+    // The profile scope was not asked for
+    assert!(claims.get("preferred_username").is_none());
+
+    let access_token = body["access_token"].as_str().unwrap();
+    let bearer = vec![("authorization", format!("Bearer {}", access_token))];
+    let (status, _, body) = request(&gateway, "GET", "/oauth/userinfo", &bearer, String::new());
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(
+        json!({
+            "sub": "2",
+            "email": "user@example.com",
+            "email_verified": false
+        }),
+        body
+    );
+
+    // The user themselves gets every claim
+    let bearer = vec![("authorization", format!("Bearer {}", user))];
+    let (_, _, body) = request(&gateway, "GET", "/oauth/userinfo", &bearer, String::new());
+    assert_eq!("user", body["preferred_username"]);
+    assert_eq!("user@example.com", body["email"]);
+}
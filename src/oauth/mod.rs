@@ -0,0 +1,643 @@
+//! OAuth 2.0 authorization server (feature `rest`)
+//!
+//! Lets other apps act on behalf of a user without ever seeing their password.
+//! It is built on the users of the [`UserStore`] and the sessions of the
+//! [`AuthServer`]: a token issued for a user is an ordinary session token, so
+//! `get_user` accepts it like a token from `authenticate`. It is refused by
+//! the rpcs which require an admin though, even if the user is one.
+//!
+//! - Clients are registered by admins, and are either confidential (they get a
+//! secret) or public (like a mobile app, which can not keep a secret).
+//! - The authorization code grant requires PKCE with `S256` for every client
+//! ([RFC 7636](https://tools.ietf.org/html/rfc7636)).
+//! - The client credentials grant lets confidential clients get a token for
+//! themselves, which belongs to no user.
+//! - Tokens can be revoked ([RFC 7009](https://tools.ietf.org/html/rfc7009))
+//! and introspected ([RFC 7662](https://tools.ietf.org/html/rfc7662)).
+//!
+//! Only the clients are stored in the database. Authorization codes and the
+//! tokens of clients live in memory, like the sessions.
+//!
+//! OpenID Connect is supported on top of this, see [`oidc`]. The HTTP
+//! endpoints are in [`endpoints`].
+//!
+//! [`UserStore`]: crate::store::UserStore
+use chrono::offset::Utc;
+use chrono::{DateTime, Duration};
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::sync::{Arc, RwLock};
+use url::Url;
+
+use datatypes::auth::responses::{AuthError, Role};
+use datatypes::valid::ids::UserId;
+use datatypes::valid::token::Token;
+
+use crate::redact::Fingerprint;
+use crate::service::AuthServer;
+use crate::store::Client;
+use self::oidc::Oidc;
+use crate::{IntError, IntErrorKind};
+
+pub mod endpoints;
+pub mod oidc;
+
+/// The scope a client asks for to get an ID token
+const OPENID_SCOPE: &str = "openid";
+
+/// How long an authorization code can be exchanged for a token
+const CODE_LIFETIME: i64 = 60;
+
+/// Allowed length of a PKCE code verifier
+const MIN_VERIFIER_LEN: usize = 43;
+const MAX_VERIFIER_LEN: usize = 128;
+
+/// An error as described in section 5.2 of RFC 6749
+#[derive(Debug, Clone, PartialEq)]
+pub enum OAuthError {
+    InvalidRequest(&'static str),
+    InvalidClient,
+    InvalidGrant,
+    UnauthorizedClient,
+    UnsupportedGrantType,
+    UnsupportedResponseType,
+    ServerError,
+}
+
+impl OAuthError {
+    /// The `error` code sent to the client
+    pub fn code(&self) -> &'static str {
+        match self {
+            OAuthError::InvalidRequest(_) => "invalid_request",
+            OAuthError::InvalidClient => "invalid_client",
+            OAuthError::InvalidGrant => "invalid_grant",
+            OAuthError::UnauthorizedClient => "unauthorized_client",
+            OAuthError::UnsupportedGrantType => "unsupported_grant_type",
+            OAuthError::UnsupportedResponseType => "unsupported_response_type",
+            OAuthError::ServerError => "server_error",
+        }
+    }
+
+    /// The `error_description` sent to the client, if any
+    pub fn description(&self) -> Option<&'static str> {
+        match self {
+            OAuthError::InvalidRequest(description) => Some(description),
+            _ => None,
+        }
+    }
+}
+
+impl Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.description() {
+            Some(description) => write!(f, "{}: {}", self.code(), description),
+            None => write!(f, "{}", self.code()),
+        }
+    }
+}
+
+impl From<IntError> for OAuthError {
+    fn from(e: IntError) -> OAuthError {
+        match e.kind() {
+            IntErrorKind::InvalidClient => OAuthError::InvalidClient,
+            _ => {
+                error!("OAuth request failed: {}", e);
+                OAuthError::ServerError
+            }
+        }
+    }
+}
+
+impl From<AuthError> for OAuthError {
+    fn from(_: AuthError) -> OAuthError {
+        OAuthError::ServerError
+    }
+}
+
+/// The id, and the secret of a confidential client, which are only shown once
+#[derive(Serialize, Debug)]
+pub struct Registration {
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+/// A successful response of the token endpoint
+#[derive(Serialize, Debug)]
+pub struct TokenResponse {
+    pub access_token: Token,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Only issued for the `openid` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+/// What is known about a token, `active: false` if nothing
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// Id of the user of the token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+}
+
+/// A request to the authorization endpoint, see section 4.1.1 of RFC 6749
+#[derive(Debug, Default)]
+pub struct AuthorizeRequest<'a> {
+    pub response_type: Option<&'a str>,
+    pub client_id: Option<&'a str>,
+    pub redirect_uri: Option<&'a str>,
+    pub state: Option<&'a str>,
+    pub code_challenge: Option<&'a str>,
+    pub code_challenge_method: Option<&'a str>,
+    pub scope: Option<&'a str>,
+    /// Sent back in the ID token, see section 3.1.2.1 of OpenID Connect Core
+    pub nonce: Option<&'a str>,
+}
+
+/// An authorization code waiting to be exchanged
+struct Code {
+    client_id: String,
+    /// Only set if the client sent one, then it must be sent again
+    redirect_uri: Option<String>,
+    challenge: String,
+    scope: Option<String>,
+    nonce: Option<String>,
+    user_id: UserId,
+    role: Role,
+    created: DateTime<Utc>,
+}
+
+/// A token issued to a client
+struct Grant {
+    client_id: String,
+    /// The user the client acts for, `None` for the client credentials grant
+    user_id: Option<UserId>,
+    /// The scope the user agreed to
+    scope: Option<String>,
+    created: DateTime<Utc>,
+}
+
+/// The state of the authorization server
+#[derive(Clone)]
+pub struct OAuth {
+    server: AuthServer,
+    oidc: Oidc,
+    codes: Arc<RwLock<HashMap<String, Code>>>,
+    grants: Arc<RwLock<HashMap<Token, Grant>>>,
+}
+
+impl OAuth {
+    pub fn new(server: AuthServer, oidc: Oidc) -> OAuth {
+        OAuth {
+            server,
+            oidc,
+            codes: Arc::default(),
+            grants: Arc::default(),
+        }
+    }
+
+    pub fn oidc(&self) -> &Oidc {
+        &self.oidc
+    }
+
+    /// Registers a client which may be sent codes on `redirect_uris`
+    ///
+    /// A confidential client gets a secret, a public client, like a mobile
+    /// app, can not keep one.
+    pub fn register_client(
+        &self,
+        name: &str,
+        redirect_uris: &[String],
+        confidential: bool,
+    ) -> Result<Registration, OAuthError> {
+        if name.trim().is_empty() {
+            return Err(OAuthError::InvalidRequest("name must not be empty"));
+        }
+        // Confidential clients may only use the client credentials grant
+        if redirect_uris.is_empty() && !confidential {
+            return Err(OAuthError::InvalidRequest(
+                "a public client needs at least one redirect_uri",
+            ));
+        }
+        for uri in redirect_uris {
+            match Url::parse(uri) {
+                Ok(ref url) if url.fragment().is_none() && !uri.contains(' ') => {}
+                _ => {
+                    return Err(OAuthError::InvalidRequest(
+                        "redirect_uris must be absolute uris without a fragment",
+                    ))
+                }
+            }
+        }
+
+        let client_secret = if confidential {
+            Some(random_string(32))
+        } else {
+            None
+        };
+        let client = Client {
+            id: random_string(16),
+            name: name.to_owned(),
+            secret_hash: client_secret.as_ref().map(|s| hash_secret(s)),
+            redirect_uris: redirect_uris.join(" "),
+        };
+        self.server.store().insert_client(&client)?;
+        info!("Registered oauth client '{}' ({})", client.name, client.id);
+
+        Ok(Registration {
+            client_id: client.id,
+            client_secret,
+        })
+    }
+
+    /// Returns the client if the secret is right
+    ///
+    /// Confidential clients must send their secret, public clients have none
+    /// to send.
+    pub fn authenticate_client(
+        &self,
+        client_id: &str,
+        secret: Option<&str>,
+    ) -> Result<Client, OAuthError> {
+        let client = self.server.store().fetch_client(client_id)?;
+        let authenticated = match (&client.secret_hash, secret) {
+            (Some(hash), Some(secret)) => constant_time_eq(hash, &hash_secret(secret)),
+            (None, None) => true,
+            _ => false,
+        };
+        if authenticated {
+            Ok(client)
+        } else {
+            trace!("Client failed to authenticate");
+            Err(OAuthError::InvalidClient)
+        }
+    }
+
+    /// Issues a code to the client for a user who has agreed to it
+    ///
+    /// Returns the uri to redirect the user to. That uri carries the code, or
+    /// the error if the request was invalid. An `Err` is only returned if the
+    /// client or the redirect uri is invalid, then the user must not be
+    /// redirected at all.
+    pub fn authorize(
+        &self,
+        user_id: UserId,
+        role: Role,
+        request: &AuthorizeRequest,
+    ) -> Result<Url, OAuthError> {
+        let client_id = request
+            .client_id
+            .ok_or(OAuthError::InvalidRequest("client_id is required"))?;
+        let client = self.server.store().fetch_client(client_id)?;
+        let registered: Vec<&str> = client.redirect_uris.split_whitespace().collect();
+        let redirect_uri = match request.redirect_uri {
+            Some(uri) if registered.contains(&uri) => uri,
+            Some(_) => {
+                return Err(OAuthError::InvalidRequest(
+                    "redirect_uri is not registered for the client",
+                ))
+            }
+            None if registered.len() == 1 => registered[0],
+            None => return Err(OAuthError::InvalidRequest("redirect_uri is required")),
+        };
+        let mut redirect = Url::parse(redirect_uri).map_err(|_| OAuthError::ServerError)?;
+
+        match self.issue_code(&client, user_id, role, request) {
+            Ok(code) => {
+                debug!("Issued authorization code to client {}", client.id);
+                redirect.query_pairs_mut().append_pair("code", &code);
+            }
+            Err(e) => {
+                debug!("Refused authorization request: {}", e);
+                redirect.query_pairs_mut().append_pair("error", e.code());
+                if let Some(description) = e.description() {
+                    redirect
+                        .query_pairs_mut()
+                        .append_pair("error_description", description);
+                }
+            }
+        }
+        if let Some(state) = request.state {
+            redirect.query_pairs_mut().append_pair("state", state);
+        }
+        Ok(redirect)
+    }
+
+    fn issue_code(
+        &self,
+        client: &Client,
+        user_id: UserId,
+        role: Role,
+        request: &AuthorizeRequest,
+    ) -> Result<String, OAuthError> {
+        if request.response_type != Some("code") {
+            return Err(OAuthError::UnsupportedResponseType);
+        }
+        let challenge = request
+            .code_challenge
+            .ok_or(OAuthError::InvalidRequest("code_challenge is required"))?;
+        if request.code_challenge_method != Some("S256") {
+            return Err(OAuthError::InvalidRequest(
+                "code_challenge_method must be S256",
+            ));
+        }
+
+        let code = random_string(32);
+        let now = Utc::now();
+        let mut codes = self.codes.write().map_err(|e| {
+            error!("Unable to write to 'codes': {}", e);
+            OAuthError::ServerError
+        })?;
+        codes.retain(|_, c| now - c.created < Duration::seconds(CODE_LIFETIME));
+        codes.insert(
+            code.clone(),
+            Code {
+                client_id: client.id.clone(),
+                redirect_uri: request.redirect_uri.map(|uri| uri.to_owned()),
+                challenge: challenge.to_owned(),
+                scope: request.scope.map(|scope| scope.to_owned()),
+                nonce: request.nonce.map(|nonce| nonce.to_owned()),
+                user_id,
+                role,
+                created: now,
+            },
+        );
+        Ok(code)
+    }
+
+    /// The authorization code grant, see section 4.1.3 of RFC 6749
+    ///
+    /// A code can only be used once, even if the exchange fails. If the code
+    /// was issued for the `openid` scope, an ID token is issued as well.
+    pub fn exchange_code(
+        &self,
+        client: &Client,
+        code: &str,
+        redirect_uri: Option<&str>,
+        code_verifier: &str,
+    ) -> Result<TokenResponse, OAuthError> {
+        let code = self
+            .codes
+            .write()
+            .map_err(|e| {
+                error!("Unable to write to 'codes': {}", e);
+                OAuthError::ServerError
+            })?.remove(code)
+            .ok_or_else(|| {
+                trace!("Unknown authorization code");
+                OAuthError::InvalidGrant
+            })?;
+
+        if code.client_id != client.id
+            || Utc::now() - code.created >= Duration::seconds(CODE_LIFETIME)
+            || code.redirect_uri.as_ref().map(|uri| uri.as_str()) != redirect_uri
+        {
+            trace!("Authorization code is expired or was issued for something else");
+            return Err(OAuthError::InvalidGrant);
+        }
+        if !verify_pkce(code_verifier, &code.challenge) {
+            trace!("Code verifier does not match the challenge");
+            return Err(OAuthError::InvalidGrant);
+        }
+
+        let openid = code
+            .scope
+            .as_ref()
+            .map_or(false, |scope| scope.split(' ').any(|s| s == OPENID_SCOPE));
+        let user = self.server.store().fetch_user_by_id(*code.user_id)?;
+        let scope = code.scope.as_ref().map_or("", |scope| scope.as_str());
+        let id_token = if openid {
+            let nonce = code.nonce.as_ref().map(|nonce| nonce.as_str());
+            Some(self.oidc.id_token(&user, &client.id, scope, nonce)?)
+        } else {
+            None
+        };
+
+        let token = self.server.start_client_session(&user, code.role, &client.id)?;
+        self.record_grant(&token, client, Some(code.user_id), code.scope.clone())?;
+        debug!(
+            "Issued token {} to client {}",
+            Fingerprint(&token),
+            client.id
+        );
+        Ok(TokenResponse {
+            scope: code.scope,
+            id_token,
+            ..self.token_response(token)
+        })
+    }
+
+    /// The client credentials grant, see section 4.4 of RFC 6749
+    pub fn client_credentials(&self, client: &Client) -> Result<TokenResponse, OAuthError> {
+        if client.secret_hash.is_none() {
+            return Err(OAuthError::UnauthorizedClient);
+        }
+        let token = Token::new(random_string(45));
+        self.record_grant(&token, client, None, None)?;
+        debug!(
+            "Issued client token {} to client {}",
+            Fingerprint(&token),
+            client.id
+        );
+        Ok(self.token_response(token))
+    }
+
+    /// Revokes a token issued to the client, see RFC 7009
+    ///
+    /// Unknown tokens and tokens of other clients are ignored, so a client can
+    /// not learn anything about them.
+    pub fn revoke(&self, client: &Client, token: &Token) -> Result<(), OAuthError> {
+        let mut grants = self.grants.write().map_err(|e| {
+            error!("Unable to write to 'grants': {}", e);
+            OAuthError::ServerError
+        })?;
+        let owned = grants
+            .get(token)
+            .map_or(false, |grant| grant.client_id == client.id);
+        if owned {
+            if let Some(Grant {
+                user_id: Some(_), ..
+            }) = grants.remove(token)
+            {
+                // The session may already have ended by `deauthenticate`
+                let _ = self.server.remove_token(token);
+            }
+            debug!("Revoked token {}", Fingerprint(token));
+        }
+        Ok(())
+    }
+
+    /// Describes a token to a confidential client, see RFC 7662
+    ///
+    /// Both tokens issued through OAuth and tokens from `authenticate` can be
+    /// introspected.
+    pub fn introspect(&self, client: &Client, token: &Token) -> Result<Introspection, OAuthError> {
+        if client.secret_hash.is_none() {
+            return Err(OAuthError::UnauthorizedClient);
+        }
+        let lifetime = self.server.token_lifetime();
+        let now = Utc::now();
+
+        let session = self
+            .server
+            .sessions()
+            .read()
+            .map_err(|e| {
+                error!("Unable to read 'tokens': {}", e);
+                OAuthError::ServerError
+            })?.get(token)
+            .cloned();
+        let grants = self.grants.read().map_err(|e| {
+            error!("Unable to read 'grants': {}", e);
+            OAuthError::ServerError
+        })?;
+        let grant = grants.get(token);
+
+        let (created, client_id, user) = match (grant, session) {
+            // A token of a user which has been revoked or has logged out
+            (Some(Grant {
+                user_id: Some(_), ..
+            }), None) => return Ok(Introspection::default()),
+            (Some(grant), session) => (
+                grant.created,
+                Some(grant.client_id.clone()),
+                session.map(|session| (session.user_id, session.role)),
+            ),
+            (None, Some(session)) => (
+                session.created,
+                None,
+                Some((session.user_id, session.role)),
+            ),
+            (None, None) => return Ok(Introspection::default()),
+        };
+        if now - created >= lifetime {
+            return Ok(Introspection::default());
+        }
+
+        Ok(Introspection {
+            active: true,
+            client_id,
+            sub: user.map(|(user_id, _)| (*user_id).to_string()),
+            role: user.map(|(_, role)| role.into()),
+            token_type: Some("Bearer"),
+            iat: Some(created.timestamp()),
+            exp: Some((created + lifetime).timestamp()),
+        })
+    }
+
+    /// The scope of a token issued to a client, `None` for the sessions of
+    /// users
+    pub fn scope(&self, token: &Token) -> Result<Option<String>, OAuthError> {
+        let grants = self.grants.read().map_err(|e| {
+            error!("Unable to read 'grants': {}", e);
+            OAuthError::ServerError
+        })?;
+        Ok(grants
+            .get(token)
+            .map(|grant| grant.scope.clone().unwrap_or_default()))
+    }
+
+    fn record_grant(
+        &self,
+        token: &Token,
+        client: &Client,
+        user_id: Option<UserId>,
+        scope: Option<String>,
+    ) -> Result<(), OAuthError> {
+        let now = Utc::now();
+        let lifetime = self.server.token_lifetime();
+        let mut grants = self.grants.write().map_err(|e| {
+            error!("Unable to write to 'grants': {}", e);
+            OAuthError::ServerError
+        })?;
+        grants.retain(|_, grant| now - grant.created < lifetime);
+        grants.insert(
+            token.clone(),
+            Grant {
+                client_id: client.id.clone(),
+                user_id,
+                scope,
+                created: now,
+            },
+        );
+        Ok(())
+    }
+
+    fn token_response(&self, access_token: Token) -> TokenResponse {
+        TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: self.server.token_lifetime().num_seconds(),
+            scope: None,
+            id_token: None,
+        }
+    }
+}
+
+/// Checks a PKCE code verifier against an `S256` challenge
+pub fn verify_pkce(verifier: &str, challenge: &str) -> bool {
+    let valid_verifier = verifier.len() >= MIN_VERIFIER_LEN
+        && verifier.len() <= MAX_VERIFIER_LEN
+        && verifier
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c));
+    valid_verifier && constant_time_eq(&pkce_challenge(verifier), challenge)
+}
+
+/// The `S256` challenge of a PKCE code verifier
+pub fn pkce_challenge(verifier: &str) -> String {
+    base64::encode_config(&Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// A random url safe string made from `bytes` random bytes
+fn random_string(bytes: usize) -> String {
+    let mut random_bytes = vec![0u8; bytes];
+    thread_rng().fill(&mut random_bytes[..]);
+    base64::encode_config(&random_bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Client secrets are long and random, so a plain SHA-256 is enough
+fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Compares without leaking where the first difference is
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[test]
+fn test_pkce() {
+    // The example of appendix B in RFC 7636
+    let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+    assert_eq!(challenge, pkce_challenge(verifier));
+    assert!(verify_pkce(verifier, challenge));
+
+    assert!(!verify_pkce("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXK", challenge));
+    assert!(!verify_pkce("too-short", &pkce_challenge("too-short")));
+    let invalid = "with spaces with spaces with spaces with spaces";
+    assert!(!verify_pkce(invalid, &pkce_challenge(invalid)));
+}
@@ -0,0 +1,359 @@
+//! OpenID Connect on top of the authorization server
+//!
+//! A client which asks for the `openid` scope gets an ID token next to the
+//! access token. ID tokens are JWTs signed with RS256, with the claims of
+//! [`UserClaims`].
+//!
+//! The signing key is replaced every `oidc.key_rotation` seconds. Old keys are
+//! still published in the JWKS until the last ID token signed with them has
+//! expired. The keys only live in memory, so a restart replaces them, and
+//! every instance has keys of its own. Clients are expected to fetch the JWKS
+//! again when they see an unknown `kid`, as the spec asks of them.
+use chrono::offset::Utc;
+use chrono::{DateTime, Duration};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, RwLock};
+
+use super::OAuthError;
+use crate::config::{OidcConfig, RestConfig};
+use crate::store::User;
+
+/// Size of the signing keys in bits
+const KEY_BITS: u32 = 2048;
+
+/// The scope a client asks for to get the `profile` claims
+const PROFILE_SCOPE: &str = "profile";
+
+/// The scope a client asks for to get the `email` claims
+const EMAIL_SCOPE: &str = "email";
+
+/// The standard claims about a user, sent in ID tokens and by `userinfo`
+///
+/// A client only gets the claims of the scopes it was granted, see section 5.4
+/// of OpenID Connect Core.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserClaims {
+    pub sub: String,
+    /// Only for the `profile` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    /// Only for the `email` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// Only for the `email` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
+
+impl UserClaims {
+    /// The claims of the scopes in `scope`, separated by spaces
+    pub fn scoped(user: &User, scope: &str) -> UserClaims {
+        let has = |wanted: &str| scope.split(' ').any(|s| s == wanted);
+        let claims = UserClaims::from(user);
+        UserClaims {
+            sub: claims.sub,
+            preferred_username: claims.preferred_username.filter(|_| has(PROFILE_SCOPE)),
+            email: claims.email.filter(|_| has(EMAIL_SCOPE)),
+            email_verified: claims.email_verified.filter(|_| has(EMAIL_SCOPE)),
+        }
+    }
+}
+
+/// Every claim, for the user themselves
+impl<'a> From<&'a User> for UserClaims {
+    fn from(user: &'a User) -> UserClaims {
+        UserClaims {
+            sub: user.id.to_string(),
+            preferred_username: Some(user.username.clone()),
+            email: Some(user.email.clone()),
+            email_verified: Some(user.verified),
+        }
+    }
+}
+
+/// The claims of an ID token
+#[derive(Serialize)]
+struct IdTokenClaims<'a> {
+    iss: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<&'a str>,
+    #[serde(flatten)]
+    user: UserClaims,
+}
+
+struct SigningKey {
+    kid: String,
+    key: PKey<Private>,
+    /// The public key as a JWK
+    jwk: Value,
+    created: DateTime<Utc>,
+}
+
+impl SigningKey {
+    fn generate() -> Result<SigningKey, ErrorStack> {
+        let rsa = Rsa::generate(KEY_BITS)?;
+        let n = base64url(&rsa.n().to_vec());
+        let e = base64url(&rsa.e().to_vec());
+        let key = PKey::from_rsa(rsa)?;
+
+        // The JWK thumbprint of RFC 7638, the members must be in this order
+        let thumbprint = format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, e, n);
+        let kid = base64url(&Sha256::digest(thumbprint.as_bytes()));
+        let jwk = json!({
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": kid,
+            "n": n,
+            "e": e,
+        });
+
+        Ok(SigningKey {
+            kid,
+            key,
+            jwk,
+            created: Utc::now(),
+        })
+    }
+}
+
+/// The identity provider
+#[derive(Clone)]
+pub struct Oidc {
+    issuer: String,
+    id_token_lifetime: Duration,
+    key_rotation: Duration,
+    /// Oldest first, the last key is the one to sign with
+    keys: Arc<RwLock<Vec<SigningKey>>>,
+}
+
+impl Oidc {
+    pub fn new(config: &OidcConfig, rest: &RestConfig) -> Oidc {
+        Oidc {
+            issuer: config.issuer_url(rest),
+            id_token_lifetime: Duration::seconds(config.id_token_lifetime),
+            key_rotation: Duration::seconds(config.key_rotation),
+            keys: Arc::default(),
+        }
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Makes an ID token about `user` for the client `client_id`, with the
+    /// claims of `scope`
+    pub fn id_token(
+        &self,
+        user: &User,
+        client_id: &str,
+        scope: &str,
+        nonce: Option<&str>,
+    ) -> Result<String, OAuthError> {
+        let now = Utc::now();
+        let claims = IdTokenClaims {
+            iss: &self.issuer,
+            aud: client_id,
+            iat: now.timestamp(),
+            exp: (now + self.id_token_lifetime).timestamp(),
+            nonce,
+            user: UserClaims::scoped(user, scope),
+        };
+
+        self.rotate()?;
+        let keys = self.keys.read().map_err(|e| {
+            error!("Unable to read 'keys': {}", e);
+            OAuthError::ServerError
+        })?;
+        let key = keys.last().ok_or(OAuthError::ServerError)?;
+        sign(key, &claims).map_err(|e| {
+            error!("Unable to sign ID token: {}", e);
+            OAuthError::ServerError
+        })
+    }
+
+    /// The JSON Web Key Set with every key an unexpired ID token may be
+    /// signed with
+    pub fn jwks(&self) -> Result<Value, OAuthError> {
+        self.rotate()?;
+        let keys = self.keys.read().map_err(|e| {
+            error!("Unable to read 'keys': {}", e);
+            OAuthError::ServerError
+        })?;
+        let jwks: Vec<&Value> = keys.iter().rev().map(|key| &key.jwk).collect();
+        Ok(json!({ "keys": jwks }))
+    }
+
+    /// The discovery document of OpenID Connect Discovery 1.0
+    pub fn discovery(&self) -> Value {
+        let endpoint = |path: &str| format!("{}{}", self.issuer, path);
+        json!({
+            "issuer": self.issuer,
+            "authorization_endpoint": endpoint("/oauth/authorize"),
+            "token_endpoint": endpoint("/oauth/token"),
+            "userinfo_endpoint": endpoint("/oauth/userinfo"),
+            "jwks_uri": endpoint("/.well-known/jwks.json"),
+            "revocation_endpoint": endpoint("/oauth/revoke"),
+            "introspection_endpoint": endpoint("/oauth/introspect"),
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code", "client_credentials"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+            "scopes_supported": ["openid", "profile", "email"],
+            "token_endpoint_auth_methods_supported":
+                ["client_secret_basic", "client_secret_post", "none"],
+            "code_challenge_methods_supported": ["S256"],
+            "claims_supported": [
+                "iss", "aud", "iat", "exp", "nonce",
+                "sub", "preferred_username", "email", "email_verified"
+            ],
+        })
+    }
+
+    /// Makes a new key when the current one is too old, and drops the keys no
+    /// unexpired ID token can be signed with
+    fn rotate(&self) -> Result<(), OAuthError> {
+        let is_current = |keys: &[SigningKey]| {
+            keys.last()
+                .map_or(false, |key| Utc::now() - key.created < self.key_rotation)
+        };
+        let current = is_current(&self.keys.read().map_err(|e| {
+            error!("Unable to read 'keys': {}", e);
+            OAuthError::ServerError
+        })?);
+        if current {
+            return Ok(());
+        }
+
+        // Generating a key takes a while, which must not block the requests
+        // which sign with or publish the current keys
+        let key = SigningKey::generate().map_err(|e| {
+            error!("Unable to generate a signing key: {}", e);
+            OAuthError::ServerError
+        })?;
+        let mut keys = self.keys.write().map_err(|e| {
+            error!("Unable to write to 'keys': {}", e);
+            OAuthError::ServerError
+        })?;
+        // Another request may have rotated the key in the meantime
+        if !is_current(&keys) {
+            info!("Rotated the ID token signing key, the new key is {}", key.kid);
+            keys.push(key);
+        }
+
+        // A key stops being used `key_rotation` after it was made, and the
+        // last token it signed expires `id_token_lifetime` after that
+        let oldest = Utc::now() - self.key_rotation - self.id_token_lifetime;
+        keys.retain(|key| key.created > oldest);
+        Ok(())
+    }
+}
+
+/// Makes a JWS in the compact serialization
+fn sign<T: Serialize>(key: &SigningKey, claims: &T) -> Result<String, failure::Error> {
+    let header = json!({ "alg": "RS256", "typ": "JWT", "kid": key.kid });
+    let mut jwt = format!(
+        "{}.{}",
+        base64url(&serde_json::to_vec(&header)?),
+        base64url(&serde_json::to_vec(claims)?)
+    );
+
+    let mut signer = Signer::new(MessageDigest::sha256(), &key.key)?;
+    signer.update(jwt.as_bytes())?;
+    let signature = signer.sign_to_vec()?;
+
+    jwt.push('.');
+    jwt.push_str(&base64url(&signature));
+    Ok(jwt)
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Checks the signature of an ID token against a JWKS, and returns its claims
+#[cfg(test)]
+pub fn verify(jwt: &str, jwks: &Value) -> Value {
+    use openssl::bn::BigNum;
+    use openssl::sign::Verifier;
+
+    let decode = |part: &str| base64::decode_config(part, base64::URL_SAFE_NO_PAD).unwrap();
+    let parts: Vec<&str> = jwt.split('.').collect();
+    assert_eq!(3, parts.len());
+    let header: Value = serde_json::from_slice(&decode(parts[0])).unwrap();
+    assert_eq!("RS256", header["alg"]);
+
+    let jwk = jwks["keys"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|jwk| jwk["kid"] == header["kid"])
+        .expect("the key is in the JWKS");
+    let component = |name: &str| BigNum::from_slice(&decode(jwk[name].as_str().unwrap())).unwrap();
+    let rsa = Rsa::from_public_components(component("n"), component("e")).unwrap();
+    let key = PKey::from_rsa(rsa).unwrap();
+
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key).unwrap();
+    verifier
+        .update(format!("{}.{}", parts[0], parts[1]).as_bytes())
+        .unwrap();
+    assert!(verifier.verify(&decode(parts[2])).unwrap());
+
+    serde_json::from_slice(&decode(parts[1])).unwrap()
+}
+
+#[test]
+fn test_id_token() {
+    let config = OidcConfig {
+        issuer: "https://auth.example.com/".to_owned(),
+        id_token_lifetime: 3600,
+        // Every token is signed with a new key
+        key_rotation: 0,
+    };
+    let oidc = Oidc::new(&config, &RestConfig::default());
+    let user = User {
+        id: 7,
+        email: "oidc@example.com".to_owned(),
+        username: "oidc".to_owned(),
+        password: String::new(),
+        banned: false,
+        verified: true,
+        email_token: None,
+        created_at: chrono::NaiveDateTime::from_timestamp(0, 0),
+    };
+
+    let scope = "openid profile email";
+    let first = oidc.id_token(&user, "client", scope, Some("n-0S6_WzA2Mj")).unwrap();
+    let second = oidc.id_token(&user, "client", "openid", None).unwrap();
+    let jwks = oidc.jwks().unwrap();
+    assert_eq!(3, jwks["keys"].as_array().unwrap().len());
+
+    let claims = verify(&first, &jwks);
+    assert_eq!("https://auth.example.com", claims["iss"]);
+    assert_eq!("client", claims["aud"]);
+    assert_eq!("7", claims["sub"]);
+    assert_eq!("oidc", claims["preferred_username"]);
+    assert_eq!("oidc@example.com", claims["email"]);
+    assert_eq!(true, claims["email_verified"]);
+    assert_eq!("n-0S6_WzA2Mj", claims["nonce"]);
+    assert_eq!(
+        3600,
+        claims["exp"].as_i64().unwrap() - claims["iat"].as_i64().unwrap()
+    );
+    let claims = verify(&second, &jwks);
+    assert!(claims.get("nonce").is_none());
+    assert_eq!("7", claims["sub"]);
+    assert!(claims.get("preferred_username").is_none());
+    assert!(claims.get("email").is_none());
+    assert!(claims.get("email_verified").is_none());
+}
@@ -0,0 +1,119 @@
+//! Wrappers which make secrets and personal data safe to log
+//!
+//! Never log a `Token`, an email or a username directly, wrap it in one of
+//! these first:
+//!
+//! ```ignore
+//! debug!("Received request for token: {}", Fingerprint(&token));
+//! debug!("Received request from: {}", Masked(&username));
+//! debug!("Registering: {}", Masked(&email));
+//! ```
+//!
+//! Passwords are kept in a [`Secret`] from the moment they are received, which
+//! prints nothing but `<redacted>`.
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Debug, Display};
+
+use datatypes::valid::token::Token;
+
+/// Shows a token as the first bytes of its SHA-256 hash
+///
+/// The same token always has the same fingerprint, so log lines can still be
+/// correlated, but the token itself can not be recovered.
+pub struct Fingerprint<'a>(pub &'a Token);
+
+impl<'a> Display for Fingerprint<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The serialized form is the only public view of the token's value.
+        // A fallback would give every token the same fingerprint.
+        let serialized = serde_json::to_vec(self.0).expect("a token serializes to json");
+        let hash = Sha256::digest(&serialized);
+        write!(f, "tok:")?;
+        for byte in &hash[..4] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Debug for Fingerprint<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Shows only the first character of a username, or of the local part of an
+/// email, e.g. `j***` or `j***@example.com`
+pub struct Masked<T: Display>(pub T);
+
+impl<T: Display> Display for Masked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = self.0.to_string();
+        let (local, domain) = match value.rfind('@') {
+            Some(at) => value.split_at(at),
+            None => (value.as_str(), ""),
+        };
+        match local.chars().next() {
+            Some(first) => write!(f, "{}***{}", first, domain),
+            None => write!(f, "***{}", domain),
+        }
+    }
+}
+
+impl<T: Display> Debug for Masked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// A value which must never be logged, like a password
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(value)
+    }
+
+    /// Gives access to the secret value
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+impl<T> Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+#[test]
+fn test_masked() {
+    assert_eq!("j***@example.com", Masked("john@example.com").to_string());
+    assert_eq!("j***", Masked("john").to_string());
+    assert_eq!("***@example.com", Masked("@example.com").to_string());
+    assert_eq!("***", Masked("").to_string());
+    assert_eq!("<redacted>", format!("{:?}", Secret::new("hunter2")));
+}
+
+#[test]
+fn test_fingerprint() {
+    let token = Token::new("secret-token".to_owned());
+    let fingerprint = Fingerprint(&token).to_string();
+
+    assert_eq!(12, fingerprint.len());
+    assert!(!fingerprint.contains("secret-token"));
+    assert_eq!(
+        fingerprint,
+        Fingerprint(&Token::new("secret-token".to_owned())).to_string()
+    );
+    assert_ne!(
+        fingerprint,
+        Fingerprint(&Token::new("other-token".to_owned())).to_string()
+    );
+}
@@ -0,0 +1,920 @@
+//! A JSON over HTTP gateway to the rpc service (feature `rest`)
+//!
+//! Lets clients which do not speak tarpc, like the web and mobile front-ends,
+//! use the service. Every endpoint calls the same [`FutureService`] method as
+//! the rpc server, so both behave alike.
+//!
+//! | Endpoint                   | Rpc              | Token |
+//! |----------------------------|------------------|-------|
+//! | `POST /v1/sessions`        | `authenticate`   |       |
+//! | `DELETE /v1/sessions`      | `deauthenticate` | yes   |
+//! | `POST /v1/users`           | `register`       |       |
+//! | `GET /v1/user`             | `get_user`       | yes   |
+//! | `PUT /v1/users/{id}/role`  | `set_user_role`  | admin |
+//!
+//! The OAuth 2.0 and OpenID Connect endpoints under `/oauth` and
+//! `/.well-known` are described in [`crate::oauth::endpoints`].
+//!
+//! Tokens are sent as `Authorization: Bearer <token>`. Errors are returned as
+//! `{"error": "invalid_token", "request_id": "..."}`, where the request id is
+//! the one in the logs, and is also sent back in the `X-Request-Id` header.
+//!
+//! The OpenAPI document is generated from [`ROUTES`] and served on
+//! `GET /openapi.json`.
+use failure::ResultExt;
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, Server, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::thread;
+
+use datatypes::auth::responses::{AuthError, Role};
+use datatypes::valid::ids::UserId;
+use datatypes::valid::token::Token;
+
+use auth_client::{ErrorDetail, ServiceError};
+
+use crate::http::json;
+use crate::logging;
+use crate::oauth::oidc::Oidc;
+use crate::oauth::{endpoints, OAuth, OAuthError};
+use crate::service::{AuthServer, FutureService, ADMIN_ROLE};
+use crate::{IntErrorKind, IntResult};
+
+/// Header with the id of a request
+const REQUEST_ID: &str = "x-request-id";
+
+/// Largest request body which is accepted
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// An endpoint of the gateway
+pub struct Route {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub rpc: &'static str,
+    pub summary: &'static str,
+    /// Schema of the request body, if any
+    pub request: Option<&'static str>,
+    /// Whether the body is a form instead of json
+    pub form: bool,
+    /// Parameters of the query string
+    pub query: &'static [&'static str],
+    /// Status of a successful response, and the schema of its body if any
+    pub response: (u16, Option<&'static str>),
+    /// Role the token must have, `Some("")` for any token
+    pub token: Option<&'static str>,
+    /// Statuses of the errors the endpoint may return
+    pub errors: &'static [u16],
+}
+
+/// Every endpoint of the gateway
+pub static ROUTES: &[Route] = &[
+    Route {
+        method: "POST",
+        path: "/v1/sessions",
+        rpc: "authenticate",
+        summary: "Logs in and returns a new token",
+        request: Some("AuthPayload"),
+        form: false,
+        query: &[],
+        response: (200, Some("Token")),
+        token: None,
+        errors: &[400, 401, 500],
+    },
+    Route {
+        method: "DELETE",
+        path: "/v1/sessions",
+        rpc: "deauthenticate",
+        summary: "Logs out, the token can not be used anymore",
+        request: None,
+        form: false,
+        query: &[],
+        response: (204, None),
+        token: Some(""),
+        errors: &[401, 500],
+    },
+    Route {
+        method: "POST",
+        path: "/v1/users",
+        rpc: "register",
+        summary: "Registers a new user",
+        request: Some("RegisterUserPayload"),
+        form: false,
+        query: &[],
+        response: (201, Some("AddUserPayload")),
+        token: None,
+        errors: &[400, 409, 500],
+    },
+    Route {
+        method: "GET",
+        path: "/v1/user",
+        rpc: "get_user",
+        summary: "Returns the id and role of the user of the token",
+        request: None,
+        form: false,
+        query: &[],
+        response: (200, Some("UserRole")),
+        token: Some(""),
+        errors: &[401, 500],
+    },
+    Route {
+        method: "PUT",
+        path: "/v1/users/{id}/role",
+        rpc: "set_user_role",
+        summary: "Changes the role of a user",
+        request: Some("RoleBody"),
+        form: false,
+        query: &[],
+        response: (204, None),
+        token: Some(ADMIN_ROLE),
+        errors: &[400, 401, 403, 500],
+    },
+    Route {
+        method: "POST",
+        path: "/oauth/clients",
+        rpc: "oauth_register_client",
+        summary: "Registers an OAuth client, the secret is only shown once",
+        request: Some("NewClient"),
+        form: false,
+        query: &[],
+        response: (201, Some("Registration")),
+        token: Some(ADMIN_ROLE),
+        errors: &[400, 401, 403, 500],
+    },
+    Route {
+        method: "GET",
+        path: "/oauth/authorize",
+        rpc: "oauth_authorize",
+        summary: "Redirects the user back to the client with a code",
+        request: None,
+        form: false,
+        query: &[
+            "response_type",
+            "client_id",
+            "redirect_uri",
+            "state",
+            "code_challenge",
+            "code_challenge_method",
+            "scope",
+            "nonce",
+        ],
+        response: (302, None),
+        token: Some(""),
+        errors: &[400, 401, 500],
+    },
+    Route {
+        method: "POST",
+        path: "/oauth/token",
+        rpc: "oauth_token",
+        summary: "Issues a token for a code or for the client itself",
+        request: Some("TokenRequest"),
+        form: true,
+        query: &[],
+        response: (200, Some("TokenResponse")),
+        token: None,
+        errors: &[400, 401, 500],
+    },
+    Route {
+        method: "POST",
+        path: "/oauth/revoke",
+        rpc: "oauth_revoke",
+        summary: "Revokes a token issued to the client",
+        request: Some("TokenParameter"),
+        form: true,
+        query: &[],
+        response: (200, None),
+        token: None,
+        errors: &[400, 401, 500],
+    },
+    Route {
+        method: "POST",
+        path: "/oauth/introspect",
+        rpc: "oauth_introspect",
+        summary: "Describes a token to a confidential client",
+        request: Some("TokenParameter"),
+        form: true,
+        query: &[],
+        response: (200, Some("Introspection")),
+        token: None,
+        errors: &[400, 401, 500],
+    },
+    Route {
+        method: "GET",
+        path: "/oauth/userinfo",
+        rpc: "oidc_userinfo",
+        summary: "Returns the claims about the user of the token",
+        request: None,
+        form: false,
+        query: &[],
+        response: (200, Some("UserClaims")),
+        token: Some(""),
+        errors: &[401, 500],
+    },
+    Route {
+        method: "GET",
+        path: "/.well-known/openid-configuration",
+        rpc: "oidc_discovery",
+        summary: "The OpenID Connect discovery document",
+        request: None,
+        form: false,
+        query: &[],
+        response: (200, Some("Discovery")),
+        token: None,
+        errors: &[],
+    },
+    Route {
+        method: "GET",
+        path: "/.well-known/jwks.json",
+        rpc: "oidc_jwks",
+        summary: "The public keys ID tokens are signed with",
+        request: None,
+        form: false,
+        query: &[],
+        response: (200, Some("Jwks")),
+        token: None,
+        errors: &[500],
+    },
+];
+
+/// The response of `GET /v1/user`
+#[derive(Serialize)]
+struct UserRole {
+    id: UserId,
+    role: Role,
+}
+
+/// The request body of `PUT /v1/users/{id}/role`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoleBody {
+    role: Role,
+}
+
+/// The body of every error response
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    /// Only set for OAuth errors, as required by RFC 6749
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_description: Option<&'static str>,
+    request_id: String,
+}
+
+/// Why a request failed
+#[derive(Debug)]
+pub(crate) enum RestError {
+    Auth(AuthError),
+    /// The email of a new or changed user belongs to another user
+    ExistingEmail,
+    /// An `AuthError` about the request, not the credentials
+    Invalid(AuthError),
+    OAuth(OAuthError),
+    BadRequest(String),
+    Forbidden,
+    NotFound,
+    TooLarge,
+}
+
+impl RestError {
+    fn status(&self) -> (StatusCode, &'static str) {
+        match self {
+            RestError::Auth(error) => auth_status(error),
+            RestError::ExistingEmail => (StatusCode::CONFLICT, "existing_email"),
+            RestError::Invalid(error) => (StatusCode::BAD_REQUEST, auth_status(error).1),
+            RestError::OAuth(error) => (oauth_status(error), error.code()),
+            RestError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request"),
+            RestError::Forbidden => (StatusCode::FORBIDDEN, "forbidden"),
+            RestError::NotFound => (StatusCode::NOT_FOUND, "not_found"),
+            RestError::TooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "too_large"),
+        }
+    }
+
+    fn into_response(self, request_id: String) -> Response<Body> {
+        let (status, error) = self.status();
+        let error_description = match self {
+            RestError::OAuth(ref error) => error.description(),
+            _ => None,
+        };
+        let message = match self {
+            RestError::BadRequest(message) => Some(message),
+            _ => None,
+        };
+        json(
+            status,
+            &ErrorBody {
+                error: error.to_owned(),
+                message,
+                error_description,
+                request_id,
+            },
+        )
+    }
+}
+
+fn auth_status(error: &AuthError) -> (StatusCode, &'static str) {
+    match error {
+        AuthError::InvalidUsername => (StatusCode::UNAUTHORIZED, "invalid_username"),
+        AuthError::InvalidPassword => (StatusCode::UNAUTHORIZED, "invalid_password"),
+        AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "invalid_token"),
+        AuthError::ExistingUser => (StatusCode::CONFLICT, "existing_user"),
+        AuthError::InternalServerError => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+        #[allow(unreachable_patterns)]
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "error"),
+    }
+}
+
+fn oauth_status(error: &OAuthError) -> StatusCode {
+    match error {
+        OAuthError::InvalidClient => StatusCode::UNAUTHORIZED,
+        OAuthError::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+impl From<OAuthError> for RestError {
+    fn from(error: OAuthError) -> RestError {
+        RestError::OAuth(error)
+    }
+}
+
+impl From<AuthError> for RestError {
+    fn from(error: AuthError) -> RestError {
+        RestError::Auth(error)
+    }
+}
+
+impl From<ServiceError> for RestError {
+    fn from(error: ServiceError) -> RestError {
+        // The request id is already in the response header
+        match error.detail {
+            Some(ErrorDetail::ExistingEmail) => RestError::ExistingEmail,
+            None => RestError::Auth(error.error),
+        }
+    }
+}
+
+pub(crate) type RestFuture = Box<Future<Item = Response<Body>, Error = RestError> + Send>;
+
+/// What the endpoints are served by
+#[derive(Clone)]
+pub struct Gateway {
+    server: AuthServer,
+    oauth: OAuth,
+}
+
+impl Gateway {
+    pub fn new(server: AuthServer, oidc: Oidc) -> Gateway {
+        let oauth = OAuth::new(server.clone(), oidc);
+        Gateway { server, oauth }
+    }
+}
+
+/// Starts the gateway on `address` in a background thread
+pub fn spawn(address: SocketAddr, server: AuthServer, oidc: Oidc) -> IntResult<()> {
+    let builder = Server::try_bind(&address).context(IntErrorKind::ServerError)?;
+
+    let gateway = Gateway::new(server, oidc);
+    let new_service = move || {
+        let gateway = gateway.clone();
+        service_fn(move |request: Request<Body>| handle(&gateway, request))
+    };
+    let listener = builder
+        .serve(new_service)
+        .map_err(|e| error!("REST gateway failed: {}", e));
+
+    thread::Builder::new()
+        .name("rest-http".to_owned())
+        .spawn(move || hyper::rt::run(listener))
+        .context(IntErrorKind::ServerError)?;
+
+    info!("Serving the REST gateway on http://{}", address);
+    Ok(())
+}
+
+/// Handles a request, turning errors into responses
+pub(crate) fn handle(
+    gateway: &Gateway,
+    request: Request<Body>,
+) -> Box<Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned());
+    let _span = logging::enter_request("rest", request_id);
+    let request_id = logging::request_id().unwrap_or_default();
+    debug!("Received {} {}", request.method(), request.uri().path());
+
+    let response = dispatch(gateway, request).then(move |result| {
+        let mut response = result.unwrap_or_else(|e| e.into_response(request_id.clone()));
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(REQUEST_ID, value);
+        }
+        Ok(response)
+    });
+    Box::new(response)
+}
+
+fn dispatch(gateway: &Gateway, request: Request<Body>) -> RestFuture {
+    let (parts, body) = request.into_parts();
+    let token = bearer(&parts.headers);
+    let path = parts.uri.path().trim_matches('/').to_owned();
+    let segments: Vec<&str> = path.split('/').collect();
+    let server = gateway.server.clone();
+    let oauth = &gateway.oauth;
+    // The body is read after `handle` returned, so the rpcs which are called
+    // with it enter the span of the request again
+    let span = logging::current();
+
+    match (parts.method.as_str(), &segments[..]) {
+        ("GET", ["openapi.json"]) => Box::new(future::ok(json(StatusCode::OK, &openapi()))),
+        ("POST", ["v1", "sessions"]) => Box::new(
+            read_json(body)
+                .and_then(move |payload| {
+                    let _span = logging::enter(span);
+                    server.authenticate(None, payload).from_err()
+                }).map(|token| json(StatusCode::OK, &token)),
+        ),
+        ("DELETE", ["v1", "sessions"]) => Box::new(
+            future::result(token.and_then(|token| Ok(server.deauthenticate(None, token)?)))
+                .map(|()| empty(StatusCode::NO_CONTENT)),
+        ),
+        ("POST", ["v1", "users"]) => Box::new(
+            read_json(body)
+                .and_then(move |payload| {
+                    let _span = logging::enter(span);
+                    server.register(None, payload).map_err(|e| match e.error {
+                        // The username of a new user is bad input, not a
+                        // failed login
+                        error @ AuthError::InvalidUsername => RestError::Invalid(error),
+                        error => RestError::Auth(error),
+                    })
+                }).map(|user| json(StatusCode::CREATED, &user)),
+        ),
+        ("GET", ["v1", "user"]) => Box::new(
+            future::result(token.and_then(|token| Ok(server.get_user(None, token)?)))
+                .map(|user| {
+                    let (id, role) = (user.id, user.role);
+                    json(StatusCode::OK, &UserRole { id, role })
+                }),
+        ),
+        ("PUT", ["v1", "users", id, "role"]) => {
+            let id = match id.parse::<u32>() {
+                Ok(id) => UserId::from(id),
+                Err(_) => return Box::new(future::err(RestError::NotFound)),
+            };
+            let admin = token.and_then(|token| require_admin(&server, token));
+            Box::new(
+                future::result(admin)
+                    .and_then(|()| read_json(body))
+                    .and_then(move |body: RoleBody| {
+                        let _span = logging::enter(span);
+                        let payload = datatypes::auth::requests::SetUserRolePayload {
+                            id,
+                            role: body.role,
+                        };
+                        server.set_user_role(None, payload).from_err()
+                    }).map(|()| empty(StatusCode::NO_CONTENT)),
+            )
+        }
+        ("POST", ["oauth", "clients"]) => endpoints::register_client(oauth, &server, token, body),
+        ("GET", ["oauth", "authorize"]) => {
+            endpoints::authorize(oauth, &server, token, parts.uri.query())
+        }
+        ("POST", ["oauth", "token"]) => endpoints::token(oauth, &parts.headers, body),
+        ("POST", ["oauth", "revoke"]) => endpoints::revoke(oauth, &parts.headers, body),
+        ("POST", ["oauth", "introspect"]) => endpoints::introspect(oauth, &parts.headers, body),
+        ("GET", ["oauth", "userinfo"]) => endpoints::userinfo(oauth, &server, token),
+        ("GET", [".well-known", "openid-configuration"]) => endpoints::discovery(oauth),
+        ("GET", [".well-known", "jwks.json"]) => endpoints::jwks(oauth),
+        _ => Box::new(future::err(RestError::NotFound)),
+    }
+}
+
+/// Fails with `Forbidden` unless the token may be used for admin requests,
+/// see [`AuthServer::lookup_admin`]
+pub(crate) fn require_admin(server: &AuthServer, token: Token) -> Result<(), RestError> {
+    match server.lookup_admin(&token)? {
+        (_, true) => Ok(()),
+        (_, false) => Err(RestError::Forbidden),
+    }
+}
+
+/// The token of the `Authorization: Bearer <token>` header
+fn bearer(headers: &HeaderMap) -> Result<Token, RestError> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            if v.starts_with("Bearer ") {
+                Some(v["Bearer ".len()..].trim())
+            } else {
+                None
+            }
+        }).filter(|token| !token.is_empty())
+        .map(|token| Token::new(token.to_owned()))
+        .ok_or(RestError::Auth(AuthError::InvalidToken))
+}
+
+/// Reads a body of at most [`MAX_BODY_SIZE`] bytes
+pub(crate) fn read_body(body: Body) -> impl Future<Item = Vec<u8>, Error = RestError> + Send {
+    body.map_err(|e| RestError::BadRequest(e.to_string()))
+        .fold(Vec::new(), |mut bytes, chunk| {
+            if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+                return Either::A(future::err(RestError::TooLarge));
+            }
+            bytes.extend_from_slice(&chunk);
+            Either::B(future::ok(bytes))
+        })
+}
+
+/// Reads and parses a json body
+pub(crate) fn read_json<T>(body: Body) -> impl Future<Item = T, Error = RestError> + Send
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    read_body(body).and_then(|bytes| {
+        serde_json::from_slice(&bytes).map_err(|e| RestError::BadRequest(e.to_string()))
+    })
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// The OpenAPI 3 document of the gateway
+pub fn openapi() -> Value {
+    let mut paths = serde_json::Map::new();
+    for route in ROUTES {
+        let mut responses = serde_json::Map::new();
+        let (status, schema) = route.response;
+        responses.insert(
+            status.to_string(),
+            match schema {
+                Some(schema) => json!({
+                    "description": "Success",
+                    "content": { "application/json": { "schema": reference(schema) } }
+                }),
+                None => json!({ "description": "Success" }),
+            },
+        );
+        for status in route.errors {
+            let description = StatusCode::from_u16(*status)
+                .ok()
+                .and_then(|s| s.canonical_reason())
+                .unwrap_or("Error");
+            responses.insert(
+                status.to_string(),
+                json!({
+                    "description": description,
+                    "content": { "application/json": { "schema": reference("Error") } }
+                }),
+            );
+        }
+
+        let mut operation = json!({
+            "operationId": route.rpc,
+            "summary": route.summary,
+            "responses": responses,
+        });
+        if let Some(schema) = route.request {
+            let content_type = if route.form {
+                "application/x-www-form-urlencoded"
+            } else {
+                "application/json"
+            };
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { content_type: { "schema": reference(schema) } }
+            });
+        }
+        let query: Vec<Value> = route
+            .query
+            .iter()
+            .map(|name| json!({ "name": name, "in": "query", "schema": { "type": "string" } }))
+            .collect();
+        if !query.is_empty() {
+            operation["parameters"] = json!(query);
+        }
+        if route.path.contains("{id}") {
+            operation["parameters"] = json!([{
+                "name": "id",
+                "in": "path",
+                "required": true,
+                "schema": { "type": "integer", "format": "uint32" }
+            }]);
+        }
+        if route.form {
+            operation["security"] = json!([{ "client": [] }]);
+        }
+        if let Some(role) = route.token {
+            operation["security"] = json!([{ "bearer": [] }]);
+            if !role.is_empty() {
+                let description = format!("The token must have the role '{}'", role);
+                operation["description"] = json!(description);
+            }
+        }
+
+        let methods = paths.entry(route.path).or_insert_with(|| json!({}));
+        methods[route.method.to_lowercase()] = operation;
+    }
+
+    json!({
+        "openapi": "3.0.0",
+        "info": {
+            "title": "auth-service",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+                "client": { "type": "http", "scheme": "basic" }
+            },
+            "schemas": {
+                "AuthPayload": {
+                    "type": "object",
+                    "required": ["username", "password"],
+                    "properties": {
+                        "username": { "type": "string", "maxLength": 20 },
+                        "password": { "type": "string", "format": "password" }
+                    }
+                },
+                "RegisterUserPayload": {
+                    "type": "object",
+                    "required": ["username", "password", "email"],
+                    "properties": {
+                        "username": { "type": "string", "maxLength": 20 },
+                        "password": { "type": "string", "format": "password" },
+                        "email": { "type": "string", "format": "email", "maxLength": 255 }
+                    }
+                },
+                "AddUserPayload": {
+                    "type": "object",
+                    "required": ["id", "username"],
+                    "properties": {
+                        "id": { "type": "integer", "format": "uint32" },
+                        "username": { "type": "string" }
+                    }
+                },
+                "Token": { "type": "string" },
+                "Role": { "type": "string", "example": "user" },
+                "UserRole": {
+                    "type": "object",
+                    "required": ["id", "role"],
+                    "properties": {
+                        "id": { "type": "integer", "format": "uint32" },
+                        "role": reference("Role")
+                    }
+                },
+                "RoleBody": {
+                    "type": "object",
+                    "required": ["role"],
+                    "properties": { "role": reference("Role") }
+                },
+                "NewClient": {
+                    "type": "object",
+                    "required": ["name"],
+                    "properties": {
+                        "name": { "type": "string" },
+                        "redirect_uris": { "type": "array", "items": { "type": "string" } },
+                        "confidential": { "type": "boolean", "default": false }
+                    }
+                },
+                "Registration": {
+                    "type": "object",
+                    "required": ["client_id"],
+                    "properties": {
+                        "client_id": { "type": "string" },
+                        "client_secret": { "type": "string" }
+                    }
+                },
+                "TokenRequest": {
+                    "type": "object",
+                    "required": ["grant_type"],
+                    "properties": {
+                        "grant_type": {
+                            "type": "string",
+                            "enum": ["authorization_code", "client_credentials"]
+                        },
+                        "code": { "type": "string" },
+                        "redirect_uri": { "type": "string" },
+                        "code_verifier": { "type": "string" },
+                        "client_id": { "type": "string" },
+                        "client_secret": { "type": "string" }
+                    }
+                },
+                "TokenResponse": {
+                    "type": "object",
+                    "required": ["access_token", "token_type", "expires_in"],
+                    "properties": {
+                        "access_token": { "type": "string" },
+                        "token_type": { "type": "string", "example": "Bearer" },
+                        "expires_in": { "type": "integer" },
+                        "scope": { "type": "string" },
+                        "id_token": { "type": "string", "format": "jwt" }
+                    }
+                },
+                "TokenParameter": {
+                    "type": "object",
+                    "required": ["token"],
+                    "properties": {
+                        "token": { "type": "string" },
+                        "client_id": { "type": "string" },
+                        "client_secret": { "type": "string" }
+                    }
+                },
+                "Introspection": {
+                    "type": "object",
+                    "required": ["active"],
+                    "properties": {
+                        "active": { "type": "boolean" },
+                        "client_id": { "type": "string" },
+                        "sub": { "type": "string" },
+                        "role": { "type": "string" },
+                        "token_type": { "type": "string" },
+                        "iat": { "type": "integer" },
+                        "exp": { "type": "integer" }
+                    }
+                },
+                "UserClaims": {
+                    "type": "object",
+                    "required": ["sub"],
+                    "description": "The claims of the granted scopes",
+                    "properties": {
+                        "sub": { "type": "string" },
+                        "preferred_username": { "type": "string" },
+                        "email": { "type": "string", "format": "email" },
+                        "email_verified": { "type": "boolean" }
+                    }
+                },
+                "Discovery": {
+                    "type": "object",
+                    "required": ["issuer", "jwks_uri"],
+                    "properties": {
+                        "issuer": { "type": "string" },
+                        "jwks_uri": { "type": "string" }
+                    },
+                    "additionalProperties": true
+                },
+                "Jwks": {
+                    "type": "object",
+                    "required": ["keys"],
+                    "properties": {
+                        "keys": { "type": "array", "items": { "type": "object" } }
+                    }
+                },
+                "Error": {
+                    "type": "object",
+                    "required": ["error", "request_id"],
+                    "properties": {
+                        "error": { "type": "string", "example": "invalid_token" },
+                        "message": { "type": "string" },
+                        "error_description": { "type": "string" },
+                        "request_id": { "type": "string" }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn reference(schema: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", schema) })
+}
+
+#[cfg(test)]
+fn call(
+    gateway: &Gateway,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    body: Value,
+) -> (StatusCode, Value) {
+    let mut request = Request::builder();
+    request.method(method).uri(path);
+    if let Some(token) = token {
+        request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let request = request.body(Body::from(body.to_string())).unwrap();
+
+    let response = handle(gateway, request).wait().unwrap();
+    let status = response.status();
+    let bytes = response.into_body().concat2().wait().unwrap();
+    let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+    (status, body)
+}
+
+#[test]
+fn test_gateway() {
+    use crate::config::Config;
+    use crate::store::memory::MemoryStore;
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    config.security.hash_cycles = 1000;
+    let server = AuthServer::with_store(&config, Arc::new(MemoryStore::default()));
+    let server = Gateway::new(server, Oidc::new(&config.oidc, &config.rest));
+
+    let user = json!({
+        "username": "gateway",
+        "password": "G4teway-Passw0rd",
+        "email": "gateway@example.com"
+    });
+    let login = json!({ "username": "gateway", "password": "G4teway-Passw0rd" });
+
+    let (status, _) = call(&server, "POST", "/v1/users", None, user.clone());
+    assert_eq!(StatusCode::CREATED, status);
+    let (status, body) = call(&server, "POST", "/v1/users", None, user);
+    assert_eq!(StatusCode::CONFLICT, status);
+    assert_eq!("existing_user", body["error"]);
+    assert!(body["request_id"].as_str().map_or(false, |id| !id.is_empty()));
+    let other = json!({
+        "username": "other",
+        "password": "G4teway-Passw0rd",
+        "email": "gateway@example.com"
+    });
+    let (status, body) = call(&server, "POST", "/v1/users", None, other);
+    assert_eq!(StatusCode::CONFLICT, status);
+    assert_eq!("existing_email", body["error"]);
+
+    let (status, token) = call(&server, "POST", "/v1/sessions", None, login);
+    assert_eq!(StatusCode::OK, status);
+    let token = token.as_str().unwrap().to_owned();
+
+    let (status, body) = call(&server, "GET", "/v1/user", Some(&token), Value::Null);
+    assert_eq!(StatusCode::OK, status);
+    assert!(body["id"].is_number());
+
+    let role = json!({ "role": "admin" });
+    let (status, _) = call(&server, "PUT", "/v1/users/1/role", Some(&token), role);
+    assert_eq!(StatusCode::FORBIDDEN, status);
+
+    let (status, _) = call(&server, "DELETE", "/v1/sessions", Some(&token), Value::Null);
+    assert_eq!(StatusCode::NO_CONTENT, status);
+    let (status, body) = call(&server, "GET", "/v1/user", Some(&token), Value::Null);
+    assert_eq!(StatusCode::UNAUTHORIZED, status);
+    assert_eq!("invalid_token", body["error"]);
+
+    let invalid = json!({
+        "username": "not valid!",
+        "password": "G4teway-Passw0rd",
+        "email": "invalid@example.com"
+    });
+    let (status, _) = call(&server, "POST", "/v1/users", None, invalid);
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+
+    let (status, _) = call(&server, "POST", "/v1/sessions", None, json!("not an object"));
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+    let (status, _) = call(&server, "GET", "/v1/nothing", None, Value::Null);
+    assert_eq!(StatusCode::NOT_FOUND, status);
+}
+
+#[test]
+fn test_openapi_covers_routes() {
+    let document = openapi();
+    for route in ROUTES {
+        let operation = &document["paths"][route.path][route.method.to_lowercase()];
+        assert_eq!(route.rpc, operation["operationId"], "{} {}", route.method, route.path);
+    }
+}
+
+#[test]
+fn test_rpcs_log_under_request_id() {
+    use crate::config::Config;
+    use crate::store::memory::MemoryStore;
+    use std::sync::Arc;
+
+    let capture = logging::capture();
+    let mut config = Config::default();
+    config.security.hash_cycles = 1000;
+    let server = AuthServer::with_store(&config, Arc::new(MemoryStore::default()));
+    let server = Gateway::new(server, Oidc::new(&config.oidc, &config.rest));
+
+    let user = json!({
+        "username": "spans",
+        "password": "Sp4ns-Passw0rd",
+        "email": "spans@example.com"
+    });
+    let login = json!({ "username": "spans", "password": "Wr0ng-Passw0rd" });
+    call(&server, "POST", "/v1/users", None, user.clone());
+
+    // The error bodies have the id of the response header
+    let (_, register) = call(&server, "POST", "/v1/users", None, user);
+    let (_, authenticate) = call(&server, "POST", "/v1/sessions", None, login);
+
+    let output = capture.output();
+    for (rpc, body) in &[("register", register), ("authenticate", authenticate)] {
+        let id = body["request_id"].as_str().unwrap();
+        let span = format!("[{} {}", rpc, id);
+        assert!(output.contains(&span), "no log line in {}", span);
+    }
+}
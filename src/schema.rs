@@ -1,3 +1,38 @@
+table! {
+    dead_letters (id) {
+        id -> Unsigned<Bigint>,
+        webhook_id -> Unsigned<Integer>,
+        event -> Text,
+        attempts -> Unsigned<Integer>,
+        error -> Text,
+        failed_at -> Timestamp,
+    }
+}
+
+table! {
+    event_sinks (name) {
+        name -> Varchar,
+        position -> Unsigned<Bigint>,
+    }
+}
+
+table! {
+    oauth_clients (id) {
+        id -> Varchar,
+        name -> Varchar,
+        secret_hash -> Nullable<Varchar>,
+        redirect_uris -> Text,
+    }
+}
+
+table! {
+    outbox (id) {
+        id -> Unsigned<Bigint>,
+        event -> Text,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     roles (id) {
         id -> Unsigned<Integer>,
@@ -14,9 +49,29 @@ table! {
         banned -> Bool,
         verified -> Bool,
         email_token -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    webhooks (id) {
+        id -> Unsigned<Integer>,
+        url -> Varchar,
+        secret -> Varchar,
+        events -> Text,
+        created_at -> Timestamp,
     }
 }
 
+joinable!(dead_letters -> webhooks (webhook_id));
 joinable!(roles -> users (id));
 
-allow_tables_to_appear_in_same_query!(roles, users,);
+allow_tables_to_appear_in_same_query!(
+    dead_letters,
+    event_sinks,
+    oauth_clients,
+    outbox,
+    roles,
+    users,
+    webhooks,
+);
@@ -1,12 +1,16 @@
 use chrono::offset::Utc;
-use chrono::DateTime;
+use chrono::{Duration, NaiveDateTime};
+use futures::future::{self, Either};
+use futures::{Future, Stream};
 use futures_cpupool::CpuFuture;
 use futures_cpupool::CpuPool;
-use pbkdf2::{pbkdf2_check, CheckError};
 use rand::{thread_rng, Rng};
 use std::collections::HashMap;
 use std::convert::TryInto;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{self, Instant};
+use tokio_core::reactor::{Handle, Interval};
 
 use datatypes::auth::requests::*;
 use datatypes::auth::responses::*;
@@ -14,16 +18,39 @@ use datatypes::content::requests::AddUserPayload;
 use datatypes::valid::ids::UserId;
 use datatypes::valid::token::Token;
 
-use crate::db;
-use crate::IntResult;
+use auth_client::{
+    AddWebhookPayload, Cursor, DeadLetter, ErrorDetail, Event, Invalidations, ListUsersPayload,
+    NewWebhook, ReplayReport, ServiceError, UpdateUserPayload, UserInfo, UserPage, UserProfile,
+    Webhook, MAX_PAGE_SIZE, USER_INFO_VERSION,
+};
 
-const PASS_PEPPER: &str = "4NqD&8Bh%d";
-const HASH_PASS_CYCLES: u32 = 10000;
+use crate::authenticator::Authenticators;
+use crate::config::{Config, SecurityConfig};
+use crate::events::{self, EventFeed};
+use crate::health::{self, Heartbeat, HealthReport};
+use crate::invalidation::InvalidationLog;
+use crate::logging;
+use crate::metrics::{self, RequestTimer};
+use crate::redact::{Fingerprint, Masked, Secret};
+use crate::sessions::{self, Session, Sessions};
+use crate::store::{self, Store, UserChanges};
+use crate::webhooks;
+use crate::{IntError, IntErrorKind, IntResult};
+
+/// Role of the users who may change the roles of others, list and update
+/// users
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Longest email address, the size of its column
+const MAX_EMAIL_LENGTH: usize = 255;
+
+/// Seconds between the removals of expired sessions
+const PRUNE_INTERVAL_SECS: u64 = 60;
 
 /// The auth server which will have the rpc services
 #[derive(Clone)]
 pub struct AuthServer {
-    /// An in-memory mapping between a Token and a UserId
+    /// An in-memory mapping between a Token and a Session
     ///
     /// The hashmap also stores the time that the token was created. This can
     /// be used to remove stale tokens and force the creation of a new token on
@@ -35,270 +62,1623 @@ pub struct AuthServer {
     /// object (threadsafely because its atomic).
     /// - [`std::sync::RwLock`] makes sure that either multiple references can
     /// read immutably OR one reference can mutate the 'HashMap'.
-    tokens: Arc<RwLock<HashMap<Token, (UserId, Role, DateTime<Utc>)>>>,
+    tokens: Sessions,
+    /// File the sessions are flushed to on shutdown, if any
+    tokens_store: Option<String>,
+    /// Revoked sessions and changed roles, for clients which cache
+    /// `get_user`
+    invalidations: InvalidationLog,
+    /// Clients waiting for events
+    events: EventFeed,
+
+    /// How long a token is valid after it was created
+    token_lifetime: Duration,
+    security: Arc<SecurityConfig>,
+    /// Backends which check passwords, see [`crate::authenticator`]
+    authenticators: Arc<Authenticators>,
+    /// Whether the clients of this server may call privileged rpcs, see
+    /// [`AuthServer::unprivileged`]
+    privileged: bool,
+
+    /// Number of requests currently running on the `CpuPool`
+    in_flight: Arc<AtomicUsize>,
+    /// Shows that the event loop is still running
+    heartbeat: Heartbeat,
 
     // Pools
     pool: CpuPool,
-    db_pool: db::DbPool,
+    store: Store,
 }
 
-impl AuthServer {
-    /// Try to make a new server by creating a connection pool to the database
-    pub fn try_new(database_url: &str) -> IntResult<Self> {
-        let tokens = Arc::<RwLock<HashMap<Token, (UserId, Role, DateTime<Utc>)>>>::default();
-        let db_pool = db::setup_connection_pool(database_url)?;
+/// Counts a request as in flight for as long as it is alive
+struct InFlight(Arc<AtomicUsize>);
 
-        Ok(AuthServer {
-            tokens,
-            pool: CpuPool::new_num_cpus(),
-            db_pool,
-        })
+impl InFlight {
+    fn new(counter: Arc<AtomicUsize>) -> InFlight {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(counter)
     }
 }
 
-service! {
-    rpc authenticate(payload: AuthPayload) -> Token | AuthError;
-    rpc deauthenticate(payload: Token) -> () | AuthError;
-    rpc register(payload: RegisterUserPayload) -> AddUserPayload | AuthError;
-    rpc get_user(payload: Token) -> (UserId, Role) | AuthError;
-    rpc set_user_role(payload: SetUserRolePayload) -> () | AuthError;
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
-impl FutureService for AuthServer {
-    type AuthenticateFut = CpuFuture<Token, AuthError>;
-    type DeauthenticateFut = Result<(), AuthError>;
-    type RegisterFut = CpuFuture<AddUserPayload, AuthError>;
-    type GetUserFut = Result<(UserId, Role), AuthError>;
-    type SetUserRoleFut = CpuFuture<(), AuthError>;
+impl AuthServer {
+    /// Try to make a new server by connecting to the configured database
+    ///
+    /// Stored sessions are loaded if `tokens.store` is configured.
+    pub fn try_new(config: &Config) -> IntResult<Self> {
+        let mut server = Self::with_store(config, store::connect(&config.database)?);
+        if !config.tokens.store.is_empty() {
+            server.tokens = sessions::load(&config.tokens.store, &*server.store)?;
+            server.tokens_store = Some(config.tokens.store.clone());
+        }
+        Ok(server)
+    }
+
+    /// Make a new server which persists users in the given store
+    pub fn with_store(config: &Config, store: Store) -> Self {
+        AuthServer {
+            tokens: Sessions::default(),
+            tokens_store: None,
+            invalidations: InvalidationLog::default(),
+            events: EventFeed::default(),
+            token_lifetime: Duration::seconds(config.tokens.lifetime),
+            security: Arc::new(config.security.clone()),
+            authenticators: Arc::new(Authenticators::from_config(config, &store)),
+            privileged: true,
+            in_flight: Arc::default(),
+            heartbeat: Heartbeat::default(),
+            pool: CpuPool::new_num_cpus(),
+            store,
+        }
+    }
+
+    /// The same server, but privileged rpcs like `set_user_role` fail
+    ///
+    /// Served to the clients which are not trusted with them, see
+    /// [`crate::tls`].
+    pub fn unprivileged(mut self) -> Self {
+        self.privileged = false;
+        self
+    }
 
-    fn get_user(&self, token: Token) -> Self::GetUserFut {
-        debug!("Received get role request for token: {:?}", &token);
+    /// Number of requests which are still being processed
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// The heartbeat of the event loop, which must be spawned on it
+    pub fn heartbeat(&self) -> &Heartbeat {
+        &self.heartbeat
+    }
+
+    /// Only checks whether the event loop is running
+    pub fn liveness(&self) -> health::Check {
+        health::liveness(&self.heartbeat)
+    }
+
+    /// Checks the event loop, the database and the migrations
+    ///
+    /// This blocks on a database connection, so it should not be called on
+    /// the event loop.
+    pub fn health(&self) -> HealthReport {
+        health::report(&self.heartbeat, &*self.store)
+    }
+
+    /// Runs [`AuthServer::health`] on the `CpuPool`, for callers on an event
+    /// loop
+    pub fn spawn_health(&self) -> CpuFuture<HealthReport, ()> {
+        let server = self.clone();
+        self.pool.spawn_fn(move || Ok(server.health()))
+    }
+
+    /// The sessions of the logged in users
+    pub fn sessions(&self) -> &Sessions {
+        &self.tokens
+    }
+
+    /// The invalidations of cached sessions, which must be spawned on the
+    /// event loop
+    pub fn invalidations(&self) -> &InvalidationLog {
+        &self.invalidations
+    }
+
+    /// The clients waiting for events, which must be spawned on the event
+    /// loop
+    pub fn events(&self) -> &EventFeed {
+        &self.events
+    }
+
+    /// How long a token is valid after it was created
+    pub fn token_lifetime(&self) -> Duration {
+        self.token_lifetime
+    }
 
+    /// The store of the users
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// Logs a user in without checking a password
+    pub fn start_session(&self, user: &store::User, role: Role) -> Result<Token, AuthError> {
+        self.insert_session(user.id.into(), user.username.clone(), user.verified, role, None)
+    }
+
+    /// Starts a session for an OAuth client which acts for a user
+    ///
+    /// The client gets the role of the user, but the session is refused by
+    /// the rpcs which require an admin.
+    pub fn start_client_session(
+        &self,
+        user: &store::User,
+        role: Role,
+        client_id: &str,
+    ) -> Result<Token, AuthError> {
+        let client_id = Some(client_id.to_owned());
+        self.insert_session(user.id.into(), user.username.clone(), user.verified, role, client_id)
+    }
+
+    /// Starts a session with a new token
+    fn insert_session(
+        &self,
+        user_id: UserId,
+        username: String,
+        verified: bool,
+        role: Role,
+        client_id: Option<String>,
+    ) -> Result<Token, AuthError> {
+        let token = new_token();
+        let session = Session {
+            user_id,
+            role,
+            username,
+            verified,
+            created: Utc::now(),
+            client_id,
+        };
         self.tokens
-            .read()
+            .write()
             .map_err(|e| {
-                error!("Unable to read 'tokens': {}", e);
+                error!("Unable to write to 'tokens': {}", e);
                 AuthError::InternalServerError
-            })?.get(&token)
-            .map(|(user_id, role, _)| {
-                trace!("Found token; role: {:?}", *role);
-                (*user_id, *role)
-            }).ok_or_else(|| {
-                trace!("No token found");
-                AuthError::InvalidToken
+            })?.insert(token.clone(), session);
+        Ok(token)
+    }
+
+    /// Writes the sessions which have not expired to the configured token
+    /// store, if any
+    pub fn flush_sessions(&self) -> IntResult<()> {
+        match self.tokens_store {
+            Some(ref path) => {
+                self.prune_sessions()?;
+                sessions::flush(&self.tokens, path)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the expired sessions
+    pub fn prune_sessions(&self) -> IntResult<()> {
+        let pruned = sessions::prune(&self.tokens, self.token_lifetime)?;
+        if pruned > 0 {
+            debug!("Removed {} expired sessions", pruned);
+        }
+        Ok(())
+    }
+
+    /// Removes the expired sessions every `PRUNE_INTERVAL_SECS` on the event
+    /// loop of `handle`
+    pub fn spawn_pruning(&self, handle: &Handle) -> IntResult<()> {
+        let server = self.clone();
+        let prune = Interval::new(time::Duration::from_secs(PRUNE_INTERVAL_SECS), handle)
+            .map_err(|e| {
+                error!("Unable to create interval: {}", e);
+                IntErrorKind::ServerError
+            })?.for_each(move |()| {
+                // A failure is logged, and tried again on the next tick
+                let _ = server.prune_sessions();
+                Ok(())
+            }).map_err(|e| error!("Session pruning stopped: {}", e));
+        handle.spawn(prune);
+        Ok(())
+    }
+
+    /// Updates the gauges which are only sampled when metrics are scraped
+    pub fn update_gauges(&self) {
+        if let Ok(tokens) = self.tokens.read() {
+            metrics::ACTIVE_SESSIONS.set(tokens.len() as f64);
+        }
+        metrics::IN_FLIGHT.set(self.in_flight() as f64);
+        if let Some(state) = self.store.pool_state() {
+            let connections = &metrics::DB_CONNECTIONS;
+            connections.with_label_values(&["idle"]).set(f64::from(state.idle));
+            connections.with_label_values(&["in_use"]).set(f64::from(state.in_use));
+            connections.with_label_values(&["max"]).set(f64::from(state.max));
+        }
+    }
+
+    /// Runs a request on the `CpuPool`, counting it as in flight until done
+    ///
+    /// Must be called in the span of the request, whose id is returned with
+    /// an error.
+    fn spawn<F>(&self, rpc: &'static str, f: F) -> CpuFuture<F::Item, ServiceError>
+    where
+        F: Future<Error = AuthError> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        self.spawn_refusal(rpc, f.map_err(Refusal::from))
+    }
+
+    /// Like [`AuthServer::spawn`], for an rpc whose errors may have a detail
+    fn spawn_refusal<F>(&self, rpc: &'static str, f: F) -> CpuFuture<F::Item, ServiceError>
+    where
+        F: Future<Error = Refusal> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        let guard = InFlight::new(self.in_flight.clone());
+        let timer = RequestTimer::start(rpc);
+        let request_id = logging::request_id().unwrap_or_default();
+        self.pool.spawn(f.then(move |result| {
+            let (result, detail) = match result {
+                Ok(item) => (Ok(item), None),
+                Err(Refusal { error, detail }) => (Err(error), detail),
+            };
+            timer.observe(&result);
+            drop(guard);
+            result.map_err(|error| ServiceError {
+                error,
+                detail,
+                request_id,
             })
+        }))
     }
 
-    fn deauthenticate(&self, token: Token) -> Self::DeauthenticateFut {
-        debug!("Received deauthenticate request for token: {:?}", &token);
+    /// Returns the user of a token which has not expired
+    fn lookup_token(&self, token: &Token) -> Result<UserInfo, AuthError> {
+        let tokens = self.tokens.read().map_err(|e| {
+            error!("Unable to read 'tokens': {}", e);
+            AuthError::InternalServerError
+        })?;
+        let user = lookup(&tokens, token, self.token_lifetime)?;
+        logging::set_user_id(*user.id);
+        Ok(user)
+    }
 
-        self.tokens
+    /// Returns the user of every token, reading the sessions only once
+    fn lookup_tokens(
+        &self,
+        tokens: &[Token],
+    ) -> Result<Vec<Result<UserInfo, AuthError>>, AuthError> {
+        let sessions = self.tokens.read().map_err(|e| {
+            error!("Unable to read 'tokens': {}", e);
+            AuthError::InternalServerError
+        })?;
+        Ok(tokens
+            .iter()
+            .map(|token| lookup(&sessions, token, self.token_lifetime))
+            .collect())
+    }
+
+    /// Removes a token, which logs the user out
+    pub fn remove_token(&self, token: &Token) -> Result<(), AuthError> {
+        let session = self
+            .tokens
             .write()
             .map_err(|e| {
                 error!("Unable to write to 'tokens': {}", e);
                 AuthError::InternalServerError
-            })?.remove(&token)
+            })?.remove(token)
             .ok_or({ AuthError::InvalidToken })?;
 
+        logging::set_user_id(*session.user_id);
         trace!("Found and removed token");
+        self.invalidations.publish(session.user_id);
+        Ok(())
+    }
+
+    /// Gives a user a new role, in the store and in their sessions, and
+    /// tells the clients which cache `get_user`
+    fn change_role(&self, user_id: UserId, role: Role) -> Result<(), AuthError> {
+        if let Err(e) = self.store.update_role(*user_id, role.into()) {
+            error!("Error updating role: {}", e);
+            return Err(AuthError::InternalServerError);
+        }
+        trace!("Successfully update role");
+        // The sessions of the user get the new role right away
+        self.tokens
+            .write()
+            .map_err(|e| {
+                error!("Unable to write to 'tokens': {}", e);
+                AuthError::InternalServerError
+            })?.values_mut()
+            .filter(|session| session.user_id == user_id)
+            .for_each(|session| session.role = role);
+        self.invalidations.publish(user_id);
+        self.events.notify();
+        Ok(())
+    }
+
+    /// Gives the sessions of a changed user their new username and
+    /// verification, or ends them if the user is banned, and tells the
+    /// clients which cache `get_user`
+    fn change_sessions(&self, user: &store::User) -> Result<(), AuthError> {
+        let user_id = UserId::from(user.id);
+        {
+            let mut tokens = self.tokens.write().map_err(|e| {
+                error!("Unable to write to 'tokens': {}", e);
+                AuthError::InternalServerError
+            })?;
+            if user.banned {
+                tokens.retain(|_, session| session.user_id != user_id);
+                trace!("Removed the sessions of the banned user");
+            } else {
+                tokens
+                    .values_mut()
+                    .filter(|session| session.user_id == user_id)
+                    .for_each(|session| {
+                        session.username = user.username.clone();
+                        session.verified = user.verified;
+                    });
+            }
+        }
+        self.invalidations.publish(user_id);
+        self.events.notify();
         Ok(())
     }
 
-    fn authenticate(&self, payload: AuthPayload) -> Self::AuthenticateFut {
+    /// Fails a privileged rpc if the clients of this server may not call it
+    fn check_privileged(&self, rpc: &str) -> Result<(), AuthError> {
+        if self.privileged {
+            Ok(())
+        } else {
+            warn!("Refused {} from an unprivileged client", rpc);
+            Err(AuthError::InvalidToken)
+        }
+    }
+
+    /// The user of the token, and whether the token may be used for the
+    /// admin rpcs
+    ///
+    /// Only sessions with the admin role may, except for the sessions of
+    /// OAuth clients.
+    pub(crate) fn lookup_admin(&self, token: &Token) -> Result<(UserInfo, bool), AuthError> {
+        let tokens = self.tokens.read().map_err(|e| {
+            error!("Unable to read 'tokens': {}", e);
+            AuthError::InternalServerError
+        })?;
+        let user = lookup(&tokens, token, self.token_lifetime)?;
+        logging::set_user_id(*user.id);
+
+        let role: String = user.role.clone().into();
+        let delegated = tokens.get(token).map_or(false, |s| s.client_id.is_some());
+        Ok((user, role == ADMIN_ROLE && !delegated))
+    }
+
+    /// Fails unless the token may be used for the admin rpcs, see
+    /// [`AuthServer::lookup_admin`]
+    fn require_admin(&self, token: &Token, rpc: &str) -> Result<UserInfo, AuthError> {
+        let (user, admin) = self.lookup_admin(token)?;
+        if admin {
+            Ok(user)
+        } else {
+            warn!(
+                "Refused {} from user {}, who is not an admin or is an OAuth client",
+                rpc, *user.id
+            );
+            Err(AuthError::InvalidToken)
+        }
+    }
+
+    /// Reads the events after `since` on the `CpuPool`
+    fn read_events(&self, since: u64) -> CpuFuture<Vec<Event>, AuthError> {
+        let store = self.store.clone();
+        self.pool.spawn_fn(move || {
+            let events = store.fetch_events(since, events::MAX_EVENTS).map_err(|e| {
+                error!("Unable to read events: {}", e);
+                AuthError::InternalServerError
+            })?;
+            Ok(events::contiguous(since, events, Utc::now().timestamp()))
+        })
+    }
+}
+
+/// Returns the user of a token in `sessions` which is at most `lifetime` old
+fn lookup(
+    sessions: &HashMap<Token, Session>,
+    token: &Token,
+    lifetime: Duration,
+) -> Result<UserInfo, AuthError> {
+    let oldest_valid = Utc::now() - lifetime;
+    sessions
+        .get(token)
+        .filter(|session| {
+            let valid = session.created > oldest_valid;
+            if !valid {
+                trace!("Token has expired");
+            }
+            valid
+        }).map(|session| {
+            trace!("Found token; role: {:?}", session.role);
+            UserInfo {
+                version: USER_INFO_VERSION,
+                id: session.user_id,
+                username: session.username.clone(),
+                role: session.role,
+                verified: session.verified,
+                issued_at: session.created.timestamp(),
+                expires_at: (session.created + lifetime).timestamp(),
+            }
+        }).ok_or_else(|| {
+            trace!("No token found");
+            AuthError::InvalidToken
+        })
+}
+
+/// A user and their role, as admins see them
+fn profile(user: store::User, role: &store::Role) -> UserProfile {
+    UserProfile {
+        id: user.id.into(),
+        username: user.username,
+        email: user.email,
+        role: (&*role.name).into(),
+        banned: user.banned,
+        verified: user.verified,
+        created_at: user.created_at.timestamp(),
+    }
+}
+
+/// The profile of a user, `InvalidRequest` if there is no such user
+fn fetch_profile(store: &store::UserStore, user_id: u32) -> Result<UserProfile, AuthError> {
+    let user = store.fetch_user_by_id(user_id).map_err(|e| match e.kind() {
+        IntErrorKind::InvalidUsername => {
+            debug!("No user {}", user_id);
+            AuthError::InvalidRequest
+        }
+        _ => {
+            error!("Unable to fetch user: {}", e);
+            AuthError::InternalServerError
+        }
+    })?;
+    let role = store.fetch_user_role(user_id).map_err(|e| {
+        error!("Unable to fetch role: {}", e);
+        AuthError::InternalServerError
+    })?;
+    Ok(profile(user, &role))
+}
+
+/// Whether a username follows the same rules as in a registration
+fn valid_username(username: &str) -> bool {
+    // The rules are those of the validated username `register` returns
+    username
+        .to_owned()
+        .try_into()
+        .map(|username| AddUserPayload {
+            id: 0u32.into(),
+            username,
+        }).is_ok()
+}
+
+/// Whether an email address has the form `<local>@<domain>.<tld>`, without
+/// any whitespace
+fn valid_email(email: &str) -> bool {
+    let mut parts = email.split('@');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => {
+            email.len() <= MAX_EMAIL_LENGTH
+                && !local.is_empty()
+                && domain.split('.').count() > 1
+                && domain.split('.').all(|label| !label.is_empty())
+                && !email.chars().any(char::is_whitespace)
+        }
+        _ => false,
+    }
+}
+
+/// Writes the changes an admin made to a user to the audit log
+fn audit(admin: &UserInfo, user: &store::User, changes: &UserChanges) {
+    let mut changed = vec![];
+    if let Some(ref username) = changes.username {
+        changed.push(format!(
+            "username {} -> {}",
+            Masked(&user.username),
+            Masked(username)
+        ));
+    }
+    if let Some(ref email) = changes.email {
+        changed.push(format!("email {} -> {}", Masked(&user.email), Masked(email)));
+    }
+    if let Some(verified) = changes.verified {
+        changed.push(format!("verified {} -> {}", user.verified, verified));
+    }
+    if let Some(banned) = changes.banned {
+        changed.push(format!("banned {} -> {}", user.banned, banned));
+    }
+    info!(
+        target: logging::AUDIT_TARGET,
+        "Admin {} updated user {}: {}",
+        *admin.id,
+        user.id,
+        changed.join(", ")
+    );
+}
+
+/// An error for a client, with the id of the current request
+fn refuse(error: AuthError) -> ServiceError {
+    ServiceError {
+        error,
+        detail: None,
+        request_id: logging::request_id().unwrap_or_default(),
+    }
+}
+
+/// Like [`refuse`], for an error which happens after the span of the request
+/// was left
+fn refuse_later() -> impl FnOnce(AuthError) -> ServiceError {
+    let request_id = logging::request_id().unwrap_or_default();
+    move |error| ServiceError {
+        error,
+        detail: None,
+        request_id,
+    }
+}
+
+/// Why a spawned rpc failed, which becomes its `ServiceError`
+struct Refusal {
+    error: AuthError,
+    detail: Option<ErrorDetail>,
+}
+
+impl From<AuthError> for Refusal {
+    fn from(error: AuthError) -> Refusal {
+        Refusal {
+            error,
+            detail: None,
+        }
+    }
+}
+
+/// Tells a taken email from a taken username, which are the same `AuthError`
+impl From<IntError> for Refusal {
+    fn from(error: IntError) -> Refusal {
+        let detail = match error.kind() {
+            IntErrorKind::ExistingEmail => Some(ErrorDetail::ExistingEmail),
+            _ => None,
+        };
+        Refusal {
+            error: error.into(),
+            detail,
+        }
+    }
+}
+
+/// Makes a new random token
+fn new_token() -> Token {
+    let mut random_bytes = [0u8; 60];
+    thread_rng().fill(&mut random_bytes[..]);
+    Token::new(base64::encode(&random_bytes[..]))
+}
+
+/// The rpcs, which are defined in the client crate so that every consumer
+/// shares them
+pub use auth_client::{FutureService, FutureServiceExt};
+
+impl FutureService for AuthServer {
+    type AuthenticateFut = CpuFuture<Token, ServiceError>;
+    type DeauthenticateFut = Result<(), ServiceError>;
+    type RegisterFut = CpuFuture<AddUserPayload, ServiceError>;
+    type GetUserFut = Result<UserInfo, ServiceError>;
+    type GetUsersFut = Result<Vec<Result<UserInfo, AuthError>>, ServiceError>;
+    type SetUserRoleFut = CpuFuture<(), ServiceError>;
+    type HealthFut = CpuFuture<HealthReport, ServiceError>;
+    type InvalidationsFut = Box<Future<Item = Invalidations, Error = ServiceError>>;
+    type EventsFut = Box<Future<Item = Vec<Event>, Error = ServiceError>>;
+    type AddWebhookFut = CpuFuture<NewWebhook, ServiceError>;
+    type RemoveWebhookFut = CpuFuture<(), ServiceError>;
+    type WebhooksFut = CpuFuture<Vec<Webhook>, ServiceError>;
+    type DeadLettersFut = CpuFuture<Vec<DeadLetter>, ServiceError>;
+    type ReplayDeadLettersFut = CpuFuture<ReplayReport, ServiceError>;
+    type ListUsersFut = CpuFuture<UserPage, ServiceError>;
+    type GetUserByIdFut = CpuFuture<UserProfile, ServiceError>;
+    type UpdateUserFut = CpuFuture<UserProfile, ServiceError>;
+
+    fn get_user(&self, request_id: Option<String>, token: Token) -> Self::GetUserFut {
+        let _span = logging::enter_request("get_user", request_id);
+        let timer = RequestTimer::start("get_user");
+        debug!("Received get role request for token: {}", Fingerprint(&token));
+
+        let result = self.lookup_token(&token);
+        timer.observe(&result);
+        result.map_err(refuse)
+    }
+
+    fn get_users(&self, request_id: Option<String>, tokens: Vec<Token>) -> Self::GetUsersFut {
+        let _span = logging::enter_request("get_users", request_id);
+        let timer = RequestTimer::start("get_users");
+        debug!("Received get users request for {} tokens", tokens.len());
+
+        let result = self.lookup_tokens(&tokens);
+        timer.observe(&result);
+        result.map_err(refuse)
+    }
+
+    fn deauthenticate(&self, request_id: Option<String>, token: Token) -> Self::DeauthenticateFut {
+        let _span = logging::enter_request("deauthenticate", request_id);
+        let timer = RequestTimer::start("deauthenticate");
+        debug!(
+            "Received deauthenticate request for token: {}",
+            Fingerprint(&token)
+        );
+
+        let result = self.remove_token(&token);
+        timer.observe(&result);
+        result.map_err(refuse)
+    }
+
+    fn authenticate(
+        &self,
+        request_id: Option<String>,
+        payload: AuthPayload,
+    ) -> Self::AuthenticateFut {
+        let _span = logging::enter_request("authenticate", request_id);
         debug!(
             "Received authentication request from: {}",
-            &payload.username
+            Masked(&payload.username)
         );
 
-        let cloned_pool = self.db_pool.clone();
-        let cloned_tokens = self.tokens.clone();
+        let server = self.clone();
 
+        let span = logging::current();
         let f = futures::lazy(move || {
-            cloned_pool
-                .get()
-                .map_err(|e| {
-                    error!("Unable to get a database connection from the pool: {}", e);
-                    AuthError::InternalServerError
-                }).and_then(|con| {
-                    let AuthPayload {
-                        username,
-                        password: plain_password,
-                    } = payload;
-
-                    // Get hashed password from database for current username
-                    let db::User {
-                        password: hashed_password,
-                        id: user_id,
-                        ..
-                    } = match db::fetch_user(&con, &username) {
-                        Ok(v) => {
-                            trace!("Found user");
-                            v
-                        }
-                        Err(e) => {
-                            trace!("User not found");
-                            return Err(e.into());
-                        }
-                    };
-
-                    // 'Pepper' the password
-                    let pepper_pass = plain_password.into_inner() + &PASS_PEPPER;
-
-                    // Check if the already stored hashed password matches the password
-                    // that the user sent
-                    match pbkdf2_check(&pepper_pass, &hashed_password) {
-                        Ok(_) => {
-                            trace!("Password matches");
-
-                            let mut random_bytes = [0u8; 60];
-                            thread_rng().fill(&mut random_bytes[..]);
-
-                            let user_id = user_id.into();
-                            let db::Role {
-                                name: user_role, ..
-                            } = match db::fetch_user_role(&con, user_id) {
-                                Ok(v) => {
-                                    trace!("Found user role");
-                                    v
-                                }
-                                Err(e) => {
-                                    trace!("Failed to find user role");
-                                    return Err(e.into());
-                                }
-                            };
-
-                            // Wrap in a empty scope, so that as soon as we're done writing
-                            // to the 'HashMap', we'll drop the 'RwLockGuard' and hence make
-                            // the 'HashMap' avaliable to other threads etc.
-                            trace!("Generating token");
-                            let token = Token::new(base64::encode(&random_bytes[..]));
-                            let now = chrono::offset::Utc::now();
-                            {
-                                let token_clone = token.clone();
-                                cloned_tokens
-                                    .write()
-                                    .map_err(|e| {
-                                        error!("Unable to write to 'tokens': {}", e);
-                                        AuthError::InternalServerError
-                                    })?.insert(
-                                        token_clone,
-                                        (user_id.into(), user_role.as_str().into(), now),
-                                    );
-                            }
-                            trace!("Returning token");
-                            Ok(token)
-                        }
-                        // The password does NOT match, return `InvalidPassword`
-                        Err(CheckError::HashMismatch) => {
-                            trace!("Password does not match");
-                            Err(AuthError::InvalidPassword)
-                        }
-                        // TODO handle the situation where the internally stored password
-                        // is badly formatted
-                        //
-                        // The internal 'hashed_password' does not have the correct
-                        // format. This is probably a result of corruption. This
-                        // will hopefully be a rare occurence. Perhaps reset password?
-                        Err(CheckError::InvalidFormat) => {
-                            error!("Hashed password has invalid format");
-                            Err(AuthError::InternalServerError)
-                        }
-                    }
-                })
+            let _span = logging::enter(span);
+            let AuthPayload {
+                username,
+                password: plain_password,
+            } = payload;
+
+            let password = Secret::new(plain_password.into_inner());
+            let identity = server.authenticators.authenticate(&username, &password)?;
+            logging::set_user_id(identity.user_id);
+            let role: Role = identity.role.as_str().into();
+            if identity.role_changed {
+                server.change_role(identity.user_id.into(), role)?;
+            }
+
+            trace!("Generating token");
+            let token = server.insert_session(
+                identity.user_id.into(),
+                identity.username,
+                identity.verified,
+                role,
+                None,
+            )?;
+            trace!("Returning token");
+            Ok(token)
         });
-        self.pool.spawn(f)
+        self.spawn("authenticate", f)
     }
 
-    fn register(&self, payload: RegisterUserPayload) -> Self::RegisterFut {
-        debug!("Received register user request from: {}", &payload.username);
+    fn register(
+        &self,
+        request_id: Option<String>,
+        payload: RegisterUserPayload,
+    ) -> Self::RegisterFut {
+        let _span = logging::enter_request("register", request_id);
+        debug!(
+            "Received register user request from: {}",
+            Masked(&payload.username)
+        );
 
-        let cloned_pool = self.db_pool.clone();
+        let store = self.store.clone();
+        let security = self.security.clone();
+        let events = self.events.clone();
 
+        let span = logging::current();
         let f = futures::lazy(move || {
-            cloned_pool
-                .get()
+            let _span = logging::enter(span);
+            let RegisterUserPayload {
+                username,
+                password: plain_password,
+                email,
+            } = payload;
+
+            // 'Pepper' the password
+            let pepper_pass = Secret::new(plain_password.into_inner() + &security.pepper);
+
+            // Hash the password of the user
+            trace!("Hashing password");
+            let hash_start = Instant::now();
+            let hashed_password = pbkdf2::pbkdf2_simple(pepper_pass.expose(), security.hash_cycles)
                 .map_err(|e| {
-                    error!("Unable to get a database connection from the pool: {}", e);
+                    error!("Unable to hash password, {}", e);
                     AuthError::InternalServerError
-                }).and_then(|con| {
-                    let RegisterUserPayload {
-                        username,
-                        password: plain_password,
-                        email,
-                    } = payload;
-
-                    // Check if username is in DB
-                    match db::fetch_user(&con, &username) {
-                        Ok(_) => {
-                            trace!("The username already exists");
-                            return Err(AuthError::ExistingUser);
-                        }
-                        Err(_) => {}
-                    };
-
-                    // 'Pepper' the password
-                    let pepper_pass = plain_password.into_inner() + &PASS_PEPPER;
-
-                    // Hash the password of the user
-                    trace!("Hashing password");
-                    let hashed_password = pbkdf2::pbkdf2_simple(&pepper_pass, HASH_PASS_CYCLES)
-                        .map_err(|e| {
-                            error!("Unable to hash password, {}", e);
-                            AuthError::InternalServerError
-                        })?;
-
-                    // Insert the user info into DB
-                    trace!("Inserting user");
-                    db::insert_user(
-                        &con,
-                        username.into_inner(),
-                        email.into_inner(),
-                        hashed_password,
-                    ).map_err(|e| {
-                        error!("Unable to insert user: {}", e);
-                        e.into()
-                    }).and_then(|user| {
-                        let username = user.username;
-                        let id = user.id;
-                        username
-                            .try_into()
-                            .map_err(|_| AuthError::InvalidUsername)
-                            .map(move |name| (id.into(), name))
-                    }).map(|(id, username)| {
-                        trace!("Returning user payload");
-                        AddUserPayload { id, username }
-                    })
+                })?;
+            metrics::PASSWORD_HASH_DURATION
+                .with_label_values(&["hash"])
+                .observe(metrics::seconds(hash_start));
+
+            // Insert the user info into DB. A taken username or email is
+            // detected by the unique constraints, so concurrent
+            // registrations cannot both succeed
+            trace!("Inserting user");
+            store
+                .insert_user(username.into_inner(), email.into_inner(), hashed_password)
+                .map_err(|e| {
+                    match e.kind() {
+                        IntErrorKind::ExistingUser => trace!("The username already exists"),
+                        IntErrorKind::ExistingEmail => trace!("The email already exists"),
+                        _ => error!("Unable to insert user: {}", e),
+                    }
+                    Refusal::from(e)
+                }).and_then(|user| {
+                    events.notify();
+                    let username = user.username;
+                    let id = user.id;
+                    logging::set_user_id(id);
+                    username
+                        .try_into()
+                        .map_err(|_| Refusal::from(AuthError::InvalidUsername))
+                        .map(move |name| (id.into(), name))
+                }).map(|(id, username)| {
+                    trace!("Returning user payload");
+                    AddUserPayload { id, username }
                 })
         });
 
-        self.pool.spawn(f)
+        self.spawn_refusal("register", f)
     }
 
-    fn set_user_role(&self, payload: SetUserRolePayload) -> Self::SetUserRoleFut {
+    fn set_user_role(
+        &self,
+        request_id: Option<String>,
+        payload: SetUserRolePayload,
+    ) -> Self::SetUserRoleFut {
+        let _span = logging::enter_request("set_user_role", request_id);
         debug!("Received set user role request from: {}", &payload.id);
 
-        let cloned_pool = self.db_pool.clone();
+        let server = self.clone();
 
+        let span = logging::current();
         let f = futures::lazy(move || {
-            cloned_pool
-                .get()
+            let _span = logging::enter(span);
+            logging::set_user_id(*payload.id);
+            if !server.privileged {
+                warn!("Refused set_user_role from an unprivileged client");
+                return Err(AuthError::InvalidToken);
+            }
+            server.change_role(payload.id, payload.role)
+        });
+
+        self.spawn("set_user_role", f)
+    }
+
+    fn health(&self, request_id: Option<String>) -> Self::HealthFut {
+        let _span = logging::enter_request("health", request_id);
+        trace!("Received health request");
+
+        let server = self.clone();
+        let f = futures::lazy(move || Ok(server.health()));
+
+        self.spawn("health", f)
+    }
+
+    fn invalidations(&self, request_id: Option<String>, since: Cursor) -> Self::InvalidationsFut {
+        // Not counted as in flight nor timed, a long poll is meant to wait
+        let _span = logging::enter_request("invalidations", request_id);
+        trace!("Received invalidations request");
+        Box::new(self.invalidations.poll(since).map_err(refuse_later()))
+    }
+
+    fn events(&self, request_id: Option<String>, since: u64) -> Self::EventsFut {
+        // Not counted as in flight nor timed, like invalidations
+        let _span = logging::enter_request("events", request_id);
+        trace!("Received events request after {}", since);
+        if let Err(e) = self.check_privileged("events") {
+            return Box::new(future::err(refuse(e)));
+        }
+
+        // Waiting starts before reading, so events written in between are
+        // not missed
+        let waiting = self.events.wait();
+        let server = self.clone();
+        let events = self.read_events(since).and_then(move |events| {
+            if events.is_empty() {
+                Either::A(waiting.and_then(move |()| server.read_events(since)))
+            } else {
+                Either::B(future::ok(events))
+            }
+        });
+        Box::new(events.map_err(refuse_later()))
+    }
+
+    fn add_webhook(
+        &self,
+        request_id: Option<String>,
+        payload: AddWebhookPayload,
+    ) -> Self::AddWebhookFut {
+        let _span = logging::enter_request("add_webhook", request_id);
+        debug!("Received add webhook request for {}", payload.url);
+
+        let admin = self.require_admin(&payload.token, "add_webhook");
+        let store = self.store.clone();
+        let span = logging::current();
+        let f = futures::lazy(move || {
+            let _span = logging::enter(span);
+            admin?;
+            if let Err(e) = webhooks::parse_url(&payload.url) {
+                warn!("Refused webhook: {}", e);
+                return Err(AuthError::InvalidRequest);
+            }
+            if payload.events.is_empty() {
+                warn!("Refused webhook without events");
+                return Err(AuthError::InvalidRequest);
+            }
+
+            let secret = webhooks::new_secret();
+            let webhook = store
+                .insert_webhook(&payload.url, &secret, &payload.events)
                 .map_err(|e| {
-                    error!("Unable to get a database connection from the pool: {}", e);
+                    error!("Unable to add webhook: {}", e);
                     AuthError::InternalServerError
-                }).and_then(
-                    |con| match db::update_role(&con, *payload.id, payload.role.into()) {
-                        Ok(_) => {
-                            trace!("Successfully update role");
-                            Ok(())
-                        }
-                        Err(e) => {
-                            error!("Error updating role: {}", e);
-                            Err(AuthError::InternalServerError)
-                        }
-                    },
-                )
+                })?;
+            info!("Added webhook {} for {}", webhook.id, webhook.url);
+            Ok(NewWebhook {
+                webhook: webhook.into(),
+                secret,
+            })
+        });
+
+        self.spawn("add_webhook", f)
+    }
+
+    fn remove_webhook(
+        &self,
+        request_id: Option<String>,
+        token: Token,
+        id: u32,
+    ) -> Self::RemoveWebhookFut {
+        let _span = logging::enter_request("remove_webhook", request_id);
+        debug!("Received remove webhook request for {}", id);
+
+        let admin = self.require_admin(&token, "remove_webhook");
+        let store = self.store.clone();
+        let span = logging::current();
+        let f = futures::lazy(move || {
+            let _span = logging::enter(span);
+            admin?;
+            match store.delete_webhook(id) {
+                Ok(true) => {
+                    info!("Removed webhook {}", id);
+                    Ok(())
+                }
+                Ok(false) => {
+                    debug!("No webhook {} to remove", id);
+                    Err(AuthError::InvalidRequest)
+                }
+                Err(e) => {
+                    error!("Unable to remove webhook: {}", e);
+                    Err(AuthError::InternalServerError)
+                }
+            }
         });
 
-        self.pool.spawn(f)
+        self.spawn("remove_webhook", f)
     }
+
+    fn webhooks(&self, request_id: Option<String>, token: Token) -> Self::WebhooksFut {
+        let _span = logging::enter_request("webhooks", request_id);
+        trace!("Received webhooks request");
+
+        let admin = self.require_admin(&token, "webhooks");
+        let store = self.store.clone();
+        let span = logging::current();
+        let f = futures::lazy(move || {
+            let _span = logging::enter(span);
+            admin?;
+            let webhooks = store.fetch_webhooks().map_err(|e| {
+                error!("Unable to read webhooks: {}", e);
+                AuthError::InternalServerError
+            })?;
+            Ok(webhooks.into_iter().map(Webhook::from).collect())
+        });
+
+        self.spawn("webhooks", f)
+    }
+
+    fn dead_letters(
+        &self,
+        request_id: Option<String>,
+        token: Token,
+        webhook_id: Option<u32>,
+    ) -> Self::DeadLettersFut {
+        let _span = logging::enter_request("dead_letters", request_id);
+        trace!("Received dead letters request");
+
+        let admin = self.require_admin(&token, "dead_letters");
+        let store = self.store.clone();
+        let span = logging::current();
+        let f = futures::lazy(move || {
+            let _span = logging::enter(span);
+            admin?;
+            store.fetch_dead_letters(webhook_id).map_err(|e| {
+                error!("Unable to read dead letters: {}", e);
+                AuthError::InternalServerError
+            })
+        });
+
+        self.spawn("dead_letters", f)
+    }
+
+    fn replay_dead_letters(
+        &self,
+        request_id: Option<String>,
+        token: Token,
+        webhook_id: Option<u32>,
+    ) -> Self::ReplayDeadLettersFut {
+        let _span = logging::enter_request("replay_dead_letters", request_id);
+        debug!("Received replay dead letters request");
+
+        let admin = self.require_admin(&token, "replay_dead_letters");
+        let store = self.store.clone();
+        let span = logging::current();
+        let f = futures::lazy(move || {
+            let _span = logging::enter(span);
+            admin?;
+            let report = webhooks::replay(&*store, webhook_id).map_err(|e| {
+                error!("Unable to replay dead letters: {}", e);
+                AuthError::InternalServerError
+            })?;
+            info!(
+                "Replayed dead letters, {} delivered, {} failed and {} pending",
+                report.delivered, report.failed, report.pending
+            );
+            Ok(report)
+        });
+
+        self.spawn("replay_dead_letters", f)
+    }
+
+    fn list_users(
+        &self,
+        request_id: Option<String>,
+        payload: ListUsersPayload,
+    ) -> Self::ListUsersFut {
+        let _span = logging::enter_request("list_users", request_id);
+        trace!("Received list users request");
+
+        let admin = self.require_admin(&payload.token, "list_users");
+        let store = self.store.clone();
+        let span = logging::current();
+        let f = futures::lazy(move || {
+            let _span = logging::enter(span);
+            admin?;
+            let filter = payload.filter;
+            let timestamps = filter.created_after.iter().chain(&filter.created_before);
+            for &timestamp in timestamps {
+                if NaiveDateTime::from_timestamp_opt(timestamp, 0).is_none() {
+                    warn!("Refused list_users with timestamp {}", timestamp);
+                    return Err(AuthError::InvalidRequest);
+                }
+            }
+            let limit = match payload.limit {
+                0 => MAX_PAGE_SIZE,
+                limit => limit.min(MAX_PAGE_SIZE),
+            };
+            let after = payload.after.map_or(0, |id| *id);
+
+            // One more than asked for tells whether there is a next page
+            let mut users = store.list_users(&filter, after, limit + 1).map_err(|e| {
+                error!("Unable to list users: {}", e);
+                AuthError::InternalServerError
+            })?;
+            let next = if users.len() > limit as usize {
+                users.truncate(limit as usize);
+                users.last().map(|(user, _)| user.id.into())
+            } else {
+                None
+            };
+            Ok(UserPage {
+                users: users
+                    .into_iter()
+                    .map(|(user, role)| profile(user, &role))
+                    .collect(),
+                next,
+            })
+        });
+
+        self.spawn("list_users", f)
+    }
+
+    fn get_user_by_id(
+        &self,
+        request_id: Option<String>,
+        token: Token,
+        id: UserId,
+    ) -> Self::GetUserByIdFut {
+        let _span = logging::enter_request("get_user_by_id", request_id);
+        trace!("Received get user by id request");
+
+        let admin = self.require_admin(&token, "get_user_by_id");
+        let store = self.store.clone();
+        let span = logging::current();
+        let f = futures::lazy(move || {
+            let _span = logging::enter(span);
+            admin?;
+            logging::set_user_id(*id);
+            fetch_profile(&*store, *id)
+        });
+
+        self.spawn("get_user_by_id", f)
+    }
+
+    fn update_user(
+        &self,
+        request_id: Option<String>,
+        payload: UpdateUserPayload,
+    ) -> Self::UpdateUserFut {
+        let _span = logging::enter_request("update_user", request_id);
+        debug!("Received update user request for: {}", &payload.id);
+
+        let admin = self.require_admin(&payload.token, "update_user");
+        let server = self.clone();
+        let span = logging::current();
+        let f = futures::lazy(move || -> Result<UserProfile, Refusal> {
+            let _span = logging::enter(span);
+            let admin = admin?;
+            let id = *payload.id;
+            let changes = UserChanges {
+                username: payload.username,
+                email: payload.email,
+                verified: payload.verified,
+                banned: payload.banned,
+            };
+            if changes.is_empty() {
+                debug!("Refused update_user without changes");
+                return Err(AuthError::InvalidRequest.into());
+            }
+            if let Some(ref username) = changes.username {
+                if !valid_username(username) {
+                    debug!("Refused invalid username: {}", Masked(username));
+                    return Err(AuthError::InvalidUsername.into());
+                }
+            }
+            if let Some(ref email) = changes.email {
+                if !valid_email(email) {
+                    debug!("Refused invalid email: {}", Masked(email));
+                    return Err(AuthError::InvalidRequest.into());
+                }
+            }
+
+            let previous = server.store.update_user(id, &changes).map_err(|e| match e.kind() {
+                IntErrorKind::InvalidUsername => {
+                    debug!("No user {} to update", id);
+                    Refusal::from(AuthError::InvalidRequest)
+                }
+                IntErrorKind::ExistingUser | IntErrorKind::ExistingEmail => {
+                    trace!("Unable to update user: {}", e);
+                    Refusal::from(e)
+                }
+                _ => {
+                    error!("Unable to update user: {}", e);
+                    Refusal::from(AuthError::InternalServerError)
+                }
+            })?;
+            audit(&admin, &previous, &changes);
+            let user = changes.apply(previous);
+            server.change_sessions(&user)?;
+
+            let role = server.store.fetch_user_role(id).map_err(|e| {
+                error!("Unable to fetch role: {}", e);
+                AuthError::InternalServerError
+            })?;
+            Ok(profile(user, &role))
+        });
+
+        self.spawn_refusal("update_user", f)
+    }
+}
+
+/// A server with the default configuration on an empty in-memory store, for
+/// tests
+#[cfg(test)]
+fn test_server() -> (AuthServer, Arc<crate::store::memory::MemoryStore>) {
+    let store = Arc::new(crate::store::memory::MemoryStore::default());
+    (AuthServer::with_store(&Config::default(), store.clone()), store)
+}
+
+#[test]
+fn test_no_secrets_in_logs() {
+    use crate::store::memory::MemoryStore;
+    use auth_client::{BlockingClient, Options};
+    use std::sync::mpsc;
+    use std::thread;
+    use tarpc::future::server::Options as ServerOptions;
+
+    let capture = logging::capture();
+    let mut config = Config::default();
+    config.security.hash_cycles = 1000;
+    let pepper = config.security.pepper.clone();
+    let (address_tx, address_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let server = AuthServer::with_store(&config, Arc::new(MemoryStore::default()));
+        let (handle, listener) = server
+            .listen(([127, 0, 0, 1], 0).into(), &core.handle(), ServerOptions::default())
+            .unwrap();
+        address_tx.send(handle.addr()).unwrap();
+        core.run(listener).unwrap();
+    });
+    let mut client = BlockingClient::new(address_rx.recv().unwrap(), Options::default()).unwrap();
+
+    let username = "secretive";
+    let email = "secretive@example.com";
+    let password = "Sup3r-Secret-Passw0rd";
+    let auth_payload = |password: &str| AuthPayload {
+        username: username.to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+        password: password.to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+    };
+
+    client
+        .register(RegisterUserPayload {
+            username: username.to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+            password: password.to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+            email: email.to_owned().try_into().unwrap_or_else(|_| panic!("email")),
+        }).unwrap();
+    let token = client.authenticate(auth_payload(password)).unwrap();
+    client.get_user(token.clone()).unwrap();
+    client.deauthenticate(token.clone()).unwrap();
+    assert!(client.get_user(token.clone()).is_err());
+    assert!(client.authenticate(auth_payload("Wr0ng-Passw0rd")).is_err());
+
+    let raw_token = serde_json::to_string(&token).unwrap();
+    let secrets: [&str; 5] = [
+        username,
+        email,
+        password,
+        raw_token.trim_matches('"'),
+        &pepper,
+    ];
+
+    // What the text and json formatters wrote, for every test of the binary
+    let output = capture.output();
+    assert!(output.contains(&Fingerprint(&token).to_string()));
+    for secret in &secrets {
+        assert!(!output.contains(secret), "secret leaked in the logs: {}", secret);
+    }
+}
+
+#[test]
+fn test_unprivileged() {
+    use crate::store::memory::add_user;
+    use crate::store::UserStore;
+
+    let (server, store) = test_server();
+    let user = add_user(&store, "user");
+    let payload = || SetUserRolePayload {
+        id: user.id.into(),
+        role: "moderator".into(),
+    };
+
+    assert!(server.clone().unprivileged().set_user_role(None, payload()).wait().is_err());
+    assert_eq!("user", store.fetch_user_role(user.id).unwrap().name);
+    server.set_user_role(None, payload()).wait().unwrap();
+    assert_eq!("moderator", store.fetch_user_role(user.id).unwrap().name);
+}
+
+#[test]
+fn test_user_info() {
+    use crate::store::memory::MemoryStore;
+
+    let mut config = Config::default();
+    config.security.hash_cycles = 1000;
+    let server = AuthServer::with_store(&config, Arc::new(MemoryStore::default()));
+    let (name, secret) = ("info", "Inf0-Passw0rd");
+    let register = RegisterUserPayload {
+        username: name.to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+        password: secret.to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+        email: "info@example.com".to_owned().try_into().unwrap_or_else(|_| panic!("email")),
+    };
+    let user = server.register(None, register).wait().unwrap();
+    let before = Utc::now().timestamp();
+    let login = AuthPayload {
+        username: name.to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+        password: secret.to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+    };
+    let token = server.authenticate(None, login).wait().unwrap();
+
+    let session = server.tokens.read().unwrap()[&token].clone();
+    assert_eq!(user.id, session.user_id);
+    assert_eq!("info", session.username);
+    assert!(!session.verified);
+    assert_eq!(None, session.client_id);
+
+    let info = server.get_user(None, token.clone()).unwrap();
+    assert_eq!(USER_INFO_VERSION, info.version);
+    assert_eq!(session.user_id, info.id);
+    assert_eq!("info", info.username);
+    assert_eq!("user", String::from(info.role));
+    assert!(!info.verified);
+    assert!(info.issued_at >= before && info.issued_at <= Utc::now().timestamp());
+    assert_eq!(info.issued_at + config.tokens.lifetime, info.expires_at);
+
+    server.deauthenticate(None, token.clone()).unwrap();
+    assert!(server.tokens.read().unwrap().get(&token).is_none());
+    assert!(server.get_user(None, token).is_err());
+}
+
+#[test]
+fn test_directory_roles() {
+    use crate::authenticator::ldap::{Entry, FakeDirectory, LdapAuthenticator};
+    use crate::store::memory::MemoryStore;
+    use crate::store::UserStore;
+
+    let admins = "cn=admins,ou=groups,dc=example,dc=com";
+    let mut config = Config::default();
+    config.ldap.user_dn = "uid={username},ou=people,dc=example,dc=com".to_owned();
+    config.ldap.group_roles = vec![crate::config::GroupRole {
+        group: admins.to_owned(),
+        role: "admin".to_owned(),
+    }];
+    let store = Arc::new(MemoryStore::default());
+    let mut server = AuthServer::with_store(&config, store.clone());
+    let mut directory = |groups: &[&str]| {
+        let mut entry = Entry::new();
+        entry.insert("mail".to_owned(), vec!["jdoe@example.com".to_owned()]);
+        entry.insert(
+            "memberOf".to_owned(),
+            groups.iter().map(|g| g.to_string()).collect(),
+        );
+        let mut directory = FakeDirectory::default();
+        directory.add("uid=jdoe,ou=people,dc=example,dc=com", "Secr3t-Passw0rd", entry);
+        server.authenticators = Arc::new(Authenticators::new(vec![Box::new(
+            LdapAuthenticator::new(config.ldap.clone(), Box::new(directory), store.clone()),
+        )]));
+        server.clone()
+    };
+    let payload = || AuthPayload {
+        username: "jdoe".to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+        password: "Secr3t-Passw0rd".to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+    };
+
+    let server = directory(&[admins]);
+    let token = server.authenticate(None, payload()).wait().unwrap();
+    let user = server.get_user(None, token.clone()).unwrap();
+    assert_eq!("admin", String::from(user.role));
+    assert_eq!("admin", store.fetch_user_role(*user.id).unwrap().name);
+
+    // Leaving the group changes the role of the open session too, and tells
+    // the clients which cache it
+    let start = server.invalidations.poll(Cursor::default()).wait().unwrap();
+    let server = directory(&[]);
+    server.authenticate(None, payload()).wait().unwrap();
+    assert_eq!("user", store.fetch_user_role(*user.id).unwrap().name);
+    let role = server.get_user(None, token).unwrap().role;
+    assert_eq!("user", String::from(role));
+    let changed = server.invalidations.poll(start.cursor).wait().unwrap();
+    assert_eq!(vec![user.id], changed.users);
+}
+
+#[test]
+fn test_client() {
+    use crate::store::memory::MemoryStore;
+    use auth_client::{BlockingClient, Error, Options};
+    use std::sync::mpsc;
+    use std::thread;
+    use tarpc::future::server::Options as ServerOptions;
+
+    let mut config = Config::default();
+    config.security.hash_cycles = 1000;
+    let (address_tx, address_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let server = AuthServer::with_store(&config, Arc::new(MemoryStore::default()));
+        let (handle, listener) = server
+            .listen(([127, 0, 0, 1], 0).into(), &core.handle(), ServerOptions::default())
+            .unwrap();
+        address_tx.send(handle.addr()).unwrap();
+        core.run(listener).unwrap();
+    });
+
+    let address = address_rx.recv().unwrap();
+    let mut client = BlockingClient::new(address, Options::default()).unwrap();
+
+    let user = client
+        .register(RegisterUserPayload {
+            username: "client".to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+            password: "Cl1ent-Passw0rd".to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+            email: "client@example.com".to_owned().try_into().unwrap_or_else(|_| panic!("email")),
+        }).unwrap();
+    let token = client
+        .authenticate(AuthPayload {
+            username: "client".to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+            password: "Cl1ent-Passw0rd".to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+        }).unwrap();
+    let info = client.get_user(token.clone()).unwrap();
+    assert_eq!(user.id, info.id);
+    assert_eq!("client", info.username);
+    assert!(!info.verified);
+
+    client.deauthenticate(token.clone()).unwrap();
+    client.set_request_id("caller-1");
+    match client.get_user(token) {
+        Err(Error::Service(AuthError::InvalidToken, ref id, _)) if id == "caller-1" => {}
+        other => panic!("expected InvalidToken for caller-1, got {:?}", other),
+    }
+    // The heartbeat is not spawned in tests
+    assert!(!client.health().unwrap().live);
+}
+
+#[test]
+fn test_events() {
+    use crate::store::memory::MemoryStore;
+    use auth_client::EventKind;
+
+    let mut config = Config::default();
+    config.security.hash_cycles = 1000;
+    let server = AuthServer::with_store(&config, Arc::new(MemoryStore::default()));
+    assert!(server.clone().unprivileged().events(None, 0).wait().is_err());
+
+    let events = server.events(None, 0);
+    let user = server
+        .register(None, RegisterUserPayload {
+            username: "events".to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+            password: "Ev3nts-Passw0rd".to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+            email: "events@example.com".to_owned().try_into().unwrap_or_else(|_| panic!("email")),
+        }).wait()
+        .unwrap();
+
+    // The waiting client is woken by the registration
+    let events = events.wait().unwrap();
+    let registered = EventKind::UserRegistered {
+        id: user.id,
+        username: "events".to_owned(),
+    };
+    assert_eq!(vec![registered], events.iter().map(|e| e.kind.clone()).collect::<Vec<_>>());
+
+    // The next call returns the role change, and nothing else
+    server
+        .set_user_role(None, SetUserRolePayload {
+            id: user.id,
+            role: "moderator".into(),
+        }).wait()
+        .unwrap();
+    let events = server.events(None, events[0].id).wait().unwrap();
+    let changed = EventKind::RoleChanged {
+        id: user.id,
+        role: "moderator".into(),
+    };
+    assert_eq!(vec![changed], events.iter().map(|e| e.kind.clone()).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_get_users() {
+    use crate::store::memory::add_user;
+
+    let (server, store) = test_server();
+    let user = |name: &str| add_user(&store, name);
+    let (first, second) = (user("first"), user("second"));
+    let expired = server.start_session(&user("expired"), "user".into()).unwrap();
+    server
+        .tokens
+        .write()
+        .unwrap()
+        .get_mut(&expired)
+        .unwrap()
+        .created = Utc::now() - Duration::days(365);
+    let first = server.start_session(&first, "user".into()).unwrap();
+    let second = server.start_session(&second, "admin".into()).unwrap();
+
+    let users = server
+        .get_users(None, vec![second, new_token(), first, expired])
+        .unwrap();
+    assert_eq!(4, users.len());
+    assert_eq!("second", users[0].as_ref().unwrap().username);
+    assert!(users[1].is_err());
+    let first = users[2].as_ref().unwrap();
+    assert_eq!("first", first.username);
+    assert_eq!(first.issued_at + Config::default().tokens.lifetime, first.expires_at);
+    assert!(users[3].is_err());
+    assert!(server.get_users(None, vec![]).unwrap().is_empty());
+}
+
+#[test]
+fn test_webhooks() {
+    use crate::store::memory::add_user;
+    use crate::store::UserStore;
+    use auth_client::{EventKind, EventType};
+
+    let (server, store) = test_server();
+    let user = |name: &str| add_user(&store, name);
+    let admin = server.start_session(&user("admin"), "admin".into()).unwrap();
+    let moderator = server.start_session(&user("moderator"), "moderator".into()).unwrap();
+    let (address, received) = events::receive(&["200 OK"]);
+    let payload = |token: &Token, url: &str, events: Vec<EventType>| AddWebhookPayload {
+        token: token.clone(),
+        url: url.to_owned(),
+        events,
+    };
+    let url = format!("http://{}/hook", address);
+    let events = vec![EventType::UserRegistered];
+
+    let refused = payload(&moderator, &url, events.clone());
+    assert!(server.add_webhook(None, refused).wait().is_err());
+    let unsupported = payload(&admin, "tarpc://127.0.0.1:1", events.clone());
+    assert!(server.add_webhook(None, unsupported).wait().is_err());
+    assert!(server.add_webhook(None, payload(&admin, &url, vec![])).wait().is_err());
+    let added = server.add_webhook(None, payload(&admin, &url, events)).wait().unwrap();
+    assert_eq!(
+        vec![added.webhook.clone()],
+        server.webhooks(None, admin.clone()).wait().unwrap()
+    );
+    assert!(server.webhooks(None, moderator.clone()).wait().is_err());
+
+    // An event the webhook gave up on is delivered by a replay
+    let event = Event {
+        id: 1,
+        created_at: 0,
+        kind: EventKind::UserDeleted { id: 1.into() },
+    };
+    store
+        .insert_dead_letter(added.webhook.id, &event, 5, "refused")
+        .unwrap();
+    let letters = server.dead_letters(None, admin.clone(), Some(added.webhook.id));
+    assert_eq!(1, letters.wait().unwrap().len());
+    assert!(server.dead_letters(None, moderator.clone(), None).wait().is_err());
+    assert!(server.replay_dead_letters(None, moderator.clone(), None).wait().is_err());
+    let report = server.replay_dead_letters(None, admin.clone(), None).wait().unwrap();
+    let delivered = ReplayReport {
+        delivered: 1,
+        failed: 0,
+        pending: 0,
+    };
+    assert_eq!(delivered, report);
+    assert!(server.dead_letters(None, admin.clone(), None).wait().unwrap().is_empty());
+
+    let (headers, body) = received.join().unwrap().remove(0);
+    assert_eq!(event, serde_json::from_slice::<Event>(&body).unwrap());
+    let timestamp = headers["x-auth-timestamp"].parse().unwrap();
+    assert_eq!(
+        webhooks::signature(&added.secret, timestamp, &body),
+        headers["x-auth-signature"]
+    );
+
+    let id = added.webhook.id;
+    assert!(server.remove_webhook(None, moderator, id).wait().is_err());
+    server.remove_webhook(None, admin.clone(), id).wait().unwrap();
+    assert!(server.webhooks(None, admin.clone()).wait().unwrap().is_empty());
+    assert!(server.remove_webhook(None, admin, id).wait().is_err());
+}
+
+#[test]
+fn test_list_users() {
+    use crate::store::memory::add_user;
+    use auth_client::UserFilter;
+
+    let (server, store) = test_server();
+    let user = |name: &str| add_user(&store, name);
+    let admin = server.start_session(&user("admin"), "admin".into()).unwrap();
+    let moderator = server.start_session(&user("moderator"), "moderator".into()).unwrap();
+    for name in &["first", "second", "third"] {
+        user(name);
+    }
+    let payload = |token: &Token, after, limit| ListUsersPayload {
+        token: token.clone(),
+        filter: UserFilter::default(),
+        after,
+        limit,
+    };
+
+    assert!(server.list_users(None, payload(&moderator, None, 10)).wait().is_err());
+    assert!(server.list_users(None, payload(&new_token(), None, 10)).wait().is_err());
+
+    let first = server.list_users(None, payload(&admin, None, 3)).wait().unwrap();
+    let names: Vec<&str> = first.users.iter().map(|u| u.username.as_str()).collect();
+    assert_eq!(vec!["admin", "moderator", "first"], names);
+    assert!(first.next.is_some());
+    let second = server.list_users(None, payload(&admin, first.next, 3)).wait().unwrap();
+    let names: Vec<&str> = second.users.iter().map(|u| u.username.as_str()).collect();
+    assert_eq!(vec!["second", "third"], names);
+    assert_eq!(None, second.next);
+
+    let mut filtered = payload(&admin, None, 0);
+    filtered.filter.username_prefix = Some("th".to_owned());
+    let third = server.list_users(None, filtered.clone()).wait().unwrap();
+    assert_eq!(1, third.users.len());
+    assert_eq!("third@example.com", third.users[0].email);
+    // Prefixes match in any case
+    filtered.filter.username_prefix = Some("TH".to_owned());
+    assert_eq!(third, server.list_users(None, filtered.clone()).wait().unwrap());
+    filtered.filter.created_after = Some(i64::max_value());
+    assert!(server.list_users(None, filtered).wait().is_err());
+}
+
+#[test]
+fn test_update_user() {
+    use crate::store::memory::add_user;
+    use crate::store::UserStore;
+
+    let (server, store) = test_server();
+    let user = |name: &str| add_user(&store, name);
+    let admin = server.start_session(&user("admin"), "admin".into()).unwrap();
+    let moderator = server.start_session(&user("moderator"), "moderator".into()).unwrap();
+    let (anna, bob) = (user("anna"), user("bob"));
+    let anna_token = server.start_session(&anna, "user".into()).unwrap();
+    let bob_token = server.start_session(&bob, "user".into()).unwrap();
+    let payload = |token: &Token, id: u32| UpdateUserPayload {
+        token: token.clone(),
+        id: id.into(),
+        username: None,
+        email: None,
+        verified: None,
+        banned: None,
+    };
+    let outcome = |payload| {
+        let result = server.update_user(None, payload).wait();
+        metrics::outcome(&result.map_err(|e| e.error))
+    };
+
+    let mut changes = payload(&admin, anna.id);
+    changes.username = Some("annabel".to_owned());
+    changes.email = Some("annabel@example.org".to_owned());
+    changes.banned = Some(true);
+    assert_eq!(
+        "invalid_token",
+        outcome(UpdateUserPayload {
+            token: moderator.clone(),
+            ..changes.clone()
+        })
+    );
+
+    let start = server.invalidations.poll(Cursor::default()).wait().unwrap();
+    let updated = server.update_user(None, changes).wait().unwrap();
+    assert_eq!("annabel", updated.username);
+    assert_eq!("annabel@example.org", updated.email);
+    assert!(updated.banned && !updated.verified);
+    let by_id = |token: &Token, id: u32| server.get_user_by_id(None, token.clone(), id.into());
+    assert_eq!(updated, by_id(&admin, anna.id).wait().unwrap());
+    assert!(by_id(&moderator, anna.id).wait().is_err());
+    assert!(by_id(&admin, bob.id + 1).wait().is_err());
+
+    // Banning ends the sessions of the user
+    assert!(server.get_user(None, anna_token).is_err());
+    let changed = server.invalidations.poll(start.cursor).wait().unwrap();
+    assert_eq!(vec![UserId::from(anna.id)], changed.users);
+
+    // Nothing, invalid values and unknown users are refused
+    assert_eq!("error", outcome(payload(&admin, bob.id)));
+    let mut invalid = payload(&admin, bob.id);
+    invalid.username = Some("b".repeat(100));
+    assert_eq!("invalid_username", outcome(invalid));
+    for email in &["bob", "bob@", "@example.com", "bob@example", "b ob@example.com"] {
+        let mut invalid = payload(&admin, bob.id);
+        invalid.email = Some(email.to_string());
+        assert_eq!("error", outcome(invalid));
+    }
+    let mut unknown = payload(&admin, bob.id + 1);
+    unknown.verified = Some(true);
+    assert_eq!("error", outcome(unknown));
+
+    // A taken email is told apart from a taken username by the detail
+    let mut taken = payload(&admin, bob.id);
+    taken.username = Some("annabel".to_owned());
+    assert_eq!("existing_user", outcome(taken.clone()));
+    assert_eq!(None, server.update_user(None, taken).wait().unwrap_err().detail);
+    let mut taken = payload(&admin, bob.id);
+    taken.email = Some("annabel@example.org".to_owned());
+    assert_eq!("existing_user", outcome(taken.clone()));
+    let detail = server.update_user(None, taken).wait().unwrap_err().detail;
+    assert_eq!(Some(ErrorDetail::ExistingEmail), detail);
+    assert_eq!(bob, store.fetch_user_by_id(bob.id).unwrap());
+
+    // The open sessions of the user get the changes right away
+    let mut changes = payload(&admin, bob.id);
+    changes.username = Some("robert".to_owned());
+    changes.verified = Some(true);
+    server.update_user(None, changes).wait().unwrap();
+    let info = server.get_user(None, bob_token).unwrap();
+    assert_eq!("robert", info.username);
+    assert!(info.verified);
 }
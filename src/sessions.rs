@@ -0,0 +1,243 @@
+//! Persistence of the in-memory sessions
+//!
+//! The sessions normally only live in memory, so a restart logs everyone out.
+//! If `tokens.store` is configured, the sessions are written to that file when
+//! the server shuts down and read back when it starts.
+use chrono::offset::Utc;
+use chrono::{DateTime, Duration};
+use failure::ResultExt;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use datatypes::auth::responses::Role;
+use datatypes::valid::ids::UserId;
+use datatypes::valid::token::Token;
+
+use crate::store::UserStore;
+use crate::{IntErrorKind, IntResult};
+
+/// An in-memory mapping between a Token and a Session, see [`crate::service::AuthServer`]
+pub type Sessions = Arc<RwLock<HashMap<Token, Session>>>;
+
+/// A logged in user, with what `get_user` tells about them
+///
+/// Everything but the role is taken from the user when they log in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub user_id: UserId,
+    pub role: Role,
+    /// Missing in sessions stored before it was kept, see [`load`]
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub verified: bool,
+    pub created: DateTime<Utc>,
+    /// The OAuth client the session was issued to, which acts for the user
+    /// but may not use the admin rpcs
+    #[serde(default)]
+    pub client_id: Option<String>,
+}
+
+/// A session as it is written to the file
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    token: Token,
+    #[serde(flatten)]
+    session: Session,
+}
+
+/// Reads sessions from `path`, no file means no sessions
+///
+/// Sessions stored before the username and `verified` were kept get them
+/// from `store`, and are dropped if their user is gone.
+pub fn load(path: &str, store: &UserStore) -> IntResult<Sessions> {
+    let sessions = Sessions::default();
+    if !Path::new(path).exists() {
+        info!("No stored sessions found in '{}'", path);
+        return Ok(sessions);
+    }
+
+    let file = File::open(path).context(IntErrorKind::ServerError)?;
+    let stored: Vec<StoredSession> = serde_json::from_reader(BufReader::new(file))
+        .context(IntErrorKind::ServerError)
+        .map_err(|e| {
+            error!("Unable to read stored sessions from '{}': {}", path, e);
+            e
+        })?;
+
+    {
+        let mut map = sessions.write().map_err(|e| {
+            error!("Unable to write to 'tokens': {}", e);
+            IntErrorKind::ServerError
+        })?;
+        for mut s in stored {
+            if s.session.username.is_empty() {
+                let user = match store.fetch_user_by_id(*s.session.user_id) {
+                    Ok(user) => user,
+                    Err(ref e) if e.kind() == IntErrorKind::InvalidUsername => {
+                        debug!("Dropping a session of the removed user {}", *s.session.user_id);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Unable to fetch the user of a stored session: {}", e);
+                        return Err(e);
+                    }
+                };
+                s.session.username = user.username;
+                s.session.verified = user.verified;
+            }
+            map.insert(s.token, s.session);
+        }
+        info!("Loaded {} sessions from '{}'", map.len(), path);
+    }
+    Ok(sessions)
+}
+
+/// Removes the sessions which are older than `lifetime`, and returns how many
+/// were removed
+///
+/// Expired sessions are already refused by `get_user`, this only frees them.
+pub fn prune(sessions: &Sessions, lifetime: Duration) -> IntResult<usize> {
+    let oldest_valid = Utc::now() - lifetime;
+    let mut map = sessions.write().map_err(|e| {
+        error!("Unable to write to 'tokens': {}", e);
+        IntErrorKind::ServerError
+    })?;
+    let before = map.len();
+    map.retain(|_, session| session.created > oldest_valid);
+    Ok(before - map.len())
+}
+
+/// Writes all sessions to `path`
+///
+/// The sessions are first written to a temporary file which then replaces
+/// `path`, so a crash midway never leaves a half written file behind. The
+/// tokens in it are as good as passwords, so only the owner may read it.
+pub fn flush(sessions: &Sessions, path: &str) -> IntResult<()> {
+    let stored: Vec<StoredSession> = sessions
+        .read()
+        .map_err(|e| {
+            error!("Unable to read 'tokens': {}", e);
+            IntErrorKind::ServerError
+        })?.iter()
+        .map(|(token, session)| StoredSession {
+            token: token.clone(),
+            session: session.clone(),
+        }).collect();
+
+    let tmp_path = format!("{}.tmp", path);
+    // Left behind by a crash, and maybe with other permissions
+    if Path::new(&tmp_path).exists() {
+        fs::remove_file(&tmp_path).context(IntErrorKind::ServerError)?;
+    }
+    {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .context(IntErrorKind::ServerError)?;
+        serde_json::to_writer(BufWriter::new(file), &stored).context(IntErrorKind::ServerError)?;
+    }
+    fs::rename(&tmp_path, path).context(IntErrorKind::ServerError)?;
+
+    info!("Stored {} sessions in '{}'", stored.len(), path);
+    Ok(())
+}
+
+#[test]
+fn test_prune() {
+    let sessions = Sessions::default();
+    let session = |age: i64| Session {
+        user_id: 1.into(),
+        role: "user".into(),
+        username: "user".to_owned(),
+        verified: false,
+        created: Utc::now() - Duration::seconds(age),
+        client_id: None,
+    };
+    {
+        let mut map = sessions.write().unwrap();
+        map.insert(Token::new("fresh".to_owned()), session(10));
+        map.insert(Token::new("expired".to_owned()), session(120));
+    }
+
+    assert_eq!(1, prune(&sessions, Duration::seconds(60)).unwrap());
+    let map = sessions.read().unwrap();
+    assert!(map.contains_key(&Token::new("fresh".to_owned())));
+    assert!(!map.contains_key(&Token::new("expired".to_owned())));
+}
+
+#[test]
+fn test_flush_is_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join(format!("auth-service-sessions-{}", std::process::id()));
+    let path = path.to_string_lossy().into_owned();
+    let sessions = Sessions::default();
+    sessions.write().unwrap().insert(
+        Token::new("token".to_owned()),
+        Session {
+            user_id: 1.into(),
+            role: "user".into(),
+            username: "user".to_owned(),
+            verified: true,
+            created: Utc::now(),
+            client_id: None,
+        },
+    );
+
+    // A stale temporary file which anyone could read is replaced
+    fs::write(format!("{}.tmp", path), b"[]").unwrap();
+    flush(&sessions, &path).unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(0o600, mode & 0o777);
+    let store = crate::store::memory::MemoryStore::default();
+    assert_eq!(
+        *sessions.read().unwrap(),
+        *load(&path, &store).unwrap().read().unwrap()
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_load_old_sessions() {
+    use crate::store::memory::{add_user, MemoryStore};
+
+    let path = std::env::temp_dir().join(format!("auth-service-old-{}", std::process::id()));
+    let path = path.to_string_lossy().into_owned();
+    let store = MemoryStore::default();
+    let user = add_user(&store, "old");
+    // As they were written before the username and verified were kept
+    let stored = |token: &str, user_id: u32| {
+        let session = StoredSession {
+            token: Token::new(token.to_owned()),
+            session: Session {
+                user_id: user_id.into(),
+                role: "user".into(),
+                username: String::new(),
+                verified: false,
+                created: Utc::now(),
+                client_id: None,
+            },
+        };
+        let mut session = serde_json::to_value(&session).unwrap();
+        session.as_object_mut().unwrap().remove("username");
+        session.as_object_mut().unwrap().remove("verified");
+        session
+    };
+    let stored = vec![stored("kept", user.id), stored("gone", user.id + 1)];
+    fs::write(&path, serde_json::to_vec(&stored).unwrap()).unwrap();
+
+    let sessions = load(&path, &store).unwrap();
+    let map = sessions.read().unwrap();
+    assert_eq!(1, map.len());
+    let session = &map[&Token::new("kept".to_owned())];
+    assert_eq!("old", session.username);
+    assert_eq!(user.verified, session.verified);
+    fs::remove_file(&path).unwrap();
+}
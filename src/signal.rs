@@ -0,0 +1,23 @@
+//! Signals which ask the server to shut down or to reload
+use futures::{Future, Stream};
+use std::io;
+#[cfg(feature = "tls")]
+use tokio_signal::unix::SIGHUP;
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+/// Resolves with the name of the signal once SIGTERM or SIGINT is received
+pub fn shutdown() -> impl Future<Item = &'static str, Error = io::Error> {
+    let term = Signal::new(SIGTERM).flatten_stream().map(|_| "SIGTERM");
+    let int = Signal::new(SIGINT).flatten_stream().map(|_| "SIGINT");
+
+    term.select(int)
+        .into_future()
+        .map(|(signal, _)| signal.unwrap_or("end of signal stream"))
+        .map_err(|(e, _)| e)
+}
+
+/// Yields every time SIGHUP is received
+#[cfg(feature = "tls")]
+pub fn reload() -> impl Stream<Item = (), Error = io::Error> {
+    Signal::new(SIGHUP).flatten_stream().map(|_| ())
+}
@@ -0,0 +1,671 @@
+//! A [`UserStore`] which keeps everything in memory
+//!
+//! Useful for running the service locally and in tests, without a database
+//! server. Everything is lost when the process exits.
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use auth_client::{DeadLetter, Event, EventKind, EventType, UserFilter};
+
+use super::{webhook_sink, Client, Role, User, UserChanges, UserStore, Webhook};
+use crate::{IntErrorKind, IntResult};
+
+/// Same limits as the `VARCHAR` columns of the sql schema
+const MAX_USERNAME_LEN: usize = 20;
+const MAX_EMAIL_LEN: usize = 255;
+
+/// Whether a value is longer than its `VARCHAR` column, which counts
+/// characters rather than bytes
+fn too_long(value: &str, max: usize) -> bool {
+    value.chars().count() > max
+}
+
+/// Whether two values collide in a `UNIQUE` column, which ignores case with
+/// the default collation of MySQL
+fn same_text(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+#[derive(Default)]
+struct Tables {
+    users: BTreeMap<u32, User>,
+    roles: BTreeMap<u32, Role>,
+    clients: BTreeMap<String, Client>,
+    last_id: u32,
+    outbox: BTreeMap<u64, Event>,
+    sinks: BTreeMap<String, u64>,
+    last_event_id: u64,
+    webhooks: BTreeMap<u32, Webhook>,
+    last_webhook_id: u32,
+    dead_letters: BTreeMap<u64, DeadLetter>,
+    last_dead_letter_id: u64,
+}
+
+impl Tables {
+    fn publish(&mut self, kind: EventKind) {
+        self.last_event_id += 1;
+        let id = self.last_event_id;
+        self.outbox.insert(
+            id,
+            Event {
+                id,
+                created_at: Utc::now().timestamp(),
+                kind,
+            },
+        );
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    tables: RwLock<Tables>,
+}
+
+impl MemoryStore {
+    fn read(&self) -> IntResult<RwLockReadGuard<Tables>> {
+        self.tables.read().map_err(|e| {
+            error!("Unable to read from the memory store: {}", e);
+            IntErrorKind::ConnectionError.into()
+        })
+    }
+
+    fn write(&self) -> IntResult<RwLockWriteGuard<Tables>> {
+        self.tables.write().map_err(|e| {
+            error!("Unable to write to the memory store: {}", e);
+            IntErrorKind::ConnectionError.into()
+        })
+    }
+
+    fn modify_user<F>(&self, user_id: u32, f: F) -> IntResult<bool>
+    where
+        F: FnOnce(&mut User),
+    {
+        Ok(self.write()?.users.get_mut(&user_id).map(f).is_some())
+    }
+
+    /// Updates a user, and publishes an event if there is such a user
+    fn modify_user_with_event<F>(&self, user_id: u32, f: F, kind: EventKind) -> IntResult<bool>
+    where
+        F: FnOnce(&mut User),
+    {
+        let mut tables = self.write()?;
+        let updated = tables.users.get_mut(&user_id).map(f).is_some();
+        if updated {
+            tables.publish(kind);
+        }
+        Ok(updated)
+    }
+}
+
+impl UserStore for MemoryStore {
+    fn insert_user(&self, username: String, email: String, password: String) -> IntResult<User> {
+        if too_long(&username, MAX_USERNAME_LEN) || too_long(&email, MAX_EMAIL_LEN) {
+            error!("Unable to insert user: value too long");
+            Err(IntErrorKind::QueryError)?
+        }
+
+        // The write lock is held for the whole insert, which makes it atomic
+        let mut tables = self.write()?;
+        if tables.users.values().any(|u| same_text(&u.username, &username)) {
+            Err(IntErrorKind::ExistingUser)?
+        }
+        if tables.users.values().any(|u| same_text(&u.email, &email)) {
+            Err(IntErrorKind::ExistingEmail)?
+        }
+
+        tables.last_id += 1;
+        let id = tables.last_id;
+        let user = User {
+            id,
+            email,
+            username,
+            password,
+            banned: false,
+            verified: false,
+            email_token: None,
+            created_at: Utc::now().naive_utc(),
+        };
+        tables.users.insert(id, user.clone());
+        tables.roles.insert(
+            id,
+            Role {
+                id,
+                name: "user".to_string(),
+            },
+        );
+        tables.publish(EventKind::UserRegistered {
+            id: id.into(),
+            username: user.username.clone(),
+        });
+
+        Ok(user)
+    }
+
+    fn fetch_user(&self, username: &str) -> IntResult<User> {
+        self.read()?
+            .users
+            .values()
+            .find(|u| u.username == username)
+            .cloned()
+            .ok_or_else(|| IntErrorKind::InvalidUsername.into())
+    }
+
+    fn fetch_user_by_id(&self, user_id: u32) -> IntResult<User> {
+        self.read()?
+            .users
+            .get(&user_id)
+            .cloned()
+            .ok_or_else(|| IntErrorKind::InvalidUsername.into())
+    }
+
+    fn list_users(
+        &self,
+        filter: &UserFilter,
+        after: u32,
+        limit: u32,
+    ) -> IntResult<Vec<(User, Role)>> {
+        let role: Option<String> = filter.role.map(Into::into);
+        // Compared in lowercase, like `lower` does in the SQL stores
+        let username_prefix = filter.username_prefix.as_ref().map(|p| p.to_lowercase());
+        let email_prefix = filter.email_prefix.as_ref().map(|p| p.to_lowercase());
+        let starts_with = |text: &str, prefix: &Option<String>| {
+            prefix.as_ref().map_or(true, |p| text.to_lowercase().starts_with(p))
+        };
+        let matches = |user: &User, user_role: &Role| {
+            let created_at = user.created_at.timestamp();
+            starts_with(&user.username, &username_prefix)
+                && starts_with(&user.email, &email_prefix)
+                && role.as_ref().map_or(true, |role| user_role.name == *role)
+                && filter.banned.map_or(true, |banned| user.banned == banned)
+                && filter.verified.map_or(true, |verified| user.verified == verified)
+                && filter.created_after.map_or(true, |after| created_at >= after)
+                && filter.created_before.map_or(true, |before| created_at < before)
+        };
+
+        let tables = self.read()?;
+        Ok(tables
+            .users
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .filter_map(|(id, user)| tables.roles.get(id).map(|role| (user, role)))
+            .filter(|&(user, role)| matches(user, role))
+            .take(limit as usize)
+            .map(|(user, role)| (user.clone(), role.clone()))
+            .collect())
+    }
+
+    fn update_ban(&self, user_id: u32, banned: bool) -> IntResult<bool> {
+        self.modify_user_with_event(
+            user_id,
+            |u| u.banned = banned,
+            EventKind::UserBanned {
+                id: user_id.into(),
+                banned,
+            },
+        )
+    }
+
+    fn update_verify(&self, user_id: u32, verified: bool) -> IntResult<bool> {
+        self.modify_user_with_event(
+            user_id,
+            |u| u.verified = verified,
+            EventKind::UserVerified {
+                id: user_id.into(),
+                verified,
+            },
+        )
+    }
+
+    fn update_user(&self, user_id: u32, changes: &UserChanges) -> IntResult<User> {
+        let longer = |value: &Option<String>, max: usize| value.iter().any(|v| too_long(v, max));
+        if longer(&changes.username, MAX_USERNAME_LEN) || longer(&changes.email, MAX_EMAIL_LEN) {
+            error!("Unable to update user: value too long");
+            Err(IntErrorKind::QueryError)?
+        }
+
+        let mut tables = self.write()?;
+        let previous = tables
+            .users
+            .get(&user_id)
+            .cloned()
+            .ok_or(IntErrorKind::InvalidUsername)?;
+        {
+            let others = || tables.users.values().filter(|u| u.id != user_id);
+            if let Some(ref username) = changes.username {
+                if others().any(|u| same_text(&u.username, username)) {
+                    Err(IntErrorKind::ExistingUser)?
+                }
+            }
+            if let Some(ref email) = changes.email {
+                if others().any(|u| same_text(&u.email, email)) {
+                    Err(IntErrorKind::ExistingEmail)?
+                }
+            }
+        }
+
+        tables.users.insert(user_id, changes.apply(previous.clone()));
+        for kind in changes.events(&previous) {
+            tables.publish(kind);
+        }
+        Ok(previous)
+    }
+
+    fn update_email_token(&self, user_id: u32, email_token: String) -> IntResult<bool> {
+        self.modify_user(user_id, |u| u.email_token = Some(email_token))
+    }
+
+    fn update_role(&self, user_id: u32, role: String) -> IntResult<bool> {
+        let mut tables = self.write()?;
+        let kind = EventKind::RoleChanged {
+            id: user_id.into(),
+            role: role.as_str().into(),
+        };
+        let updated = tables.roles.get_mut(&user_id).map(|r| r.name = role).is_some();
+        if updated {
+            tables.publish(kind);
+        }
+        Ok(updated)
+    }
+
+    fn delete_user(&self, user_id: u32) -> IntResult<bool> {
+        let mut tables = self.write()?;
+        tables.roles.remove(&user_id);
+        let deleted = tables.users.remove(&user_id).is_some();
+        if deleted {
+            tables.publish(EventKind::UserDeleted { id: user_id.into() });
+        }
+        Ok(deleted)
+    }
+
+    fn fetch_user_role(&self, user_id: u32) -> IntResult<Role> {
+        self.read()?
+            .roles
+            .get(&user_id)
+            .cloned()
+            .ok_or_else(|| IntErrorKind::ServerError.into())
+    }
+
+    fn insert_client(&self, client: &Client) -> IntResult<()> {
+        let mut tables = self.write()?;
+        if tables.clients.contains_key(&client.id) {
+            error!("Unable to insert oauth client: id is taken");
+            Err(IntErrorKind::QueryError)?
+        }
+        tables.clients.insert(client.id.clone(), client.clone());
+        Ok(())
+    }
+
+    fn fetch_client(&self, client_id: &str) -> IntResult<Client> {
+        self.read()?
+            .clients
+            .get(client_id)
+            .cloned()
+            .ok_or_else(|| IntErrorKind::InvalidClient.into())
+    }
+
+    fn fetch_events(&self, after: u64, limit: u32) -> IntResult<Vec<Event>> {
+        Ok(self
+            .read()?
+            .outbox
+            .range(after + 1..)
+            .take(limit as usize)
+            .map(|(_, event)| event.clone())
+            .collect())
+    }
+
+    fn delete_events(&self, up_to: u64, created_before: i64) -> IntResult<usize> {
+        let mut tables = self.write()?;
+        let before = tables.outbox.len();
+        tables
+            .outbox
+            .retain(|id, event| *id > up_to || event.created_at >= created_before);
+        Ok(before - tables.outbox.len())
+    }
+
+    fn fetch_sink_position(&self, sink: &str) -> IntResult<u64> {
+        Ok(self.read()?.sinks.get(sink).cloned().unwrap_or(0))
+    }
+
+    fn update_sink_position(&self, sink: &str, position: u64) -> IntResult<()> {
+        self.write()?.sinks.insert(sink.to_owned(), position);
+        Ok(())
+    }
+
+    fn insert_webhook(&self, url: &str, secret: &str, events: &[EventType]) -> IntResult<Webhook> {
+        let mut tables = self.write()?;
+        tables.last_webhook_id += 1;
+        let webhook = Webhook {
+            id: tables.last_webhook_id,
+            url: url.to_owned(),
+            secret: secret.to_owned(),
+            events: events.to_vec(),
+            created_at: Utc::now().timestamp(),
+        };
+        let position = tables.last_event_id;
+        tables.sinks.insert(webhook_sink(webhook.id), position);
+        tables.webhooks.insert(webhook.id, webhook.clone());
+        Ok(webhook)
+    }
+
+    fn fetch_webhooks(&self) -> IntResult<Vec<Webhook>> {
+        Ok(self.read()?.webhooks.values().cloned().collect())
+    }
+
+    fn delete_webhook(&self, webhook_id: u32) -> IntResult<bool> {
+        let mut tables = self.write()?;
+        tables
+            .dead_letters
+            .retain(|_, letter| letter.webhook_id != webhook_id);
+        tables.sinks.remove(&webhook_sink(webhook_id));
+        Ok(tables.webhooks.remove(&webhook_id).is_some())
+    }
+
+    fn insert_dead_letter(
+        &self,
+        webhook_id: u32,
+        event: &Event,
+        attempts: u32,
+        error: &str,
+    ) -> IntResult<()> {
+        let mut tables = self.write()?;
+        tables.last_dead_letter_id += 1;
+        let id = tables.last_dead_letter_id;
+        tables.dead_letters.insert(
+            id,
+            DeadLetter {
+                id,
+                webhook_id,
+                event: event.clone(),
+                attempts,
+                error: error.to_owned(),
+                failed_at: Utc::now().timestamp(),
+            },
+        );
+        Ok(())
+    }
+
+    fn fetch_dead_letters(&self, webhook_id: Option<u32>) -> IntResult<Vec<DeadLetter>> {
+        Ok(self
+            .read()?
+            .dead_letters
+            .values()
+            .filter(|letter| webhook_id.map_or(true, |id| letter.webhook_id == id))
+            .cloned()
+            .collect())
+    }
+
+    fn update_dead_letter(&self, id: u64, error: &str) -> IntResult<bool> {
+        Ok(self
+            .write()?
+            .dead_letters
+            .get_mut(&id)
+            .map(|letter| {
+                letter.attempts += 1;
+                letter.error = error.to_owned();
+                letter.failed_at = Utc::now().timestamp();
+            }).is_some())
+    }
+
+    fn delete_dead_letter(&self, id: u64) -> IntResult<bool> {
+        Ok(self.write()?.dead_letters.remove(&id).is_some())
+    }
+}
+
+/// Adds a user with the email `<name>@example.com`, for tests
+#[cfg(test)]
+pub fn add_user(store: &MemoryStore, name: &str) -> User {
+    store
+        .insert_user(name.to_owned(), format!("{}@example.com", name), "-".to_owned())
+        .unwrap()
+}
+
+#[test]
+fn test_insert_user() {
+    let store = MemoryStore::default();
+    let user = store
+        .insert_user(
+            "test_username".to_string(),
+            "test_email".to_string(),
+            "test_password".to_string(),
+        ).unwrap();
+
+    assert_eq!(user, store.fetch_user("test_username").unwrap());
+    assert_eq!(user, store.fetch_user_by_id(user.id).unwrap());
+    assert!(store.fetch_user_by_id(user.id + 1).is_err());
+    assert_eq!(
+        Role {
+            id: user.id,
+            name: "user".to_string()
+        },
+        store.fetch_user_role(user.id).unwrap()
+    );
+    assert!(store
+        .insert_user(
+            "REEEEEEEEEEEEEEEEEEEEEEEEEEEEEEE".to_string(),
+            "email_test1".to_string(),
+            "password1".to_string(),
+        ).is_err());
+}
+
+#[test]
+fn test_insert_user_existing() {
+    let store = MemoryStore::default();
+    store
+        .insert_user("user1".to_string(), "email1".to_string(), "pw".to_string())
+        .unwrap();
+
+    let same_username =
+        store.insert_user("user1".to_string(), "email2".to_string(), "pw".to_string());
+    assert_eq!(IntErrorKind::ExistingUser, same_username.unwrap_err().kind());
+
+    let same_email =
+        store.insert_user("user2".to_string(), "email1".to_string(), "pw".to_string());
+    assert_eq!(IntErrorKind::ExistingEmail, same_email.unwrap_err().kind());
+    assert!(store.fetch_user("user2").is_err());
+
+    // Like MySQL, case is ignored and lengths are in characters
+    let other_case =
+        store.insert_user("USER1".to_string(), "email3".to_string(), "pw".to_string());
+    assert_eq!(IntErrorKind::ExistingUser, other_case.unwrap_err().kind());
+    let other_case =
+        store.insert_user("user3".to_string(), "EMAIL1".to_string(), "pw".to_string());
+    assert_eq!(IntErrorKind::ExistingEmail, other_case.unwrap_err().kind());
+    let wide = "ü".repeat(MAX_USERNAME_LEN);
+    store
+        .insert_user(wide, "email4".to_string(), "pw".to_string())
+        .unwrap();
+}
+
+#[test]
+fn test_update_functions() {
+    let store = MemoryStore::default();
+    let user = store
+        .insert_user(
+            "username1".to_string(),
+            "email1".to_string(),
+            "password1".to_string(),
+        ).unwrap();
+
+    assert_eq!(true, store.update_role(user.id, "moderator".to_string()).unwrap());
+    assert_eq!("moderator", store.fetch_user_role(user.id).unwrap().name);
+    assert_eq!(true, store.update_ban(user.id, true).unwrap());
+    assert_eq!(true, store.update_verify(user.id, true).unwrap());
+    assert_eq!(
+        true,
+        store.update_email_token(user.id, "123456789".to_string()).unwrap()
+    );
+    assert_eq!(false, store.update_ban(user.id + 1, true).unwrap());
+
+    let updated = store.fetch_user("username1").unwrap();
+    assert!(updated.banned && updated.verified);
+    assert_eq!(Some("123456789".to_string()), updated.email_token);
+}
+
+#[test]
+fn test_update_user() {
+    let store = MemoryStore::default();
+    let (anna, bob) = (add_user(&store, "anna"), add_user(&store, "bob"));
+    let last_event = store.fetch_events(0, 100).unwrap().last().map_or(0, |e| e.id);
+
+    let changes = UserChanges {
+        username: Some("annabel".to_string()),
+        email: Some("annabel@example.com".to_string()),
+        verified: Some(false),
+        banned: Some(true),
+    };
+    assert_eq!(anna, store.update_user(anna.id, &changes).unwrap());
+    let updated = store.fetch_user_by_id(anna.id).unwrap();
+    assert_eq!(changes.apply(anna.clone()), updated);
+    // Verified was false already, so there is no event for it
+    let events: Vec<_> = store
+        .fetch_events(last_event, 100)
+        .unwrap()
+        .into_iter()
+        .map(|event| event.kind)
+        .collect();
+    assert_eq!(
+        vec![
+            EventKind::UserRenamed {
+                id: anna.id.into(),
+                username: "annabel".to_string(),
+            },
+            EventKind::UserBanned {
+                id: anna.id.into(),
+                banned: true,
+            },
+        ],
+        events
+    );
+
+    let taken = |changes: UserChanges| store.update_user(anna.id, &changes).unwrap_err().kind();
+    assert_eq!(
+        IntErrorKind::ExistingUser,
+        taken(UserChanges {
+            username: Some("bob".to_string()),
+            ..UserChanges::default()
+        })
+    );
+    assert_eq!(
+        IntErrorKind::ExistingEmail,
+        taken(UserChanges {
+            email: Some(bob.email.clone()),
+            ..UserChanges::default()
+        })
+    );
+    assert_eq!(
+        IntErrorKind::InvalidUsername,
+        store.update_user(bob.id + 1, &changes).unwrap_err().kind()
+    );
+    // A user may keep their own username
+    let same = UserChanges {
+        username: Some("annabel".to_string()),
+        ..UserChanges::default()
+    };
+    assert_eq!(updated, store.update_user(anna.id, &same).unwrap());
+}
+
+#[test]
+fn test_list_users() {
+    let store = MemoryStore::default();
+    let anna = add_user(&store, "anna");
+    let andre = add_user(&store, "andre");
+    let bob = add_user(&store, "bob");
+    store.update_role(andre.id, "admin".to_string()).unwrap();
+    store.update_ban(bob.id, true).unwrap();
+    let ids = |filter: &UserFilter, after: u32, limit: u32| {
+        let users = store.list_users(filter, after, limit).unwrap();
+        users.iter().map(|(user, _)| user.id).collect::<Vec<_>>()
+    };
+
+    let all = UserFilter::default();
+    assert_eq!(vec![anna.id, andre.id, bob.id], ids(&all, 0, 10));
+    // Pages continue after the last id
+    assert_eq!(vec![anna.id, andre.id], ids(&all, 0, 2));
+    assert_eq!(vec![bob.id], ids(&all, andre.id, 2));
+
+    let prefix = UserFilter {
+        username_prefix: Some("an".to_string()),
+        ..UserFilter::default()
+    };
+    assert_eq!(vec![anna.id, andre.id], ids(&prefix, 0, 10));
+    let upper = UserFilter {
+        username_prefix: Some("AN".to_string()),
+        ..UserFilter::default()
+    };
+    assert_eq!(vec![anna.id, andre.id], ids(&upper, 0, 10));
+    let admins = UserFilter {
+        role: Some("admin".into()),
+        ..prefix.clone()
+    };
+    let users = store.list_users(&admins, 0, 10).unwrap();
+    assert_eq!(vec![(andre.clone(), store.fetch_user_role(andre.id).unwrap())], users);
+    let banned = UserFilter {
+        banned: Some(true),
+        email_prefix: Some("bob@".to_string()),
+        ..UserFilter::default()
+    };
+    assert_eq!(vec![bob.id], ids(&banned, 0, 10));
+
+    let created_at = anna.created_at.timestamp();
+    let later = UserFilter {
+        created_after: Some(created_at + 3600),
+        ..UserFilter::default()
+    };
+    assert!(ids(&later, 0, 10).is_empty());
+    let earlier = UserFilter {
+        created_before: Some(created_at + 3600),
+        ..UserFilter::default()
+    };
+    assert_eq!(3, ids(&earlier, 0, 10).len());
+}
+
+#[test]
+fn test_outbox() {
+    let store = MemoryStore::default();
+    let user = store
+        .insert_user("user1".to_string(), "email1".to_string(), "pw".to_string())
+        .unwrap();
+    store.update_ban(user.id, true).unwrap();
+    store.update_ban(user.id + 1, true).unwrap();
+    store.update_role(user.id, "admin".to_string()).unwrap();
+    assert_eq!(true, store.delete_user(user.id).unwrap());
+    assert_eq!(false, store.delete_user(user.id).unwrap());
+
+    // Only the changes which were made are published
+    let kinds: Vec<EventKind> = store
+        .fetch_events(0, 10)
+        .unwrap()
+        .into_iter()
+        .map(|event| event.kind)
+        .collect();
+    let id = user.id.into();
+    assert_eq!(
+        vec![
+            EventKind::UserRegistered {
+                id,
+                username: "user1".to_string()
+            },
+            EventKind::UserBanned { id, banned: true },
+            EventKind::RoleChanged {
+                id,
+                role: "admin".into()
+            },
+            EventKind::UserDeleted { id },
+        ],
+        kinds
+    );
+    assert!(store.fetch_user_by_id(user.id).is_err());
+
+    let events = store.fetch_events(1, 2).unwrap();
+    assert_eq!(vec![2, 3], events.iter().map(|e| e.id).collect::<Vec<_>>());
+
+    assert_eq!(0, store.fetch_sink_position("content").unwrap());
+    store.update_sink_position("content", 3).unwrap();
+    assert_eq!(3, store.fetch_sink_position("content").unwrap());
+
+    // Events are only deleted up to the given id, and when old enough
+    assert_eq!(0, store.delete_events(3, 0).unwrap());
+    assert_eq!(3, store.delete_events(3, i64::max_value()).unwrap());
+    assert_eq!(4, store.fetch_events(0, 10).unwrap()[0].id);
+}
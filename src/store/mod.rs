@@ -0,0 +1,403 @@
+//! Persistence of users, their roles, OAuth clients and webhooks
+//!
+//! Every change other services care about is also written to the `outbox`, in
+//! the same transaction as the change itself, see [`crate::events`].
+//!
+//! All access to the database goes through the [`UserStore`] trait, so the
+//! service does not care which database it is running against. Which backend
+//! is used is decided by the scheme of the database url:
+//!
+//! - `mysql://...` (feature `mysql`, enabled by default)
+//! - `postgres://...` (feature `postgres`)
+//! - `sqlite://<path>` (feature `sqlite`)
+//! - `memory://` (always available, nothing is persisted)
+use chrono::NaiveDateTime;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use auth_client::{DeadLetter, Event, EventKind, EventType, UserFilter};
+
+use crate::config::DatabaseConfig;
+use crate::{IntErrorKind, IntResult};
+
+pub mod memory;
+#[cfg(feature = "mysql")]
+pub mod mysql;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+#[macro_use]
+pub(crate) mod sql;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+#[derive(Queryable, PartialEq, Debug, Clone)]
+pub struct User {
+    pub id: u32,
+    pub email: String,
+    pub username: String,
+    pub password: String,
+    pub banned: bool,
+    pub verified: bool,
+    pub email_token: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, PartialEq, Debug, Clone)]
+pub struct Role {
+    pub id: u32,
+    pub name: String,
+}
+
+/// Changes an admin makes to a user, fields which are none are left as they
+/// are
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct UserChanges {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub verified: Option<bool>,
+    pub banned: Option<bool>,
+}
+
+impl UserChanges {
+    pub fn is_empty(&self) -> bool {
+        *self == UserChanges::default()
+    }
+
+    /// The user with the changes made
+    pub fn apply(&self, mut user: User) -> User {
+        if let Some(ref username) = self.username {
+            user.username = username.clone();
+        }
+        if let Some(ref email) = self.email {
+            user.email = email.clone();
+        }
+        if let Some(verified) = self.verified {
+            user.verified = verified;
+        }
+        if let Some(banned) = self.banned {
+            user.banned = banned;
+        }
+        user
+    }
+
+    /// The events of the changes to `user`, fields which keep their value
+    /// have none
+    pub fn events(&self, user: &User) -> Vec<EventKind> {
+        let mut events = vec![];
+        match self.username {
+            Some(ref username) if *username != user.username => {
+                events.push(EventKind::UserRenamed {
+                    id: user.id.into(),
+                    username: username.clone(),
+                })
+            }
+            _ => {}
+        }
+        match self.verified {
+            Some(verified) if verified != user.verified => events.push(EventKind::UserVerified {
+                id: user.id.into(),
+                verified,
+            }),
+            _ => {}
+        }
+        match self.banned {
+            Some(banned) if banned != user.banned => events.push(EventKind::UserBanned {
+                id: user.id.into(),
+                banned,
+            }),
+            _ => {}
+        }
+        events
+    }
+}
+
+/// A client application registered for OAuth, see [`crate::oauth`]
+#[derive(Queryable, PartialEq, Debug, Clone)]
+pub struct Client {
+    pub id: String,
+    pub name: String,
+    /// SHA-256 of the secret of a confidential client, public clients have none
+    pub secret_hash: Option<String>,
+    /// The uris authorization codes may be sent to, separated by spaces
+    pub redirect_uris: String,
+}
+
+/// A webhook, with the secret its deliveries are signed with, see
+/// [`crate::webhooks`]
+#[derive(PartialEq, Debug, Clone)]
+pub struct Webhook {
+    pub id: u32,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<EventType>,
+    /// In seconds since the epoch
+    pub created_at: i64,
+}
+
+/// The webhook as the rpcs show it, without the secret
+impl From<Webhook> for auth_client::Webhook {
+    fn from(webhook: Webhook) -> auth_client::Webhook {
+        auth_client::Webhook {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook.events,
+            created_at: webhook.created_at,
+        }
+    }
+}
+
+/// Connections of a database pool
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PoolState {
+    pub idle: u32,
+    pub in_use: u32,
+    pub max: u32,
+}
+
+/// Storage of users, their roles, OAuth clients and webhooks
+///
+/// Every implementation must be safe to share between the threads of the
+/// `CpuPool` which runs the rpc calls.
+///
+/// Usernames and emails are unique regardless of case with MySQL, whose
+/// default collation ignores it, and with the in-memory store, which follows
+/// MySQL. SQLite and PostgreSQL compare them as they are, so `Alice` and
+/// `alice` can both register there.
+pub trait UserStore: Send + Sync {
+    /// Creates a user with the role 'user', and a `UserRegistered` event
+    ///
+    /// Returns `ExistingUser` or `ExistingEmail` if the username or email is
+    /// already taken.
+    fn insert_user(&self, username: String, email: String, password: String) -> IntResult<User>;
+
+    /// Returns the user with the given username
+    fn fetch_user(&self, username: &str) -> IntResult<User>;
+
+    /// Returns the user with the given id
+    fn fetch_user_by_id(&self, user_id: u32) -> IntResult<User>;
+
+    /// Returns at most `limit` users which match `filter` and whose id is
+    /// above `after`, by id, with their roles
+    fn list_users(
+        &self,
+        filter: &UserFilter,
+        after: u32,
+        limit: u32,
+    ) -> IntResult<Vec<(User, Role)>>;
+
+    /// Updates banned status of a user, with a `UserBanned` event. Returns
+    /// true if updated, false if not.
+    fn update_ban(&self, user_id: u32, banned: bool) -> IntResult<bool>;
+
+    /// Updates verified status of a user, with a `UserVerified` event. Returns
+    /// true if updated, false if not.
+    fn update_verify(&self, user_id: u32, verified: bool) -> IntResult<bool>;
+
+    /// Changes the username, email, verified or banned status of a user, with
+    /// `UserRenamed`, `UserVerified` and `UserBanned` events for the values
+    /// which are different. Returns the user as it was before.
+    ///
+    /// Returns `InvalidUsername` if there is no such user, and `ExistingUser`
+    /// or `ExistingEmail` if the username or email belongs to another user.
+    fn update_user(&self, user_id: u32, changes: &UserChanges) -> IntResult<User>;
+
+    /// Updates email token of a user. Returns true if updated, false if not.
+    fn update_email_token(&self, user_id: u32, email_token: String) -> IntResult<bool>;
+
+    /// Updates the role of a user, with a `RoleChanged` event. Returns true if
+    /// updated, false if not.
+    fn update_role(&self, user_id: u32, role: String) -> IntResult<bool>;
+
+    /// Deletes a user and their role, with a `UserDeleted` event. Returns true
+    /// if deleted, false if there was no such user.
+    fn delete_user(&self, user_id: u32) -> IntResult<bool>;
+
+    /// Returns the role of a user
+    fn fetch_user_role(&self, user_id: u32) -> IntResult<Role>;
+
+    /// Registers an OAuth client
+    fn insert_client(&self, client: &Client) -> IntResult<()>;
+
+    /// Returns the OAuth client with the given id, or `InvalidClient`
+    fn fetch_client(&self, client_id: &str) -> IntResult<Client>;
+
+    /// Returns at most `limit` events of the outbox with an id above `after`,
+    /// by id
+    fn fetch_events(&self, after: u64, limit: u32) -> IntResult<Vec<Event>>;
+
+    /// Deletes the events up to and including `up_to` which were written
+    /// before `created_before`, in seconds since the epoch. Returns the number
+    /// of deleted events.
+    fn delete_events(&self, up_to: u64, created_before: i64) -> IntResult<usize>;
+
+    /// Returns the id of the last event delivered to a sink, 0 if none was
+    fn fetch_sink_position(&self, sink: &str) -> IntResult<u64>;
+
+    /// Records that the events up to `position` were delivered to a sink
+    fn update_sink_position(&self, sink: &str, position: u64) -> IntResult<()>;
+
+    /// Adds a webhook, which gets the events written after it was added
+    fn insert_webhook(&self, url: &str, secret: &str, events: &[EventType]) -> IntResult<Webhook>;
+
+    /// Returns every webhook, by id
+    fn fetch_webhooks(&self) -> IntResult<Vec<Webhook>>;
+
+    /// Deletes a webhook, with its dead letters and its position. Returns true
+    /// if deleted, false if there was no such webhook.
+    fn delete_webhook(&self, webhook_id: u32) -> IntResult<bool>;
+
+    /// Stores an event a webhook did not accept after `attempts` deliveries
+    fn insert_dead_letter(
+        &self,
+        webhook_id: u32,
+        event: &Event,
+        attempts: u32,
+        error: &str,
+    ) -> IntResult<()>;
+
+    /// Returns the dead letters of a webhook, or of every webhook, by id
+    fn fetch_dead_letters(&self, webhook_id: Option<u32>) -> IntResult<Vec<DeadLetter>>;
+
+    /// Records another failed delivery of a dead letter. Returns true if
+    /// updated, false if not.
+    fn update_dead_letter(&self, id: u64, error: &str) -> IntResult<bool>;
+
+    /// Deletes a dead letter which was delivered. Returns true if deleted,
+    /// false if not.
+    fn delete_dead_letter(&self, id: u64) -> IntResult<bool>;
+
+    /// Connections of the pool, if the store has one
+    fn pool_state(&self) -> Option<PoolState> {
+        None
+    }
+
+    /// Checks that a connection to the database can be checked out
+    fn ping(&self) -> IntResult<()> {
+        Ok(())
+    }
+
+    /// Number of embedded migrations not yet applied to the database
+    fn pending_migrations(&self) -> IntResult<usize> {
+        Ok(0)
+    }
+}
+
+/// A store which can be shared between the server and its worker threads
+pub type Store = Arc<UserStore>;
+
+/// Opens the store matching the scheme of the database url
+pub fn connect(config: &DatabaseConfig) -> IntResult<Store> {
+    let database_url = config.url.as_str();
+    let pool_size = config.pool_size;
+    let scheme = database_url.split("://").next().unwrap_or("");
+
+    match scheme {
+        #[cfg(feature = "mysql")]
+        "mysql" => Ok(Arc::new(mysql::MysqlStore::connect(database_url, pool_size)?)),
+        #[cfg(feature = "postgres")]
+        "postgres" | "postgresql" => Ok(Arc::new(postgres::PgStore::connect(
+            database_url,
+            pool_size,
+        )?)),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Ok(Arc::new(sqlite::SqliteStore::connect(
+            database_url.trim_left_matches("sqlite://"),
+            pool_size,
+        )?)),
+        "memory" => {
+            warn!("Using an in-memory store, nothing will be persisted");
+            Ok(Arc::new(memory::MemoryStore::default()))
+        }
+        _ => {
+            error!(
+                "Unsupported database '{}', is the matching feature enabled?",
+                scheme
+            );
+            Err(IntErrorKind::ConnectionError)?
+        }
+    }
+}
+
+/// The name the position of a webhook is stored under in `event_sinks`
+pub fn webhook_sink(webhook_id: u32) -> String {
+    format!("webhook:{}", webhook_id)
+}
+
+// Prefixes are compared in lowercase on every backend, see `UserFilter`
+sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
+/// A LIKE pattern of text which starts with `prefix`, in lowercase, for a
+/// column passed through `lower`
+pub(crate) fn prefix_pattern(prefix: &str) -> String {
+    format!("{}%", escape_like(&prefix.to_lowercase()))
+}
+
+/// Escapes `%`, `_` and `\` for a LIKE pattern, with `\` as the escape
+/// character
+pub(crate) fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Encodes a value for a json column, like `event` of the outbox
+pub(crate) fn encode_json<T: Serialize>(value: &T) -> IntResult<String> {
+    serde_json::to_string(value).map_err(|e| {
+        error!("Unable to encode column: {}", e);
+        IntErrorKind::QueryError.into()
+    })
+}
+
+/// Decodes a json column, `what` is the row it is from
+pub(crate) fn decode_json<T: DeserializeOwned>(what: &str, text: &str) -> IntResult<T> {
+    serde_json::from_str(text).map_err(|e| {
+        error!("Unable to decode {}: {}", what, e);
+        IntErrorKind::QueryError.into()
+    })
+}
+
+/// Encodes an event for the `event` column of the outbox
+pub(crate) fn encode_event(kind: &EventKind) -> IntResult<String> {
+    encode_json(kind)
+}
+
+/// Decodes a row of the outbox
+pub(crate) fn decode_event(id: u64, created_at: i64, event: &str) -> IntResult<Event> {
+    Ok(Event {
+        id,
+        created_at,
+        kind: decode_json(&format!("event {}", id), event)?,
+    })
+}
+
+/// Maps a unique-constraint violation on `users` to the column it concerns
+pub(crate) fn unique_violation(e: &DieselError) -> Option<IntErrorKind> {
+    match e {
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+            // MySQL does not report the constraint name, only a message in
+            // the form "Duplicate entry '<value>' for key '<key>'"
+            let key = info
+                .constraint_name()
+                .or_else(|| info.message().rsplit("for key").next())
+                .unwrap_or("");
+
+            if key.contains("email") {
+                Some(IntErrorKind::ExistingEmail)
+            } else if key.contains("username") {
+                Some(IntErrorKind::ExistingUser)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
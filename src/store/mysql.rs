@@ -0,0 +1,155 @@
+//! A [`UserStore`] backed by MySQL/MariaDB, using the queries in [`crate::db`]
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::MysqlConnection;
+
+use auth_client::{DeadLetter, Event, EventType, UserFilter};
+
+use super::{Client, PoolState, Role, User, UserChanges, UserStore, Webhook};
+use crate::db::{self, DbPool};
+use crate::migration;
+use crate::{IntErrorKind, IntResult};
+
+pub struct MysqlStore {
+    pool: DbPool,
+}
+
+impl MysqlStore {
+    /// Makes a store with a pool of connections to the database
+    pub fn connect(database_url: &str, pool_size: u32) -> IntResult<Self> {
+        let pool = db::setup_connection_pool(database_url, pool_size)?;
+        Ok(MysqlStore { pool })
+    }
+
+    fn conn(&self) -> IntResult<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        self.pool.get().map_err(|e| {
+            error!("Unable to get a database connection from the pool: {}", e);
+            IntErrorKind::ConnectionError.into()
+        })
+    }
+}
+
+impl UserStore for MysqlStore {
+    fn insert_user(&self, username: String, email: String, password: String) -> IntResult<User> {
+        db::insert_user(&*self.conn()?, username, email, password)
+    }
+
+    fn fetch_user(&self, username: &str) -> IntResult<User> {
+        db::fetch_user(&*self.conn()?, username)
+    }
+
+    fn fetch_user_by_id(&self, user_id: u32) -> IntResult<User> {
+        db::fetch_user_by_id(&*self.conn()?, user_id)
+    }
+
+    fn list_users(
+        &self,
+        filter: &UserFilter,
+        after: u32,
+        limit: u32,
+    ) -> IntResult<Vec<(User, Role)>> {
+        db::list_users(&*self.conn()?, filter, after, limit)
+    }
+
+    fn update_ban(&self, user_id: u32, banned: bool) -> IntResult<bool> {
+        db::update_ban(&*self.conn()?, user_id, banned)
+    }
+
+    fn update_verify(&self, user_id: u32, verified: bool) -> IntResult<bool> {
+        db::update_verify(&*self.conn()?, user_id, verified)
+    }
+
+    fn update_user(&self, user_id: u32, changes: &UserChanges) -> IntResult<User> {
+        db::update_user(&*self.conn()?, user_id, changes)
+    }
+
+    fn update_email_token(&self, user_id: u32, email_token: String) -> IntResult<bool> {
+        db::update_email_token(&*self.conn()?, user_id, email_token)
+    }
+
+    fn update_role(&self, user_id: u32, role: String) -> IntResult<bool> {
+        db::update_role(&*self.conn()?, user_id, role)
+    }
+
+    fn delete_user(&self, user_id: u32) -> IntResult<bool> {
+        db::delete_user(&*self.conn()?, user_id)
+    }
+
+    fn fetch_user_role(&self, user_id: u32) -> IntResult<Role> {
+        db::fetch_user_role(&*self.conn()?, user_id)
+    }
+
+    fn insert_client(&self, client: &Client) -> IntResult<()> {
+        db::insert_client(&*self.conn()?, client)
+    }
+
+    fn fetch_client(&self, client_id: &str) -> IntResult<Client> {
+        db::fetch_client(&*self.conn()?, client_id)
+    }
+
+    fn fetch_events(&self, after: u64, limit: u32) -> IntResult<Vec<Event>> {
+        db::fetch_events(&*self.conn()?, after, limit)
+    }
+
+    fn delete_events(&self, up_to: u64, created_before: i64) -> IntResult<usize> {
+        db::delete_events(&*self.conn()?, up_to, created_before)
+    }
+
+    fn fetch_sink_position(&self, sink: &str) -> IntResult<u64> {
+        db::fetch_sink_position(&*self.conn()?, sink)
+    }
+
+    fn update_sink_position(&self, sink: &str, position: u64) -> IntResult<()> {
+        db::update_sink_position(&*self.conn()?, sink, position)
+    }
+
+    fn insert_webhook(&self, url: &str, secret: &str, events: &[EventType]) -> IntResult<Webhook> {
+        db::insert_webhook(&*self.conn()?, url, secret, events)
+    }
+
+    fn fetch_webhooks(&self) -> IntResult<Vec<Webhook>> {
+        db::fetch_webhooks(&*self.conn()?)
+    }
+
+    fn delete_webhook(&self, webhook_id: u32) -> IntResult<bool> {
+        db::delete_webhook(&*self.conn()?, webhook_id)
+    }
+
+    fn insert_dead_letter(
+        &self,
+        webhook_id: u32,
+        event: &Event,
+        attempts: u32,
+        error: &str,
+    ) -> IntResult<()> {
+        db::insert_dead_letter(&*self.conn()?, webhook_id, event, attempts, error)
+    }
+
+    fn fetch_dead_letters(&self, webhook_id: Option<u32>) -> IntResult<Vec<DeadLetter>> {
+        db::fetch_dead_letters(&*self.conn()?, webhook_id)
+    }
+
+    fn update_dead_letter(&self, id: u64, error: &str) -> IntResult<bool> {
+        db::update_dead_letter(&*self.conn()?, id, error)
+    }
+
+    fn delete_dead_letter(&self, id: u64) -> IntResult<bool> {
+        db::delete_dead_letter(&*self.conn()?, id)
+    }
+
+    fn ping(&self) -> IntResult<()> {
+        self.conn().map(|_| ())
+    }
+
+    fn pending_migrations(&self) -> IntResult<usize> {
+        migration::pending(&*self.conn()?, migration::embedded::MYSQL)
+    }
+
+    fn pool_state(&self) -> Option<PoolState> {
+        let state = self.pool.state();
+        Some(PoolState {
+            idle: state.idle_connections,
+            in_use: state.connections - state.idle_connections,
+            max: self.pool.max_size(),
+        })
+    }
+}
@@ -0,0 +1,8 @@
+//! A [`UserStore`] backed by PostgreSQL
+//!
+//! The schema is found in `migrations_postgres/`.
+sql_store!(
+    PgStore,
+    diesel::PgConnection,
+    crate::migration::embedded::POSTGRES
+);
@@ -0,0 +1,776 @@
+//! Shared implementation of the SQLite and PostgreSQL stores
+//!
+//! Neither database has unsigned integers, so ids are stored as `Integer`
+//! and converted to the `u32` used by the rest of the service.
+
+pub mod schema {
+    table! {
+        dead_letters (id) {
+            id -> BigInt,
+            webhook_id -> Integer,
+            event -> Text,
+            attempts -> Integer,
+            error -> Text,
+            failed_at -> Timestamp,
+        }
+    }
+
+    table! {
+        event_sinks (name) {
+            name -> Varchar,
+            position -> BigInt,
+        }
+    }
+
+    table! {
+        oauth_clients (id) {
+            id -> Varchar,
+            name -> Varchar,
+            secret_hash -> Nullable<Varchar>,
+            redirect_uris -> Text,
+        }
+    }
+
+    table! {
+        outbox (id) {
+            id -> BigInt,
+            event -> Text,
+            created_at -> Timestamp,
+        }
+    }
+
+    table! {
+        roles (id) {
+            id -> Integer,
+            name -> Varchar,
+        }
+    }
+
+    table! {
+        users (id) {
+            id -> Integer,
+            email -> Varchar,
+            username -> Varchar,
+            password -> Text,
+            banned -> Bool,
+            verified -> Bool,
+            email_token -> Nullable<Varchar>,
+            created_at -> Timestamp,
+        }
+    }
+
+    table! {
+        webhooks (id) {
+            id -> Integer,
+            url -> Varchar,
+            secret -> Varchar,
+            events -> Text,
+            created_at -> Timestamp,
+        }
+    }
+
+    joinable!(roles -> users (id));
+
+    allow_tables_to_appear_in_same_query!(
+        dead_letters,
+        event_sinks,
+        oauth_clients,
+        outbox,
+        roles,
+        users,
+        webhooks,
+    );
+}
+
+use chrono::NaiveDateTime;
+
+use auth_client::DeadLetter;
+
+use self::schema::*;
+use super::{decode_json, Role, User, UserChanges, Webhook};
+use crate::IntResult;
+
+#[derive(Queryable)]
+pub struct UserRow {
+    pub id: i32,
+    pub email: String,
+    pub username: String,
+    pub password: String,
+    pub banned: bool,
+    pub verified: bool,
+    pub email_token: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<UserRow> for User {
+    fn from(row: UserRow) -> User {
+        User {
+            id: row.id as u32,
+            email: row.email,
+            username: row.username,
+            password: row.password,
+            banned: row.banned,
+            verified: row.verified,
+            email_token: row.email_token,
+            created_at: row.created_at,
+        }
+    }
+}
+
+#[derive(Queryable)]
+pub struct RoleRow {
+    pub id: i32,
+    pub name: String,
+}
+
+impl From<RoleRow> for Role {
+    fn from(row: RoleRow) -> Role {
+        Role {
+            id: row.id as u32,
+            name: row.name,
+        }
+    }
+}
+
+#[derive(Queryable)]
+pub struct WebhookRow {
+    pub id: i32,
+    pub url: String,
+    pub secret: String,
+    pub events: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl WebhookRow {
+    pub fn decode(self) -> IntResult<Webhook> {
+        Ok(Webhook {
+            id: self.id as u32,
+            events: decode_json(&format!("webhook {}", self.id), &self.events)?,
+            url: self.url,
+            secret: self.secret,
+            created_at: self.created_at.timestamp(),
+        })
+    }
+}
+
+#[derive(Queryable)]
+pub struct DeadLetterRow {
+    pub id: i64,
+    pub webhook_id: i32,
+    pub event: String,
+    pub attempts: i32,
+    pub error: String,
+    pub failed_at: NaiveDateTime,
+}
+
+impl DeadLetterRow {
+    pub fn decode(self) -> IntResult<DeadLetter> {
+        Ok(DeadLetter {
+            id: self.id as u64,
+            webhook_id: self.webhook_id as u32,
+            event: decode_json(&format!("dead letter {}", self.id), &self.event)?,
+            attempts: self.attempts as u32,
+            error: self.error,
+            failed_at: self.failed_at.timestamp(),
+        })
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "users"]
+pub struct NewUser<'a> {
+    pub email: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+/// The columns `UserChanges` sets, none are left as they are
+#[derive(AsChangeset)]
+#[table_name = "users"]
+pub struct UserChangeset<'a> {
+    pub username: Option<&'a str>,
+    pub email: Option<&'a str>,
+    pub verified: Option<bool>,
+    pub banned: Option<bool>,
+}
+
+impl<'a> From<&'a UserChanges> for UserChangeset<'a> {
+    fn from(changes: &'a UserChanges) -> UserChangeset<'a> {
+        UserChangeset {
+            username: changes.username.as_ref().map(|s| s.as_str()),
+            email: changes.email.as_ref().map(|s| s.as_str()),
+            verified: changes.verified,
+            banned: changes.banned,
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "roles"]
+pub struct NewRole<'a> {
+    pub id: i32,
+    pub name: &'a str,
+}
+
+/// Implements a pooled [`UserStore`] for a diesel connection type
+///
+/// The queries are the same as the ones in [`crate::db`], only written
+/// against the portable schema above.
+macro_rules! sql_store {
+    ($store:ident, $conn:ty, $migrations:expr) => {
+        use chrono::NaiveDateTime;
+        use diesel::prelude::*;
+        use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+        use failure::ResultExt;
+
+        use auth_client::{DeadLetter, Event, EventKind, EventType, UserFilter};
+
+        use crate::store::sql::schema::{
+            dead_letters, event_sinks, oauth_clients, outbox, roles, users, webhooks,
+        };
+        use crate::store::sql::{
+            DeadLetterRow, NewRole, NewUser, RoleRow, UserChangeset, UserRow, WebhookRow,
+        };
+        use crate::store::{
+            decode_event, encode_event, encode_json, lower, prefix_pattern, unique_violation,
+            webhook_sink, Client, PoolState, Role, User, UserChanges, UserStore, Webhook,
+        };
+        use crate::{IntError, IntErrorKind, IntResult};
+
+        pub struct $store {
+            pool: Pool<ConnectionManager<$conn>>,
+        }
+
+        impl $store {
+            /// Makes a store with a pool of connections to the database
+            pub fn connect(database_url: &str, pool_size: u32) -> IntResult<Self> {
+                let manager = ConnectionManager::<$conn>::new(database_url);
+                let pool = Pool::builder()
+                    .max_size(pool_size)
+                    .build(manager)
+                    .context(IntErrorKind::ConnectionError)?;
+                Ok($store { pool })
+            }
+
+            fn conn(&self) -> IntResult<PooledConnection<ConnectionManager<$conn>>> {
+                self.pool.get().map_err(|e| {
+                    error!("Unable to get a database connection from the pool: {}", e);
+                    IntErrorKind::ConnectionError.into()
+                })
+            }
+
+            fn update<F>(&self, what: &str, f: F) -> IntResult<bool>
+            where
+                F: FnOnce(&$conn) -> QueryResult<usize>,
+            {
+                let conn = self.conn()?;
+                let updated = f(&conn)
+                    .context(IntErrorKind::QueryError)
+                    .map_err(|e| {
+                        error!("Failed to update {}: {}", what, e);
+                        e
+                    })?;
+                Ok(updated > 0)
+            }
+
+            /// Like `update`, but also writes an event if a row was updated,
+            /// in the same transaction
+            fn update_with_event<F>(&self, what: &str, kind: EventKind, f: F) -> IntResult<bool>
+            where
+                F: FnOnce(&$conn) -> QueryResult<usize>,
+            {
+                let conn = self.conn()?;
+                conn.transaction::<_, IntError, _>(|| {
+                    let updated = f(&conn)
+                        .context(IntErrorKind::QueryError)
+                        .map_err(|e| {
+                            error!("Failed to update {}: {}", what, e);
+                            e
+                        })?;
+                    if updated > 0 {
+                        Self::insert_event(&conn, &kind)?;
+                    }
+                    Ok(updated > 0)
+                })
+            }
+
+            /// Sets the position of a sink, which must run in a transaction
+            ///
+            /// Neither database has an upsert diesel can write for both.
+            fn upsert_sink_position(
+                conn: &$conn,
+                sink: &str,
+                position: u64,
+            ) -> QueryResult<usize> {
+                let updated = diesel::update(event_sinks::table.find(sink))
+                    .set(event_sinks::position.eq(position as i64))
+                    .execute(conn)?;
+                if updated > 0 {
+                    return Ok(updated);
+                }
+                diesel::insert_into(event_sinks::table)
+                    .values((
+                        event_sinks::name.eq(sink),
+                        event_sinks::position.eq(position as i64),
+                    )).execute(conn)
+            }
+
+            /// Writes an event to the outbox, in the transaction of the change
+            /// it is about
+            fn insert_event(conn: &$conn, kind: &EventKind) -> IntResult<()> {
+                diesel::insert_into(outbox::table)
+                    .values(outbox::event.eq(encode_event(kind)?))
+                    .execute(conn)
+                    .context(IntErrorKind::QueryError)
+                    .map_err(|e| {
+                        error!("Unable to insert event: {}", e);
+                        e
+                    })?;
+                Ok(())
+            }
+        }
+
+        impl UserStore for $store {
+            fn insert_user(
+                &self,
+                username: String,
+                email: String,
+                password: String,
+            ) -> IntResult<User> {
+                let conn = self.conn()?;
+                let new_user = NewUser {
+                    email: &email,
+                    username: &username,
+                    password: &password,
+                };
+
+                conn.transaction::<_, IntError, _>(|| {
+                    diesel::insert_into(users::table)
+                        .values(&new_user)
+                        .execute(&*conn)
+                        .map_err(|e| match unique_violation(&e) {
+                            Some(kind) => {
+                                trace!("Unable to insert user: {}", kind);
+                                IntError::from(kind)
+                            }
+                            None => {
+                                error!("Unable to insert user: {}", e);
+                                e.into()
+                            }
+                        })?;
+
+                    let user: User = users::table
+                        .filter(users::username.eq(&username))
+                        .first::<UserRow>(&*conn)?
+                        .into();
+
+                    diesel::insert_into(roles::table)
+                        .values(&NewRole {
+                            id: user.id as i32,
+                            name: "user",
+                        }).execute(&*conn)
+                        .context(IntErrorKind::QueryError)
+                        .map_err(|e| {
+                            error!("Unable to insert user role: {}", e);
+                            e
+                        })?;
+
+                    Self::insert_event(
+                        &conn,
+                        &EventKind::UserRegistered {
+                            id: user.id.into(),
+                            username: user.username.clone(),
+                        },
+                    )?;
+
+                    Ok(user)
+                })
+            }
+
+            fn fetch_user(&self, username: &str) -> IntResult<User> {
+                users::table
+                    .filter(users::username.eq(username))
+                    .first::<UserRow>(&*self.conn()?)
+                    .optional()
+                    .context(IntErrorKind::QueryError)?
+                    .map(User::from)
+                    .ok_or(IntErrorKind::InvalidUsername)
+                    .map_err(|e| {
+                        error!("Unable to fetch user: {}", e);
+                        e.into()
+                    })
+            }
+
+            fn fetch_user_by_id(&self, user_id: u32) -> IntResult<User> {
+                users::table
+                    .find(user_id as i32)
+                    .first::<UserRow>(&*self.conn()?)
+                    .optional()
+                    .context(IntErrorKind::QueryError)?
+                    .map(User::from)
+                    .ok_or(IntErrorKind::InvalidUsername)
+                    .map_err(|e| {
+                        error!("Unable to fetch user: {}", e);
+                        e.into()
+                    })
+            }
+
+            fn list_users(
+                &self,
+                filter: &UserFilter,
+                after: u32,
+                limit: u32,
+            ) -> IntResult<Vec<(User, Role)>> {
+                let mut query = users::table
+                    .inner_join(roles::table)
+                    .filter(users::id.gt(after as i32))
+                    .order(users::id)
+                    .limit(i64::from(limit))
+                    .into_boxed();
+                if let Some(ref prefix) = filter.username_prefix {
+                    let pattern = prefix_pattern(prefix);
+                    query = query.filter(lower(users::username).like(pattern).escape('\\'));
+                }
+                if let Some(ref prefix) = filter.email_prefix {
+                    let pattern = prefix_pattern(prefix);
+                    query = query.filter(lower(users::email).like(pattern).escape('\\'));
+                }
+                if let Some(role) = filter.role {
+                    let role: String = role.into();
+                    query = query.filter(roles::name.eq(role));
+                }
+                if let Some(banned) = filter.banned {
+                    query = query.filter(users::banned.eq(banned));
+                }
+                if let Some(verified) = filter.verified {
+                    query = query.filter(users::verified.eq(verified));
+                }
+                if let Some(created_after) = filter.created_after {
+                    let created_after = NaiveDateTime::from_timestamp(created_after, 0);
+                    query = query.filter(users::created_at.ge(created_after));
+                }
+                if let Some(created_before) = filter.created_before {
+                    let created_before = NaiveDateTime::from_timestamp(created_before, 0);
+                    query = query.filter(users::created_at.lt(created_before));
+                }
+
+                let rows = query
+                    .load::<(UserRow, RoleRow)>(&*self.conn()?)
+                    .context(IntErrorKind::QueryError)
+                    .map_err(|e| {
+                        error!("Unable to list users: {}", e);
+                        e
+                    })?;
+                Ok(rows
+                    .into_iter()
+                    .map(|(user, role)| (User::from(user), Role::from(role)))
+                    .collect())
+            }
+
+            fn update_ban(&self, user_id: u32, banned: bool) -> IntResult<bool> {
+                let event = EventKind::UserBanned {
+                    id: user_id.into(),
+                    banned,
+                };
+                self.update_with_event("banned status", event, |conn| {
+                    diesel::update(users::table.find(user_id as i32))
+                        .set(users::banned.eq(banned))
+                        .execute(conn)
+                })
+            }
+
+            fn update_verify(&self, user_id: u32, verified: bool) -> IntResult<bool> {
+                let event = EventKind::UserVerified {
+                    id: user_id.into(),
+                    verified,
+                };
+                self.update_with_event("verified status", event, |conn| {
+                    diesel::update(users::table.find(user_id as i32))
+                        .set(users::verified.eq(verified))
+                        .execute(conn)
+                })
+            }
+
+            fn update_user(&self, user_id: u32, changes: &UserChanges) -> IntResult<User> {
+                let conn = self.conn()?;
+                conn.transaction::<_, IntError, _>(|| {
+                    let previous: User = users::table
+                        .find(user_id as i32)
+                        .first::<UserRow>(&*conn)
+                        .optional()
+                        .context(IntErrorKind::QueryError)?
+                        .map(User::from)
+                        .ok_or(IntErrorKind::InvalidUsername)?;
+                    // Diesel refuses a changeset without columns
+                    if !changes.is_empty() {
+                        diesel::update(users::table.find(user_id as i32))
+                            .set(&UserChangeset::from(changes))
+                            .execute(&*conn)
+                            .map_err(|e| match unique_violation(&e) {
+                                Some(kind) => {
+                                    trace!("Unable to update user: {}", kind);
+                                    IntError::from(kind)
+                                }
+                                None => {
+                                    error!("Unable to update user: {}", e);
+                                    e.into()
+                                }
+                            })?;
+                    }
+
+                    for kind in changes.events(&previous) {
+                        Self::insert_event(&conn, &kind)?;
+                    }
+                    Ok(previous)
+                })
+            }
+
+            fn update_email_token(&self, user_id: u32, email_token: String) -> IntResult<bool> {
+                self.update("email token", |conn| {
+                    diesel::update(users::table.find(user_id as i32))
+                        .set(users::email_token.eq(email_token))
+                        .execute(conn)
+                })
+            }
+
+            fn update_role(&self, user_id: u32, role: String) -> IntResult<bool> {
+                let event = EventKind::RoleChanged {
+                    id: user_id.into(),
+                    role: role.as_str().into(),
+                };
+                self.update_with_event("user role", event, |conn| {
+                    diesel::update(roles::table.find(user_id as i32))
+                        .set(roles::name.eq(role))
+                        .execute(conn)
+                })
+            }
+
+            fn delete_user(&self, user_id: u32) -> IntResult<bool> {
+                let event = EventKind::UserDeleted { id: user_id.into() };
+                self.update_with_event("deleted user", event, |conn| {
+                    diesel::delete(roles::table.find(user_id as i32)).execute(conn)?;
+                    diesel::delete(users::table.find(user_id as i32)).execute(conn)
+                })
+            }
+
+            fn fetch_user_role(&self, user_id: u32) -> IntResult<Role> {
+                roles::table
+                    .find(user_id as i32)
+                    .first::<RoleRow>(&*self.conn()?)
+                    .optional()
+                    .context(IntErrorKind::QueryError)?
+                    .map(Role::from)
+                    .ok_or(IntErrorKind::ServerError)
+                    .map_err(|e| {
+                        error!("Failed to fetch user role: {}", e);
+                        e.into()
+                    })
+            }
+
+            fn insert_client(&self, client: &Client) -> IntResult<()> {
+                diesel::insert_into(oauth_clients::table)
+                    .values((
+                        oauth_clients::id.eq(&client.id),
+                        oauth_clients::name.eq(&client.name),
+                        oauth_clients::secret_hash.eq(&client.secret_hash),
+                        oauth_clients::redirect_uris.eq(&client.redirect_uris),
+                    )).execute(&*self.conn()?)
+                    .context(IntErrorKind::QueryError)
+                    .map_err(|e| {
+                        error!("Unable to insert oauth client: {}", e);
+                        e
+                    })?;
+                Ok(())
+            }
+
+            fn fetch_client(&self, client_id: &str) -> IntResult<Client> {
+                oauth_clients::table
+                    .find(client_id)
+                    .first::<Client>(&*self.conn()?)
+                    .optional()
+                    .context(IntErrorKind::QueryError)?
+                    .ok_or_else(|| {
+                        trace!("No oauth client found");
+                        IntErrorKind::InvalidClient.into()
+                    })
+            }
+
+            fn fetch_events(&self, after: u64, limit: u32) -> IntResult<Vec<Event>> {
+                outbox::table
+                    .filter(outbox::id.gt(after as i64))
+                    .order(outbox::id)
+                    .limit(i64::from(limit))
+                    .load::<(i64, String, NaiveDateTime)>(&*self.conn()?)
+                    .context(IntErrorKind::QueryError)?
+                    .into_iter()
+                    .map(|(id, event, created_at)| {
+                        decode_event(id as u64, created_at.timestamp(), &event)
+                    }).collect()
+            }
+
+            fn delete_events(&self, up_to: u64, created_before: i64) -> IntResult<usize> {
+                let created_before = NaiveDateTime::from_timestamp(created_before, 0);
+                let deleted = diesel::delete(
+                    outbox::table
+                        .filter(outbox::id.le(up_to as i64))
+                        .filter(outbox::created_at.lt(created_before)),
+                ).execute(&*self.conn()?)
+                .context(IntErrorKind::QueryError)
+                .map_err(|e| {
+                    error!("Failed to delete events: {}", e);
+                    e
+                })?;
+                Ok(deleted)
+            }
+
+            fn fetch_sink_position(&self, sink: &str) -> IntResult<u64> {
+                Ok(event_sinks::table
+                    .find(sink)
+                    .select(event_sinks::position)
+                    .first::<i64>(&*self.conn()?)
+                    .optional()
+                    .context(IntErrorKind::QueryError)?
+                    .map(|position| position as u64)
+                    .unwrap_or(0))
+            }
+
+            fn update_sink_position(&self, sink: &str, position: u64) -> IntResult<()> {
+                self.update("sink position", |conn| {
+                    conn.transaction(|| Self::upsert_sink_position(conn, sink, position))
+                }).map(|_| ())
+            }
+
+            fn insert_webhook(
+                &self,
+                url: &str,
+                secret: &str,
+                events: &[EventType],
+            ) -> IntResult<Webhook> {
+                let conn = self.conn()?;
+                let encoded_events = encode_json(&events)?;
+
+                conn.transaction::<_, IntError, _>(|| {
+                    diesel::insert_into(webhooks::table)
+                        .values((
+                            webhooks::url.eq(url),
+                            webhooks::secret.eq(secret),
+                            webhooks::events.eq(&encoded_events),
+                        )).execute(&*conn)
+                        .context(IntErrorKind::QueryError)
+                        .map_err(|e| {
+                            error!("Unable to insert webhook: {}", e);
+                            e
+                        })?;
+                    // The secret is random, so it finds the new row
+                    let webhook = webhooks::table
+                        .filter(webhooks::secret.eq(secret))
+                        .first::<WebhookRow>(&*conn)
+                        .context(IntErrorKind::QueryError)?
+                        .decode()?;
+
+                    let last_event = outbox::table
+                        .select(diesel::dsl::max(outbox::id))
+                        .first::<Option<i64>>(&*conn)
+                        .context(IntErrorKind::QueryError)?;
+                    let position = last_event.unwrap_or(0) as u64;
+                    Self::upsert_sink_position(&conn, &webhook_sink(webhook.id), position)
+                        .context(IntErrorKind::QueryError)?;
+                    Ok(webhook)
+                })
+            }
+
+            fn fetch_webhooks(&self) -> IntResult<Vec<Webhook>> {
+                webhooks::table
+                    .order(webhooks::id)
+                    .load::<WebhookRow>(&*self.conn()?)
+                    .context(IntErrorKind::QueryError)?
+                    .into_iter()
+                    .map(WebhookRow::decode)
+                    .collect()
+            }
+
+            fn delete_webhook(&self, webhook_id: u32) -> IntResult<bool> {
+                self.update("deleted webhook", |conn| {
+                    conn.transaction(|| {
+                        diesel::delete(
+                            dead_letters::table
+                                .filter(dead_letters::webhook_id.eq(webhook_id as i32)),
+                        ).execute(conn)?;
+                        diesel::delete(event_sinks::table.find(webhook_sink(webhook_id)))
+                            .execute(conn)?;
+                        diesel::delete(webhooks::table.find(webhook_id as i32)).execute(conn)
+                    })
+                })
+            }
+
+            fn insert_dead_letter(
+                &self,
+                webhook_id: u32,
+                event: &Event,
+                attempts: u32,
+                error: &str,
+            ) -> IntResult<()> {
+                diesel::insert_into(dead_letters::table)
+                    .values((
+                        dead_letters::webhook_id.eq(webhook_id as i32),
+                        dead_letters::event.eq(encode_json(event)?),
+                        dead_letters::attempts.eq(attempts as i32),
+                        dead_letters::error.eq(error),
+                    )).execute(&*self.conn()?)
+                    .context(IntErrorKind::QueryError)
+                    .map_err(|e| {
+                        error!("Unable to insert dead letter: {}", e);
+                        e
+                    })?;
+                Ok(())
+            }
+
+            fn fetch_dead_letters(&self, webhook_id: Option<u32>) -> IntResult<Vec<DeadLetter>> {
+                let mut query = dead_letters::table.order(dead_letters::id).into_boxed();
+                if let Some(webhook_id) = webhook_id {
+                    query = query.filter(dead_letters::webhook_id.eq(webhook_id as i32));
+                }
+                query
+                    .load::<DeadLetterRow>(&*self.conn()?)
+                    .context(IntErrorKind::QueryError)?
+                    .into_iter()
+                    .map(DeadLetterRow::decode)
+                    .collect()
+            }
+
+            fn update_dead_letter(&self, id: u64, error: &str) -> IntResult<bool> {
+                self.update("dead letter", |conn| {
+                    diesel::update(dead_letters::table.find(id as i64))
+                        .set((
+                            dead_letters::attempts.eq(dead_letters::attempts + 1),
+                            dead_letters::error.eq(error),
+                            dead_letters::failed_at.eq(diesel::dsl::now),
+                        )).execute(conn)
+                })
+            }
+
+            fn delete_dead_letter(&self, id: u64) -> IntResult<bool> {
+                self.update("delivered dead letter", |conn| {
+                    diesel::delete(dead_letters::table.find(id as i64)).execute(conn)
+                })
+            }
+
+            fn ping(&self) -> IntResult<()> {
+                self.conn().map(|_| ())
+            }
+
+            fn pending_migrations(&self) -> IntResult<usize> {
+                crate::migration::pending(&*self.conn()?, $migrations)
+            }
+
+            fn pool_state(&self) -> Option<PoolState> {
+                let state = self.pool.state();
+                Some(PoolState {
+                    idle: state.idle_connections,
+                    in_use: state.connections - state.idle_connections,
+                    max: self.pool.max_size(),
+                })
+            }
+        }
+    };
+}
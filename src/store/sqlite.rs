@@ -0,0 +1,8 @@
+//! A [`UserStore`] backed by a SQLite database file
+//!
+//! The schema is found in `migrations_sqlite/`.
+sql_store!(
+    SqliteStore,
+    diesel::SqliteConnection,
+    crate::migration::embedded::SQLITE
+);
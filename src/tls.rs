@@ -0,0 +1,488 @@
+//! TLS for the rpc listener (feature `tls`)
+//!
+//! tarpc only speaks plain TCP, so the listeners on `server.address` end the
+//! TLS session and serve the rpcs on the connection themselves, see
+//! [`crate::connection`]. Each connection gets the server its client may use:
+//!
+//! - the privileged one, which serves every rpc
+//! - the restricted one, where privileged rpcs like `set_user_role` fail
+//!
+//! Without `tls.client_ca` every client gets the privileged server, like on
+//! plain TCP. With it, clients must send a certificate signed by one of those
+//! CAs, and are told apart by the names in it, the common name and the DNS
+//! names. `tls.allowed_clients` limits who may connect at all, and
+//! `tls.privileged_clients` who gets the privileged server.
+//!
+//! The certificate, the key and the CAs are read again on SIGHUP. Connections
+//! which are already open keep the old ones.
+use failure::ResultExt;
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::{X509Name, X509NameRef, X509Ref};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use tokio_openssl::SslAcceptorExt;
+
+use crate::config::TlsConfig;
+use crate::connection::{self, Tracker};
+use crate::service::AuthServer;
+use crate::{IntErrorKind, IntResult};
+
+/// What a client may do, decided by its certificate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Denied,
+    Restricted,
+    Privileged,
+}
+
+/// Which clients may connect, and which may call privileged rpcs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    mutual: bool,
+    allowed: Vec<String>,
+    privileged: Vec<String>,
+}
+
+impl Policy {
+    pub fn new(config: &TlsConfig) -> Policy {
+        Policy {
+            mutual: !config.client_ca.is_empty(),
+            allowed: config.allowed_clients.clone(),
+            privileged: config.privileged_clients.clone(),
+        }
+    }
+
+    /// Decides by the names in the certificate of a client
+    pub fn access(&self, names: &[String]) -> Access {
+        if !self.mutual {
+            return Access::Privileged;
+        }
+        let listed = |list: &[String]| names.iter().any(|name| list.contains(name));
+
+        if !self.allowed.is_empty() && !listed(&self.allowed) {
+            Access::Denied
+        } else if self.privileged.is_empty() || listed(&self.privileged) {
+            Access::Privileged
+        } else {
+            Access::Restricted
+        }
+    }
+}
+
+/// The TLS listener, which can be cloned to reload it from another task
+#[derive(Clone)]
+pub struct TlsListener {
+    config: TlsConfig,
+    acceptor: Arc<RwLock<SslAcceptor>>,
+    policy: Arc<Policy>,
+}
+
+impl TlsListener {
+    /// Reads the certificate, the key and the CAs
+    pub fn new(config: &TlsConfig) -> IntResult<TlsListener> {
+        Ok(TlsListener {
+            config: config.clone(),
+            acceptor: Arc::new(RwLock::new(acceptor(config)?)),
+            policy: Arc::new(Policy::new(config)),
+        })
+    }
+
+    /// Reads the files again, the old ones are kept if that fails
+    pub fn reload(&self) {
+        let reloaded = match acceptor(&self.config) {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                error!("Unable to reload the TLS certificate, keeping the old one: {}", e);
+                return;
+            }
+        };
+        match self.acceptor.write() {
+            Ok(mut acceptor) => {
+                *acceptor = reloaded;
+                info!("Reloaded the TLS certificate");
+            }
+            Err(e) => error!("Unable to write to 'acceptor': {}", e),
+        }
+    }
+
+    /// Accepts TLS connections on `address`, and serves `server` on them, or
+    /// the unprivileged `server` for restricted clients, until `tracker`
+    /// stops it
+    pub fn serve(
+        &self,
+        address: SocketAddr,
+        server: &AuthServer,
+        tracker: &Tracker,
+        handle: &Handle,
+    ) -> IntResult<impl Future<Item = (), Error = ()>> {
+        let listener = TcpListener::bind(&address, handle).context(IntErrorKind::ServerError)?;
+        Ok(tracker.accept(self.accept(listener, server, tracker, handle)))
+    }
+
+    /// Accepts the TLS connections of `listener`, see [`TlsListener::serve`]
+    fn accept(
+        &self,
+        listener: TcpListener,
+        server: &AuthServer,
+        tracker: &Tracker,
+        handle: &Handle,
+    ) -> impl Future<Item = (), Error = ()> {
+        let listener_handle = handle.clone();
+        let tls = self.clone();
+        let server = server.clone();
+        let tracker = tracker.clone();
+
+        listener
+            .incoming()
+            .map_err(|e| error!("TLS listener failed: {}", e))
+            .for_each(move |(stream, peer)| {
+                let acceptor = match tls.acceptor.read() {
+                    Ok(acceptor) => acceptor.clone(),
+                    Err(e) => {
+                        error!("Unable to read 'acceptor': {}", e);
+                        return Ok(());
+                    }
+                };
+                let policy = tls.policy.clone();
+                let server = server.clone();
+
+                let connection = acceptor
+                    .accept_async(stream)
+                    .map_err(move |e| debug!("TLS handshake with {} failed: {}", peer, e))
+                    .and_then(move |stream| {
+                        let client = stream
+                            .get_ref()
+                            .ssl()
+                            .peer_certificate()
+                            .map(|cert| names(&cert))
+                            .unwrap_or_default();
+                        let server = match policy.access(&client) {
+                            Access::Denied => {
+                                warn!("Refused client {:?} from {}", client, peer);
+                                return Either::A(future::ok(()));
+                            }
+                            Access::Restricted => server.unprivileged(),
+                            Access::Privileged => server,
+                        };
+                        debug!("Accepted client {:?} from {}", client, peer);
+
+                        Either::B(connection::serve(stream, server))
+                    });
+                listener_handle.spawn(tracker.connection(connection));
+                Ok(())
+            })
+    }
+}
+
+/// Makes an acceptor from the files in the configuration
+pub fn acceptor(config: &TlsConfig) -> IntResult<SslAcceptor> {
+    let tls_error = |e: ErrorStack, file: &str| {
+        error!("Unable to use '{}': {}", file, e);
+        IntErrorKind::TlsError
+    };
+
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .context(IntErrorKind::TlsError)?;
+    builder
+        .set_certificate_chain_file(&config.cert)
+        .map_err(|e| tls_error(e, &config.cert))?;
+    builder
+        .set_private_key_file(&config.key, SslFiletype::PEM)
+        .map_err(|e| tls_error(e, &config.key))?;
+    builder
+        .check_private_key()
+        .map_err(|e| tls_error(e, &config.key))?;
+
+    if !config.client_ca.is_empty() {
+        builder
+            .set_ca_file(&config.client_ca)
+            .map_err(|e| tls_error(e, &config.client_ca))?;
+        // Tells clients which certificates are accepted
+        let names = X509Name::load_client_ca_file(&config.client_ca)
+            .map_err(|e| tls_error(e, &config.client_ca))?;
+        builder.set_client_ca_list(names);
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    Ok(builder.build())
+}
+
+/// The common name and the DNS names of a certificate
+pub fn names(cert: &X509Ref) -> Vec<String> {
+    let mut names: Vec<String> = common_name(cert.subject_name()).into_iter().collect();
+    if let Some(alt_names) = cert.subject_alt_names() {
+        names.extend(
+            alt_names
+                .iter()
+                .filter_map(|name| name.dnsname())
+                .map(|name| name.to_owned()),
+        );
+    }
+    names
+}
+
+fn common_name(name: &X509NameRef) -> Option<String> {
+    name.entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|cn| cn.to_string())
+}
+
+/// A self signed certificate with the given common name and DNS names, and
+/// its key, in PEM
+#[cfg(test)]
+pub fn certificate(common_name: &str, dns_names: &[&str]) -> (Vec<u8>, Vec<u8>) {
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+        .unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    if !dns_names.is_empty() {
+        let mut alt_names = SubjectAlternativeName::new();
+        for name in dns_names {
+            alt_names.dns(name);
+        }
+        let extension = alt_names.build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(extension).unwrap();
+    }
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let cert = builder.build().to_pem().unwrap();
+    (cert, key.private_key_to_pem_pkcs8().unwrap())
+}
+
+#[test]
+fn test_policy() {
+    let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    let mut config = TlsConfig::default();
+    config.cert = "server.pem".to_owned();
+    assert_eq!(Access::Privileged, Policy::new(&config).access(&[]));
+
+    config.client_ca = "clients.pem".to_owned();
+    assert_eq!(Access::Privileged, Policy::new(&config).access(&names(&["forum"])));
+
+    config.allowed_clients = names(&["forum", "admin"]);
+    config.privileged_clients = names(&["admin"]);
+    let policy = Policy::new(&config);
+    assert_eq!(Access::Denied, policy.access(&names(&["billing"])));
+    assert_eq!(Access::Restricted, policy.access(&names(&["forum"])));
+    assert_eq!(Access::Privileged, policy.access(&names(&["web", "admin"])));
+}
+
+#[test]
+fn test_acceptor_and_names() {
+    use openssl::x509::X509;
+    use std::fs;
+
+    let (cert, key) = certificate("auth", &["auth.internal", "localhost"]);
+    assert_eq!(
+        vec!["auth", "auth.internal", "localhost"],
+        names(&X509::from_pem(&cert).unwrap())
+    );
+
+    let dir = std::env::temp_dir().join(format!("auth-service-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    fs::write(path("server.pem"), &cert).unwrap();
+    fs::write(path("server.key"), &key).unwrap();
+    let clients = certificate("clients", &[]);
+    fs::write(path("clients.pem"), &clients.0).unwrap();
+
+    let mut config = TlsConfig {
+        cert: path("server.pem"),
+        key: path("server.key"),
+        client_ca: path("clients.pem"),
+        ..TlsConfig::default()
+    };
+    let listener = TlsListener::new(&config).unwrap();
+
+    // A broken file keeps the old certificate
+    config.key = path("clients.pem");
+    assert!(acceptor(&config).is_err());
+    let broken = TlsListener {
+        config,
+        ..listener.clone()
+    };
+    broken.reload();
+    let acceptor = listener.acceptor.read().unwrap().clone();
+    assert_eq!(cert, presented(&acceptor, &clients));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The certificate `acceptor` presents to a client with the certificate and
+/// key `client`, in PEM
+#[cfg(test)]
+fn presented(acceptor: &SslAcceptor, client: &(Vec<u8>, Vec<u8>)) -> Vec<u8> {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let connector = connector(client);
+    let client = thread::spawn(move || {
+        let stream = TcpStream::connect(address).unwrap();
+        let stream = connector.connect("localhost", stream).unwrap();
+        stream.ssl().peer_certificate().unwrap().to_pem().unwrap()
+    });
+    let _stream = acceptor.accept(listener.accept().unwrap().0).unwrap();
+    client.join().unwrap()
+}
+
+/// A connector which sends the certificate and key `client`, and trusts any
+/// server
+#[cfg(test)]
+fn connector(client: &(Vec<u8>, Vec<u8>)) -> openssl::ssl::SslConnector {
+    use openssl::pkey::PKey;
+    use openssl::ssl::SslConnector;
+    use openssl::x509::X509;
+
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder
+        .set_certificate(&X509::from_pem(&client.0).unwrap())
+        .unwrap();
+    builder
+        .set_private_key(&PKey::private_key_from_pem(&client.1).unwrap())
+        .unwrap();
+    builder.set_verify(SslVerifyMode::NONE);
+    builder.build()
+}
+
+/// Copies bytes both ways until both sides are closed
+#[cfg(test)]
+fn proxy<A, B>(client: A, upstream: B) -> impl Future<Item = (), Error = ()>
+where
+    A: tokio_io::AsyncRead + tokio_io::AsyncWrite,
+    B: tokio_io::AsyncRead + tokio_io::AsyncWrite,
+{
+    use tokio_io::io::{copy, shutdown};
+    use tokio_io::AsyncRead;
+
+    let (client_read, client_write) = client.split();
+    let (upstream_read, upstream_write) = upstream.split();
+
+    let requests = copy(client_read, upstream_write).and_then(|(_, _, w)| shutdown(w));
+    let responses = copy(upstream_read, client_write).and_then(|(_, _, w)| shutdown(w));
+    requests
+        .join(responses)
+        .map(|_| ())
+        .map_err(|e| debug!("Connection closed: {}", e))
+}
+
+#[test]
+fn test_restricted_client() {
+    use crate::config::Config;
+    use crate::store::memory::MemoryStore;
+    use auth_client::{BlockingClient, Error, Options};
+    use datatypes::auth::requests::{RegisterUserPayload, SetUserRolePayload};
+    use datatypes::auth::responses::AuthError;
+    use std::convert::TryInto;
+    use std::fs;
+    use std::sync::mpsc;
+    use std::thread;
+    use tokio_core::net::TcpStream;
+    use tokio_openssl::SslConnectorExt;
+
+    let dir = std::env::temp_dir().join(format!("auth-service-mtls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    let (cert, key) = certificate("auth", &["localhost"]);
+    fs::write(path("server.pem"), &cert).unwrap();
+    fs::write(path("server.key"), &key).unwrap();
+    let admin = certificate("admin", &[]);
+    let forum = certificate("forum", &[]);
+    fs::write(path("clients.pem"), [&admin.0[..], &forum.0[..]].concat()).unwrap();
+
+    let mut config = Config::default();
+    config.security.hash_cycles = 1000;
+    config.tls = TlsConfig {
+        cert: path("server.pem"),
+        key: path("server.key"),
+        client_ca: path("clients.pem"),
+        allowed_clients: vec!["admin".to_owned(), "forum".to_owned()],
+        privileged_clients: vec!["admin".to_owned()],
+        ..TlsConfig::default()
+    };
+    let tls = TlsListener::new(&config.tls).unwrap();
+    let server = AuthServer::with_store(&config, Arc::new(MemoryStore::default()));
+    fs::remove_dir_all(&dir).unwrap();
+
+    // BlockingClient only speaks plain TCP, so each client gets a listener
+    // which forwards its connections over TLS with its certificate
+    let (address_tx, address_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+        let loopback = ([127, 0, 0, 1], 0).into();
+        let listener = TcpListener::bind(&loopback, &handle).unwrap();
+        let target = listener.local_addr().unwrap();
+        let (tracker, _) = connection::tracker();
+        handle.spawn(tls.accept(listener, &server, &tracker, &handle));
+
+        for client in &[admin, forum] {
+            let connector = connector(client);
+            let bridge = TcpListener::bind(&loopback, &handle).unwrap();
+            address_tx.send(bridge.local_addr().unwrap()).unwrap();
+            let bridge_handle = handle.clone();
+            handle.spawn(
+                bridge
+                    .incoming()
+                    .for_each(move |(stream, _)| {
+                        let connector = connector.clone();
+                        let connection = TcpStream::connect(&target, &bridge_handle)
+                            .map_err(|e| panic!("{}", e))
+                            .and_then(move |upstream| {
+                                connector
+                                    .connect_async("localhost", upstream)
+                                    .map_err(|e| panic!("{}", e))
+                            }).and_then(|upstream| proxy(stream, upstream));
+                        bridge_handle.spawn(connection);
+                        Ok(())
+                    }).map_err(|e| panic!("{}", e)),
+            );
+        }
+        core.run(future::empty::<(), ()>()).unwrap();
+    });
+    let mut admin = BlockingClient::new(address_rx.recv().unwrap(), Options::default()).unwrap();
+    let mut forum = BlockingClient::new(address_rx.recv().unwrap(), Options::default()).unwrap();
+
+    let user = forum
+        .register(RegisterUserPayload {
+            username: "mtls".to_owned().try_into().unwrap_or_else(|_| panic!("username")),
+            password: "Mtl5-Passw0rd".to_owned().try_into().unwrap_or_else(|_| panic!("password")),
+            email: "mtls@example.com".to_owned().try_into().unwrap_or_else(|_| panic!("email")),
+        }).unwrap();
+    let role = || SetUserRolePayload {
+        id: user.id.into(),
+        role: "moderator".into(),
+    };
+    match forum.set_user_role(role()) {
+        Err(Error::Service(AuthError::InvalidToken, _, _)) => {}
+        other => panic!("expected InvalidToken, got {:?}", other),
+    }
+    admin.set_user_role(role()).unwrap();
+}
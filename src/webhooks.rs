@@ -0,0 +1,215 @@
+//! Webhooks, which get the events they are subscribed to posted to them
+//!
+//! Webhooks are added with the `add_webhook` rpc and kept in the store, the
+//! dispatcher of [`crate::events`] picks them up like the configured sinks.
+//! A webhook starts at the end of the outbox, and gets only the types of
+//! events it chose. Every delivery carries these headers:
+//!
+//! - `X-Auth-Event-Id`: the id of the event, which is delivered again until
+//! it is accepted, so receivers should ignore ids they have seen.
+//! - `X-Auth-Timestamp`: when the delivery was signed, in seconds since the
+//! epoch. Receivers should refuse old ones, so a delivery can not be replayed
+//! by someone who got hold of it.
+//! - `X-Auth-Signature`: `sha256=` and the hex HMAC-SHA256 of
+//! `<timestamp>.<body>` keyed with the secret of the webhook.
+//!
+//! An event which is still refused after `events.webhook_attempts` attempts
+//! becomes a dead letter, and the webhook moves on to the next event. Dead
+//! letters are kept until `replay_dead_letters` delivers them.
+use chrono::Utc;
+use failure::ResultExt;
+use hmac::{Hmac, Mac};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, Uri};
+use rand::{thread_rng, Rng};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Core;
+
+use auth_client::ReplayReport;
+
+use crate::events::{self, Target};
+use crate::store::UserStore;
+use crate::{IntErrorKind, IntResult};
+
+pub const EVENT_ID_HEADER: &str = "x-auth-event-id";
+pub const TIMESTAMP_HEADER: &str = "x-auth-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-auth-signature";
+
+/// Longest url a webhook may have, the size of its column
+pub const MAX_URL_LENGTH: usize = 2048;
+
+/// Seconds `replay` may take, well within the default timeout of the clients
+const REPLAY_BUDGET_SECS: u64 = 3;
+
+/// A secret for a new webhook
+pub fn new_secret() -> String {
+    let mut random_bytes = [0u8; 32];
+    thread_rng().fill(&mut random_bytes[..]);
+    base64::encode_config(&random_bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Parses the url of a webhook, which must be `http://` or `https://`
+pub fn parse_url(url: &str) -> Result<Uri, String> {
+    if url.len() > MAX_URL_LENGTH {
+        return Err(format!("url is longer than {} bytes", MAX_URL_LENGTH));
+    }
+    match Target::parse(url)? {
+        Target::Http(uri) => Ok(uri),
+        Target::Tarpc(_) => Err(format!(
+            "url '{}' must start with 'http://' or 'https://'",
+            url
+        )),
+    }
+}
+
+/// The value of `X-Auth-Signature` for a body signed at `timestamp`
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    // Keys of any length are fine for HMAC
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("any key length");
+    mac.input(timestamp.to_string().as_bytes());
+    mac.input(b".");
+    mac.input(body);
+
+    let mut signature = "sha256=".to_owned();
+    for byte in mac.result().code() {
+        let _ = write!(signature, "{:02x}", byte);
+    }
+    signature
+}
+
+/// Adds the id of the event, the timestamp and the signature to a delivery
+pub fn sign(headers: &mut HeaderMap, secret: &str, event_id: u64, body: &[u8]) {
+    let timestamp = Utc::now().timestamp();
+    let signature = signature(secret, timestamp, body);
+    headers.insert(HeaderName::from_static(EVENT_ID_HEADER), event_id.into());
+    headers.insert(HeaderName::from_static(TIMESTAMP_HEADER), timestamp.into());
+    // Only ascii is written to the signature
+    if let Ok(signature) = HeaderValue::from_str(&signature) {
+        headers.insert(HeaderName::from_static(SIGNATURE_HEADER), signature);
+    }
+}
+
+/// Delivers the dead letters of a webhook, or of every webhook, again
+///
+/// Letters which are accepted are deleted, the others stay with one more
+/// attempt. Gives up after `REPLAY_BUDGET_SECS`, the letters which were not
+/// tried by then are reported as pending.
+pub fn replay(store: &UserStore, webhook_id: Option<u32>) -> IntResult<ReplayReport> {
+    replay_within(store, webhook_id, Duration::from_secs(REPLAY_BUDGET_SECS))
+}
+
+fn replay_within(
+    store: &UserStore,
+    webhook_id: Option<u32>,
+    budget: Duration,
+) -> IntResult<ReplayReport> {
+    let deadline = Instant::now() + budget;
+    let letters = store.fetch_dead_letters(webhook_id)?;
+    let mut report = ReplayReport::default();
+    if letters.is_empty() {
+        return Ok(report);
+    }
+
+    let webhooks = store.fetch_webhooks()?;
+    let mut core = Core::new().context(IntErrorKind::ServerError)?;
+    let http = events::http_client(&core)?;
+    let total = letters.len();
+    for (tried, letter) in letters.into_iter().enumerate() {
+        let now = Instant::now();
+        if now >= deadline {
+            report.pending = (total - tried) as u32;
+            break;
+        }
+        let webhook = match webhooks.iter().find(|w| w.id == letter.webhook_id) {
+            Some(webhook) => webhook,
+            // Removed since the letters were read
+            None => continue,
+        };
+        let delivered = parse_url(&webhook.url).and_then(|uri| {
+            events::post(
+                &mut core,
+                &http,
+                &uri,
+                &letter.event,
+                Some(webhook.secret.as_str()),
+                deadline - now,
+            )
+        });
+        match delivered {
+            Ok(()) => {
+                info!("Replayed event {} to webhook {}", letter.event.id, webhook.id);
+                store.delete_dead_letter(letter.id)?;
+                report.delivered += 1;
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to replay event {} to webhook {}: {}",
+                    letter.event.id, webhook.id, e
+                );
+                store.update_dead_letter(letter.id, &e)?;
+                report.failed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[test]
+fn test_signature() {
+    assert_eq!(
+        "sha256=609971a88c442686c6b01752415b6ceedfb39908e2d249e6fa73a2de2937351f",
+        signature("secret", 1543665600, br#"{"id":1}"#)
+    );
+    assert_ne!(
+        signature("secret", 1543665600, br#"{"id":1}"#),
+        signature("secret", 1543665601, br#"{"id":1}"#)
+    );
+}
+
+#[test]
+fn test_parse_url() {
+    assert!(parse_url("http://localhost:8080/hook").is_ok());
+    assert!(parse_url("https://hooks.example.com/auth").is_ok());
+    assert!(parse_url("tarpc://127.0.0.1:10002").is_err());
+    assert!(parse_url("ftp://localhost/hook").is_err());
+    let long = format!("http://localhost/{}", "a".repeat(MAX_URL_LENGTH));
+    assert!(parse_url(&long).is_err());
+}
+
+#[test]
+fn test_replay_budget() {
+    use crate::store::memory::MemoryStore;
+    use auth_client::{Event, EventKind, EventType};
+
+    // Connections are accepted by the kernel, but nobody ever responds
+    let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", silent.local_addr().unwrap());
+    let store = MemoryStore::default();
+    let webhook = store
+        .insert_webhook(&url, "secret", &[EventType::UserDeleted])
+        .unwrap();
+    for id in 1..3 {
+        let event = Event {
+            id,
+            created_at: 0,
+            kind: EventKind::UserDeleted { id: 1.into() },
+        };
+        store
+            .insert_dead_letter(webhook.id, &event, 5, "refused")
+            .unwrap();
+    }
+
+    // The first letter uses up the time, the second is left for later
+    let start = Instant::now();
+    let report = replay_within(&store, None, Duration::from_millis(200)).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(REPLAY_BUDGET_SECS));
+    let expected = ReplayReport {
+        delivered: 0,
+        failed: 1,
+        pending: 1,
+    };
+    assert_eq!(expected, report);
+    assert_eq!(2, store.fetch_dead_letters(None).unwrap().len());
+}